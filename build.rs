@@ -2,12 +2,40 @@ use std::fs::File;
 use std::io::Write;
 use std::path::Path;
 use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// 无法获取的构建信息使用的值，例如从源码压缩包构建时没有git
+const UNKNOWN: &str = "unknown";
 
 fn main() {
     let version = get_git_version();
     let mut f =
         File::create(Path::new(&std::env::var("OUT_DIR").unwrap()).join("VERSION")).unwrap();
-    f.write_all(version.trim().as_bytes()).unwrap()
+    f.write_all(version.trim().as_bytes()).unwrap();
+
+    let hash = git(&["rev-parse", "--short", "HEAD"]);
+    let branch = git(&["rev-parse", "--abbrev-ref", "HEAD"]);
+    // 只有成功执行了 git status 才知道工作区是否有修改
+    let dirty = Command::new("git")
+        .args(["status", "--porcelain"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| (!output.stdout.is_empty()).to_string());
+    println!(
+        "cargo:rustc-env=BUILD_GIT_HASH={}",
+        hash.as_deref().unwrap_or(UNKNOWN)
+    );
+    println!(
+        "cargo:rustc-env=BUILD_GIT_BRANCH={}",
+        branch.as_deref().unwrap_or(UNKNOWN)
+    );
+    println!(
+        "cargo:rustc-env=BUILD_GIT_DIRTY={}",
+        dirty.as_deref().unwrap_or(UNKNOWN)
+    );
+    println!("cargo:rustc-env=BUILD_TIMESTAMP={}", build_timestamp());
+    println!("cargo:rustc-env=BUILD_RUSTC={}", rustc_version());
 }
 
 // 定义一个函数，用于获取git版本号
@@ -16,23 +44,55 @@ fn get_git_version() -> String {
     let version = env!("CARGO_PKG_VERSION").to_string();
 
     // 执行git命令，获取git描述信息
-    let child = Command::new("git").args(["describe", "--always"]).output();
-
-    // 匹配git命令的执行结果
-    match child {
-        // 如果执行成功
-        Ok(child) => {
-            // 将git描述信息转换为字符串
-            let buf = String::from_utf8(child.stdout).expect("failed to read stdout");
-            // 将Cargo包的版本号和git描述信息拼接起来
-            version + "-" + &buf
-        }
-        // 如果执行失败
+    match git(&["describe", "--always"]) {
+        // 将Cargo包的版本号和git描述信息拼接起来
+        Some(describe) => version + "-" + &describe,
+        // 没有git或不在git仓库中时只使用Cargo包的版本号
+        None => version,
+    }
+}
+
+/// 执行git命令并返回输出，git不存在、命令失败或输出为空时返回 `None`
+fn git(args: &[&str]) -> Option<String> {
+    let output = match Command::new("git").args(args).output() {
+        Ok(output) => output,
         Err(why) => {
-            // 打印错误信息
-            eprintln!("`git describe` err: {}", why);
-            // 返回Cargo包的版本号
-            version
+            eprintln!("`git {}` err: {}", args.join(" "), why);
+            return None;
         }
+    };
+    if !output.status.success() {
+        return None;
     }
-}
\ No newline at end of file
+    let stdout = String::from_utf8(output.stdout).ok()?;
+    let stdout = stdout.trim();
+    (!stdout.is_empty()).then(|| stdout.to_string())
+}
+
+/// 构建时间的unix时间戳，设置了 `SOURCE_DATE_EPOCH` 时使用它，使构建可以复现
+fn build_timestamp() -> String {
+    if let Some(epoch) = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.trim().parse::<u64>().ok())
+    {
+        return epoch.to_string();
+    }
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs().to_string())
+        .unwrap_or_else(|_| UNKNOWN.to_string())
+}
+
+/// 编译使用的rustc版本，例如 `rustc 1.88.0 (6b00bc388 2025-06-23)`
+fn rustc_version() -> String {
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    Command::new(rustc)
+        .arg("--version")
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|version| version.trim().to_string())
+        .filter(|version| !version.is_empty())
+        .unwrap_or_else(|| UNKNOWN.to_string())
+}
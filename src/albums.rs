@@ -0,0 +1,173 @@
+//! 最近收到的相册消息
+//!
+//! 相册中的每张图片是一条单独的消息，回复相册并 @机器人 zip 时只能拿到被回复的那一条。
+//! 这里按 `media_group_id` 记录最近收到的相册消息，打包时找回同一相册的其他消息。
+//! 相册的消息陆续到达，最后一条到达后 [`SETTLE`] 内仍可能有新的消息，打包前需要等待。
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use teloxide::types::{ChatId, Message};
+
+/// 相册的最后一条消息到达后，等待这么久没有新的消息才认为相册已经完整
+pub const SETTLE: Duration = Duration::from_secs(1);
+/// 相册保留的时间，超过后回复它只能打包被回复的消息
+const KEEP: Duration = Duration::from_secs(30 * 60);
+/// 一个相册最多包含的消息数量，telegram的限制为10条
+const MAX_ALBUM_SIZE: usize = 10;
+
+#[derive(Debug)]
+struct Album {
+    messages: Vec<Message>,
+    last_seen: Instant,
+}
+
+#[derive(Debug, Default)]
+pub struct RecentAlbums {
+    albums: Mutex<HashMap<(ChatId, String), Album>>,
+}
+
+impl RecentAlbums {
+    pub fn new() -> Self {
+        RecentAlbums::default()
+    }
+
+    /// 记录属于相册的消息，不属于相册的消息忽略
+    pub fn record(&self, msg: &Message) {
+        self.record_at(msg, Instant::now());
+    }
+
+    fn record_at(&self, msg: &Message, now: Instant) {
+        let Some(group) = msg.media_group_id() else {
+            return;
+        };
+        let mut albums = self.albums.lock().unwrap();
+        albums.retain(|_, album| now.duration_since(album.last_seen) < KEEP);
+        let album = albums
+            .entry((msg.chat.id, group.to_string()))
+            .or_insert_with(|| Album {
+                messages: Vec::new(),
+                last_seen: now,
+            });
+        album.last_seen = now;
+        if album.messages.len() < MAX_ALBUM_SIZE
+            && album.messages.iter().all(|existing| existing.id != msg.id)
+        {
+            album.messages.push(msg.clone());
+        }
+    }
+
+    /// 相册还可能有消息没有到达时，需要再等待的时间
+    pub fn settle_time(&self, msg: &Message) -> Option<Duration> {
+        self.settle_time_at(msg, Instant::now())
+    }
+
+    fn settle_time_at(&self, msg: &Message, now: Instant) -> Option<Duration> {
+        let group = msg.media_group_id()?;
+        let albums = self.albums.lock().unwrap();
+        let album = albums.get(&(msg.chat.id, group.to_string()))?;
+        SETTLE.checked_sub(now.duration_since(album.last_seen))
+    }
+
+    /// `msg` 所在相册中已经收到的所有消息，按消息id排列
+    ///
+    /// 不属于相册或相册已经过期时只有 `msg` 自己。
+    pub fn album(&self, msg: &Message) -> Vec<Message> {
+        let mut messages = msg
+            .media_group_id()
+            .and_then(|group| {
+                let albums = self.albums.lock().unwrap();
+                albums
+                    .get(&(msg.chat.id, group.to_string()))
+                    .map(|album| album.messages.clone())
+            })
+            .unwrap_or_default();
+        if messages.iter().all(|existing| existing.id != msg.id) {
+            messages.push(msg.clone());
+        }
+        messages.sort_by_key(|msg| msg.id.0);
+        messages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+    use serde_json::json;
+
+    fn album_message(id: i32, group: &str) -> Message {
+        let photo = json!([{
+            "file_id": format!("photo_{}", id),
+            "file_unique_id": format!("unique_{}", id),
+            "width": 800,
+            "height": 600,
+        }]);
+        test_util::message(id, 0, json!({"photo": photo, "media_group_id": group}))
+    }
+
+    #[test]
+    fn finds_siblings_in_order() {
+        let albums = RecentAlbums::new();
+        let now = Instant::now();
+        for id in [12, 10, 11] {
+            albums.record_at(&album_message(id, "a"), now);
+        }
+        albums.record_at(&album_message(20, "b"), now);
+        albums.record_at(&test_util::text(21, "not an album"), now);
+
+        let ids = albums
+            .album(&album_message(11, "a"))
+            .iter()
+            .map(|msg| msg.id.0)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, [10, 11, 12]);
+    }
+
+    #[test]
+    fn unknown_album_contains_only_target() {
+        let albums = RecentAlbums::new();
+        let target = album_message(5, "missing");
+        assert_eq!(albums.album(&target).len(), 1);
+        let target = test_util::text(6, "plain");
+        assert_eq!(albums.album(&target).len(), 1);
+        assert_eq!(albums.settle_time(&target), None);
+    }
+
+    #[test]
+    fn waits_until_album_settles() {
+        let albums = RecentAlbums::new();
+        let now = Instant::now();
+        albums.record_at(&album_message(1, "a"), now);
+        let target = album_message(1, "a");
+        assert_eq!(albums.settle_time_at(&target, now), Some(SETTLE));
+        let later = now + Duration::from_millis(400);
+        assert_eq!(
+            albums.settle_time_at(&target, later),
+            Some(SETTLE - Duration::from_millis(400))
+        );
+        // 新的消息到达后重新开始等待
+        albums.record_at(&album_message(2, "a"), later);
+        assert_eq!(albums.settle_time_at(&target, later), Some(SETTLE));
+        assert_eq!(albums.settle_time_at(&target, later + SETTLE * 2), None);
+    }
+
+    #[test]
+    fn expired_albums_are_dropped() {
+        let albums = RecentAlbums::new();
+        let now = Instant::now();
+        albums.record_at(&album_message(1, "a"), now);
+        albums.record_at(&album_message(2, "b"), now + KEEP);
+        assert_eq!(albums.album(&album_message(3, "a")).len(), 1);
+        assert_eq!(albums.album(&album_message(3, "b")).len(), 2);
+    }
+
+    #[test]
+    fn duplicates_are_recorded_once() {
+        let albums = RecentAlbums::new();
+        let now = Instant::now();
+        albums.record_at(&album_message(1, "a"), now);
+        albums.record_at(&album_message(1, "a"), now);
+        assert_eq!(albums.album(&album_message(1, "a")).len(), 1);
+    }
+}
@@ -0,0 +1,103 @@
+//! 命令的简写
+//!
+//! 内置 `/sc`（`/startcollect`）和 `/ec`（`/stopcollect`），`COMMAND_ALIASES` 可以追加更多，
+//! 以逗号分隔，每项为 `简写=命令`，例如 `p=pack,st=settings`。
+//! 收到消息后先把简写替换为完整的命令再解析，原来的命令不受影响。
+
+use std::borrow::Cow;
+use teloxide::types::BotCommand;
+
+/// 内置的简写
+const BUILTIN_ALIASES: &str = "sc=startcollect,ec=stopcollect";
+
+#[derive(Debug, Clone, Default)]
+pub struct CommandAliases {
+    /// 简写和对应的命令，都不带 `/`，小写
+    aliases: Vec<(String, String)>,
+}
+
+impl CommandAliases {
+    /// 内置的简写加上 `COMMAND_ALIASES` 中的简写
+    ///
+    /// `is_command` 判断一个名称是否是已有的命令；简写与已有命令或其他简写重名、
+    /// 指向不存在的命令或格式不正确时 panic。
+    pub fn from_env(is_command: impl Fn(&str) -> bool) -> Self {
+        let value = format!(
+            "{},{}",
+            BUILTIN_ALIASES,
+            std::env::var("COMMAND_ALIASES").unwrap_or_default()
+        );
+        let mut aliases: Vec<(String, String)> = Vec::new();
+        for entry in value.split(',').filter(|entry| !entry.trim().is_empty()) {
+            let (alias, command) = entry.split_once('=').unwrap_or_else(|| {
+                panic!(
+                    "COMMAND_ALIASES entry {:?} is invalid, expected alias=command",
+                    entry
+                )
+            });
+            let alias = alias.trim().trim_start_matches('/').to_lowercase();
+            let command = command.trim().trim_start_matches('/').to_lowercase();
+            // telegram的命令只能包含小写字母、数字和下划线，最长32个字符
+            let valid_name = |name: &str| {
+                (1..=32).contains(&name.len())
+                    && name
+                        .chars()
+                        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+            };
+            if !valid_name(&alias) {
+                panic!(
+                    "COMMAND_ALIASES alias {:?} is not a valid command name",
+                    alias
+                );
+            }
+            if is_command(&alias) || aliases.iter().any(|(existing, _)| *existing == alias) {
+                panic!(
+                    "COMMAND_ALIASES alias {:?} conflicts with an existing command",
+                    alias
+                );
+            }
+            if !is_command(&command) {
+                panic!("COMMAND_ALIASES refers to an unknown command {:?}", command);
+            }
+            aliases.push((alias, command));
+        }
+        CommandAliases { aliases }
+    }
+
+    /// 将消息开头的简写替换为完整的命令，保留 `@机器人` 和参数
+    pub fn expand<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let Some(rest) = text.strip_prefix('/') else {
+            return Cow::Borrowed(text);
+        };
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        let (name, args) = rest.split_at(end);
+        let (name, mention) = match name.split_once('@') {
+            Some((name, bot)) => (name, Some(bot)),
+            None => (name, None),
+        };
+        let Some((_, command)) = self
+            .aliases
+            .iter()
+            .find(|(alias, _)| alias.eq_ignore_ascii_case(name))
+        else {
+            return Cow::Borrowed(text);
+        };
+        Cow::Owned(match mention {
+            Some(bot) => format!("/{}@{}{}", command, bot, args),
+            None => format!("/{}{}", command, args),
+        })
+    }
+
+    /// 在 `commands` 之后加上简写，用于注册命令菜单；隐藏命令的简写不会出现在菜单中
+    pub fn with_menu_entries(&self, mut commands: Vec<BotCommand>) -> Vec<BotCommand> {
+        for (alias, command) in &self.aliases {
+            if commands
+                .iter()
+                .any(|listed| listed.command.trim_start_matches('/') == command)
+            {
+                commands.push(BotCommand::new(alias, format!("同 /{}", command)));
+            }
+        }
+        commands
+    }
+}
@@ -0,0 +1,458 @@
+use crate::VERSION;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use teloxide::types::ChatId;
+use uuid::Uuid;
+use zip::ZipWriter;
+use zip::write::FileOptions;
+
+/// 机器人上传文件的大小上限
+pub const UPLOAD_LIMIT: u64 = 50 * 1024 * 1024;
+
+/// 分卷时每卷图片的总大小上限，留出一些给压缩包自身的开销
+pub const MAX_VOLUME_SIZE: u64 = 49 * 1024 * 1024;
+
+/// 压缩包中文件的压缩方式
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// deflate 压缩
+    #[default]
+    Deflated,
+    /// 只存储不压缩，图片本身已经压缩过，这样打包更快
+    Stored,
+}
+
+impl Compression {
+    /// 解析 `/compression` 的参数或 `DEFAULT_COMPRESSION`
+    pub fn parse(arg: &str) -> Option<Self> {
+        match arg.trim().to_lowercase().as_str() {
+            "deflate" | "deflated" => Some(Compression::Deflated),
+            "store" | "stored" | "none" => Some(Compression::Stored),
+            _ => None,
+        }
+    }
+
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Compression::Deflated => "deflate 压缩",
+            Compression::Stored => "仅存储",
+        }
+    }
+
+    fn method(&self) -> zip::CompressionMethod {
+        match self {
+            Compression::Deflated => zip::CompressionMethod::Deflated,
+            Compression::Stored => zip::CompressionMethod::Stored,
+        }
+    }
+}
+
+/// 用户通过 /comment 设置的压缩包注释最多包含的字符数
+pub const MAX_NOTE_CHARS: usize = 500;
+
+/// 压缩包的元数据
+pub enum ArchiveMetadata {
+    /// 写入压缩包注释，文件时间为打包时的时间
+    Comment(String),
+    /// 将所有时间戳置零，只写入用户设置的注释，相同的输入会得到完全相同的压缩包
+    Reproducible(Option<String>),
+}
+
+impl ArchiveMetadata {
+    /// 根据会话设置生成元数据，注释包含版本、任务id、会话id的哈希和打包时间
+    ///
+    /// `note` 为用户设置的注释，写在最前面。
+    pub fn for_job(reproducible: bool, job_id: Uuid, chat_id: ChatId, note: Option<&str>) -> Self {
+        if reproducible {
+            return ArchiveMetadata::Reproducible(note.map(str::to_string));
+        }
+        let mut hasher = std::hash::DefaultHasher::new();
+        chat_id.hash(&mut hasher);
+        let generated = format!(
+            "telegram-images-bot {} job={} chat={:016x} time={}",
+            VERSION,
+            job_id,
+            hasher.finish(),
+            chrono::Local::now().to_rfc3339()
+        );
+        ArchiveMetadata::Comment(match note {
+            Some(note) => format!("{}\n\n{}", note, generated),
+            None => generated,
+        })
+    }
+}
+
+/// 压缩包说明文件的名称
+pub const README_NAME: &str = "README.txt";
+
+/// 压缩包说明文件中记录的来源信息
+pub struct ReadmeInfo<'a> {
+    /// 会话的名称，例如 `@username (123456)`
+    pub chat: &'a str,
+    /// 最早和最晚收到的图片的时间
+    pub collected: (
+        chrono::DateTime<chrono::Local>,
+        chrono::DateTime<chrono::Local>,
+    ),
+    pub image_count: usize,
+    /// 图片的总大小，已格式化
+    pub total_size: String,
+    /// 分卷时为第几卷和总卷数，从1开始
+    pub part: Option<(usize, usize)>,
+    /// 群组会话中每个人贡献的数量，已格式化
+    pub contributors: Option<String>,
+    /// 语音和音频的文件名、时长和大小，已格式化，每行一个
+    pub audio: Option<String>,
+    /// 视频消息的文件名、时长、大小和尺寸，已格式化，每行一个
+    pub video_notes: Option<String>,
+    /// 贴纸所在的贴纸包，已格式化
+    pub sticker_sets: Option<String>,
+}
+
+impl ReadmeInfo<'_> {
+    /// 生成说明文件的内容
+    pub fn render(&self) -> String {
+        const TIME_FORMAT: &str = "%Y-%m-%d %H:%M";
+        let mut text = format!(
+            "由 telegram-images-bot {} 生成\n\n收集时间：{} 至 {}\n会话：{}\n图片数量：{}\n图片总大小：{}\n",
+            VERSION.trim(),
+            self.collected.0.format(TIME_FORMAT),
+            self.collected.1.format(TIME_FORMAT),
+            self.chat,
+            self.image_count,
+            self.total_size
+        );
+        if let Some((part, total)) = self.part {
+            text.push_str(&format!("分卷：第 {}/{} 卷\n", part, total));
+        }
+        if let Some(contributors) = &self.contributors {
+            text.push_str(&format!("贡献者：{}\n", contributors));
+        }
+        if let Some(audio) = &self.audio {
+            text.push_str(&format!("语音和音频：\n{}\n", audio));
+        }
+        if let Some(video_notes) = &self.video_notes {
+            text.push_str(&format!("视频消息：\n{}\n", video_notes));
+        }
+        if let Some(sticker_sets) = &self.sticker_sets {
+            text.push_str(&format!("贴纸包：{}\n", sticker_sets));
+        }
+        text
+    }
+}
+
+/// 将 `files` 和内存中的 `entries`（文件名和内容）以 `compression` 方式打包到 `dst_file`
+///
+/// `folder` 返回文件在压缩包中所在的文件夹，为 `None` 时放在根目录。
+/// 每写入一个图片文件调用一次 `on_file`。
+pub fn create_zip(
+    files: &[PathBuf],
+    entries: &[(&str, &[u8])],
+    dst_file: &Path,
+    metadata: ArchiveMetadata,
+    compression: Compression,
+    folder: impl Fn(&Path) -> Option<&'static str>,
+    mut on_file: impl FnMut(),
+) -> zip::result::ZipResult<()> {
+    let file = File::create(dst_file)?;
+    let mut zip = ZipWriter::new(file);
+    let mut options = FileOptions::<()>::default()
+        .compression_method(compression.method())
+        .unix_permissions(0o755);
+    match metadata {
+        ArchiveMetadata::Comment(comment) => zip.set_comment(comment),
+        ArchiveMetadata::Reproducible(note) => {
+            if let Some(note) = note {
+                zip.set_comment(note);
+            }
+            options = options.last_modified_time(zip::DateTime::default());
+        }
+    }
+
+    let mut buffer = Vec::new();
+    for path in files {
+        let name = path.file_name().unwrap().to_str().unwrap();
+        let name = match folder(path) {
+            Some(folder) => format!("{}/{}", folder, name),
+            None => name.to_string(),
+        };
+
+        if path.is_file() {
+            zip.start_file(name, options)?;
+            let mut f = File::open(path)?;
+            f.read_to_end(&mut buffer)?;
+            zip.write_all(&buffer)?;
+            buffer.clear();
+        }
+        on_file();
+    }
+    for (name, content) in entries {
+        zip.start_file(*name, options)?;
+        zip.write_all(content)?;
+    }
+    zip.finish()?;
+    Ok(())
+}
+
+/// 按顺序将文件分卷，`files` 为文件路径和大小
+///
+/// 每卷最多 `max_items` 个文件，且总大小不超过 `max_size`，先达到哪个限制就在哪里分卷。
+/// 单个文件超过 `max_size` 时独占一卷。
+pub fn split_volumes(
+    files: &[(PathBuf, u64)],
+    max_items: Option<usize>,
+    max_size: u64,
+) -> Vec<Vec<PathBuf>> {
+    let mut volumes = Vec::new();
+    let mut current = Vec::new();
+    let mut current_size = 0;
+    for (path, size) in files {
+        let full = max_items.is_some_and(|max| current.len() >= max);
+        if !current.is_empty() && (full || current_size + size > max_size) {
+            volumes.push(std::mem::take(&mut current));
+            current_size = 0;
+        }
+        current.push(path.clone());
+        current_size += size;
+    }
+    if !current.is_empty() {
+        volumes.push(current);
+    }
+    volumes
+}
+
+/// 每张图片单独打包时各压缩包的文件名，与图片同名，例如 `image_01.zip`
+///
+/// 同名不同扩展名的文件（例如动态贴纸和它的预览图）保留扩展名以免重名，例如 `sticker_1_png.zip`。
+pub fn single_file_zip_names(volumes: &[Vec<PathBuf>]) -> Vec<String> {
+    let stem = |path: &PathBuf| {
+        path.file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    };
+    let stems = volumes
+        .iter()
+        .filter_map(|volume| volume.first())
+        .map(stem)
+        .collect::<Vec<_>>();
+    volumes
+        .iter()
+        .zip(&stems)
+        .map(|(volume, name)| {
+            let duplicated = stems.iter().filter(|other| *other == name).count() > 1;
+            match volume.first() {
+                Some(path) if duplicated => format!(
+                    "{}.zip",
+                    path.file_name()
+                        .unwrap_or_default()
+                        .to_string_lossy()
+                        .replace('.', "_")
+                ),
+                _ => format!("{}.zip", name),
+            }
+        })
+        .collect()
+}
+
+/// 分卷的文件名，例如 `name_01.zip`，序号宽度由总卷数决定，只有一卷时不加序号
+pub fn volume_name(base: &str, index: usize, total: usize) -> String {
+    if total <= 1 {
+        return format!("{}.zip", base);
+    }
+    let width = total.to_string().len().max(2);
+    format!("{}_{:0width$}.zip", base, index + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 在 `dir` 中创建 `count` 个图片文件，返回它们的路径
+    fn write_images(dir: &Path, count: usize) -> Vec<PathBuf> {
+        (1..=count)
+            .map(|index| {
+                let path = dir.join(crate::naming::image_file_name(index, count, "jpg"));
+                std::fs::write(&path, format!("image {}", index)).unwrap();
+                path
+            })
+            .collect()
+    }
+
+    fn build(files: &[PathBuf], dst: &Path, metadata: ArchiveMetadata) {
+        create_zip(
+            files,
+            &[("captions.txt", b"hello".as_slice())],
+            dst,
+            metadata,
+            Compression::Deflated,
+            |_| None,
+            || {},
+        )
+        .unwrap();
+    }
+
+    fn read_comment(path: &Path) -> String {
+        let archive = zip::ZipArchive::new(File::open(path).unwrap()).unwrap();
+        String::from_utf8(archive.comment().to_vec()).unwrap()
+    }
+
+    #[test]
+    fn reproducible_archives_are_identical() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = write_images(dir.path(), 3);
+        let first = dir.path().join("first.zip");
+        let second = dir.path().join("second.zip");
+        build(&files, &first, ArchiveMetadata::Reproducible(None));
+        // 打包时间不同也不影响结果
+        std::thread::sleep(std::time::Duration::from_millis(1100));
+        build(&files, &second, ArchiveMetadata::Reproducible(None));
+
+        assert_eq!(
+            std::fs::read(&first).unwrap(),
+            std::fs::read(&second).unwrap()
+        );
+        assert_eq!(read_comment(&first), "");
+    }
+
+    #[test]
+    fn archive_comment_identifies_the_job() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = write_images(dir.path(), 2);
+        let dst = dir.path().join("out.zip");
+        let job_id = Uuid::new_v4();
+        build(
+            &files,
+            &dst,
+            ArchiveMetadata::for_job(false, job_id, ChatId(42), None),
+        );
+
+        let comment = read_comment(&dst);
+        assert!(comment.starts_with(&format!("telegram-images-bot {}", VERSION)));
+        assert!(comment.contains(&format!("job={}", job_id)));
+        assert!(comment.contains("chat=") && !comment.contains("chat=42 "));
+        let time = comment.split("time=").nth(1).unwrap();
+        assert!(chrono::DateTime::parse_from_rfc3339(time).is_ok());
+    }
+
+    #[test]
+    fn reproducible_mode_omits_generated_comment() {
+        match ArchiveMetadata::for_job(true, Uuid::new_v4(), ChatId(42), None) {
+            ArchiveMetadata::Reproducible(None) => {}
+            _ => panic!("reproducible archives must not carry a generated comment"),
+        }
+    }
+
+    #[test]
+    fn entries_keep_send_order_with_many_images() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = write_images(dir.path(), 120);
+        let dst = dir.path().join("out.zip");
+        build(&files, &dst, ArchiveMetadata::Reproducible(None));
+
+        let mut archive = zip::ZipArchive::new(File::open(&dst).unwrap()).unwrap();
+        let names = (0..120)
+            .map(|index| archive.by_index(index).unwrap().name().to_string())
+            .collect::<Vec<_>>();
+        let mut sorted = names.clone();
+        sorted.sort();
+        assert_eq!(names, sorted);
+        assert_eq!(names[0], "image_001.jpg");
+        assert_eq!(names[9], "image_010.jpg");
+        assert_eq!(names[119], "image_120.jpg");
+    }
+
+    #[test]
+    fn mixed_media_is_split_into_folders() {
+        use crate::download::MediaKind;
+
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image_1.jpg");
+        let audio = dir.path().join("audio_2.ogg");
+        std::fs::write(&image, b"jpg").unwrap();
+        std::fs::write(&audio, b"ogg").unwrap();
+        let kinds = [
+            (image.clone(), MediaKind::Image),
+            (audio.clone(), MediaKind::Audio),
+        ];
+        let dst = dir.path().join("out.zip");
+        create_zip(
+            &[image, audio],
+            &[("README.txt", b"readme".as_slice())],
+            &dst,
+            ArchiveMetadata::Reproducible(None),
+            Compression::Stored,
+            |path| {
+                kinds
+                    .iter()
+                    .find(|(file, _)| file == path)
+                    .map(|(_, kind)| kind.folder())
+            },
+            || {},
+        )
+        .unwrap();
+
+        let archive = zip::ZipArchive::new(File::open(&dst).unwrap()).unwrap();
+        let mut names = archive.file_names().collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(
+            names,
+            ["README.txt", "audio/audio_2.ogg", "images/image_1.jpg"]
+        );
+    }
+
+    #[test]
+    fn flat_layout_by_default() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = write_images(dir.path(), 2);
+        let dst = dir.path().join("out.zip");
+        build(&files, &dst, ArchiveMetadata::Reproducible(None));
+        let archive = zip::ZipArchive::new(File::open(&dst).unwrap()).unwrap();
+        assert!(archive.file_names().all(|name| !name.contains('/')));
+    }
+
+    #[test]
+    fn user_note_is_read_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = write_images(dir.path(), 1);
+        let note = "东京之旅 2024 📷\n第二行";
+        assert!(note.chars().count() <= MAX_NOTE_CHARS);
+
+        // 注释写在生成的信息之前
+        let dst = dir.path().join("note.zip");
+        let job_id = Uuid::new_v4();
+        build(
+            &files,
+            &dst,
+            ArchiveMetadata::for_job(false, job_id, ChatId(42), Some(note)),
+        );
+        let comment = read_comment(&dst);
+        let (user, generated) = comment.split_once("\n\n").unwrap();
+        assert_eq!(user, note);
+        assert!(generated.contains(&format!("job={}", job_id)));
+
+        // 可复现模式只写入用户的注释
+        let dst = dir.path().join("reproducible.zip");
+        build(
+            &files,
+            &dst,
+            ArchiveMetadata::for_job(true, job_id, ChatId(42), Some(note)),
+        );
+        assert_eq!(read_comment(&dst), note);
+    }
+
+    #[test]
+    fn longest_note_fits_in_comment() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = write_images(dir.path(), 1);
+        let note = "注".repeat(MAX_NOTE_CHARS);
+        let dst = dir.path().join("long.zip");
+        build(
+            &files,
+            &dst,
+            ArchiveMetadata::for_job(false, Uuid::new_v4(), ChatId(42), Some(&note)),
+        );
+        assert!(read_comment(&dst).starts_with(&format!("{}\n\n", note)));
+    }
+}
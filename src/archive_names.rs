@@ -0,0 +1,168 @@
+//! 每个会话用过的压缩包名称
+//!
+//! 同一个会话再次使用相同的名称时自动加上序号，例如 `holiday (2)`，避免重新处理、
+//! SFTP 上传等按名称保存的结果互相覆盖。记录的是分卷之前的名称，分卷的编号加在序号之后，
+//! 例如 `holiday (2)_01.zip`。名称持久化到文件中，每行为会话id和名称，用制表符分隔。
+
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::path::PathBuf;
+use teloxide::types::ChatId;
+use tokio::sync::Mutex;
+
+#[derive(Debug)]
+pub struct ArchiveNames {
+    path: PathBuf,
+    names: Mutex<HashMap<ChatId, HashSet<String>>>,
+}
+
+impl ArchiveNames {
+    /// 从文件加载，文件不存在时从空记录开始
+    pub fn load(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let mut names = HashMap::<ChatId, HashSet<String>>::new();
+        for line in std::fs::read_to_string(&path).unwrap_or_default().lines() {
+            let Some((chat_id, name)) = line.split_once('\t') else {
+                continue;
+            };
+            if let Ok(chat_id) = chat_id.trim().parse() {
+                names
+                    .entry(ChatId(chat_id))
+                    .or_default()
+                    .insert(name.to_string());
+            }
+        }
+        ArchiveNames {
+            path,
+            names: Mutex::new(names),
+        }
+    }
+
+    /// 保存名称的文件
+    pub fn path(&self) -> &std::path::Path {
+        &self.path
+    }
+
+    /// 会话用过 `name` 时返回下一次会使用的名称
+    pub async fn suggest(&self, chat_id: ChatId, name: &str) -> Option<String> {
+        let names = self.names.lock().await;
+        let used = names.get(&chat_id)?;
+        used.contains(name).then(|| with_suffix(name, used))
+    }
+
+    /// 记录本次使用的名称并返回
+    ///
+    /// 会话用过 `name` 时，`overwrite` 为 `true` 则沿用原来的名称，否则加上序号。
+    pub async fn claim(&self, chat_id: ChatId, name: &str, overwrite: bool) -> String {
+        let mut names = self.names.lock().await;
+        let used = names.entry(chat_id).or_default();
+        let name = match used.contains(name) {
+            true if overwrite => return name.to_string(),
+            true => with_suffix(name, used),
+            false => name.to_string(),
+        };
+        used.insert(name.clone());
+
+        let appended = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| writeln!(file, "{}\t{}", chat_id.0, name));
+        if let Err(why) = appended {
+            log::error!(
+                "无法保存压缩包名称 {} 到 {}: {}",
+                name,
+                self.path.display(),
+                why
+            );
+        }
+        name
+    }
+}
+
+/// 在 `name` 后加上 ` (2)`、` (3)` 等序号，返回第一个没有用过的名称
+pub fn with_suffix(name: &str, used: &HashSet<String>) -> String {
+    (2..)
+        .map(|n| format!("{} ({})", name, n))
+        .find(|candidate| !used.contains(candidate))
+        .expect("there is always an unused suffix")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::volume_name;
+
+    #[test]
+    fn suffix_skips_used_numbers() {
+        let used = HashSet::from(["holiday".to_string(), "holiday (2)".to_string()]);
+        assert_eq!(with_suffix("holiday", &used), "holiday (3)");
+        assert_eq!(with_suffix("trip", &used), "trip (2)");
+    }
+
+    #[tokio::test]
+    async fn repeated_names_get_suffix() {
+        let dir = tempfile::tempdir().unwrap();
+        let names = ArchiveNames::load(dir.path().join("names.txt"));
+        let chat = ChatId(1);
+        assert_eq!(names.suggest(chat, "holiday").await, None);
+        assert_eq!(names.claim(chat, "holiday", false).await, "holiday");
+        assert_eq!(
+            names.suggest(chat, "holiday").await.as_deref(),
+            Some("holiday (2)")
+        );
+        assert_eq!(names.claim(chat, "holiday", false).await, "holiday (2)");
+        assert_eq!(names.claim(chat, "holiday", false).await, "holiday (3)");
+        // 明确选择覆盖时沿用原来的名称
+        assert_eq!(names.claim(chat, "holiday", true).await, "holiday");
+        // 其他会话不受影响
+        assert_eq!(names.claim(ChatId(2), "holiday", false).await, "holiday");
+    }
+
+    #[tokio::test]
+    async fn names_survive_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("names.txt");
+        let names = ArchiveNames::load(&path);
+        names.claim(ChatId(-100), "holiday", false).await;
+        names.claim(ChatId(-100), "holiday", false).await;
+        // 无法解析的行被忽略
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| writeln!(file, "garbage\nabc\tname"))
+            .unwrap();
+
+        let names = ArchiveNames::load(&path);
+        assert_eq!(
+            names.claim(ChatId(-100), "holiday", false).await,
+            "holiday (3)"
+        );
+        assert_eq!(names.suggest(ChatId(-100), "name").await, None);
+    }
+
+    #[tokio::test]
+    async fn suffix_comes_before_volume_number() {
+        let dir = tempfile::tempdir().unwrap();
+        let names = ArchiveNames::load(dir.path().join("names.txt"));
+        let chat = ChatId(1);
+        let first = names.claim(chat, "holiday", false).await;
+        let second = names.claim(chat, "holiday", false).await;
+        let volumes = |base: &str, total: usize| {
+            (0..total)
+                .map(|index| volume_name(base, index, total))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(volumes(&first, 2), ["holiday_01.zip", "holiday_02.zip"]);
+        assert_eq!(
+            volumes(&second, 2),
+            ["holiday (2)_01.zip", "holiday (2)_02.zip"]
+        );
+        assert_eq!(volumes(&second, 1), ["holiday (2).zip"]);
+        // 分卷的文件名不会与另一次打包的文件名相同
+        let third = names.claim(chat, "holiday", false).await;
+        let all = [volumes(&first, 12), volumes(&second, 1), volumes(&third, 3)].concat();
+        assert_eq!(all.iter().collect::<HashSet<_>>().len(), all.len());
+        assert_eq!(volumes(&first, 12)[11], "holiday_12.zip");
+    }
+}
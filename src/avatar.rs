@@ -0,0 +1,80 @@
+//! /avatar：打包用户的历史头像或会话的头像
+//!
+//! 不经过收集会话，直接下载并发送一个小压缩包。
+
+use teloxide::prelude::*;
+use teloxide::types::{FileId, Recipient};
+
+/// 要获取头像的对象
+#[derive(Debug, Clone)]
+pub enum Target {
+    /// 用户和显示的名称
+    User(UserId, String),
+    /// 群组或频道，也可以是与机器人互动过的用户的私聊
+    Chat(Recipient),
+}
+
+impl Target {
+    /// 按回复的消息或参数确定对象，两者都没有时返回 `None`
+    ///
+    /// 参数可以是用户id、以 `-100` 开头的会话id或公开会话的 `@username`。
+    pub fn resolve(msg: &Message, arg: &str) -> Option<Self> {
+        let arg = arg.trim();
+        if arg.is_empty() {
+            let reply = msg.reply_to_message()?;
+            // 匿名管理员和频道身份的消息没有真正的发送者，使用所代表的会话
+            if let Some(sender_chat) = &reply.sender_chat {
+                return Some(Target::Chat(sender_chat.id.into()));
+            }
+            let user = reply.from.as_ref()?;
+            return Some(Target::User(user.id, user.full_name()));
+        }
+        match arg.parse::<i64>() {
+            Ok(id) => match u64::try_from(id) {
+                Ok(user_id) => Some(Target::User(UserId(user_id), id.to_string())),
+                Err(_) => Some(Target::Chat(ChatId(id).into())),
+            },
+            Err(_) => Some(Target::Chat(Recipient::ChannelUsername(format!(
+                "@{}",
+                arg.trim_start_matches('@')
+            )))),
+        }
+    }
+}
+
+/// 对象的名称和最多 `limit` 张头像的最大尺寸，没有公开的头像时列表为空
+pub async fn collect(
+    bot: &Bot,
+    target: Target,
+    limit: u8,
+) -> Result<(String, Vec<FileId>), Box<dyn std::error::Error + Send + Sync>> {
+    match target {
+        Target::User(user_id, name) => {
+            let photos = bot
+                .get_user_profile_photos(user_id)
+                .limit(limit.clamp(1, 100))
+                .await?;
+            let file_ids = photos
+                .photos
+                .iter()
+                .filter_map(|sizes| sizes.iter().max_by_key(|p| p.width * p.height))
+                .map(|photo| photo.file.id.clone())
+                .collect();
+            Ok((name, file_ids))
+        }
+        Target::Chat(recipient) => {
+            let chat = bot.get_chat(recipient).await?;
+            let name = chat
+                .title()
+                .map(str::to_string)
+                .or_else(|| chat.username().map(|username| format!("@{}", username)))
+                .or_else(|| chat.first_name().map(str::to_string))
+                .unwrap_or_else(|| chat.id.to_string());
+            let file_ids = chat
+                .photo
+                .map(|photo| vec![photo.big_file_id])
+                .unwrap_or_default();
+            Ok((name, file_ids))
+        }
+    }
+}
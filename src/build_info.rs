@@ -0,0 +1,61 @@
+//! 编译时由 build.rs 记录的构建信息
+//!
+//! 没有git（例如从源码压缩包构建）时，提交、分支和是否有未提交的修改为 `unknown`。
+
+use chrono::{DateTime, Utc};
+
+/// 本程序的构建信息
+#[derive(Debug, Clone, Copy)]
+pub struct BuildInfo {
+    /// Cargo包的版本号和 `git describe`，与 [`crate::VERSION`] 相同
+    pub version: &'static str,
+    /// 提交的短哈希
+    pub git_hash: &'static str,
+    pub git_branch: &'static str,
+    /// 构建时工作区是否有未提交的修改：`true`、`false` 或 `unknown`
+    pub git_dirty: &'static str,
+    /// 构建时间的unix时间戳，设置了 `SOURCE_DATE_EPOCH` 时为它的值
+    pub timestamp: &'static str,
+    pub rustc: &'static str,
+}
+
+pub const BUILD: BuildInfo = BuildInfo {
+    version: crate::VERSION,
+    git_hash: env!("BUILD_GIT_HASH"),
+    git_branch: env!("BUILD_GIT_BRANCH"),
+    git_dirty: env!("BUILD_GIT_DIRTY"),
+    timestamp: env!("BUILD_TIMESTAMP"),
+    rustc: env!("BUILD_RUSTC"),
+};
+
+impl BuildInfo {
+    /// 构建时间，无法解析时为 `None`
+    pub fn built_at(&self) -> Option<DateTime<Utc>> {
+        DateTime::from_timestamp(self.timestamp.parse().ok()?, 0)
+    }
+
+    /// /version 的回复，每项一行，检查到新版本时在版本后说明
+    pub fn describe(&self) -> String {
+        let commit = match self.git_dirty {
+            "true" => format!("{}（有未提交的修改）", self.git_hash),
+            _ => self.git_hash.to_string(),
+        };
+        let built_at = self
+            .built_at()
+            .map_or(self.timestamp.to_string(), |built_at| {
+                built_at.format("%Y-%m-%d %H:%M:%S UTC").to_string()
+            });
+        let update = crate::update_check::available()
+            .map(|release| format!("（有新版本 {} 可用）", release.tag))
+            .unwrap_or_default();
+        format!(
+            "当前版本：{}{}\n提交：{}\n分支：{}\n构建时间：{}\n编译器：{}",
+            self.version.trim(),
+            update,
+            commit,
+            self.git_branch,
+            built_at,
+            self.rustc
+        )
+    }
+}
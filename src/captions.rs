@@ -0,0 +1,95 @@
+//! 压缩包中与图片同名的说明文字文件
+
+use teloxide::types::{Message, MessageEntityKind};
+
+/// 是否以及如何保存图片的说明文字
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum CaptionFiles {
+    /// 不保存
+    #[default]
+    Off,
+    /// 保存为纯文本，链接和格式会丢失
+    Plain,
+    /// 将说明文字中的链接、粗体等格式转换为 Markdown
+    Markdown,
+}
+
+impl CaptionFiles {
+    /// 解析 `/captions` 的参数
+    pub fn parse(arg: &str) -> Option<Self> {
+        match arg.trim().to_lowercase().as_str() {
+            "off" => Some(CaptionFiles::Off),
+            "plain" | "text" | "on" => Some(CaptionFiles::Plain),
+            "markdown" | "md" => Some(CaptionFiles::Markdown),
+            _ => None,
+        }
+    }
+
+    pub fn describe(&self) -> &'static str {
+        match self {
+            CaptionFiles::Off => "不保存",
+            CaptionFiles::Plain => "保存为纯文本",
+            CaptionFiles::Markdown => "保存为 Markdown，保留链接和格式",
+        }
+    }
+
+    /// 消息的说明文字文件的内容，不保存或没有说明文字时为 `None`
+    pub fn render(&self, msg: &Message) -> Option<String> {
+        let caption = msg.caption()?;
+        match self {
+            CaptionFiles::Off => None,
+            CaptionFiles::Plain => Some(caption.to_string()),
+            CaptionFiles::Markdown => Some(render_markdown(msg).unwrap_or(caption.to_string())),
+        }
+    }
+}
+
+/// 说明文字文件在压缩包中的名称，与图片同名，扩展名为 `.txt` 或 `.md`
+pub fn file_name(image_name: &str, mode: CaptionFiles) -> String {
+    let stem = image_name
+        .rsplit_once('.')
+        .map_or(image_name, |(stem, _)| stem);
+    match mode {
+        CaptionFiles::Markdown => format!("{}.md", stem),
+        _ => format!("{}.txt", stem),
+    }
+}
+
+/// 将说明文字和其中的格式转换为 Markdown，Markdown 无法表示的格式（例如下划线）保持为普通文本
+fn render_markdown(msg: &Message) -> Option<String> {
+    let caption = msg.caption()?;
+    let entities = msg.parse_caption_entities()?;
+
+    // 每个格式在开始和结束的位置插入标记，同一位置先结束内层再开始外层，保证正确嵌套
+    let mut marks = Vec::new();
+    for entity in &entities {
+        let (open, close) = match entity.kind() {
+            MessageEntityKind::Bold => ("**".to_string(), "**".to_string()),
+            MessageEntityKind::Italic => ("*".to_string(), "*".to_string()),
+            MessageEntityKind::Strikethrough => ("~~".to_string(), "~~".to_string()),
+            MessageEntityKind::Code => ("`".to_string(), "`".to_string()),
+            MessageEntityKind::Pre { language } => (
+                format!("```{}\n", language.as_deref().unwrap_or_default()),
+                "\n```".to_string(),
+            ),
+            MessageEntityKind::TextLink { url } => ("[".to_string(), format!("]({})", url)),
+            MessageEntityKind::TextMention { user } => {
+                ("[".to_string(), format!("]({})", user.url()))
+            }
+            _ => continue,
+        };
+        marks.push((entity.start(), 1, std::cmp::Reverse(entity.end()), open));
+        marks.push((entity.end(), 0, std::cmp::Reverse(entity.start()), close));
+    }
+    marks.sort_by_key(|&(at, order, other, _)| (at, order, other));
+
+    let mut text = String::with_capacity(caption.len() + marks.len() * 2);
+    let mut position = 0;
+    for (at, _, _, mark) in marks {
+        text.push_str(&caption[position..at]);
+        text.push_str(&mark);
+        position = at;
+    }
+    text.push_str(&caption[position..]);
+    Some(text)
+}
@@ -0,0 +1,324 @@
+//! 与 Telegram Desktop 导出格式兼容的 `result.json`
+//!
+//! 开启 `/exportformat telegram` 后，每个压缩包中附带一个 [`RESULT_NAME`]，结构与 Telegram Desktop
+//! 导出单个会话时的 JSON 相同：顶层是会话的 `name`、`type` 和 `id`，`messages` 中每个文件对应一条消息。
+//! 以图片形式发送的消息使用 `photo`，其余文件使用 `file` 和 `media_type`，路径是文件在压缩包中的位置。
+//! 与 Desktop 一样，`date` 为不带时区的本地时间，`date_unixtime` 为字符串形式的时间戳，
+//! 会话和频道的 id 不带 `-100` 前缀。
+
+use crate::download::MediaKind;
+use chrono::{DateTime, Utc};
+use serde_json::{Value, json};
+use teloxide::types::{Chat, ChatId, Message};
+
+/// 压缩包中导出文件的名称
+pub const RESULT_NAME: &str = "result.json";
+
+/// 导出的会话
+#[derive(Debug, Clone)]
+pub struct ChatInfo {
+    name: String,
+    /// Desktop 导出中的会话类型，例如 `personal_chat`、`private_supergroup`
+    kind: &'static str,
+    id: i64,
+}
+
+impl ChatInfo {
+    pub fn of(chat: &Chat) -> Self {
+        let public = chat.username().is_some();
+        let kind = if chat.is_private() {
+            "personal_chat"
+        } else if chat.is_group() {
+            "private_group"
+        } else if chat.is_supergroup() {
+            if public {
+                "public_supergroup"
+            } else {
+                "private_supergroup"
+            }
+        } else if public {
+            "public_channel"
+        } else {
+            "private_channel"
+        };
+        let name = match chat.title() {
+            Some(title) => title.to_string(),
+            None => full_name(chat.first_name(), chat.last_name()),
+        };
+        ChatInfo {
+            name,
+            kind,
+            id: bare_id(chat.id),
+        }
+    }
+}
+
+/// 一个文件来自的消息
+#[derive(Debug, Clone)]
+pub struct Origin {
+    id: i32,
+    date: DateTime<Utc>,
+    from: String,
+    /// 例如 `user123456` 或 `channel1234567890`
+    from_id: String,
+    /// 以图片形式发送时最大尺寸的宽和高
+    photo_size: Option<(u32, u32)>,
+    /// Desktop 导出中文件的类型，例如 `voice_message`、`sticker`
+    media_type: Option<&'static str>,
+    text: Option<String>,
+}
+
+impl Origin {
+    pub fn of(msg: &Message) -> Self {
+        let (from, from_id) = match (&msg.sender_chat, &msg.from) {
+            (Some(sender_chat), _) => (
+                sender_chat.title().map(str::to_string).unwrap_or_else(|| {
+                    full_name(sender_chat.first_name(), sender_chat.last_name())
+                }),
+                format!("channel{}", bare_id(sender_chat.id)),
+            ),
+            (None, Some(user)) => (user.full_name(), format!("user{}", user.id)),
+            (None, None) => (String::new(), String::new()),
+        };
+        let media_type = if msg.voice().is_some() {
+            Some("voice_message")
+        } else if msg.audio().is_some() {
+            Some("audio_file")
+        } else if msg.sticker().is_some() {
+            Some("sticker")
+        } else if msg.video_note().is_some() {
+            Some("video_message")
+        } else {
+            None
+        };
+        Origin {
+            id: msg.id.0,
+            date: msg.date,
+            from,
+            from_id,
+            photo_size: msg
+                .photo()
+                .and_then(|sizes| sizes.iter().max_by_key(|size| size.width * size.height))
+                .map(|size| (size.width, size.height)),
+            media_type,
+            text: None,
+        }
+    }
+
+    /// 消息发送的时间
+    pub fn date(&self) -> DateTime<Utc> {
+        self.date
+    }
+
+    /// 文件对应的说明文字
+    pub fn text(&self) -> Option<&str> {
+        self.text.as_deref()
+    }
+
+    /// 设置消息的文字，使用的是文件对应的说明文字，而不是消息本身的
+    pub fn with_text(mut self, text: Option<String>) -> Self {
+        self.text = text.filter(|text| !text.is_empty());
+        self
+    }
+}
+
+/// 生成 `result.json` 的内容，`files` 为文件的来源、在压缩包中的路径和类型
+pub fn render(chat: &ChatInfo, files: &[(&Origin, String, MediaKind)]) -> String {
+    let messages = files
+        .iter()
+        .map(|(origin, path, kind)| message(origin, path, *kind))
+        .collect::<Vec<_>>();
+    let export = json!({
+        "name": chat.name,
+        "type": chat.kind,
+        "id": chat.id,
+        "messages": messages,
+    });
+    serde_json::to_string_pretty(&export).expect("json values always serialize")
+}
+
+fn message(origin: &Origin, path: &str, kind: MediaKind) -> Value {
+    let date = origin.date.with_timezone(&chrono::Local);
+    let mut message = json!({
+        "id": origin.id,
+        "type": "message",
+        "date": date.format("%Y-%m-%dT%H:%M:%S").to_string(),
+        "date_unixtime": origin.date.timestamp().to_string(),
+        "from": origin.from,
+        "from_id": origin.from_id,
+    });
+    match (kind, origin.photo_size) {
+        (MediaKind::Image, Some((width, height))) => {
+            message["photo"] = json!(path);
+            message["width"] = json!(width);
+            message["height"] = json!(height);
+        }
+        _ => {
+            message["file"] = json!(path);
+            if let Some(media_type) = origin.media_type {
+                message["media_type"] = json!(media_type);
+            }
+        }
+    }
+    let text = origin.text.as_deref().unwrap_or_default();
+    message["text"] = json!(text);
+    message["text_entities"] = match text {
+        "" => json!([]),
+        text => json!([{ "type": "plain", "text": text }]),
+    };
+    message
+}
+
+/// Desktop 导出中使用的 id：用户 id 不变，群组和频道去掉负号和 `-100` 前缀
+fn bare_id(chat_id: ChatId) -> i64 {
+    match chat_id.0 {
+        id if id > 0 => id,
+        id if id < -1_000_000_000_000 => -id - 1_000_000_000_000,
+        id => -id,
+    }
+}
+
+fn full_name(first_name: Option<&str>, last_name: Option<&str>) -> String {
+    [first_name, last_name]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+    use serde_json::Map;
+
+    /// Telegram Desktop 导出的一段 `result.json`
+    const FIXTURE: &str = include_str!("../assets/fixtures/telegram_export.json");
+
+    fn voice(id: i32, date: i64) -> Message {
+        test_util::message(
+            id,
+            date,
+            json!({
+                "voice": {
+                    "file_id": "voice",
+                    "file_unique_id": "voice_unique",
+                    "duration": 3,
+                    "mime_type": "audio/ogg",
+                },
+            }),
+        )
+    }
+
+    fn export() -> Value {
+        let chat = ChatInfo::of(&test_util::text(1, "").chat);
+        let photo = Origin::of(&test_util::photo(1, 1714564800, &[(320, 240), (1280, 960)]))
+            .with_text(Some("日落".to_string()));
+        let voice = Origin::of(&voice(2, 1714564805)).with_text(Some(String::new()));
+        let files = [
+            (&photo, "images/image_1.jpg".to_string(), MediaKind::Image),
+            (&voice, "audio/voice_2.ogg".to_string(), MediaKind::Audio),
+        ];
+        serde_json::from_str(&render(&chat, &files)).unwrap()
+    }
+
+    fn object(value: &Value) -> &Map<String, Value> {
+        value.as_object().expect("expected a json object")
+    }
+
+    /// 两个值的 JSON 类型相同
+    fn same_type(a: &Value, b: &Value) -> bool {
+        std::mem::discriminant(a) == std::mem::discriminant(b)
+    }
+
+    #[test]
+    fn matches_desktop_schema() {
+        let fixture: Value = serde_json::from_str(FIXTURE).unwrap();
+        let export = export();
+        for key in ["name", "type", "id"] {
+            assert_eq!(export[key], fixture[key], "{}", key);
+        }
+
+        let messages = export["messages"].as_array().unwrap();
+        let expected = fixture["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), expected.len());
+        for (message, expected) in messages.iter().zip(expected) {
+            // 导出的字段都出现在 Desktop 的导出中，且类型相同；Desktop 额外的文件大小等字段可以省略
+            for (key, value) in object(message) {
+                let Some(expected) = expected.get(key) else {
+                    panic!("{} is not in the desktop export", key);
+                };
+                assert!(
+                    same_type(value, expected),
+                    "{}: {} vs {}",
+                    key,
+                    value,
+                    expected
+                );
+            }
+            for key in [
+                "id",
+                "type",
+                "date",
+                "date_unixtime",
+                "from",
+                "from_id",
+                "text",
+            ] {
+                assert!(message.get(key).is_some(), "missing {}", key);
+            }
+            for key in [
+                "id",
+                "type",
+                "date_unixtime",
+                "from",
+                "from_id",
+                "text",
+                "text_entities",
+                "width",
+                "height",
+                "media_type",
+            ] {
+                assert_eq!(message.get(key), expected.get(key), "{}", key);
+            }
+        }
+    }
+
+    #[test]
+    fn dates_use_desktop_format() {
+        let export = export();
+        let photo = &export["messages"][0];
+        let date = photo["date"].as_str().unwrap();
+        // 不带时区的本地时间
+        let local = chrono::NaiveDateTime::parse_from_str(date, "%Y-%m-%dT%H:%M:%S").unwrap();
+        let expected = DateTime::from_timestamp(1714564800, 0)
+            .unwrap()
+            .with_timezone(&chrono::Local)
+            .naive_local();
+        assert_eq!(local, expected);
+        assert_eq!(photo["photo"], "images/image_1.jpg");
+        assert_eq!(export["messages"][1]["file"], "audio/voice_2.ogg");
+    }
+
+    #[test]
+    fn channel_ids_drop_prefix() {
+        assert_eq!(bare_id(ChatId(42)), 42);
+        assert_eq!(bare_id(ChatId(-1001234567890)), 1234567890);
+        assert_eq!(bare_id(ChatId(-4567)), 4567);
+
+        let msg = test_util::message(
+            1,
+            0,
+            json!({
+                "text": "hi",
+                "chat": {"id": -1001234567890i64, "type": "channel", "title": "News"},
+                "sender_chat": {"id": -1001234567890i64, "type": "channel", "title": "News"},
+            }),
+        );
+        let chat = ChatInfo::of(&msg.chat);
+        assert_eq!((chat.kind, chat.id), ("private_channel", 1234567890));
+        let origin = Origin::of(&msg);
+        assert_eq!(origin.from, "News");
+        assert_eq!(origin.from_id, "channel1234567890");
+    }
+}
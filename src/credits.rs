@@ -0,0 +1,111 @@
+//! 群组会话中记录每张图片是谁发送的，并在结果中致谢
+
+use teloxide::prelude::*;
+
+/// 发送这条消息的人的显示名称
+///
+/// 优先使用 `@用户名`，没有时使用名字。匿名管理员显示为“匿名管理员”，
+/// 以频道身份发送的消息显示频道的名称。
+pub fn contributor(msg: &Message) -> String {
+    if let Some(sender_chat) = &msg.sender_chat {
+        if sender_chat.id == msg.chat.id {
+            return "匿名管理员".to_string();
+        }
+        return sender_chat
+            .title()
+            .map(str::to_string)
+            .or_else(|| {
+                sender_chat
+                    .username()
+                    .map(|username| format!("@{}", username))
+            })
+            .unwrap_or_else(|| sender_chat.id.to_string());
+    }
+    match &msg.from {
+        Some(user) => match &user.username {
+            Some(username) => format!("@{}", username),
+            None => user.full_name(),
+        },
+        None => "未知用户".to_string(),
+    }
+}
+
+/// 按数量从多到少排列的贡献者和图片数量，数量相同时先出现的在前
+pub fn leaderboard<'a>(names: impl IntoIterator<Item = &'a str>) -> Vec<(&'a str, usize)> {
+    let mut counts: Vec<(&str, usize)> = Vec::new();
+    for name in names {
+        match counts.iter_mut().find(|(existing, _)| *existing == name) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((name, 1)),
+        }
+    }
+    // 稳定排序，保留第一次出现的顺序
+    counts.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+    counts
+}
+
+/// 例如 `@alice 23 张、@bob 7 张`
+pub fn format_leaderboard(counts: &[(&str, usize)]) -> String {
+    counts
+        .iter()
+        .map(|(name, count)| format!("{} {} 张", name, count))
+        .collect::<Vec<_>>()
+        .join("、")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+    use serde_json::json;
+
+    /// 以 `sender_chat` 身份发到测试群组的消息，`from` 是telegram的服务账号
+    fn sent_as(sender_chat: serde_json::Value) -> Message {
+        test_util::message(
+            1,
+            0,
+            json!({
+                "text": "hi",
+                "chat": {"id": test_util::GROUP_ID, "type": "supergroup", "title": "Group"},
+                "from": {"id": 1087968824, "is_bot": true, "first_name": "Group", "username": "GroupAnonymousBot"},
+                "sender_chat": sender_chat,
+            }),
+        )
+    }
+
+    #[test]
+    fn anonymous_admin() {
+        let msg =
+            sent_as(json!({"id": test_util::GROUP_ID, "type": "supergroup", "title": "Group"}));
+        assert_eq!(contributor(&msg), "匿名管理员");
+    }
+
+    #[test]
+    fn linked_channel() {
+        let msg = sent_as(json!({"id": -1002000, "type": "channel", "title": "News"}));
+        assert_eq!(contributor(&msg), "News");
+        let msg = sent_as(json!({"id": -1002000, "type": "channel", "username": "news"}));
+        assert_eq!(contributor(&msg), "@news");
+    }
+
+    #[test]
+    fn regular_user() {
+        assert_eq!(contributor(&test_util::text(1, "hi")), "Alice");
+        let msg = test_util::message(
+            1,
+            0,
+            json!({"from": {"id": 7, "is_bot": false, "first_name": "Bob", "username": "bob"}}),
+        );
+        assert_eq!(contributor(&msg), "@bob");
+    }
+
+    #[test]
+    fn leaderboard_orders_by_count() {
+        let counts = leaderboard(["@bob", "匿名管理员", "@bob", "@alice", "匿名管理员", "@bob"]);
+        assert_eq!(counts, [("@bob", 3), ("匿名管理员", 2), ("@alice", 1)]);
+        assert_eq!(
+            format_leaderboard(&counts[..2]),
+            "@bob 3 张、匿名管理员 2 张"
+        );
+    }
+}
@@ -0,0 +1,75 @@
+//! 重复命令和按钮连点的去重
+//!
+//! 网络不好时用户经常连续发送同一条命令，或者连续点击同一个按钮。同一个会话中同一个用户在
+//! [`WINDOW`] 内发送的相同命令或点击的相同按钮只处理第一次，之后的命令直接忽略，
+//! 按钮点击只回复一个提示。
+
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use teloxide::types::{ChatId, UserId};
+
+/// 相同的操作在这段时间内只处理一次
+pub const WINDOW: Duration = Duration::from_secs(2);
+
+/// 会话、发送者和命令文本或按钮数据
+type Action = (ChatId, Option<UserId>, String);
+
+#[derive(Debug)]
+pub struct Debouncer {
+    window: Duration,
+    /// 每个操作最后一次被处理的时间
+    seen: Mutex<HashMap<Action, Instant>>,
+}
+
+impl Debouncer {
+    pub fn new(window: Duration) -> Self {
+        Debouncer {
+            window,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// 操作是否在窗口内已经处理过，没有处理过时记录下来
+    ///
+    /// `action` 为命令的文本或按钮的数据，多余的空白不影响比较。
+    pub fn is_repeat(&self, chat_id: ChatId, user: Option<UserId>, action: &str) -> bool {
+        let now = Instant::now();
+        let action = action.split_whitespace().collect::<Vec<_>>().join(" ");
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, at| now.duration_since(*at) < self.window);
+        match seen.entry((chat_id, user, action)) {
+            Entry::Occupied(_) => true,
+            Entry::Vacant(entry) => {
+                entry.insert(now);
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_action_by_same_user_is_repeat() {
+        let debouncer = Debouncer::new(Duration::from_secs(60));
+        let (chat, alice, bob) = (ChatId(1), Some(UserId(1)), Some(UserId(2)));
+        assert!(!debouncer.is_repeat(chat, alice, "/stop"));
+        assert!(debouncer.is_repeat(chat, alice, " /stop "));
+        // 群组中不同的人发送相同的命令分别处理
+        assert!(!debouncer.is_repeat(chat, bob, "/stop"));
+        assert!(!debouncer.is_repeat(ChatId(2), alice, "/stop"));
+        assert!(!debouncer.is_repeat(chat, alice, "/stop now"));
+    }
+
+    #[test]
+    fn window_expires() {
+        let debouncer = Debouncer::new(Duration::ZERO);
+        assert!(!debouncer.is_repeat(ChatId(1), None, "stop"));
+        assert!(!debouncer.is_repeat(ChatId(1), None, "stop"));
+        assert_eq!(debouncer.seen.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,723 @@
+use crate::external::{self, ExternalError};
+use crate::progress::Progress;
+use crate::throttle::RateLimiter;
+use reqwest::Client;
+use std::fmt;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+/// telegram文件下载地址的前缀，与 Bot API 使用同一个域名但由不同的服务提供
+pub const TELEGRAM_FILE_URL: &str = "https://api.telegram.org/file/";
+/// bot 通过 getFile 能下载的文件大小上限
+pub const GET_FILE_LIMIT: u64 = 20 * 1024 * 1024;
+
+/// 单个文件实际的大小上限，`configured` 为 `MAX_FILE_BYTES`，0 表示不额外限制
+///
+/// bot 无法下载超过 20MB 的文件，`MAX_FILE_BYTES` 只能设置得更小。
+pub fn file_limit(configured: u64) -> u64 {
+    match configured {
+        0 => GET_FILE_LIMIT,
+        limit => limit.min(GET_FILE_LIMIT),
+    }
+}
+
+/// 大小为 `size` 的文件是否超过了 `limit`，正好等于上限的文件仍然下载
+pub fn exceeds_limit(size: u32, limit: u64) -> bool {
+    u64::from(size) > limit
+}
+
+/// telegram文件的下载地址
+pub fn telegram_file_url(token: &str, file_path: &str) -> FileUrl {
+    FileUrl::Remote(format!("{}bot{}/{}", TELEGRAM_FILE_URL, token, file_path))
+}
+
+/// 要下载的文件地址，或者已经在本地的文件
+///
+/// telegram文件的地址中包含 bot token，`Display` 和 `Debug` 会将其替换为 `***`，
+/// 只有发送请求时才使用完整的地址。
+#[derive(Clone, PartialEq, Eq)]
+pub enum FileUrl {
+    /// telegram的文件
+    Remote(String),
+    /// 用户发送的链接，只能通过 [`crate::external`] 访问
+    External(reqwest::Url),
+    /// 从用户发送的压缩包中解压出的文件，“下载”时移动到目标位置
+    Local(PathBuf),
+}
+
+impl From<reqwest::Url> for FileUrl {
+    fn from(url: reqwest::Url) -> Self {
+        FileUrl::External(url)
+    }
+}
+
+impl fmt::Display for FileUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileUrl::Remote(url) => f.write_str(&redact_token(url)),
+            FileUrl::External(url) => write!(f, "{}", url),
+            FileUrl::Local(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+impl fmt::Debug for FileUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileUrl::Remote(url) => write!(f, "Remote({:?})", redact_token(url)),
+            FileUrl::External(url) => write!(f, "External({:?})", url.as_str()),
+            FileUrl::Local(path) => write!(f, "Local({:?})", path),
+        }
+    }
+}
+
+/// 将文本中形如 `bot123456:ABC...` 的 bot token 替换为 `bot***`
+///
+/// 用于可能包含下载地址的错误信息，在写入日志或回复用户之前调用。
+pub fn redact_token(text: &str) -> String {
+    let mut redacted = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("bot") {
+        let (before, candidate) = rest.split_at(start);
+        redacted.push_str(before);
+        let after = &candidate[3..];
+        let id_len = after
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(after.len());
+        let secret_len = after[id_len..]
+            .strip_prefix(':')
+            .map(|secret| {
+                secret
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '-'))
+                    .unwrap_or(secret.len())
+            })
+            .unwrap_or_default();
+        if id_len > 0 && secret_len > 0 {
+            redacted.push_str("bot***");
+            rest = &after[id_len + 1 + secret_len..];
+        } else {
+            redacted.push_str("bot");
+            rest = after;
+        }
+    }
+    redacted.push_str(rest);
+    redacted
+}
+
+/// 下载的文件类型，决定下载后如何校验
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MediaKind {
+    /// 图片，下载后检查是否是有效图片
+    Image,
+    /// 语音或音频，来自telegram的文件服务器，不做内容校验
+    Audio,
+    /// 贴纸，来自telegram的文件服务器，动态和视频贴纸不是图片，不做内容校验
+    Sticker,
+    /// 圆形的视频消息，来自telegram的文件服务器，不做内容校验
+    VideoNote,
+}
+
+impl MediaKind {
+    /// 按类型分文件夹时使用的文件夹名称
+    pub fn folder(&self) -> &'static str {
+        match self {
+            MediaKind::Image => "images",
+            MediaKind::Audio => "audio",
+            MediaKind::Sticker => "stickers",
+            MediaKind::VideoNote => "videos",
+        }
+    }
+}
+
+/// 单张图片下载失败的原因
+#[derive(Debug)]
+pub enum DownloadError {
+    /// 网络请求失败
+    Request(reqwest::Error),
+    /// 写入文件失败
+    Io(std::io::Error),
+    /// 接收到的字节数与 Content-Length 不一致
+    Truncated { expected: u64, received: u64 },
+    /// 下载内容不是有效图片，例如 CDN 返回了 HTML 错误页
+    InvalidImage,
+    /// 用户链接被拒绝或超过了大小限制
+    External(ExternalError),
+    /// 单张图片下载超时
+    TimedOut,
+    /// 任务超时或被取消，未完成的下载被中止
+    Cancelled,
+}
+
+impl fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DownloadError::Request(why) => write!(f, "网络请求失败: {}", why),
+            DownloadError::Io(why) => write!(f, "写入文件失败: {}", why),
+            DownloadError::Truncated { expected, received } => {
+                write!(f, "下载内容不完整（{}/{} 字节）", received, expected)
+            }
+            DownloadError::InvalidImage => write!(f, "下载内容不是有效图片"),
+            DownloadError::External(why) => write!(f, "{}", why),
+            DownloadError::TimedOut => write!(f, "下载超时"),
+            DownloadError::Cancelled => write!(f, "下载已取消"),
+        }
+    }
+}
+
+impl std::error::Error for DownloadError {}
+
+impl From<reqwest::Error> for DownloadError {
+    fn from(why: reqwest::Error) -> Self {
+        // 下载链接中包含 bot token，不能让它出现在日志和回复里
+        DownloadError::Request(why.without_url())
+    }
+}
+
+impl From<ExternalError> for DownloadError {
+    fn from(why: ExternalError) -> Self {
+        match why {
+            ExternalError::Request(why) => DownloadError::Request(why),
+            why => DownloadError::External(why),
+        }
+    }
+}
+
+impl From<std::io::Error> for DownloadError {
+    fn from(why: std::io::Error) -> Self {
+        DownloadError::Io(why)
+    }
+}
+
+/// 下载一个文件并写入 `path`
+///
+/// `kind` 为图片时，下载内容通过校验后才会写入磁盘。失败时重试一次。
+/// 包括重试在内超过 `timeout` 仍未完成则视为失败，限速等待的时间不计入超时。
+/// 接收的字节数计入 `progress`。
+/// `cancel` 被触发时立即中止。超时或中止都不会留下写了一半的文件。
+/// 本地文件在导入时已经校验过，直接移动到 `path`。用户链接通过 `external` 下载。
+#[allow(clippy::too_many_arguments)]
+pub async fn download_image(
+    client: &Client,
+    external: &Client,
+    limiter: &RateLimiter,
+    progress: &Progress,
+    cancel: &CancellationToken,
+    url: &FileUrl,
+    path: &Path,
+    kind: MediaKind,
+    timeout: Duration,
+) -> Result<(), DownloadError> {
+    let client = match url {
+        FileUrl::Remote(_) => client,
+        FileUrl::External(_) => external,
+        FileUrl::Local(local) => {
+            tokio::fs::rename(local, path).await?;
+            progress.add_bytes(tokio::fs::metadata(path).await?.len());
+            return Ok(());
+        }
+    };
+    let validate = kind == MediaKind::Image;
+    let throttle = Throttle::new(limiter);
+    let download = with_timeout(
+        timeout,
+        &throttle,
+        download_with_retry(client, &throttle, progress, url, path, validate),
+    );
+    let result = tokio::select! {
+        result = download => result,
+        _ = cancel.cancelled() => Err(DownloadError::Cancelled),
+    };
+    if matches!(
+        result,
+        Err(DownloadError::TimedOut | DownloadError::Cancelled)
+    ) {
+        let _ = tokio::fs::remove_file(partial_path(path)).await;
+    }
+    result
+}
+
+/// 下载telegram的文件到 `path`，不校验内容，例如用户发送的压缩包
+pub async fn download_file(
+    client: &Client,
+    limiter: &RateLimiter,
+    url: &FileUrl,
+    path: &Path,
+    timeout: Duration,
+) -> Result<(), DownloadError> {
+    if !matches!(url, FileUrl::Remote(_)) {
+        return Err(DownloadError::Io(std::io::Error::other(
+            "not a telegram file",
+        )));
+    }
+    // 不在任务的进度中显示
+    let progress = Progress::new(1, None);
+    let throttle = Throttle::new(limiter);
+    with_timeout(
+        timeout,
+        &throttle,
+        download_with_retry(client, &throttle, &progress, url, path, false),
+    )
+    .await
+}
+
+/// 记录一个文件的下载在限速上等待了多久
+struct Throttle<'a> {
+    limiter: &'a RateLimiter,
+    waited_nanos: AtomicU64,
+}
+
+impl<'a> Throttle<'a> {
+    fn new(limiter: &'a RateLimiter) -> Self {
+        Throttle {
+            limiter,
+            waited_nanos: AtomicU64::new(0),
+        }
+    }
+
+    /// 申请写入 `bytes` 字节，必要时等待
+    ///
+    /// 等待开始前就计入 `waited`，超时检查时正在进行的等待也不算超时。
+    async fn acquire(&self, bytes: usize) {
+        let wait = self.limiter.reserve(bytes);
+        if !wait.is_zero() {
+            self.waited_nanos
+                .fetch_add(wait.as_nanos() as u64, Ordering::Relaxed);
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    fn waited(&self) -> Duration {
+        Duration::from_nanos(self.waited_nanos.load(Ordering::Relaxed))
+    }
+}
+
+/// 运行 `download`，超过 `timeout` 加上限速等待的时间仍未完成时返回 [`DownloadError::TimedOut`]
+async fn with_timeout(
+    timeout: Duration,
+    throttle: &Throttle<'_>,
+    download: impl Future<Output = Result<(), DownloadError>>,
+) -> Result<(), DownloadError> {
+    tokio::pin!(download);
+    let mut deadline = tokio::time::Instant::now() + timeout;
+    let mut credited = Duration::ZERO;
+    loop {
+        tokio::select! {
+            result = &mut download => return result,
+            _ = tokio::time::sleep_until(deadline) => {
+                let waited = throttle.waited();
+                if waited <= credited {
+                    return Err(DownloadError::TimedOut);
+                }
+                deadline += waited - credited;
+                credited = waited;
+            }
+        }
+    }
+}
+
+/// 写入过程中使用的临时文件，写完后再重命名，中断时不会留下不完整的图片
+fn partial_path(path: &Path) -> std::path::PathBuf {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".part");
+    partial.into()
+}
+
+async fn download_with_retry(
+    client: &Client,
+    throttle: &Throttle<'_>,
+    progress: &Progress,
+    url: &FileUrl,
+    path: &Path,
+    validate: bool,
+) -> Result<(), DownloadError> {
+    match try_download(client, throttle, progress, url, path, validate).await {
+        Ok(()) => Ok(()),
+        Err(why) => {
+            log::warn!("下载 {} 失败，正在重试: {}", path.display(), why);
+            try_download(client, throttle, progress, url, path, validate).await
+        }
+    }
+}
+
+async fn try_download(
+    client: &Client,
+    throttle: &Throttle<'_>,
+    progress: &Progress,
+    url: &FileUrl,
+    path: &Path,
+    validate: bool,
+) -> Result<(), DownloadError> {
+    let mut bytes = Vec::new();
+    let received = receive(client, throttle, progress, url, validate, &mut bytes).await;
+    if received.is_err() {
+        // 重试时会重新下载，失败的这次不计入进度
+        progress.discard_bytes(bytes.len() as u64);
+    }
+    received?;
+
+    let partial = partial_path(path);
+    tokio::fs::write(&partial, &bytes).await?;
+    tokio::fs::rename(&partial, path).await?;
+    Ok(())
+}
+
+/// 接收下载内容到 `bytes`，`validate` 时校验是否是有效图片
+///
+/// 用户链接的大小不超过 [`external::MAX_BODY_BYTES`]。
+async fn receive(
+    client: &Client,
+    throttle: &Throttle<'_>,
+    progress: &Progress,
+    url: &FileUrl,
+    validate: bool,
+    bytes: &mut Vec<u8>,
+) -> Result<(), DownloadError> {
+    let (mut response, limit) = match url {
+        FileUrl::Remote(url) => (client.get(url).send().await?.error_for_status()?, None),
+        FileUrl::External(url) => (
+            external::fetch(client, url.clone()).await?,
+            Some(external::MAX_BODY_BYTES),
+        ),
+        FileUrl::Local(_) => return Err(std::io::Error::other("not a remote file").into()),
+    };
+    let expected = response.content_length();
+    let too_large = |size: u64| limit.filter(|limit| size > *limit);
+    if let Some(limit) = expected.and_then(too_large) {
+        return Err(ExternalError::TooLarge(limit).into());
+    }
+
+    bytes.reserve(expected.unwrap_or_default() as usize);
+    while let Some(chunk) = response.chunk().await? {
+        throttle.acquire(chunk.len()).await;
+        progress.add_bytes(chunk.len() as u64);
+        bytes.extend_from_slice(&chunk);
+        if let Some(limit) = too_large(bytes.len() as u64) {
+            return Err(ExternalError::TooLarge(limit).into());
+        }
+    }
+
+    if let Some(expected) = expected {
+        let received = bytes.len() as u64;
+        if received != expected {
+            return Err(DownloadError::Truncated { expected, received });
+        }
+    }
+    if validate && !is_valid_image(bytes) {
+        return Err(DownloadError::InvalidImage);
+    }
+    Ok(())
+}
+
+/// 根据文件头判断图片格式，返回对应的扩展名
+pub fn sniff_image_format(bytes: &[u8]) -> Option<&'static str> {
+    match bytes {
+        [0xFF, 0xD8, 0xFF, ..] => Some("jpg"),
+        [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, ..] => Some("png"),
+        [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => Some("gif"),
+        [b'R', b'I', b'F', b'F', _, _, _, _, rest @ ..] if rest.starts_with(b"WEBP") => {
+            Some("webp")
+        }
+        [b'B', b'M', ..] => Some("bmp"),
+        _ => None,
+    }
+}
+
+/// 校验下载内容是否为有效图片
+///
+/// 总是检查文件头；启用 `imaging` 特性时还会尝试解析图片尺寸。
+pub fn is_valid_image(bytes: &[u8]) -> bool {
+    sniff_image_format(bytes).is_some() && decodes_dimensions(bytes)
+}
+
+#[cfg(feature = "imaging")]
+fn decodes_dimensions(bytes: &[u8]) -> bool {
+    image::ImageReader::new(std::io::Cursor::new(bytes))
+        .with_guessed_format()
+        .ok()
+        .and_then(|reader| reader.into_dimensions().ok())
+        .is_some()
+}
+
+#[cfg(not(feature = "imaging"))]
+fn decodes_dimensions(_bytes: &[u8]) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use wiremock::matchers::method;
+    use wiremock::{Mock, MockServer, Request, Respond, ResponseTemplate};
+
+    const BODY: &[u8] = b"not an image, not validated";
+
+    /// 第一次请求返回500，之后正常返回
+    struct FailOnce(Arc<AtomicUsize>);
+
+    impl Respond for FailOnce {
+        fn respond(&self, _: &Request) -> ResponseTemplate {
+            match self.0.fetch_add(1, Ordering::SeqCst) {
+                0 => ResponseTemplate::new(500),
+                _ => ResponseTemplate::new(200).set_body_bytes(BODY),
+            }
+        }
+    }
+
+    async fn download(
+        server: &MockServer,
+        limiter: &RateLimiter,
+        cancel: &CancellationToken,
+        path: &Path,
+        timeout: Duration,
+    ) -> Result<(), DownloadError> {
+        let client = Client::new();
+        download_image(
+            &client,
+            &client,
+            limiter,
+            &Progress::new(1, None),
+            cancel,
+            &FileUrl::Remote(format!("{}/file.bin", server.uri())),
+            path,
+            MediaKind::Sticker,
+            timeout,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn downloads_to_path() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(BODY))
+            .mount(&server)
+            .await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.bin");
+
+        download(
+            &server,
+            &RateLimiter::new(0),
+            &CancellationToken::new(),
+            &path,
+            Duration::from_secs(5),
+        )
+        .await
+        .unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), BODY);
+        assert!(!partial_path(&path).exists());
+    }
+
+    #[tokio::test]
+    async fn retries_once_after_failure() {
+        let server = MockServer::start().await;
+        let calls = Arc::new(AtomicUsize::new(0));
+        Mock::given(method("GET"))
+            .respond_with(FailOnce(calls.clone()))
+            .mount(&server)
+            .await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.bin");
+
+        download(
+            &server,
+            &RateLimiter::new(0),
+            &CancellationToken::new(),
+            &path,
+            Duration::from_secs(5),
+        )
+        .await
+        .unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!(std::fs::read(&path).unwrap(), BODY);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_second_failure() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(500))
+            .expect(2)
+            .mount(&server)
+            .await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.bin");
+
+        let result = download(
+            &server,
+            &RateLimiter::new(0),
+            &CancellationToken::new(),
+            &path,
+            Duration::from_secs(5),
+        )
+        .await;
+        assert!(matches!(result, Err(DownloadError::Request(_))));
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn times_out_slow_download() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_bytes(BODY)
+                    .set_delay(Duration::from_secs(5)),
+            )
+            .mount(&server)
+            .await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.bin");
+        std::fs::write(partial_path(&path), b"half").unwrap();
+
+        let result = download(
+            &server,
+            &RateLimiter::new(0),
+            &CancellationToken::new(),
+            &path,
+            Duration::from_millis(200),
+        )
+        .await;
+        assert!(matches!(result, Err(DownloadError::TimedOut)));
+        assert!(!path.exists());
+        assert!(!partial_path(&path).exists());
+    }
+
+    #[tokio::test]
+    async fn cancel_aborts_download_and_removes_partial_file() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_bytes(BODY)
+                    .set_delay(Duration::from_secs(5)),
+            )
+            .mount(&server)
+            .await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.bin");
+        // 模拟上一次中断时留下的半截文件
+        std::fs::write(partial_path(&path), b"half").unwrap();
+
+        let cancel = CancellationToken::new();
+        let trigger = cancel.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            trigger.cancel();
+        });
+        let result = download(
+            &server,
+            &RateLimiter::new(0),
+            &cancel,
+            &path,
+            Duration::from_secs(30),
+        )
+        .await;
+        assert!(matches!(result, Err(DownloadError::Cancelled)));
+        assert!(!path.exists());
+        assert!(!partial_path(&path).exists());
+    }
+
+    #[tokio::test]
+    async fn throttle_wait_does_not_count_towards_timeout() {
+        let server = MockServer::start().await;
+        let body = vec![0u8; 6000];
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(body.clone()))
+            .mount(&server)
+            .await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.bin");
+        // 桶里只有4000字节的令牌，写完6000字节至少要等0.5秒，超过了超时时间
+        let limiter = RateLimiter::new(4000);
+
+        download(
+            &server,
+            &limiter,
+            &CancellationToken::new(),
+            &path,
+            Duration::from_millis(200),
+        )
+        .await
+        .unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), body);
+    }
+
+    #[test]
+    fn redacts_token_in_url() {
+        let url = telegram_file_url("123456:ABC-def_ghi", "photos/file_1.jpg");
+        assert_eq!(
+            url.to_string(),
+            "https://api.telegram.org/file/bot***/photos/file_1.jpg"
+        );
+        assert!(!format!("{:?}", url).contains("ABC-def_ghi"));
+    }
+
+    #[tokio::test]
+    async fn cancel_mid_download_leaves_no_files() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(vec![0u8; 6000]))
+            .mount(&server)
+            .await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.bin");
+        // 限速使下载在收到第一块数据后停下来等待
+        let limiter = RateLimiter::new(1000);
+
+        let cancel = CancellationToken::new();
+        let trigger = cancel.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            trigger.cancel();
+        });
+        let result = download(&server, &limiter, &cancel, &path, Duration::from_secs(30)).await;
+        assert!(matches!(result, Err(DownloadError::Cancelled)));
+        assert!(!path.exists());
+        assert!(!partial_path(&path).exists());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn file_limit_boundary() {
+        assert_eq!(file_limit(0), GET_FILE_LIMIT);
+        assert_eq!(file_limit(1024), 1024);
+        // 不能超过 getFile 的上限
+        assert_eq!(file_limit(GET_FILE_LIMIT + 1), GET_FILE_LIMIT);
+
+        assert!(!exceeds_limit(1024, 1024));
+        assert!(exceeds_limit(1025, 1024));
+        assert!(!exceeds_limit(0, 0));
+        let limit = file_limit(0);
+        assert!(!exceeds_limit(GET_FILE_LIMIT as u32, limit));
+        assert!(exceeds_limit(GET_FILE_LIMIT as u32 + 1, limit));
+    }
+
+    #[tokio::test]
+    async fn request_errors_never_contain_token() {
+        // 没有服务监听的端口，请求一定失败
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        drop(listener);
+        let url = format!(
+            "http://{}/file/bot123456:ABC-def_ghi/photos/file_1.jpg",
+            address
+        );
+        let why = Client::new().get(&url).send().await.unwrap_err();
+        // reqwest 的错误信息中带有完整的地址
+        assert!(why.to_string().contains("ABC-def_ghi"));
+        assert!(!redact_token(&why.to_string()).contains("ABC-def_ghi"));
+
+        let why = DownloadError::from(why);
+        assert!(!why.to_string().contains("ABC-def_ghi"));
+        assert!(!format!("{:?}", why).contains("ABC-def_ghi"));
+        // 包装成任务的错误后同样不包含 token
+        let boxed: Box<dyn std::error::Error + Send + Sync> = why.into();
+        assert!(!boxed.to_string().contains("ABC-def_ghi"));
+    }
+}
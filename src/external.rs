@@ -0,0 +1,407 @@
+//! 访问用户发送的链接
+//!
+//! 用户发送的链接可能指向机器人所在网络中的服务，例如云服务器的元数据接口
+//! `169.254.169.254`。图片直链、telegraph 页面和网页预览图都只能通过 [`client_builder`]
+//! 创建的客户端和 [`fetch`] 访问：
+//!
+//! - 只允许 http(s)
+//! - 每一跳之前先解析域名，任何一个地址是内网、回环、链路本地或 ULA 地址都拒绝
+//! - 客户端的 DNS 解析同样过滤这些地址，连接时使用的就是检查过的地址，
+//!   域名在检查之后改为解析到内网地址也无法连接
+//! - 最多跟随 [`MAX_REDIRECTS`] 次跳转，每次跳转都重新检查
+//! - 请求带有单独的 User-Agent，响应最多读取 [`MAX_BODY_BYTES`]
+//!
+//! 配置了代理时域名由代理解析，只有每一跳之前的检查有效。
+
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::header::LOCATION;
+use reqwest::{Client, ClientBuilder, Response, Url};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+/// 最多跟随的跳转次数
+pub const MAX_REDIRECTS: usize = 5;
+/// 从用户链接下载的文件大小上限
+pub const MAX_BODY_BYTES: u64 = 50 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum ExternalError {
+    /// 不是 http(s) 链接
+    Scheme(String),
+    /// 链接指向内网、回环等地址
+    Blocked(String),
+    TooManyRedirects,
+    /// 响应超过了大小上限
+    TooLarge(u64),
+    Resolve(std::io::Error),
+    Request(reqwest::Error),
+}
+
+impl fmt::Display for ExternalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExternalError::Scheme(scheme) => write!(f, "不支持 {} 链接", scheme),
+            ExternalError::Blocked(host) => write!(f, "拒绝访问内网地址 {}", host),
+            ExternalError::TooManyRedirects => write!(f, "跳转超过 {} 次", MAX_REDIRECTS),
+            ExternalError::TooLarge(limit) => {
+                write!(f, "文件超过了 {} 字节的大小限制", limit)
+            }
+            ExternalError::Resolve(why) => write!(f, "无法解析域名：{}", why),
+            ExternalError::Request(why) => write!(f, "{}", why),
+        }
+    }
+}
+
+impl std::error::Error for ExternalError {}
+
+impl From<reqwest::Error> for ExternalError {
+    fn from(why: reqwest::Error) -> Self {
+        ExternalError::Request(why)
+    }
+}
+
+/// 是否是不应该从外部访问的地址：内网、回环、链路本地、组播等
+pub fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                // 0.0.0.0/8、运营商级 NAT 100.64.0.0/10 和基准测试 198.18.0.0/15
+                || a == 0
+                || (a == 100 && (64..128).contains(&b))
+                || (a == 198 && (18..20).contains(&b))
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_private(IpAddr::V4(ip));
+            }
+            let first = ip.segments()[0];
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // 唯一本地地址 fc00::/7 和链路本地地址 fe80::/10
+                || (first & 0xfe00) == 0xfc00
+                || (first & 0xffc0) == 0xfe80
+        }
+    }
+}
+
+/// 域名解析出的地址都是公网地址时返回这些地址
+fn public_addresses(
+    host: &str,
+    addresses: Vec<SocketAddr>,
+) -> Result<Vec<SocketAddr>, ExternalError> {
+    if addresses.is_empty() || addresses.iter().any(|address| is_private(address.ip())) {
+        return Err(ExternalError::Blocked(host.to_string()));
+    }
+    Ok(addresses)
+}
+
+/// 检查链接是否可以访问：只允许 http(s)，主机解析出的所有地址都不能是内网地址
+pub async fn check_public(url: &Url) -> Result<(), ExternalError> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(ExternalError::Scheme(url.scheme().to_string()));
+    }
+    let Some(host) = url.host_str() else {
+        return Err(ExternalError::Blocked(url.to_string()));
+    };
+    let port = url.port_or_known_default().unwrap_or(80);
+    // IPv6 地址在链接中带有方括号
+    let addresses = match host.trim_start_matches('[').trim_end_matches(']').parse() {
+        Ok(ip) => vec![SocketAddr::new(ip, port)],
+        Err(_) => tokio::net::lookup_host((host, port))
+            .await
+            .map_err(ExternalError::Resolve)?
+            .collect(),
+    };
+    public_addresses(host, addresses).map(|_| ())
+}
+
+/// 连接时使用的 DNS 解析，过滤掉解析到内网地址的域名
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let host = name.as_str();
+            let addresses = tokio::net::lookup_host((host, 0)).await?.collect();
+            let addresses = public_addresses(host, addresses)?;
+            Ok(Box::new(addresses.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// 访问用户链接的客户端，由调用方配置代理后创建
+///
+/// 不自动跟随跳转，跳转由 [`fetch`] 逐跳检查。
+pub fn client_builder(user_agent: &str, headers: reqwest::header::HeaderMap) -> ClientBuilder {
+    Client::builder()
+        .user_agent(user_agent)
+        .default_headers(headers)
+        .dns_resolver(Arc::new(PublicResolver))
+        .redirect(reqwest::redirect::Policy::none())
+        .connect_timeout(std::time::Duration::from_secs(10))
+        .read_timeout(std::time::Duration::from_secs(30))
+}
+
+/// 请求用户链接，跟随跳转，返回最终的成功响应
+///
+/// `client` 必须由 [`client_builder`] 创建。最终的地址可以通过 [`Response::url`] 获取。
+pub async fn fetch(client: &Client, url: Url) -> Result<Response, ExternalError> {
+    follow(client, url, check_public).await
+}
+
+/// 跟随跳转，每一跳之前用 `check` 检查地址
+async fn follow(
+    client: &Client,
+    url: Url,
+    check: impl AsyncFn(&Url) -> Result<(), ExternalError>,
+) -> Result<Response, ExternalError> {
+    let mut url = url;
+    for _ in 0..=MAX_REDIRECTS {
+        check(&url).await?;
+        let response = client.get(url.clone()).send().await?;
+        if !response.status().is_redirection() {
+            return Ok(response.error_for_status()?);
+        }
+        let Some(location) = response
+            .headers()
+            .get(LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .and_then(|location| url.join(location).ok())
+        else {
+            // 没有跳转地址的 3xx 响应，交给调用方按普通响应处理
+            return Ok(response);
+        };
+        url = location;
+    }
+    Err(ExternalError::TooManyRedirects)
+}
+
+/// 读取响应开头最多 `limit` 字节，例如只需要 `<head>` 的网页
+pub async fn read_prefix(mut response: Response, limit: usize) -> Result<Vec<u8>, ExternalError> {
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        body.extend_from_slice(&chunk);
+        if body.len() >= limit {
+            body.truncate(limit);
+            break;
+        }
+    }
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::matchers::path;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn url(url: &str) -> Url {
+        Url::parse(url).unwrap()
+    }
+
+    #[test]
+    fn private_ranges() {
+        for ip in [
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "127.0.0.1",
+            "169.254.169.254",
+            "0.0.0.0",
+            "0.1.2.3",
+            "100.64.0.1",
+            "198.18.0.1",
+            "224.0.0.1",
+            "255.255.255.255",
+            "::1",
+            "::",
+            "fc00::1",
+            "fd12:3456::1",
+            "fe80::1",
+            "ff02::1",
+            "::ffff:127.0.0.1",
+            "::ffff:169.254.169.254",
+        ] {
+            assert!(is_private(ip.parse().unwrap()), "{} should be private", ip);
+        }
+        for ip in [
+            "1.1.1.1",
+            "93.184.216.34",
+            "100.128.0.1",
+            "2606:4700:4700::1111",
+            "::ffff:8.8.8.8",
+        ] {
+            assert!(!is_private(ip.parse().unwrap()), "{} should be public", ip);
+        }
+    }
+
+    #[test]
+    fn hostname_with_any_private_address_is_blocked() {
+        let public: SocketAddr = "93.184.216.34:80".parse().unwrap();
+        let private: SocketAddr = "10.0.0.1:80".parse().unwrap();
+        assert!(public_addresses("example.com", vec![public]).is_ok());
+        // 同时解析到公网和内网地址的域名，连接时可能使用内网地址
+        assert!(matches!(
+            public_addresses("rebind.example", vec![public, private]),
+            Err(ExternalError::Blocked(host)) if host == "rebind.example"
+        ));
+        assert!(public_addresses("empty.example", Vec::new()).is_err());
+    }
+
+    #[tokio::test]
+    async fn check_rejects_schemes_and_private_hosts() {
+        for blocked in [
+            "http://127.0.0.1/",
+            "http://169.254.169.254/latest/meta-data/",
+            "https://[::1]:8443/",
+            "http://[::ffff:10.0.0.1]/",
+            "http://0x7f000001/",
+            "http://2130706433/",
+            // 解析到回环地址的域名
+            "http://localhost:8080/",
+        ] {
+            assert!(
+                matches!(
+                    check_public(&url(blocked)).await,
+                    Err(ExternalError::Blocked(_))
+                ),
+                "{} should be blocked",
+                blocked
+            );
+        }
+        for scheme in [
+            "file:///etc/passwd",
+            "ftp://example.com/a.jpg",
+            "gopher://a/",
+        ] {
+            assert!(matches!(
+                check_public(&url(scheme)).await,
+                Err(ExternalError::Scheme(_))
+            ));
+        }
+    }
+
+    fn client() -> Client {
+        client_builder("test", Default::default()).build().unwrap()
+    }
+
+    /// 只允许访问测试服务器，其他地址照常检查
+    fn allow(server: &MockServer) -> impl AsyncFn(&Url) -> Result<(), ExternalError> {
+        let allowed = *server.address();
+        async move |url: &Url| {
+            if url.socket_addrs(|| None).ok().as_deref() == Some(&[allowed]) {
+                Ok(())
+            } else {
+                check_public(url).await
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn private_target_is_not_requested() {
+        let server = MockServer::start().await;
+        assert!(matches!(
+            fetch(&client(), url(&server.uri())).await,
+            Err(ExternalError::Blocked(_))
+        ));
+        assert!(server.received_requests().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn resolver_refuses_private_addresses() {
+        // 跳过逐跳检查，直接用客户端访问解析到回环地址的域名，连接时同样被拒绝
+        let server = MockServer::start().await;
+        let port = server.address().port();
+        let result = client()
+            .get(format!("http://localhost:{}/", port))
+            .send()
+            .await;
+        assert!(result.is_err());
+        assert!(server.received_requests().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn redirect_to_private_is_blocked() {
+        let server = MockServer::start().await;
+        Mock::given(path("/image.jpg"))
+            .respond_with(
+                ResponseTemplate::new(302)
+                    .insert_header("location", "http://169.254.169.254/latest/meta-data/"),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(path("/local"))
+            .respond_with(ResponseTemplate::new(301).insert_header("location", "http://localhost/"))
+            .mount(&server)
+            .await;
+        for start in ["/image.jpg", "/local"] {
+            let result = follow(
+                &client(),
+                url(&format!("{}{}", server.uri(), start)),
+                allow(&server),
+            )
+            .await;
+            assert!(
+                matches!(result, Err(ExternalError::Blocked(_))),
+                "{}: {:?}",
+                start,
+                result
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn redirects_are_followed_and_capped() {
+        let server = MockServer::start().await;
+        for hop in 0..MAX_REDIRECTS {
+            Mock::given(path(format!("/hop/{}", hop)))
+                .respond_with(
+                    ResponseTemplate::new(302)
+                        .insert_header("location", format!("/hop/{}", hop + 1)),
+                )
+                .mount(&server)
+                .await;
+        }
+        Mock::given(path(format!("/hop/{}", MAX_REDIRECTS)))
+            .respond_with(ResponseTemplate::new(200).set_body_string("done"))
+            .mount(&server)
+            .await;
+        Mock::given(path("/loop"))
+            .respond_with(ResponseTemplate::new(302).insert_header("location", "/loop"))
+            .mount(&server)
+            .await;
+
+        // 正好跳转 MAX_REDIRECTS 次
+        let response = follow(
+            &client(),
+            url(&format!("{}/hop/0", server.uri())),
+            allow(&server),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.url().path(), format!("/hop/{}", MAX_REDIRECTS));
+        assert_eq!(response.text().await.unwrap(), "done");
+
+        let requests = server.received_requests().await.unwrap().len();
+        let result = follow(
+            &client(),
+            url(&format!("{}/loop", server.uri())),
+            allow(&server),
+        )
+        .await;
+        assert!(matches!(result, Err(ExternalError::TooManyRedirects)));
+        assert_eq!(
+            server.received_requests().await.unwrap().len() - requests,
+            MAX_REDIRECTS + 1
+        );
+    }
+}
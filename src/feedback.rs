@@ -0,0 +1,26 @@
+//! /feedback 的频率限制，每个发送者在 [`COOLDOWN`] 内只能发送一次反馈
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use teloxide::types::ChatId;
+
+/// 同一个发送者两次反馈之间的最短间隔
+pub const COOLDOWN: Duration = Duration::from_secs(5 * 60);
+/// 单条反馈的最大长度，超过时截断
+pub const MAX_LENGTH: usize = 2000;
+
+/// 每个发送者上一次反馈的时间，用户以对应的私聊id表示
+static LAST_SENT: Mutex<Option<HashMap<ChatId, Instant>>> = Mutex::new(None);
+
+/// 允许发送时记录本次时间并返回 `Ok`，否则返回还需要等待的时间
+pub fn try_acquire(sender: ChatId) -> Result<(), Duration> {
+    let mut last_sent = LAST_SENT.lock().unwrap();
+    let last_sent = last_sent.get_or_insert_with(HashMap::new);
+    last_sent.retain(|_, sent_at| sent_at.elapsed() < COOLDOWN);
+    if let Some(sent_at) = last_sent.get(&sender) {
+        return Err(COOLDOWN - sent_at.elapsed());
+    }
+    last_sent.insert(sender, Instant::now());
+    Ok(())
+}
@@ -0,0 +1,248 @@
+//! /output gallery：附带网页相册的压缩包
+//!
+//! 每个压缩包的根目录中有一个由内置模板生成的 [`INDEX_NAME`]，不引用任何外部资源，
+//! 解压后直接用浏览器打开：自适应的缩略图网格，点击后全屏查看原图，并显示说明文字和日期。
+//! 启用 `imaging` 时缩略图保存在 [`THUMBS_DIR`] 中，否则网格中直接显示原图。
+//! 页面中的所有文字都经过转义，文件路径经过百分号编码。
+
+use chrono::{DateTime, Local};
+
+/// 网页相册的文件名
+pub const INDEX_NAME: &str = "index.html";
+/// 缩略图所在的文件夹
+pub const THUMBS_DIR: &str = "thumbs";
+/// 缩略图最长边的像素数
+#[cfg(feature = "imaging")]
+const THUMB_MAX_SIDE: u32 = 320;
+
+/// 相册中的一个文件
+#[derive(Debug)]
+pub struct Item<'a> {
+    /// 文件在压缩包中的路径
+    pub path: String,
+    /// 缩略图在压缩包中的路径，不是图片或没有缩略图时为 `None`
+    pub thumb: Option<String>,
+    /// 是否是图片，其余文件只在页面底部列出链接
+    pub is_image: bool,
+    pub caption: Option<&'a str>,
+    pub date: Option<DateTime<Local>>,
+}
+
+/// 文件 `name` 的缩略图在压缩包中的路径
+pub fn thumb_path(name: &str) -> String {
+    format!("{}/{}.jpg", THUMBS_DIR, name)
+}
+
+/// 生成最长边不超过 [`THUMB_MAX_SIDE`] 的 JPEG 缩略图，无法解码时返回 `None`
+///
+/// 同步执行，需要在阻塞线程中调用。
+#[cfg(feature = "imaging")]
+pub fn thumbnail(path: &std::path::Path) -> Option<Vec<u8>> {
+    let image = image::open(path).ok()?;
+    let image = image.thumbnail(THUMB_MAX_SIDE, THUMB_MAX_SIDE).to_rgb8();
+    let mut encoded = Vec::new();
+    image
+        .write_to(
+            &mut std::io::Cursor::new(&mut encoded),
+            image::ImageFormat::Jpeg,
+        )
+        .ok()?;
+    Some(encoded)
+}
+
+#[cfg(not(feature = "imaging"))]
+pub fn thumbnail(_path: &std::path::Path) -> Option<Vec<u8>> {
+    None
+}
+
+/// 生成 [`INDEX_NAME`] 的内容
+pub fn render(title: &str, items: &[Item]) -> String {
+    let images = items
+        .iter()
+        .filter(|item| item.is_image)
+        .collect::<Vec<_>>();
+    let mut grid = String::new();
+    let mut lightboxes = String::new();
+    for (i, item) in images.iter().enumerate() {
+        let id = i + 1;
+        let src = encode_path(&item.path);
+        let thumb = item.thumb.as_deref().map_or(src.clone(), encode_path);
+        let caption = item.caption.map(escape).unwrap_or_default();
+        let date = item
+            .date
+            .map(|date| date.format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_default();
+        grid.push_str(&format!(
+            "<figure><a href=\"#img-{id}\"><img src=\"{thumb}\" alt=\"{caption}\" loading=\"lazy\"></a><figcaption>{caption}<time>{date}</time></figcaption></figure>\n"
+        ));
+        let previous = match id {
+            1 => String::new(),
+            _ => format!("<a class=\"nav prev\" href=\"#img-{}\">‹</a>", id - 1),
+        };
+        let next = match id == images.len() {
+            true => String::new(),
+            false => format!("<a class=\"nav next\" href=\"#img-{}\">›</a>", id + 1),
+        };
+        lightboxes.push_str(&format!(
+            "<div class=\"lightbox\" id=\"img-{id}\"><a class=\"close\" href=\"#\"></a>{previous}<div class=\"full\"><a href=\"{src}\"><img src=\"{src}\" alt=\"{caption}\" loading=\"lazy\"></a><p>{caption} <time>{date}</time></p></div>{next}</div>\n"
+        ));
+    }
+    let others = items
+        .iter()
+        .filter(|item| !item.is_image)
+        .map(|item| {
+            format!(
+                "<li><a href=\"{}\">{}</a></li>",
+                encode_path(&item.path),
+                escape(&item.path)
+            )
+        })
+        .collect::<String>();
+    let others = match others.is_empty() {
+        true => String::new(),
+        false => format!("<h2>其他文件</h2>\n<ul>{}</ul>\n", others),
+    };
+    format!(
+        "<!DOCTYPE html>\n<html lang=\"zh\">\n<head>\n<meta charset=\"utf-8\">\n<meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n<title>{title}</title>\n<style>{STYLE}</style>\n</head>\n<body>\n<h1>{title}</h1>\n<p class=\"count\">共 {count} 张图片</p>\n<main>\n{grid}</main>\n{others}{lightboxes}</body>\n</html>\n",
+        title = escape(title),
+        count = images.len(),
+    )
+}
+
+const STYLE: &str = "body{margin:0;padding:1rem;font-family:sans-serif;background:#111;color:#eee}\
+h1{font-size:1.4rem;margin:0 0 .2rem}h2{font-size:1.1rem}a{color:#8bf}.count{color:#999;margin:0 0 1rem}\
+main{display:grid;grid-template-columns:repeat(auto-fill,minmax(160px,1fr));gap:.6rem}\
+figure{margin:0;background:#222;border-radius:4px;overflow:hidden}\
+figure img{display:block;width:100%;aspect-ratio:1;object-fit:cover}\
+figcaption{padding:.3rem .4rem;font-size:.8rem;overflow-wrap:anywhere}\
+time{display:block;color:#999;font-size:.75rem}\
+.lightbox{display:none;position:fixed;inset:0;background:rgba(0,0,0,.92);align-items:center;justify-content:center}\
+.lightbox:target{display:flex}.close{position:absolute;inset:0}\
+.full{position:relative;max-width:92vw;text-align:center}\
+.full img{max-width:92vw;max-height:85vh;object-fit:contain}.full p{margin:.4rem 0 0}\
+.nav{position:absolute;top:50%;z-index:1;padding:1rem;font-size:2.5rem;text-decoration:none;transform:translateY(-50%)}\
+.prev{left:0}.next{right:0}";
+
+/// 转义文字，使其可以放在 HTML 的元素内容和属性值中
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// 对压缩包中的路径做百分号编码，文件名中的 `#`、`?` 等字符不会被当作链接的一部分
+fn encode_path(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            }
+            byte => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOSTILE: &str = "\"><script>alert(1)</script><img src=x onerror='alert(2)'>&amp;";
+
+    fn image(path: &str, caption: Option<&'static str>) -> Item<'static> {
+        Item {
+            path: path.to_string(),
+            thumb: Some(thumb_path(path)),
+            is_image: true,
+            caption,
+            date: None,
+        }
+    }
+
+    #[test]
+    fn hostile_text_is_escaped() {
+        let items = [
+            image("image_1.jpg", Some(HOSTILE)),
+            Item {
+                path: "files/<b>\"x\".txt".to_string(),
+                thumb: None,
+                is_image: false,
+                caption: Some(HOSTILE),
+                date: None,
+            },
+        ];
+        let html = render(HOSTILE, &items);
+
+        assert!(!html.contains("<script"));
+        assert!(!html.contains("<b>"));
+        assert!(!html.contains("onerror='"));
+        // 模板自己的标签之外没有新的图片，说明文字没有跳出属性值
+        assert_eq!(html.matches("<img ").count(), 2);
+        let escaped = "&quot;&gt;&lt;script&gt;alert(1)&lt;/script&gt;&lt;img src=x onerror=&#39;alert(2)&#39;&gt;&amp;amp;";
+        assert_eq!(escape(HOSTILE), escaped);
+        assert!(html.contains(&format!("<title>{}</title>", escaped)));
+        assert!(html.contains(&format!("alt=\"{}\"", escaped)));
+        // 路径编码后放在链接中，转义后作为文字
+        assert!(html.contains("href=\"files/%3Cb%3E%22x%22.txt\""));
+        assert!(html.contains("&lt;b&gt;&quot;x&quot;.txt</a>"));
+    }
+
+    #[test]
+    fn paths_are_percent_encoded() {
+        assert_eq!(
+            encode_path("images/日落 #1?.jpg"),
+            "images/%E6%97%A5%E8%90%BD%20%231%3F.jpg"
+        );
+        assert_eq!(encode_path("a-b_c.~/d.png"), "a-b_c.~/d.png");
+    }
+
+    #[test]
+    fn grid_links_thumbnails_and_lightboxes() {
+        let date = Local::now();
+        let mut items = vec![
+            image("image_1.jpg", None),
+            image("image_2.jpg", Some("日落")),
+        ];
+        // 没有缩略图时网格中直接显示原图
+        items[1].thumb = None;
+        items[1].date = Some(date);
+        let html = render("trip", &items);
+
+        assert!(html.contains("共 2 张图片"));
+        assert!(html.contains("<img src=\"thumbs/image_1.jpg.jpg\""));
+        assert!(html.contains("<a href=\"#img-2\"><img src=\"image_2.jpg\""));
+        assert!(html.contains(&date.format("%Y-%m-%d %H:%M").to_string()));
+        // 第一张没有上一张，最后一张没有下一张
+        assert_eq!(html.matches("class=\"nav prev\"").count(), 1);
+        assert_eq!(html.matches("class=\"nav next\"").count(), 1);
+        assert!(!html.contains("其他文件"));
+        // 不引用外部资源
+        assert!(!html.contains("http://") && !html.contains("https://"));
+    }
+
+    #[cfg(feature = "imaging")]
+    #[test]
+    fn thumbnails_fit_max_side() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wide.png");
+        image::RgbImage::new(1000, 500).save(&path).unwrap();
+        let thumb = image::load_from_memory(&thumbnail(&path).unwrap()).unwrap();
+        assert_eq!(
+            (thumb.width(), thumb.height()),
+            (THUMB_MAX_SIDE, THUMB_MAX_SIDE / 2)
+        );
+
+        std::fs::write(dir.path().join("broken.png"), b"not an image").unwrap();
+        assert!(thumbnail(&dir.path().join("broken.png")).is_none());
+    }
+}
@@ -0,0 +1,152 @@
+//! 将 iPhone 以文件形式发送的 HEIC/HEIF 图片转换为 JPEG
+//!
+//! 需要启用 `imaging-heif` 特性，依赖系统的 libheif。未启用或转换失败时保留原文件。
+
+use std::path::Path;
+use teloxide::types::Message;
+
+/// 消息是否是以文件形式发送的 HEIC/HEIF 图片
+pub fn is_heif(msg: &Message) -> bool {
+    let Some(document) = msg.document() else {
+        return false;
+    };
+    let heif_mime = document.mime_type.as_ref().is_some_and(|mime| {
+        matches!(
+            mime.essence_str(),
+            "image/heic" | "image/heif" | "image/heic-sequence" | "image/heif-sequence"
+        )
+    });
+    let heif_name = document.file_name.as_deref().is_some_and(|name| {
+        let name = name.to_lowercase();
+        name.ends_with(".heic") || name.ends_with(".heif")
+    });
+    heif_mime || heif_name
+}
+
+/// 转换后的文件名，保留原文件名，扩展名改为 `.jpg`
+pub fn jpeg_name(name: &str) -> String {
+    let stem = name.rsplit_once('.').map_or(name, |(stem, _)| stem);
+    format!("{}.jpg", stem)
+}
+
+/// 将 `src` 转换为质量为 `quality`（1-100）的 JPEG 并写入 `dst`
+///
+/// 同步执行，需要在阻塞线程中调用。
+#[cfg(feature = "imaging-heif")]
+pub fn convert_to_jpeg(
+    src: &Path,
+    dst: &Path,
+    quality: u8,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    use libheif_rs::{ColorSpace, HeifContext, LibHeif, RgbChroma};
+
+    let src = src.to_str().ok_or("文件路径不是有效的 UTF-8")?;
+    let context = HeifContext::read_from_file(src)?;
+    let handle = context.primary_image_handle()?;
+    // 解码时会应用文件中记录的旋转和裁剪
+    let image = LibHeif::new().decode(&handle, ColorSpace::Rgb(RgbChroma::Rgb), None)?;
+    let plane = image
+        .planes()
+        .interleaved
+        .ok_or("解码结果中没有 RGB 数据")?;
+
+    // 每行的末尾可能有对齐用的填充，逐行复制出连续的像素
+    let row = plane.width as usize * 3;
+    let mut pixels = Vec::with_capacity(row * plane.height as usize);
+    for line in plane.data.chunks(plane.stride).take(plane.height as usize) {
+        pixels.extend_from_slice(&line[..row]);
+    }
+
+    let file = std::io::BufWriter::new(std::fs::File::create(dst)?);
+    image::codecs::jpeg::JpegEncoder::new_with_quality(file, quality.clamp(1, 100)).encode(
+        &pixels,
+        plane.width,
+        plane.height,
+        image::ExtendedColorType::Rgb8,
+    )?;
+    Ok(())
+}
+
+#[cfg(not(feature = "imaging-heif"))]
+pub fn convert_to_jpeg(
+    _src: &Path,
+    _dst: &Path,
+    _quality: u8,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    Err("编译时没有启用 imaging-heif 功能".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+
+    /// 测试图片来自 libheif-rs 的测试数据（CC BY-SA 4.0）
+    fn fixture() -> std::path::PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("assets/fixtures/sample.heif")
+    }
+
+    #[test]
+    fn detects_heif_by_mime_or_name() {
+        assert!(is_heif(&test_util::document(1, "IMG_0001.HEIC", None)));
+        assert!(is_heif(&test_util::document(2, "photo.heif", None)));
+        assert!(is_heif(&test_util::document(
+            3,
+            "photo",
+            Some("image/heic")
+        )));
+        assert!(is_heif(&test_util::document(
+            4,
+            "photo.bin",
+            Some("image/heif-sequence")
+        )));
+        assert!(!is_heif(&test_util::document(
+            5,
+            "photo.jpg",
+            Some("image/jpeg")
+        )));
+        assert!(!is_heif(&test_util::text(6, "photo.heic")));
+    }
+
+    #[test]
+    fn jpeg_name_keeps_stem() {
+        assert_eq!(jpeg_name("IMG_0001.HEIC"), "IMG_0001.jpg");
+        assert_eq!(jpeg_name("trip.2024.heif"), "trip.2024.jpg");
+        assert_eq!(jpeg_name("photo"), "photo.jpg");
+    }
+
+    #[cfg(not(feature = "imaging-heif"))]
+    #[test]
+    fn conversion_is_unavailable_without_feature() {
+        let dir = tempfile::tempdir().unwrap();
+        let dst = dir.path().join("out.jpg");
+        assert!(convert_to_jpeg(&fixture(), &dst, 90).is_err());
+        assert!(!dst.exists());
+    }
+
+    #[cfg(feature = "imaging-heif")]
+    #[test]
+    fn converts_fixture_to_jpeg() {
+        let src = fixture();
+        let dir = tempfile::tempdir().unwrap();
+        let dst = dir.path().join("sample.jpg");
+        convert_to_jpeg(&src, &dst, 80).unwrap();
+
+        let converted = image::ImageReader::open(&dst)
+            .unwrap()
+            .with_guessed_format()
+            .unwrap();
+        assert_eq!(converted.format(), Some(image::ImageFormat::Jpeg));
+        let converted = converted.decode().unwrap();
+        assert!(converted.width() > 0 && converted.height() > 0);
+    }
+
+    #[cfg(feature = "imaging-heif")]
+    #[test]
+    fn invalid_file_fails_to_convert() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("broken.heic");
+        std::fs::write(&src, b"not a heif file").unwrap();
+        assert!(convert_to_jpeg(&src, &dir.path().join("broken.jpg"), 80).is_err());
+    }
+}
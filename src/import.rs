@@ -0,0 +1,219 @@
+//! 从用户发送的 zip 压缩包中导入图片
+//!
+//! 只解压能识别为图片的文件，不会按压缩包中的路径写入，文件按顺序重新命名，
+//! 因此 `../` 之类的路径不会写到目录之外；这样的文件会被跳过并计入跳过的数量。
+//! 解压的总大小不超过限制，不信任压缩包中记录的大小，嵌套的压缩包不会展开。
+
+use crate::download;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use teloxide::types::Message;
+
+/// 单个压缩包中最多处理的文件数量
+const MAX_ENTRIES: usize = 1000;
+
+/// 消息是否是一个 zip 压缩包
+pub fn is_zip(msg: &Message) -> bool {
+    let Some(document) = msg.document() else {
+        return false;
+    };
+    let zip_mime = document.mime_type.as_ref().is_some_and(|mime| {
+        matches!(
+            mime.essence_str(),
+            "application/zip" | "application/x-zip-compressed"
+        )
+    });
+    let zip_name = document
+        .file_name
+        .as_deref()
+        .is_some_and(|name| name.to_lowercase().ends_with(".zip"));
+    zip_mime || zip_name
+}
+
+/// 导入的结果
+#[derive(Debug, Default)]
+pub struct Imported {
+    /// 解压出的图片、扩展名和大小，按在压缩包中的顺序
+    pub images: Vec<(PathBuf, &'static str, u64)>,
+    /// 跳过的文件数量：不是图片、路径不安全、嵌套的压缩包或超出大小限制
+    pub skipped: usize,
+}
+
+/// 将 `archive` 中的图片解压到 `dest`，解压的总大小不超过 `max_extracted` 字节
+///
+/// 同步执行，需要在阻塞线程中调用。解压出的文件名为 `<prefix>_<序号>.<扩展名>`。
+pub fn extract_images(
+    archive: &Path,
+    dest: &Path,
+    prefix: &str,
+    max_extracted: u64,
+) -> zip::result::ZipResult<Imported> {
+    let mut zip = zip::ZipArchive::new(std::fs::File::open(archive)?)?;
+    let mut imported = Imported::default();
+    let mut budget = max_extracted;
+    let mut buffer = Vec::new();
+    for index in 0..zip.len() {
+        if index >= MAX_ENTRIES {
+            imported.skipped += zip.len() - MAX_ENTRIES;
+            break;
+        }
+        let entry = zip.by_index(index)?;
+        if entry.is_dir() {
+            continue;
+        }
+        let name = entry.name().to_string();
+        if entry.enclosed_name().is_none() || name.to_lowercase().ends_with(".zip") {
+            log::debug!("Skipping zip entry {:?}", name);
+            imported.skipped += 1;
+            continue;
+        }
+
+        // 按实际解压出的字节数计算，多读一个字节用于判断是否超出
+        buffer.clear();
+        entry.take(budget + 1).read_to_end(&mut buffer)?;
+        if buffer.len() as u64 > budget {
+            log::warn!(
+                "Zip entry {:?} exceeds the extraction limit of {} bytes",
+                name,
+                max_extracted
+            );
+            imported.skipped += zip.len() - index;
+            break;
+        }
+        let Some(extension) =
+            download::sniff_image_format(&buffer).filter(|_| download::is_valid_image(&buffer))
+        else {
+            imported.skipped += 1;
+            continue;
+        };
+        budget -= buffer.len() as u64;
+        let path = dest.join(format!(
+            "{}_{}.{}",
+            prefix,
+            imported.images.len() + 1,
+            extension
+        ));
+        std::fs::write(&path, &buffer)?;
+        imported.images.push((path, extension, buffer.len() as u64));
+    }
+    Ok(imported)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use zip::write::{FileOptions, ZipWriter};
+
+    /// 1x1 的 GIF 图片
+    const GIF: &[u8] = b"GIF89a\x01\x00\x01\x00\x80\x00\x00\xff\xff\xff\x00\x00\x00!\xf9\x04\x01\x00\x00\x00\x00,\x00\x00\x00\x00\x01\x00\x01\x00\x00\x02\x02D\x01\x00;";
+
+    fn write_zip(path: &Path, entries: &[(&str, &[u8])]) {
+        let mut zip = ZipWriter::new(std::fs::File::create(path).unwrap());
+        let options =
+            FileOptions::<()>::default().compression_method(zip::CompressionMethod::Deflated);
+        for (name, content) in entries {
+            zip.start_file(*name, options).unwrap();
+            zip.write_all(content).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    fn extract(entries: &[(&str, &[u8])], max_extracted: u64) -> (Imported, tempfile::TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("upload.zip");
+        write_zip(&archive, entries);
+        let dest = dir.path().join("out");
+        std::fs::create_dir(&dest).unwrap();
+        let imported = extract_images(&archive, &dest, "zip3", max_extracted).unwrap();
+        (imported, dir)
+    }
+
+    /// 解压目录中的所有文件名
+    fn extracted(dir: &tempfile::TempDir) -> Vec<String> {
+        let mut names = std::fs::read_dir(dir.path().join("out"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn imports_images_in_order() {
+        let (imported, dir) = extract(
+            &[
+                ("b/second.gif", GIF),
+                ("notes.txt", b"hello"),
+                ("first.gif", GIF),
+                ("nested.zip", b"PK"),
+            ],
+            1024,
+        );
+        assert_eq!(imported.images.len(), 2);
+        assert_eq!(imported.skipped, 2);
+        assert_eq!(imported.images[0].1, "gif");
+        assert_eq!(imported.images[0].2, GIF.len() as u64);
+        assert_eq!(extracted(&dir), ["zip3_1.gif", "zip3_2.gif"]);
+    }
+
+    #[test]
+    fn malicious_names_are_skipped() {
+        let (imported, dir) = extract(
+            &[
+                ("../escape.gif", GIF),
+                ("a/../../escape.gif", GIF),
+                ("/etc/absolute.gif", GIF),
+                ("ok.gif", GIF),
+            ],
+            1024,
+        );
+        assert_eq!(imported.images.len(), 1);
+        assert_eq!(imported.skipped, 3);
+        assert_eq!(extracted(&dir), ["zip3_1.gif"]);
+        // 没有写到解压目录之外
+        assert!(!dir.path().join("escape.gif").exists());
+        assert_eq!(
+            std::fs::read_dir(dir.path()).unwrap().count(),
+            2,
+            "only upload.zip and out/"
+        );
+    }
+
+    #[test]
+    fn decompression_bomb_stops_extraction() {
+        // 压缩后很小，解压后远超限制
+        let mut bomb = GIF.to_vec();
+        bomb.resize(4 * 1024 * 1024, 0);
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("bomb.zip");
+        write_zip(
+            &archive,
+            &[("small.gif", GIF), ("bomb.gif", &bomb), ("after.gif", GIF)],
+        );
+        assert!(std::fs::metadata(&archive).unwrap().len() < 64 * 1024);
+
+        let dest = dir.path().join("out");
+        std::fs::create_dir(&dest).unwrap();
+        let imported = extract_images(&archive, &dest, "zip1", 64 * 1024).unwrap();
+        // 超出限制的文件和之后的文件都跳过，之前的保留
+        assert_eq!(imported.images.len(), 1);
+        assert_eq!(imported.skipped, 2);
+        let total = std::fs::read_dir(&dest)
+            .unwrap()
+            .map(|entry| entry.unwrap().metadata().unwrap().len())
+            .sum::<u64>();
+        assert_eq!(total, GIF.len() as u64);
+    }
+
+    #[test]
+    fn limit_counts_extracted_bytes() {
+        // 正好用完限制的文件仍然导入
+        let (imported, _dir) = extract(&[("a.gif", GIF), ("b.gif", GIF)], 2 * GIF.len() as u64);
+        assert_eq!(imported.images.len(), 2);
+        assert_eq!(imported.skipped, 0);
+        let (imported, _dir) = extract(&[("a.gif", GIF), ("b.gif", GIF)], 2 * GIF.len() as u64 - 1);
+        assert_eq!(imported.images.len(), 1);
+        assert_eq!(imported.skipped, 1);
+    }
+}
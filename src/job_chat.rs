@@ -0,0 +1,138 @@
+//! 打包任务与会话之间的交互
+//!
+//! `run_job` 只通过 [`JobChat`] 获取文件和发送消息，不直接连接 telegram。
+//! 机器人使用 [`TelegramChat`]，测试中可以换成记录消息的实现。
+
+use crate::download::{self, FileUrl};
+use crate::markdown::{self, FormattedText};
+use crate::output;
+use crate::progress::{self, Progress};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use teloxide::RequestError;
+use teloxide::prelude::*;
+use teloxide::types::{FileId, MessageId};
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+/// 可以下载的文件
+#[derive(Debug, Clone)]
+pub struct RemoteFile {
+    pub url: FileUrl,
+    pub size: u32,
+}
+
+/// 一次打包任务所在的会话，所有消息都回复触发任务的那条消息
+pub trait JobChat: Send + Sync {
+    /// 查询文件的下载地址和大小
+    fn get_file(
+        &self,
+        file_id: &FileId,
+    ) -> impl Future<Output = Result<RemoteFile, RequestError>> + Send;
+
+    /// 发送一条消息，返回它的id
+    fn reply(
+        &self,
+        text: impl Into<FormattedText> + Send,
+    ) -> impl Future<Output = Result<MessageId, RequestError>> + Send;
+
+    /// 在 `status` 消息上显示进度，直到 `stop` 被触发
+    fn show_progress(&self, status: MessageId, progress: Arc<Progress>, stop: CancellationToken);
+
+    /// 以相册的形式重新发送图片，返回发送的数量，见 [`output::send_as_albums`]
+    fn send_albums(
+        &self,
+        messages: &[Message],
+        captions: bool,
+    ) -> impl Future<Output = Result<usize, Box<dyn std::error::Error + Send + Sync>>> + Send;
+
+    /// 逐个发送文件，返回发送失败的序号和原因，见 [`output::send_as_documents`]
+    fn send_documents(
+        &self,
+        files: &[(usize, PathBuf, Option<String>)],
+    ) -> impl Future<Output = Vec<(usize, RequestError)>> + Send;
+
+    /// 发送一个压缩包，见 [`output::send_archive_with_retry`]
+    fn send_archive(
+        &self,
+        path: &Path,
+        caption: Option<&str>,
+        job_id: Uuid,
+    ) -> impl Future<Output = Result<(), RequestError>> + Send;
+
+    /// 删除任务的中间消息，失败时忽略
+    fn delete_messages(&self, messages: &[MessageId]) -> impl Future<Output = ()> + Send;
+}
+
+/// 通过机器人回复 `chat_id` 中的 `reply_to`
+pub struct TelegramChat {
+    pub bot: Arc<Bot>,
+    pub chat_id: ChatId,
+    pub reply_to: MessageId,
+}
+
+impl JobChat for TelegramChat {
+    async fn get_file(&self, file_id: &FileId) -> Result<RemoteFile, RequestError> {
+        let file = self.bot.get_file(file_id.clone()).await?;
+        Ok(RemoteFile {
+            url: download::telegram_file_url(self.bot.token(), &file.path),
+            size: file.size,
+        })
+    }
+
+    async fn reply(
+        &self,
+        text: impl Into<FormattedText> + Send,
+    ) -> Result<MessageId, RequestError> {
+        let sent = markdown::send(&self.bot, self.chat_id, Some(self.reply_to), text).await?;
+        Ok(sent.id)
+    }
+
+    fn show_progress(&self, status: MessageId, progress: Arc<Progress>, stop: CancellationToken) {
+        tokio::spawn(progress::report(
+            (*self.bot).clone(),
+            self.chat_id,
+            status,
+            progress,
+            stop,
+        ));
+    }
+
+    async fn send_albums(
+        &self,
+        messages: &[Message],
+        captions: bool,
+    ) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+        output::send_as_albums(&self.bot, self.chat_id, self.reply_to, messages, captions).await
+    }
+
+    async fn send_documents(
+        &self,
+        files: &[(usize, PathBuf, Option<String>)],
+    ) -> Vec<(usize, RequestError)> {
+        output::send_as_documents(&self.bot, self.chat_id, self.reply_to, files).await
+    }
+
+    async fn send_archive(
+        &self,
+        path: &Path,
+        caption: Option<&str>,
+        job_id: Uuid,
+    ) -> Result<(), RequestError> {
+        output::send_archive_with_retry(
+            &self.bot,
+            self.chat_id,
+            self.reply_to,
+            path,
+            caption,
+            job_id,
+        )
+        .await
+        .map(|_| ())
+    }
+
+    async fn delete_messages(&self, messages: &[MessageId]) {
+        crate::delete_interim_messages(&self.bot, self.chat_id, messages).await;
+    }
+}
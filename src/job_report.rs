@@ -0,0 +1,393 @@
+//! 一次打包任务的结果
+//!
+//! `run_job` 在处理过程中逐步填写，出错时也保留已经得到的数量。结束后用于统计、
+//! webhook 通知和给用户的汇总，汇总的格式只依赖这里的数据，不需要连接 telegram。
+
+use crate::markdown::FormattedText;
+use crate::units::format_size;
+
+/// 一次任务的结果
+#[derive(Debug, Default)]
+pub struct ProcessingReport {
+    /// 收集到的消息数量
+    pub items: usize,
+    /// 下载成功的文件数量
+    pub downloaded: usize,
+    /// 下载失败的文件：编号（从1开始）和原因
+    pub failures: Vec<(usize, String)>,
+    /// 压缩包的名称，不含扩展名
+    pub archive_name: Option<String>,
+    /// 以相册或单独文件发送成功的数量
+    pub sent: usize,
+    /// 发布的 telegraph 页面
+    pub published_url: Option<String>,
+    /// 每个压缩包的送达结果，按发送顺序
+    pub archives: Vec<ArchivePart>,
+    /// 提取下载链接时跳过或无法处理的内容
+    pub skips: SkipReport,
+    /// 没有出错但也没有交付结果（下载超时或全部下载失败），临时目录保留用于 /retry
+    pub unfinished: bool,
+}
+
+/// 一个压缩包的送达结果
+#[derive(Debug)]
+pub struct ArchivePart {
+    pub name: String,
+    /// 压缩包中的文件数量
+    pub count: usize,
+    pub size: u64,
+    /// 上传到 SFTP 服务器后的远程路径
+    pub remote_path: Option<String>,
+    /// 发送或上传失败的原因
+    pub errors: Vec<String>,
+    /// 是否至少通过一种方式送达
+    pub delivered: bool,
+}
+
+/// 提取下载链接时跳过的文件和导入的结果，这些文件不会被下载
+#[derive(Debug, Default)]
+pub struct SkipReport {
+    /// 超过 `MAX_FILE_BYTES` 而跳过的文件：第几条消息和文件大小
+    pub too_large: Vec<(usize, u32)>,
+    /// 最长边小于 `/minsize` 而跳过的图片：第几条消息和最大尺寸的宽高
+    pub undersized: Vec<(usize, u32, u32)>,
+    /// 同一组中同时有图片和原图文件，因此没有打包的图片或文件数量
+    pub paired: usize,
+    pub imports: ImportReport,
+    pub heifs: HeifReport,
+    pub previews: PreviewReport,
+}
+
+impl SkipReport {
+    /// 没有可以下载的文件时，是否有需要说明的原因；没有时只是没有收集到图片
+    pub fn explains_empty(&self) -> bool {
+        !self.too_large.is_empty()
+            || !self.undersized.is_empty()
+            || !self.imports.failed.is_empty()
+            || self.imports.archives > 0
+            || !self.heifs.failed.is_empty()
+            || !self.previews.failed.is_empty()
+            || self.previews.without_image > 0
+    }
+
+    /// 结果中跳过的部分，`photo_source` 为 `/photosource` 设置的说明
+    pub fn describe(
+        &self,
+        file_limit: u64,
+        photo_source: &str,
+        min_dimension: u32,
+    ) -> FormattedText {
+        let mut text = self
+            .imports
+            .describe()
+            .append(self.heifs.describe())
+            .append(self.previews.describe());
+        if self.paired > 0 {
+            text = text.text(format!(
+                "\n\n🖼 同一组中同时有图片和原图文件，{}，跳过了 {} 个",
+                photo_source, self.paired
+            ));
+        }
+        if !self.undersized.is_empty() {
+            text = text
+                .text("\n\n📏 以下 ")
+                .bold(self.undersized.len())
+                .text(format!(
+                    " 张图片最长边小于 {} 像素，已跳过：",
+                    min_dimension
+                ));
+            for (position, width, height) in &self.undersized {
+                text = text.text(format!("\n第 {} 条消息：{}x{}", position, width, height));
+            }
+        }
+        // 跳过的文件不会被下载，和下载失败的一起列出
+        if !self.too_large.is_empty() {
+            text = text
+                .text("\n\n⚠️ 以下 ")
+                .bold(self.too_large.len())
+                .text(format!(
+                    " 个文件超过了 {} 的大小限制，没有下载：",
+                    format_size(file_limit)
+                ));
+            for (position, size) in &self.too_large {
+                text = text.text(format!(
+                    "\n第 {} 条消息：{}",
+                    position,
+                    format_size(u64::from(*size))
+                ));
+            }
+        }
+        text
+    }
+}
+
+/// 一个任务中导入 zip 压缩包的结果
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    /// 成功导入的压缩包数量
+    pub archives: usize,
+    /// 导入的图片数量
+    pub images: usize,
+    /// 压缩包中跳过的文件数量
+    pub skipped: usize,
+    /// 无法导入的压缩包：第几条消息和原因
+    pub failed: Vec<(usize, String)>,
+}
+
+impl ImportReport {
+    /// 结果中的说明，没有压缩包时为空
+    pub fn describe(&self) -> FormattedText {
+        let mut text = FormattedText::new();
+        if self.archives > 0 {
+            text = text.text(format!(
+                "\n\n📦 从 {} 个压缩包中导入了 {} 张图片",
+                self.archives, self.images
+            ));
+            if self.skipped > 0 {
+                text = text.text(format!(
+                    "，跳过了 {} 个不是图片或无法解压的文件",
+                    self.skipped
+                ));
+            }
+        }
+        if !self.failed.is_empty() {
+            text = text.text("\n\n⚠️ 以下压缩包无法导入：");
+            for (position, why) in &self.failed {
+                text = text.text(format!("\n第 {} 条消息：{}", position, why));
+            }
+        }
+        text
+    }
+}
+
+/// 一个任务中转换 HEIC/HEIF 文件的结果
+#[derive(Debug, Default)]
+pub struct HeifReport {
+    /// 转换为 JPEG 的文件数量
+    pub converted: usize,
+    /// 没有转换、按原文件打包的文件：第几条消息和原因
+    pub kept: Vec<(usize, String)>,
+    /// 无法下载的文件：第几条消息和原因
+    pub failed: Vec<(usize, String)>,
+}
+
+impl HeifReport {
+    /// 结果中的说明，没有 HEIC/HEIF 文件时为空
+    pub fn describe(&self) -> FormattedText {
+        let mut text = FormattedText::new();
+        if self.converted > 0 {
+            text = text.text(format!(
+                "\n\n🖼 已将 {} 个 HEIC 文件转换为 JPEG",
+                self.converted
+            ));
+        }
+        if !self.kept.is_empty() {
+            text = text.text("\n\n⚠️ 以下 HEIC 文件没有转换，按原文件打包：");
+            for (position, why) in &self.kept {
+                text = text.text(format!("\n第 {} 条消息：{}", position, why));
+            }
+        }
+        if !self.failed.is_empty() {
+            text = text.text("\n\n⚠️ 以下 HEIC 文件无法下载：");
+            for (position, why) in &self.failed {
+                text = text.text(format!("\n第 {} 条消息：{}", position, why));
+            }
+        }
+        text
+    }
+}
+
+/// 网页链接预览图的处理结果
+#[derive(Debug, Default)]
+pub struct PreviewReport {
+    /// 找到预览图的页面数量
+    pub found: usize,
+    /// 没有预览图或不是网页而跳过的链接数量
+    pub without_image: usize,
+    /// 无法读取的链接：第几条消息、链接和原因
+    pub failed: Vec<(usize, String, String)>,
+}
+
+impl PreviewReport {
+    /// 结果中的说明，没有处理网页链接时为空
+    pub fn describe(&self) -> FormattedText {
+        let mut text = FormattedText::new();
+        if self.found > 0 {
+            text = text.text(format!("\n\n🔗 从 {} 个网页中找到了预览图", self.found));
+        }
+        if self.without_image > 0 {
+            text = text.text(format!(
+                "\n\n🔗 {} 个链接不是网页或没有预览图，已跳过",
+                self.without_image
+            ));
+        }
+        if !self.failed.is_empty() {
+            text = text.text("\n\n⚠️ 以下链接无法读取：");
+            for (position, url, why) in &self.failed {
+                text = text
+                    .text(format!("\n第 {} 条消息 ", position))
+                    .code(url)
+                    .text(format!("：{}", why));
+            }
+        }
+        text
+    }
+}
+
+impl ProcessingReport {
+    /// 下载失败的文件数量
+    pub fn failed(&self) -> usize {
+        self.failures.len()
+    }
+
+    /// 所有送达的压缩包的总大小
+    pub fn archive_size(&self) -> u64 {
+        self.archives
+            .iter()
+            .filter(|part| part.delivered)
+            .map(|part| part.size)
+            .sum()
+    }
+
+    /// 没能全部送达的压缩包数量
+    pub fn failed_archives(&self) -> usize {
+        self.archives
+            .iter()
+            .filter(|part| !part.errors.is_empty())
+            .count()
+    }
+
+    /// 只有一个压缩包且没能送达时返回失败的原因，这时整个任务视为失败
+    pub fn single_archive_error(&self) -> Option<String> {
+        match self.archives.as_slice() {
+            [part] if !part.delivered => Some(part.errors.join("；")),
+            _ => None,
+        }
+    }
+
+    /// 结果中压缩包的部分：分卷时列出每个压缩包，只有一个时显示大小和送达情况
+    pub fn describe_archives(&self) -> FormattedText {
+        if self.archives.len() > 1 {
+            let total_size = self.archives.iter().map(|part| part.size).sum::<u64>();
+            let mut report = FormattedText::new()
+                .text("\n\n📦 共 ")
+                .bold(self.archives.len())
+                .text(format!(" 个压缩包，合计 {}：", format_size(total_size)));
+            for (i, part) in self.archives.iter().enumerate() {
+                report = report
+                    .text(format!("\n{}. ", i + 1))
+                    .code(&part.name)
+                    .text(format!("：{} 张，{}", part.count, format_size(part.size)));
+                if let Some(path) = &part.remote_path {
+                    report = report.text("，已上传到 ").code(path);
+                }
+                for why in &part.errors {
+                    report = report.text(format!("，❌ {}", why));
+                }
+            }
+            report
+        } else {
+            let mut report = FormattedText::new();
+            for part in &self.archives {
+                report = report.text(format!("\n\n📦 压缩包大小 {}", format_size(part.size)));
+                if let Some(path) = &part.remote_path {
+                    report = report.text("\n📁 已上传到 SFTP：").code(path);
+                }
+                for why in &part.errors {
+                    report = report.text(format!("\n❌ {}", why));
+                }
+            }
+            report
+        }
+    }
+
+    /// 下载失败的文件列表，没有失败时为空
+    pub fn describe_failures(&self) -> FormattedText {
+        if self.failures.is_empty() {
+            return FormattedText::new();
+        }
+        let mut report = FormattedText::new()
+            .text("\n\n⚠️ 以下 ")
+            .bold(self.failures.len())
+            .text(" 张图片下载失败：");
+        for (index, why) in &self.failures {
+            report = report.text(format!("\n第 {} 张：{}", index, why));
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_skips_explain_nothing() {
+        let skips = SkipReport::default();
+        assert!(!skips.explains_empty());
+        assert_eq!(
+            skips.describe(1024, "优先原图文件", 0),
+            FormattedText::new()
+        );
+        // 同一组中跳过的图片有对应的原图，不算作没有文件的原因
+        let skips = SkipReport {
+            paired: 2,
+            ..Default::default()
+        };
+        assert!(!skips.explains_empty());
+    }
+
+    #[test]
+    fn skips_are_listed_in_order() {
+        let skips = SkipReport {
+            too_large: vec![(3, 2048)],
+            undersized: vec![(1, 100, 80)],
+            paired: 1,
+            imports: ImportReport {
+                failed: vec![(2, "损坏".to_string())],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(skips.explains_empty());
+        let expected = FormattedText::from("\n\n⚠️ 以下压缩包无法导入：\n第 2 条消息：损坏")
+            .text("\n\n🖼 同一组中同时有图片和原图文件，优先原图文件，跳过了 1 个")
+            .text("\n\n📏 以下 ")
+            .bold(1)
+            .text(" 张图片最长边小于 200 像素，已跳过：\n第 1 条消息：100x80")
+            .text("\n\n⚠️ 以下 ")
+            .bold(1)
+            .text(format!(
+                " 个文件超过了 {} 的大小限制，没有下载：\n第 3 条消息：{}",
+                format_size(1024),
+                format_size(2048)
+            ));
+        assert_eq!(skips.describe(1024, "优先原图文件", 200), expected);
+    }
+
+    #[test]
+    fn single_undelivered_archive_fails_the_job() {
+        let part = |delivered: bool| ArchivePart {
+            name: "a.zip".to_string(),
+            count: 1,
+            size: 10,
+            remote_path: None,
+            errors: if delivered {
+                Vec::new()
+            } else {
+                vec!["太大".to_string()]
+            },
+            delivered,
+        };
+        let mut report = ProcessingReport {
+            archives: vec![part(false)],
+            ..Default::default()
+        };
+        assert_eq!(report.single_archive_error().as_deref(), Some("太大"));
+        assert_eq!(report.archive_size(), 0);
+        report.archives.push(part(true));
+        assert_eq!(report.single_archive_error(), None);
+        assert_eq!(report.failed_archives(), 1);
+        assert_eq!(report.archive_size(), 10);
+    }
+}
@@ -0,0 +1,162 @@
+//! 打包任务的开始和结束记录
+//!
+//! 任务开始和结束时各向文件追加一行，机器人在任务进行中退出（崩溃、被杀死、断电）时
+//! 只有开始记录。下次启动时通过 [`JobJournal::take_interrupted`] 找出这些任务，
+//! 清理它们的临时目录并通知所在的会话。每行的字段用制表符分隔，开始记录为 `start`、
+//! 任务id、会话id和消息数量，结束记录为 `end` 和任务id。
+
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::PathBuf;
+use teloxide::types::ChatId;
+use uuid::Uuid;
+
+#[derive(Debug)]
+pub struct JobJournal {
+    path: PathBuf,
+    /// 追加记录时持有，避免多行交错
+    file: std::sync::Mutex<()>,
+}
+
+/// 上次运行中开始了但没有结束的任务
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterruptedJob {
+    pub job_id: Uuid,
+    pub chat_id: ChatId,
+    /// 任务包含的消息数量
+    pub items: usize,
+}
+
+impl JobJournal {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        JobJournal {
+            path: path.into(),
+            file: std::sync::Mutex::new(()),
+        }
+    }
+
+    /// 记录任务的文件
+    pub fn path(&self) -> &std::path::Path {
+        &self.path
+    }
+
+    /// 读出没有结束记录的任务并清空文件，启动时在处理任何任务之前调用
+    pub fn take_interrupted(&self) -> Vec<InterruptedJob> {
+        let _guard = self.file.lock().unwrap();
+        let content = std::fs::read_to_string(&self.path).unwrap_or_default();
+        let interrupted = interrupted(&content);
+        if !content.is_empty()
+            && let Err(why) = std::fs::write(&self.path, "")
+        {
+            log::error!("无法清空任务记录 {}: {}", self.path.display(), why);
+        }
+        interrupted
+    }
+
+    /// 记录任务开始
+    pub fn started(&self, job_id: Uuid, chat_id: ChatId, items: usize) {
+        self.append(&format!("start\t{}\t{}\t{}", job_id, chat_id.0, items));
+    }
+
+    /// 记录任务结束，无论成功、失败还是被取消
+    pub fn finished(&self, job_id: Uuid) {
+        self.append(&format!("end\t{}", job_id));
+    }
+
+    fn append(&self, line: &str) {
+        let _guard = self.file.lock().unwrap();
+        let appended = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| writeln!(file, "{}", line));
+        if let Err(why) = appended {
+            log::error!("无法写入任务记录 {}: {}", self.path.display(), why);
+        }
+    }
+}
+
+/// 按开始的顺序列出没有结束记录的任务，无法解析的行忽略
+fn interrupted(content: &str) -> Vec<InterruptedJob> {
+    let mut started = Vec::new();
+    let mut finished = HashSet::new();
+    for line in content.lines() {
+        let mut fields = line.split('\t');
+        match (fields.next(), fields.next().map(Uuid::parse_str)) {
+            (Some("start"), Some(Ok(job_id))) => {
+                let chat_id = fields.next().and_then(|id| id.parse().ok());
+                let items = fields.next().and_then(|items| items.parse().ok());
+                if let Some(chat_id) = chat_id {
+                    started.push(InterruptedJob {
+                        job_id,
+                        chat_id: ChatId(chat_id),
+                        items: items.unwrap_or_default(),
+                    });
+                }
+            }
+            (Some("end"), Some(Ok(job_id))) => {
+                finished.insert(job_id);
+            }
+            _ => {}
+        }
+    }
+    started.retain(|job| !finished.contains(&job.job_id));
+    started
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_unfinished_jobs_are_interrupted() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = JobJournal::new(dir.path().join("jobs.log"));
+        let jobs = [Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4()];
+        journal.started(jobs[0], ChatId(1), 4);
+        journal.started(jobs[1], ChatId(-100), 2);
+        journal.started(jobs[2], ChatId(1), 1);
+        journal.finished(jobs[1]);
+
+        assert_eq!(
+            journal.take_interrupted(),
+            [
+                InterruptedJob {
+                    job_id: jobs[0],
+                    chat_id: ChatId(1),
+                    items: 4,
+                },
+                InterruptedJob {
+                    job_id: jobs[2],
+                    chat_id: ChatId(1),
+                    items: 1,
+                },
+            ]
+        );
+        // 读出后清空
+        assert!(journal.take_interrupted().is_empty());
+    }
+
+    #[test]
+    fn broken_lines_are_ignored() {
+        let job_id = Uuid::new_v4();
+        let content = format!(
+            "start\tnot-a-uuid\t1\t2\nstart\t{job_id}\tchat\t2\nend\nstart\t{job_id}\t7\nbogus\n"
+        );
+        assert_eq!(
+            interrupted(&content),
+            [InterruptedJob {
+                job_id,
+                chat_id: ChatId(7),
+                items: 0,
+            }]
+        );
+        // 文件不存在时没有中断的任务
+        let dir = tempfile::tempdir().unwrap();
+        assert!(
+            JobJournal::new(dir.path().join("missing.log"))
+                .take_interrupted()
+                .is_empty()
+        );
+    }
+}
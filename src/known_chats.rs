@@ -0,0 +1,52 @@
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::PathBuf;
+use teloxide::types::ChatId;
+use tokio::sync::Mutex;
+
+/// 与机器人互动过的会话，每行一个会话id，持久化到文件中
+#[derive(Debug)]
+pub struct KnownChats {
+    path: PathBuf,
+    chats: Mutex<HashSet<ChatId>>,
+}
+
+impl KnownChats {
+    /// 从文件加载，文件不存在时从空集合开始
+    pub fn load(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let chats = std::fs::read_to_string(&path)
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| line.trim().parse().ok().map(ChatId))
+            .collect::<HashSet<_>>();
+        log::info!("已加载 {} 个互动过的会话", chats.len());
+        KnownChats {
+            path,
+            chats: Mutex::new(chats),
+        }
+    }
+
+    /// 记录会话，第一次互动时返回 `true`
+    pub async fn first_contact(&self, chat_id: ChatId) -> bool {
+        let mut chats = self.chats.lock().await;
+        if !chats.insert(chat_id) {
+            return false;
+        }
+
+        let appended = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| writeln!(file, "{}", chat_id.0));
+        if let Err(why) = appended {
+            log::error!(
+                "无法保存会话 {} 到 {}: {}",
+                chat_id,
+                self.path.display(),
+                why
+            );
+        }
+        true
+    }
+}
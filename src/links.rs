@@ -0,0 +1,56 @@
+use crate::external::{self, ExternalError};
+use reqwest::{Client, Url};
+use teloxide::prelude::*;
+use teloxide::types::MessageEntityKind;
+
+/// 会被展开为其中所有图片的 telegraph 域名
+const TELEGRAPH_HOSTS: &[&str] = &["telegra.ph", "graph.org"];
+/// telegraph 页面最多读取的字节数
+const MAX_TELEGRAPH_PAGE_BYTES: usize = 2 * 1024 * 1024;
+
+/// 提取消息文本和说明文字中的 http(s) 链接
+pub fn extract_urls(msg: &Message) -> Vec<Url> {
+    let entities = msg
+        .parse_entities()
+        .into_iter()
+        .chain(msg.parse_caption_entities())
+        .flatten();
+
+    entities
+        .filter_map(|entity| match entity.kind() {
+            MessageEntityKind::Url => Url::parse(entity.text()).ok(),
+            MessageEntityKind::TextLink { url } => Some(url.clone()),
+            _ => None,
+        })
+        .filter(|url| matches!(url.scheme(), "http" | "https"))
+        .collect()
+}
+
+/// 将用户发送的链接解析为图片下载地址
+///
+/// telegraph 页面会展开为页面中的所有图片，其他链接视为图片直链，
+/// 是否真的是图片由下载后的校验决定。`client` 必须由 [`external::client_builder`] 创建。
+pub async fn resolve_image_urls(client: &Client, url: Url) -> Result<Vec<Url>, ExternalError> {
+    let is_telegraph = url
+        .host_str()
+        .is_some_and(|host| TELEGRAPH_HOSTS.contains(&host));
+    if !is_telegraph {
+        return Ok(vec![url]);
+    }
+
+    let response = external::fetch(client, url.clone()).await?;
+    let body = external::read_prefix(response, MAX_TELEGRAPH_PAGE_BYTES).await?;
+    let html = String::from_utf8_lossy(&body);
+    Ok(image_sources(&html)
+        .filter_map(|src| url.join(src).ok())
+        .collect())
+}
+
+/// 找出 html 中所有 `<img>` 标签的 `src`
+fn image_sources(html: &str) -> impl Iterator<Item = &str> {
+    html.split("<img").skip(1).filter_map(|tag| {
+        let tag = &tag[..tag.find('>')?];
+        let src = &tag[tag.find("src=\"")? + 5..];
+        Some(&src[..src.find('"')?])
+    })
+}
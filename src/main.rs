@@ -1,363 +1,6662 @@
 use reqwest::Client;
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
+use teloxide::dispatching::UpdateHandler;
 use teloxide::prelude::*;
-use teloxide::types::InputFile;
+use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup, InputFile, Me, MessageId};
 use teloxide::utils::command::BotCommands;
-use tokio::sync::Mutex;
+use tokio::sync::OwnedSemaphorePermit;
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
 use uuid::Uuid;
-use zip::ZipWriter;
-use zip::write::FileOptions;
+
+mod albums;
+mod aliases;
+mod archive;
+mod archive_names;
+mod avatar;
+mod build_info;
+mod captions;
+mod chat_export;
+mod credits;
+mod debounce;
+mod download;
+mod external;
+mod feedback;
+mod gallery;
+mod heif;
+mod import;
+mod job_chat;
+mod job_report;
+mod journal;
+mod known_chats;
+mod links;
+mod markdown;
+mod messages;
+mod naming;
+mod notify;
+mod ordering;
+mod orientation;
+mod originals;
+mod output;
+mod pipeline;
+mod previews;
+mod progress;
+mod queue;
+mod report;
+mod resume;
+#[cfg(feature = "sftp")]
+mod sftp;
+mod state;
+mod stats;
+mod stickers;
+mod suggest;
+#[cfg(feature = "telegraph")]
+mod telegraph;
+mod telemetry;
+#[cfg(test)]
+mod test_util;
+mod throttle;
+mod units;
+mod update_check;
+mod watermark;
+mod workspace;
+
+use albums::RecentAlbums;
+use archive::{ArchiveMetadata, Compression};
+use archive_names::ArchiveNames;
+use debounce::Debouncer;
+use download::MediaKind;
+use job_chat::{JobChat, TelegramChat};
+use journal::JobJournal;
+use known_chats::KnownChats;
+use markdown::FormattedText;
+use ordering::Order;
+use originals::PhotoSource;
+use output::{Delivery, OutputMode};
+use pipeline::CollectedItem;
+use progress::Progress;
+use queue::JobQueue;
+use state::{MemoryStore, StateStore};
+use throttle::RateLimiter;
+use units::{format_duration, format_size, format_speed};
 
 pub const VERSION: &str = include_str!(concat!(env!("OUT_DIR"), "/VERSION"));
 
 #[tokio::main]
 async fn main() {
-    tracing_subscriber::fmt::init();
+    let _telemetry = telemetry::init();
     dotenv::dotenv().ok();
+    let messages_file = std::env::var_os("MESSAGES_FILE").map(PathBuf::from);
+    match messages::init(messages_file.as_deref()) {
+        Ok(0) => {}
+        Ok(count) => log::info!("已加载 {} 条自定义回复", count),
+        Err(why) => {
+            log::error!("MESSAGES_FILE 有误，{}", why);
+            telemetry::exit(1);
+        }
+    }
 
     log::info!("开始链接telegram数据中心");
-    let bot = Config::from_env().into_bot();
+    let config = Arc::new(Config::from_env());
+    if config.proxy.is_some() {
+        log::info!("已配置代理，telegram请求和图片下载都将通过代理");
+    }
+    let client = config.download_client();
+    let limiter = Arc::new(RateLimiter::new(config.max_download_rate));
+    let known_chats = Arc::new(KnownChats::load(&config.known_chats_file));
+    let queue = Arc::new(JobQueue::new(config.max_concurrent_jobs));
+    let debouncer = Arc::new(Debouncer::new(debounce::WINDOW));
+    let albums = Arc::new(RecentAlbums::new());
+    if limiter.rate() > 0 {
+        log::info!("下载限速 {}", format_speed(limiter.rate() as f64));
+    }
+    if let Err(why) = workspace::prepare_root(&config.temp_root).await {
+        log::error!(
+            "无法写入 TEMP_DIR {}，请检查路径和权限: {}",
+            config.temp_root.display(),
+            why
+        );
+        telemetry::exit(1);
+    }
+    log::info!("临时目录位于 {}", config.temp_root.display());
+    let removed = workspace::remove_stale(&config.temp_root, config.temp_max_age).await;
+    if removed > 0 {
+        log::info!("已清理 {} 个遗留的临时目录", removed);
+    }
+    let bot = config.bot();
     log::info!("链接成功");
 
+    recover_interrupted_jobs(&bot, &config.job_journal, &config.temp_root).await;
+
     log::info!("开始注册命令");
 
-    if let Err(why) = bot.set_my_commands(Command::bot_commands()).await {
+    let commands = config
+        .command_aliases
+        .with_menu_entries(Command::bot_commands());
+    if let Err(why) = bot.set_my_commands(commands).await {
         log::error!("无法注册命令: {}", why);
     } else {
         log::info!("命令注册成功");
     }
 
-    let client = Client::new();
-    let state: AppState = Arc::new(Mutex::new(HashMap::new()));
+    if let Some(schedule) = config.report_schedule.clone() {
+        let recipients = config.admin_recipients();
+        if recipients.is_empty() {
+            log::warn!("设置了 REPORT_TIME 但没有设置 ADMIN_IDS，不会发送运行汇总");
+        } else {
+            tokio::spawn(report::run(
+                bot.clone(),
+                schedule,
+                recipients,
+                config.temp_root.clone(),
+            ));
+        }
+    }
+
+    if config.update_check {
+        let recipients = config.admin_recipients();
+        if recipients.is_empty() {
+            log::warn!("设置了 UPDATE_CHECK 但没有设置 ADMIN_IDS，新版本只会显示在 /version 中");
+        }
+        tokio::spawn(update_check::run(bot.clone(), client.clone(), recipients));
+    }
+
+    let state: AppState = Arc::new(MemoryStore::new());
+    let jobs_state = Arc::clone(&state);
+    tokio::spawn(expire_failed_jobs(Arc::clone(&state), Arc::clone(&config)));
+    if !config.collect_idle_timeout.is_zero() {
+        tokio::spawn(expire_idle_sessions(
+            bot.clone(),
+            Arc::clone(&state),
+            config.collect_idle_timeout,
+        ));
+    }
 
     let handler = dptree::entry()
+        .branch(debounce_handler())
         .branch(
             Update::filter_message()
-                .filter_command::<Command>()
+                .filter_map(|msg: Message, me: Me, config: Arc<Config>| {
+                    parse_command(&msg, &me, &config.command_aliases)
+                })
                 .endpoint(command_handler),
         )
-        .branch(Update::filter_message().endpoint(handle_message));
+        .branch(
+            Update::filter_message()
+                .filter(|msg: Message, me: Me| is_addressed_elsewhere(&msg, &me))
+                .endpoint(ignore_command),
+        )
+        .branch(
+            Update::filter_message()
+                .filter(|msg: Message| command_name(&msg).is_some())
+                .endpoint(unknown_command),
+        )
+        .branch(
+            Update::filter_message()
+                .filter(|msg: Message, me: Me| is_quick_pack_request(&msg, &me))
+                .endpoint(quick_pack),
+        )
+        .branch(Update::filter_message().endpoint(handle_message))
+        .branch(Update::filter_callback_query().endpoint(handle_callback));
 
     Dispatcher::builder(bot, handler)
-        .dependencies(dptree::deps![client, state])
+        .dependencies(dptree::deps![
+            client,
+            state,
+            config,
+            limiter,
+            known_chats,
+            queue,
+            debouncer,
+            albums
+        ])
         .enable_ctrlc_handler()
         .worker_queue_size(32)
         .build()
         .dispatch()
         .await;
+
+    shutdown_jobs(&jobs_state).await;
+}
+
+/// 处理上次运行时没有完成的打包任务：删除它们的临时目录并通知所在的会话
+///
+/// 会话状态只保存在内存中，收集的消息已经随退出丢失，只能请用户重新发送。
+async fn recover_interrupted_jobs(bot: &Bot, journal: &JobJournal, temp_root: &Path) {
+    let interrupted = journal.take_interrupted();
+    if interrupted.is_empty() {
+        return;
+    }
+    log::warn!("上次运行时有 {} 个打包任务没有完成", interrupted.len());
+    for job in interrupted {
+        let temp_dir = workspace::job_dir(temp_root, job.chat_id, job.job_id);
+        if let Err(why) = tokio::fs::remove_dir_all(&temp_dir).await
+            && why.kind() != std::io::ErrorKind::NotFound
+        {
+            log::error!("无法删除 {}: {}", temp_dir.display(), why);
+        }
+        let reply = format!(
+            "⚠️ 机器人在处理你的打包任务（{} 条消息）时意外重启，任务没有完成，临时文件已清理。\n\n\
+             收集的消息没有保存下来，请发送 /startcollect 重新转发这些消息后再打包。\n任务id：{}",
+            job.items, job.job_id
+        );
+        if let Err(why) = markdown::send(bot, job.chat_id, None, reply).await {
+            log::error!(
+                "无法通知会话 {} 任务 {} 被中断: {}",
+                job.chat_id,
+                job.job_id,
+                why
+            );
+        }
+    }
+}
+
+/// 退出前取消所有任务，并等待它们清理临时文件
+async fn shutdown_jobs(state: &AppState) {
+    const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+    let running = state
+        .filter_map(|_, user_state| {
+            user_state.jobs.values().for_each(|job| job.cancel.cancel());
+            Some(user_state.jobs.len())
+        })
+        .await
+        .into_iter()
+        .sum::<usize>();
+    if running == 0 {
+        return;
+    }
+    log::info!("正在取消 {} 个任务", running);
+
+    let started = std::time::Instant::now();
+    while started.elapsed() < SHUTDOWN_TIMEOUT {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        if state
+            .count_where(|user_state| !user_state.jobs.is_empty())
+            .await
+            == 0
+        {
+            log::info!("所有任务都已结束");
+            return;
+        }
+    }
+    log::warn!("等待任务结束超时，临时文件可能没有清理干净");
 }
 
 #[derive(Debug)]
-#[repr(transparent)]
 struct Config {
-    bot_token: String,
+    /// 来自 `TG_BOT_TOKEN_FILE` 指向的文件或 `TG_BOT_TOKEN`，输出时隐藏
+    bot_token: BotToken,
+    /// 访问telegram和下载图片时使用的代理，优先使用 `SOCKS_PROXY`
+    proxy: Option<reqwest::Proxy>,
+    /// 管理员的用户id，来自以逗号分隔的 `ADMIN_IDS` 中的正数
+    admin_ids: Vec<UserId>,
+    /// 以会话身份发送时视为管理员的会话，来自 `ADMIN_IDS` 中的负数，
+    /// 例如匿名管理员所在的群组或关联的频道
+    admin_chats: Vec<ChatId>,
+    /// 下载图片时使用的 User-Agent，`DOWNLOAD_USER_AGENT`，默认为 `telegram-images-bot/<版本>`
+    user_agent: String,
+    /// 访问用户发送的链接时使用的 User-Agent，`LINK_USER_AGENT`，默认在 `user_agent` 后加上 `link-fetcher`
+    link_user_agent: String,
+    /// 下载图片时额外附加的请求头，来自以 `|` 分隔的 `DOWNLOAD_HEADERS`，例如 `Referer: https://example.com|X-Token: abc`
+    download_headers: reqwest::header::HeaderMap,
+    /// 单个文件的大小上限，`MAX_FILE_BYTES` 字节，超过的文件不下载，0表示不限制
+    max_file_bytes: u64,
+    /// 收集中可以导入的 zip 压缩包的大小上限，`ZIP_IMPORT_MAX_BYTES` 字节，默认20MB
+    zip_import_max_bytes: u64,
+    /// 单个 zip 压缩包解压出的图片合计的大小上限，`ZIP_IMPORT_MAX_EXTRACTED` 字节，默认200MB
+    zip_import_max_extracted: u64,
+    /// HEIC/HEIF 文件转换为 JPEG 时的质量，`HEIF_JPEG_QUALITY`，1-100，默认90
+    heif_jpeg_quality: u8,
+    /// 水印使用的字体，`WATERMARK_FONT`，没有设置时不能使用 /watermark
+    watermark_font: Option<watermark::Font>,
+    /// /avatar 最多打包的头像数量，`AVATAR_LIMIT`，1-100，默认10
+    avatar_limit: u8,
+    /// 单张图片的下载超时，`DOWNLOAD_TIMEOUT` 秒，默认60秒
+    download_timeout: Duration,
+    /// 整个下载阶段的超时，`DOWNLOAD_JOB_TIMEOUT` 秒，默认15分钟
+    job_timeout: Duration,
+    /// 整个处理过程的上限，`PROCESS_TIMEOUT` 秒，默认30分钟，超时后放弃剩余的下载，只发送已完成的文件
+    process_timeout: Duration,
+    /// 所有下载合计的最大速率，`MAX_DOWNLOAD_RATE` 字节每秒，0表示不限速
+    max_download_rate: u64,
+    /// 同时进行的收集会话上限，`MAX_ACTIVE_SESSIONS`，0表示不限制
+    max_active_sessions: usize,
+    /// 收集超过这个时间没有收到新消息时自动结束，`COLLECT_IDLE_TIMEOUT` 秒，默认6小时，0表示不结束
+    collect_idle_timeout: Duration,
+    /// 同时处理的打包任务上限，`MAX_CONCURRENT_JOBS`，默认2，超出的任务排队等待
+    max_concurrent_jobs: usize,
+    /// 所有任务合计同时打包的分卷数量，`ZIP_CONCURRENCY`，默认为 CPU 核心数的一半
+    zip_concurrency: usize,
+    /// 打包分卷时申请的许可，数量为 `zip_concurrency`
+    zip_slots: Arc<tokio::sync::Semaphore>,
+    /// 每张图片单独打包时最多发送的压缩包数量，`PER_IMAGE_LIMIT`，默认20，超出时按普通方式打包
+    per_image_limit: usize,
+    /// 是否在会话第一次互动时发送欢迎信息，`WELCOME_NEW_CHATS`，默认开启
+    welcome_new_chats: bool,
+    /// 保存互动过的会话的文件，`KNOWN_CHATS_FILE`
+    known_chats_file: String,
+    /// 每个会话用过的压缩包名称，保存在 `ARCHIVE_NAMES_FILE`，默认为 `archive_names.txt`
+    archive_names: Arc<ArchiveNames>,
+    /// 打包任务的开始和结束记录，保存在 `JOB_JOURNAL_FILE`，默认为 `jobs.txt`
+    job_journal: Arc<JobJournal>,
+    /// 存放临时目录的位置，`TEMP_DIR`（旧名称 `TEMP_ROOT`），默认为系统临时目录下的 `telegram-images-bot`
+    temp_root: PathBuf,
+    /// 启动时清理超过这个时间没有修改的临时目录，`TEMP_MAX_AGE` 秒，默认1小时
+    temp_max_age: Duration,
+    /// 所有临时目录合计的大小上限，`TEMP_QUOTA` 字节，0表示不限制
+    temp_quota: u64,
+    /// 失败的任务可以通过 /retry 重试的时间，`RETRY_WINDOW` 秒，默认1小时，期间保留临时目录
+    retry_window: Duration,
+    /// telegraph 账号的 access token 保存的位置，`TELEGRAPH_TOKEN_FILE`
+    #[cfg(feature = "telegraph")]
+    telegraph_token_file: PathBuf,
+    /// 上传压缩包的 SFTP 服务器，来自 `SFTP_*`，没有设置 `SFTP_HOST` 时为空
+    #[cfg(feature = "sftp")]
+    sftp: Option<sftp::SftpConfig>,
+    /// 向管理员发送运行汇总的时间，来自 `REPORT_TIME`、`REPORT_TIMEZONE` 和 `REPORT_PERIOD`
+    report_schedule: Option<report::Schedule>,
+    /// 是否每天检查一次新版本并通知管理员，`UPDATE_CHECK`，默认关闭
+    update_check: bool,
+    /// 任务结束后通知的地址，`NOTIFY_WEBHOOK_URL`，使用 `NOTIFY_WEBHOOK_SECRET` 签名
+    notify_webhook: Option<notify::Webhook>,
+    /// 新会话的初始设置，来自 `DEFAULT_FORMAT`、`DEFAULT_COMPRESSION` 和 `DEFAULT_CLEAN_CHAT`
+    default_settings: ChatSettings,
+    /// 命令的简写，内置的加上 `COMMAND_ALIASES`
+    command_aliases: aliases::CommandAliases,
 }
 
 impl Config {
     fn from_env() -> Self {
+        let admin_ids = std::env::var("ADMIN_IDS")
+            .unwrap_or_default()
+            .split(',')
+            .filter(|id| !id.trim().is_empty())
+            .map(|id| {
+                id.trim()
+                    .parse::<i64>()
+                    .expect("ADMIN_IDS must be user or chat ids")
+            })
+            .collect::<Vec<_>>();
+        let zip_concurrency = env_or(
+            "ZIP_CONCURRENCY",
+            std::thread::available_parallelism().map_or(1, |n| n.get() / 2),
+        )
+        .max(1);
+        let user_agent = env_or(
+            "DOWNLOAD_USER_AGENT",
+            format!("telegram-images-bot/{}", VERSION),
+        );
         Config {
-            bot_token: std::env::var("TG_BOT_TOKEN").expect("TG_BOT_TOKEN must be set"),
+            bot_token: BotToken(bot_token_from_env()),
+            proxy: proxy_from_env(),
+            admin_ids: admin_ids
+                .iter()
+                .filter_map(|&id| u64::try_from(id).ok())
+                .map(UserId)
+                .collect(),
+            admin_chats: admin_ids
+                .iter()
+                .filter(|&&id| id < 0)
+                .map(|&id| ChatId(id))
+                .collect(),
+            link_user_agent: env_or("LINK_USER_AGENT", format!("{} link-fetcher", user_agent)),
+            user_agent,
+            download_headers: parse_headers(&std::env::var("DOWNLOAD_HEADERS").unwrap_or_default()),
+            max_file_bytes: env_or("MAX_FILE_BYTES", 0),
+            zip_import_max_bytes: env_or("ZIP_IMPORT_MAX_BYTES", 20 * 1024 * 1024),
+            zip_import_max_extracted: env_or("ZIP_IMPORT_MAX_EXTRACTED", 200 * 1024 * 1024),
+            heif_jpeg_quality: env_or("HEIF_JPEG_QUALITY", 90).clamp(1, 100),
+            watermark_font: std::env::var("WATERMARK_FONT")
+                .ok()
+                .filter(|path| !path.is_empty())
+                .map(|path| {
+                    watermark::Font::load(Path::new(&path)).unwrap_or_else(|why| {
+                        log::error!("无法使用 WATERMARK_FONT {}: {}", path, why);
+                        telemetry::exit(1);
+                    })
+                }),
+            avatar_limit: env_or("AVATAR_LIMIT", 10).clamp(1, 100),
+            download_timeout: Duration::from_secs(env_or("DOWNLOAD_TIMEOUT", 60)),
+            job_timeout: Duration::from_secs(env_or("DOWNLOAD_JOB_TIMEOUT", 15 * 60)),
+            process_timeout: Duration::from_secs(env_or("PROCESS_TIMEOUT", 30 * 60)),
+            max_download_rate: env_or("MAX_DOWNLOAD_RATE", 0),
+            max_active_sessions: env_or("MAX_ACTIVE_SESSIONS", 0),
+            collect_idle_timeout: Duration::from_secs(env_or("COLLECT_IDLE_TIMEOUT", 6 * 60 * 60)),
+            max_concurrent_jobs: env_or("MAX_CONCURRENT_JOBS", 2),
+            zip_concurrency,
+            zip_slots: Arc::new(tokio::sync::Semaphore::new(zip_concurrency)),
+            per_image_limit: env_or("PER_IMAGE_LIMIT", 20).max(1),
+            welcome_new_chats: env_or("WELCOME_NEW_CHATS", true),
+            known_chats_file: env_or("KNOWN_CHATS_FILE", "known_chats.txt".to_string()),
+            archive_names: Arc::new(ArchiveNames::load(env_or(
+                "ARCHIVE_NAMES_FILE",
+                PathBuf::from("archive_names.txt"),
+            ))),
+            job_journal: Arc::new(JobJournal::new(env_or(
+                "JOB_JOURNAL_FILE",
+                PathBuf::from("jobs.txt"),
+            ))),
+            temp_root: env_or(
+                "TEMP_DIR",
+                env_or(
+                    "TEMP_ROOT",
+                    std::env::temp_dir().join("telegram-images-bot"),
+                ),
+            ),
+            temp_max_age: Duration::from_secs(env_or("TEMP_MAX_AGE", 60 * 60)),
+            temp_quota: env_or("TEMP_QUOTA", 0),
+            retry_window: Duration::from_secs(env_or("RETRY_WINDOW", 60 * 60)),
+            #[cfg(feature = "telegraph")]
+            telegraph_token_file: env_or(
+                "TELEGRAPH_TOKEN_FILE",
+                PathBuf::from("telegraph_token.txt"),
+            ),
+            #[cfg(feature = "sftp")]
+            sftp: sftp::SftpConfig::from_env(),
+            report_schedule: report::Schedule::from_env(),
+            update_check: env_or("UPDATE_CHECK", false),
+            notify_webhook: notify::Webhook::from_env(),
+            default_settings: ChatSettings::from_env(),
+            command_aliases: aliases::CommandAliases::from_env(|name| {
+                Command::parse(&format!("/{}", name), "").is_ok()
+            }),
         }
     }
-    fn into_bot(self) -> Bot {
-        Bot::new(self.bot_token)
+
+    /// 新会话的状态，使用默认的设置
+    fn new_session(&self) -> UserState {
+        UserState {
+            settings: self.default_settings.clone(),
+            ..Default::default()
+        }
     }
-}
 
-type AppState = Arc<Mutex<HashMap<ChatId, UserState>>>;
+    /// 修改会话的状态，没有会话时使用默认的设置创建
+    async fn update_session<R: Send>(
+        &self,
+        state: &AppState,
+        chat_id: ChatId,
+        f: impl FnOnce(&mut UserState) -> R + Send,
+    ) -> R {
+        state
+            .update_or_insert(chat_id, || self.new_session(), f)
+            .await
+    }
 
-#[derive(Debug, Default)]
-struct UserState {
-    /// 是否是收集模式
-    is_collecting: bool,
-    /// 是否设置文件名
-    is_set_file_name: bool,
-    /// 收集的消息
-    messages: Vec<Message>,
-    /// 打包的文件名
-    file_name: Option<String>,
-}
+    /// 接收运行汇总的会话：`ADMIN_IDS` 中的用户和会话
+    fn admin_recipients(&self) -> Vec<ChatId> {
+        self.admin_ids
+            .iter()
+            .map(|&user_id| ChatId::from(user_id))
+            .chain(self.admin_chats.iter().copied())
+            .collect()
+    }
 
-#[derive(BotCommands, Clone)]
-//如果不采用小写，telegram就无法注册命令
-#[command(rename_rule = "lowercase")]
-enum Command {
-    #[command(description = "显示此帮助信息")]
-    Start,
-    #[command(description = "显示此帮助信息")]
-    Help,
-    #[command(description = "开始收集图片信息")]
-    StartCollect,
-    #[command(description = "停止收集并打包下载所有图片")]
-    StopCollect,
-    #[command(description = "显示程序版本")]
-    Version,
-    #[command(description = "设置zip名称")]
-    FileName,
-}
+    /// 消息的发送者是否是管理员
+    ///
+    /// 匿名管理员和以频道身份发送的消息的 `from` 是telegram的服务账号，
+    /// 这时按 `sender_chat` 判断。
+    fn is_admin(&self, msg: &Message) -> bool {
+        if let Some(sender_chat) = &msg.sender_chat {
+            return self.admin_chats.contains(&sender_chat.id);
+        }
+        msg.from
+            .as_ref()
+            .is_some_and(|user| self.admin_ids.contains(&user.id))
+    }
 
-/// 消息处理函数
-/// 处理用户的收集消息，如果用户没有开启收集模式，则忽略。
-async fn handle_message(
-    bot: Bot,
-    msg: Message,
-    state: AppState,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let chat_id = msg.chat.id;
+    /// 是否可以将压缩包上传到 SFTP 服务器
+    fn sftp_enabled(&self) -> bool {
+        #[cfg(feature = "sftp")]
+        return self.sftp.is_some();
+        #[cfg(not(feature = "sftp"))]
+        false
+    }
 
-    let mut state_guard = state.lock().await;
-    let user_state = state_guard.entry(chat_id).or_default();
+    /// 为 `builder` 配置代理
+    fn with_proxy(&self, builder: reqwest::ClientBuilder) -> reqwest::ClientBuilder {
+        match &self.proxy {
+            Some(proxy) => builder.proxy(proxy.clone()),
+            None => builder,
+        }
+    }
 
-    if user_state.is_collecting {
-        log::trace!("用户 {} 有一个收集会话 {}", chat_id, msg.id);
-        user_state.messages.push(msg.clone());
-    } else if user_state.is_set_file_name {
-        log::trace!("用户 {} 有一个设置文件名会话 {}", chat_id, msg.id);
-        let file_name = msg.text().unwrap_or_default().to_string();
+    /// 下载图片使用的客户端，所有下载请求都会带上配置的 User-Agent 和请求头
+    fn download_client(&self) -> Client {
+        let builder = Client::builder()
+            .user_agent(&self.user_agent)
+            .default_headers(self.download_headers.clone())
+            .connect_timeout(Duration::from_secs(10))
+            .read_timeout(Duration::from_secs(30));
+        self.with_proxy(builder)
+            .build()
+            .expect("Client creation failed")
+    }
 
-        if file_name.is_empty() {
-            bot.send_message(chat_id, "❌ 文件名不能为空").await?;
-            return Ok(());
-        }
+    /// 单个文件实际的大小上限，见 [`download::file_limit`]
+    fn file_limit(&self) -> u64 {
+        download::file_limit(self.max_file_bytes)
+    }
 
-        user_state.file_name = Some(file_name);
-        bot.send_message(
-            chat_id,
-            format!(
-                "✅已设置文件名为 {}.zip",
-                user_state.file_name.as_ref().unwrap()
-            ),
-        )
-        .await?;
-        // 停止设置文件名会话
-        user_state.is_set_file_name = false;
+    /// 访问用户发送的链接使用的客户端，拒绝内网地址，见 [`external`]
+    fn external_client(&self) -> Client {
+        let builder =
+            external::client_builder(&self.link_user_agent, self.download_headers.clone());
+        self.with_proxy(builder)
+            .build()
+            .expect("Client creation failed")
     }
 
-    Ok(())
+    fn bot(&self) -> Bot {
+        // 使用teloxide的默认设置，保证长轮询正常工作
+        let client = self
+            .with_proxy(teloxide::net::default_reqwest_settings())
+            .build()
+            .expect("Client creation failed");
+        Bot::with_client(&self.bot_token.0, client)
+    }
 }
 
-/// 命令处理函数
-async fn command_handler(
-    bot: Bot,
-    msg: Message,
-    cmd: Command,
-    client: Client,
-    state: AppState,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let chat_id = msg.chat.id;
-    let bot = Arc::new(bot);
+/// bot token，`Debug` 时不输出内容，避免打印配置时泄露
+struct BotToken(String);
 
-    match cmd {
-        Command::Start | Command::Help => {
-            bot.send_message(chat_id, "你好！我是图片下载机器人。\n\n/startcollect - 开始收集图片\n/stopcollect - 停止并打包下载\n/filename - 设置文件名称").await?;
-        }
-        Command::StartCollect => {
-            start_collecting(bot, chat_id, state).await?;
+impl std::fmt::Debug for BotToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "BotToken(***)")
+    }
+}
+
+/// 读取并检查token，格式不对时直接退出，而不是等到连接时才失败
+///
+/// 设置了 `TG_BOT_TOKEN_FILE` 时从该文件读取，例如 Docker 或 Kubernetes 挂载的 secret，
+/// 否则使用 `TG_BOT_TOKEN`。
+fn bot_token_from_env() -> String {
+    let (source, raw) = match std::env::var("TG_BOT_TOKEN_FILE")
+        .ok()
+        .filter(|path| !path.is_empty())
+    {
+        Some(path) => {
+            let raw = std::fs::read_to_string(&path).unwrap_or_else(|why| {
+                log::error!("无法读取 TG_BOT_TOKEN_FILE {}: {}", path, why);
+                telemetry::exit(1);
+            });
+            if raw.trim().is_empty() {
+                log::error!("TG_BOT_TOKEN_FILE {} 是空文件", path);
+                telemetry::exit(1);
+            }
+            ("TG_BOT_TOKEN_FILE", raw)
         }
-        Command::StopCollect => {
-            // 耗时任务放入后台执行
-            tokio::spawn(stop_collecting_and_process(bot, chat_id, state, client));
+        None => (
+            "TG_BOT_TOKEN",
+            std::env::var("TG_BOT_TOKEN").expect("TG_BOT_TOKEN or TG_BOT_TOKEN_FILE must be set"),
+        ),
+    };
+    match normalize_token(&raw) {
+        Some(token) => token,
+        None => {
+            log::error!(
+                "{} 中的token格式不正确，应为 BotFather 提供的 `123456789:ABC...` 形式，请检查是否复制完整",
+                source
+            );
+            telemetry::exit(1);
         }
-        Command::Version => {
-            bot.send_message(chat_id, format!("当前版本：{}", VERSION))
-                .await?;
+    }
+}
+
+/// 去掉首尾空白和引号后检查token是否为 `<数字>:<字母数字、_ 或 ->` 的形式
+fn normalize_token(raw: &str) -> Option<String> {
+    let token = raw.trim().trim_matches(['"', '\'']);
+    let (id, secret) = token.split_once(':')?;
+    let valid_id = !id.is_empty() && id.chars().all(|c| c.is_ascii_digit());
+    let valid_secret = !secret.is_empty()
+        && secret
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    (valid_id && valid_secret).then(|| token.to_string())
+}
+
+/// 读取 `SOCKS_PROXY`，没有设置时读取 `HTTPS_PROXY`，地址无效时直接退出
+fn proxy_from_env() -> Option<reqwest::Proxy> {
+    let (key, url) = match std::env::var("SOCKS_PROXY") {
+        Ok(url) => ("SOCKS_PROXY", url),
+        Err(_) => ("HTTPS_PROXY", std::env::var("HTTPS_PROXY").ok()?),
+    };
+    if url.is_empty() {
+        return None;
+    }
+    match reqwest::Proxy::all(&url) {
+        Ok(proxy) => Some(proxy),
+        Err(why) => {
+            log::error!("{} 不是有效的代理地址: {}", key, why);
+            telemetry::exit(1);
         }
-        Command::FileName => {
-            start_set_file_name(bot, chat_id, state).await?;
+    }
+}
+
+/// 解析 `DOWNLOAD_HEADERS`，每项为 `名称: 值`，以 `|` 分隔，格式不对时直接退出
+fn parse_headers(raw: &str) -> reqwest::header::HeaderMap {
+    use reqwest::header::{HeaderName, HeaderValue};
+    let mut headers = reqwest::header::HeaderMap::new();
+    for item in raw.split('|').filter(|item| !item.trim().is_empty()) {
+        let parsed = item.split_once(':').and_then(|(name, value)| {
+            Some((
+                HeaderName::from_bytes(name.trim().as_bytes()).ok()?,
+                HeaderValue::from_str(value.trim()).ok()?,
+            ))
+        });
+        match parsed {
+            Some((name, value)) => {
+                headers.append(name, value);
+            }
+            None => {
+                log::error!(
+                    "DOWNLOAD_HEADERS 中的 `{}` 格式不正确，应为 `名称: 值`",
+                    item.trim()
+                );
+                telemetry::exit(1);
+            }
         }
     }
+    headers
+}
 
-    Ok(())
+/// 读取环境变量并解析，不存在时返回默认值，无法解析时直接退出
+fn env_or<T: std::str::FromStr>(key: &str, default: T) -> T {
+    match std::env::var(key) {
+        Ok(value) => value.trim().parse().unwrap_or_else(|_| {
+            log::error!("{} 的值 `{}` 格式不正确", key, value.trim());
+            telemetry::exit(1);
+        }),
+        Err(_) => default,
+    }
 }
 
-async fn start_set_file_name(bot: Arc<Bot>, chat: ChatId, state: AppState)->Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    bot.send_message(chat, "请将文件名发送给我，我会将其设置为压缩包名")
-        .await?;
-    let mut state_guard = state.lock().await;
-    let user_state = state_guard.entry(chat).or_default();
-    user_state.is_set_file_name = true;
+/// 发送不带参数的 /filename 后等待用户发送文件名的时间
+const FILE_NAME_PROMPT_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// 取消的收集可以通过 /restore 恢复的时间
+const RESTORE_WINDOW: Duration = Duration::from_secs(10 * 60);
+/// 最近一次打包的内容可以通过 /reprocess 重新处理的时间
+const REPROCESS_WINDOW: Duration = Duration::from_secs(30 * 60);
+/// 没有通过 /use 切换过时使用的收集名称
+const DEFAULT_COLLECTION: &str = "default";
+/// 每个会话最多同时保存的收集数量，包括正在使用的
+const MAX_COLLECTIONS: usize = 10;
 
-    Ok(())
+/// 所有会话的状态，通过 `deps!` 注入到处理函数中
+type AppState = Arc<dyn StateStore<ChatId, UserState>>;
+
+/// 会话正在进行的操作，收集和等待文件名不会同时进行
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum SessionMode {
+    /// 没有进行中的收集，也没有在等待文件名
+    #[default]
+    Idle,
+    /// 收集模式，收到的消息都会被收集
+    Collecting,
+    /// 等待用户发送文件名，保存开始等待的时间，超过 [`FILE_NAME_PROMPT_TIMEOUT`] 后不再等待
+    AwaitingFileName(std::time::Instant),
 }
 
-async fn start_collecting(
-    bot: Arc<Bot>,
-    chat_id: ChatId,
-    state: AppState,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let mut state_guard = state.lock().await;
-    let user_state = state_guard.entry(chat_id).or_default();
+#[derive(Debug, Default)]
+struct UserState {
+    /// 正在进行的操作
+    mode: SessionMode,
+    /// 收集的消息
+    messages: Vec<Message>,
+    /// 打包的文件名
+    file_name: Option<String>,
+    /// 文件名与会话用过的名称相同时沿用原来的名称，而不是加上序号
+    overwrite_file_name: bool,
+    /// 会话的设置，收集结束后保留
+    settings: ChatSettings,
+    /// 本次收集中已经通过 /pack 打包的次数
+    pack_count: u32,
+    /// 当前的收集或设置文件名会话开始的时间
+    started_at: Option<std::time::Instant>,
+    /// 收集中最近一次收到消息或切换收集的时间
+    last_activity: Option<std::time::Instant>,
+    /// 正在排队或处理的打包任务
+    jobs: HashMap<Uuid, RunningJob>,
+    /// 最近一次快速模式打包的内容，用于 /full 以原图重新打包
+    preview: Option<Batch>,
+    /// 最近一次打包的内容和打包的时间，在 [`REPROCESS_WINDOW`] 内可以用 /reprocess 重新处理
+    last_batch: Option<(Batch, std::time::Instant)>,
+    /// 最近一次失败的任务，在 `RETRY_WINDOW` 内可以用 /retry 重试
+    failed_job: Option<FailedJob>,
+    /// 通过回复并 @机器人 请求、还没有开始处理的打包
+    quick_packs: Vec<Batch>,
+    /// 通过 /cancel 取消的收集，在 [`RESTORE_WINDOW`] 内可以恢复
+    cancelled: Option<CancelledCollection>,
+    /// 开启 /pinstatus 时，收集期间置顶并随收集数量更新的状态消息
+    status_message: Option<MessageId>,
+    /// 开启 /cleanchat 时，本次收集中机器人发送的、交付结果后需要删除的消息
+    interim_messages: Vec<MessageId>,
+    /// 正在使用的收集的名称，`None` 为 [`DEFAULT_COLLECTION`]
+    collection: Option<String>,
+    /// 通过 /use 切换出去的收集，按名称保存，切换回来时恢复
+    collections: HashMap<String, Collection>,
+}
 
-    user_state.is_collecting = true;
-    user_state.messages.clear();
+/// 切换出去的收集，保存收集状态、消息和文件名
+#[derive(Debug, Default)]
+struct Collection {
+    collecting: bool,
+    messages: Vec<Message>,
+    file_name: Option<String>,
+    overwrite_file_name: bool,
+    pack_count: u32,
+    started_at: Option<std::time::Instant>,
+}
 
-    log::info!("会话 {} 开启了一个收集任务", chat_id);
-    bot.send_message(
-        chat_id,
-        "✅收集已开始，请发送图片或包含图片的消息。完成后，发送/stopcollect以结束收集",
-    )
-    .await?;
-    Ok(())
+/// 失败的任务，它的临时目录保留到重试或过期
+#[derive(Debug)]
+struct FailedJob {
+    job_id: Uuid,
+    batch: Batch,
+    /// 超过这个时间后不能再重试
+    expires_at: std::time::Instant,
 }
 
-async fn stop_collecting_and_process(
-    bot: Arc<Bot>,
-    chat_id: ChatId,
-    state: AppState,
-    client: Client,
-) {
-    if let Err(e) = process_inner(Arc::clone(&bot), chat_id, state.clone(), client.clone()).await {
-        log::error!("Error processing for chat {}: {}", chat_id, e);
-        let _ = bot
-            .send_message(chat_id, format!("❌ 处理失败: {}", e))
-            .await;
-    }
+/// 被取消的收集
+#[derive(Debug)]
+struct CancelledCollection {
+    messages: Vec<Message>,
+    file_name: Option<String>,
+    cancelled_at: std::time::Instant,
 }
 
-async fn process_inner(
-    bot: Arc<Bot>,
-    chat_id: ChatId,
-    state: AppState,
-    client: Client,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let (messages_to_process, file_name) = {
-        let mut state_guard = state.lock().await;
-        let user_state = state_guard.entry(chat_id).or_default();
+/// 正在排队或处理的打包任务
+#[derive(Debug)]
+struct RunningJob {
+    /// 用于取消任务
+    cancel: CancellationToken,
+    started_at: std::time::Instant,
+}
 
-        if !user_state.is_collecting {
-            bot.send_message(chat_id, "🤔 你还没有开始收集，请先发送 /startcollect。")
-                .await?;
-            return Ok(());
+#[derive(Debug, Default, Clone)]
+struct ChatSettings {
+    /// 输出方式
+    output_mode: OutputMode,
+    /// 是否生成可复现的压缩包
+    reproducible: bool,
+    /// 每个压缩包最多包含的图片数量
+    chunk_size: Option<usize>,
+    /// 图片的排列顺序
+    order: Order,
+    /// 快速模式，下载较小的图片生成预览
+    fast: bool,
+    /// 是否在压缩包中附带记录来源信息的 README.txt
+    readme: bool,
+    /// 是否在压缩包中按文件类型分文件夹
+    folders: bool,
+    /// 是否用图片的说明文字作为文件名
+    caption_names: bool,
+    /// 最长边小于这个像素数的图片不打包，0表示不限制
+    min_image_dimension: u32,
+    /// 是否按 EXIF 方向标记旋转图片，需要重新编码，默认关闭
+    auto_rotate: bool,
+    /// 是否在压缩包中附带与图片同名的说明文字文件
+    caption_files: captions::CaptionFiles,
+    /// 是否在压缩包中附带 Telegram Desktop 导出格式的 result.json
+    telegram_export: bool,
+    /// 是否收集贴纸
+    stickers: bool,
+    /// 是否收集语音和音频
+    audio: bool,
+    /// 是否收集圆形的视频消息
+    video_notes: bool,
+    /// 是否在视频消息旁保存它的缩略图
+    video_thumbnails: bool,
+    /// 是否每张图片单独打包成一个压缩包
+    per_image: bool,
+    /// 是否下载网页链接的预览图
+    link_previews: bool,
+    /// 打包前加在图片上的水印，默认不加
+    watermark: Option<watermark::Watermark>,
+    /// 写在压缩包注释最前面的文字，默认不写
+    archive_comment: Option<String>,
+    /// 同一组中同时有图片和原图文件时打包哪一种
+    photo_source: PhotoSource,
+    /// 收集期间是否置顶状态消息
+    pin_status: bool,
+    /// 交付结果后是否删除机器人的中间消息
+    clean_chat: bool,
+    /// 压缩包的压缩方式
+    compression: Compression,
+    /// 压缩包的送达方式
+    delivery: Delivery,
+}
+
+impl ChatSettings {
+    /// 读取 `DEFAULT_FORMAT`、`DEFAULT_COMPRESSION`、`DEFAULT_CLEAN_CHAT`、`DEFAULT_STICKERS` 和
+    /// `MIN_IMAGE_DIMENSION`，作为新会话的初始设置
+    fn from_env() -> Self {
+        let mut settings = ChatSettings {
+            clean_chat: env_or("DEFAULT_CLEAN_CHAT", false),
+            stickers: env_or("DEFAULT_STICKERS", true),
+            min_image_dimension: env_or("MIN_IMAGE_DIMENSION", 0),
+            ..Default::default()
+        };
+        if let Ok(format) = std::env::var("DEFAULT_FORMAT") {
+            settings.output_mode = OutputMode::parse(&format).unwrap_or_else(|| {
+                log::error!(
+                    "DEFAULT_FORMAT 的值 `{}` 无法识别，可选 archive、album、album caption、documents 或 gallery",
+                    format
+                );
+                telemetry::exit(1);
+            });
+        }
+        if let Ok(compression) = std::env::var("DEFAULT_COMPRESSION") {
+            settings.compression = Compression::parse(&compression).unwrap_or_else(|| {
+                log::error!(
+                    "DEFAULT_COMPRESSION 的值 `{}` 无法识别，可选 deflate 或 store",
+                    compression
+                );
+                telemetry::exit(1);
+            });
         }
+        settings
+    }
+}
 
-        user_state.is_collecting = false;
-        log::info!(
-            "Stopped collecting for chat {}. Processing {} messages.",
-            chat_id,
-            user_state.messages.len()
-        );
+impl UserState {
+    /// 是否有需要关注的会话：正在收集、设置文件名或有任务在处理
+    fn is_active(&self) -> bool {
+        self.is_collecting() || self.is_set_file_name() || !self.jobs.is_empty()
+    }
 
-        // 克隆消息列表并释放锁
-        let messages = std::mem::take(&mut user_state.messages);
-        let file_name = user_state.file_name.take();
-        (messages, file_name)
-    };
+    /// 是否是收集模式
+    fn is_collecting(&self) -> bool {
+        self.mode == SessionMode::Collecting
+    }
 
-    if messages_to_process.is_empty() {
-        bot.send_message(chat_id, "ℹ️ 你没有发送任何消息，无需处理。")
-            .await?;
-        return Ok(());
+    /// 是否正在等待用户发送文件名
+    fn is_set_file_name(&self) -> bool {
+        matches!(self.mode, SessionMode::AwaitingFileName(prompted_at) if prompted_at.elapsed() < FILE_NAME_PROMPT_TIMEOUT)
     }
 
-    bot.send_message(chat_id, "⏳ 正在处理，请稍候...").await?;
+    /// 开始收集，正在等待的文件名不再等待
+    fn start_collecting(&mut self) {
+        self.mode = SessionMode::Collecting;
+    }
 
-    let token = bot.token();
-    let mut photo_urls = Vec::new();
+    /// 开始等待用户发送文件名，正在收集时收集的消息和文件名无法区分，返回 `false`
+    fn prompt_file_name(&mut self) -> bool {
+        if self.is_collecting() {
+            return false;
+        }
+        self.mode = SessionMode::AwaitingFileName(std::time::Instant::now());
+        true
+    }
 
-    // 1. 提取所有图片的下载链接
-    for msg in &messages_to_process {
-        if let Some(photos) = msg.photo() {
-            // 获取最高分辨率的图片
-            if let Some(largest_photo) = photos.iter().max_by_key(|p| p.height * p.width) {
-                let file = bot.get_file(largest_photo.file.id.clone()).await?;
-                let url = format!("https://api.telegram.org/file/bot{}/{}", token, file.path);
-                photo_urls.push(url);
-            }
+    /// 清理并设置文件名，返回设置后的文件名，清理后为空时返回 `None`
+    ///
+    /// 设置成功后不再等待文件名，收集不受影响。
+    fn set_file_name(&mut self, name: &str) -> Option<&str> {
+        self.file_name = Some(naming::sanitize_file_name(name)?);
+        self.overwrite_file_name = false;
+        if let SessionMode::AwaitingFileName(_) = self.mode {
+            self.mode = SessionMode::Idle;
         }
+        self.file_name.as_deref()
     }
 
-    if photo_urls.is_empty() {
-        bot.send_message(chat_id, "🤷‍♀️ 在你发送的消息中没有找到任何图片。")
-            .await?;
-        return Ok(());
+    /// 正在使用的收集的名称
+    fn collection_name(&self) -> &str {
+        self.collection.as_deref().unwrap_or(DEFAULT_COLLECTION)
     }
 
-    // 2. 创建临时目录并下载图片
-    let temp_dir_name = format!("temp_{}_{}", chat_id.0, Uuid::new_v4());
-    let temp_dir = PathBuf::from(&temp_dir_name);
-    let zip_filename = if file_name.is_none() {
-        let now = chrono::Local::now().format("%Y-%m-%d:%H:%M");
-        format!("images_{}_{}.zip", now, chat_id.0)
-    } else {
-        format!("{}.zip", file_name.unwrap())
-    };
-    let zip_path = PathBuf::from(&zip_filename);
+    /// 保存当前的收集并切换到名为 `name` 的收集，没有时新建一个空的收集
+    ///
+    /// 正在等待的文件名不再等待。
+    fn switch_collection(&mut self, name: &str) {
+        let current = Collection {
+            collecting: self.is_collecting(),
+            messages: std::mem::take(&mut self.messages),
+            file_name: self.file_name.take(),
+            overwrite_file_name: std::mem::take(&mut self.overwrite_file_name),
+            pack_count: std::mem::take(&mut self.pack_count),
+            started_at: self.started_at.take(),
+        };
+        let current_name = self.collection_name().to_string();
+        // 没有任何内容的收集不需要保存
+        if current.collecting || !current.messages.is_empty() || current.file_name.is_some() {
+            self.collections.insert(current_name, current);
+        }
 
-    tokio::fs::create_dir_all(&temp_dir).await?;
+        let next = self.collections.remove(name).unwrap_or_default();
+        self.mode = match next.collecting {
+            true => SessionMode::Collecting,
+            false => SessionMode::Idle,
+        };
+        self.messages = next.messages;
+        self.file_name = next.file_name;
+        self.overwrite_file_name = next.overwrite_file_name;
+        self.pack_count = next.pack_count;
+        self.started_at = next.started_at;
+        self.last_activity = Some(std::time::Instant::now());
+        self.collection = (name != DEFAULT_COLLECTION).then(|| name.to_string());
+    }
 
-    {
-        let mut downloads = Vec::with_capacity(photo_urls.len());
+    /// 结束收集，收集的消息和文件名在 [`RESTORE_WINDOW`] 内可以通过 /restore 恢复
+    ///
+    /// 返回收集的消息数量。
+    fn cancel_collecting(&mut self, cancelled_at: std::time::Instant) -> usize {
+        let count = self.messages.len();
+        self.mode = SessionMode::Idle;
+        self.pack_count = 0;
+        self.interim_messages.clear();
+        self.cancelled = Some(CancelledCollection {
+            messages: std::mem::take(&mut self.messages),
+            file_name: self.file_name.take(),
+            cancelled_at,
+        });
+        count
+    }
 
-        for (i, url) in photo_urls.clone().into_iter().enumerate() {
-            let client = client.clone();
-            let temp_dir_cloned = temp_dir.clone(); // 克隆 temp_dir 所有权到异步块内
-            downloads.push(async move {
-                let response = client.get(url).send().await?;
-                let bytes = response.bytes().await?;
-                let file_path = temp_dir_cloned.join(format!("image_{}.jpg", i + 1));
-                tokio::fs::write(file_path, &bytes).await?;
-                Ok::<(),Box<dyn std::error::Error+Send+Sync>>(())
-            });
+    /// 失败的任务在 `now` 时已经过期时不能再重试，返回它的id以便删除临时目录
+    fn expire_failed_job(&mut self, now: std::time::Instant) -> Option<Uuid> {
+        if self.failed_job.as_ref()?.expires_at > now {
+            return None;
         }
+        self.failed_job.take().map(|failed| failed.job_id)
+    }
 
-        futures::future::join_all(downloads).await;
+    /// 收集超过 `timeout` 没有收到新消息时结束收集，释放占用的收集名额
+    ///
+    /// 返回收集的消息数量，结束的收集与 /cancel 一样可以恢复。
+    fn expire_idle(&mut self, timeout: Duration) -> Option<usize> {
+        let idle_since = self.last_activity.or(self.started_at)?;
+        if !self.is_collecting() || idle_since.elapsed() < timeout {
+            return None;
+        }
+        Some(self.cancel_collecting(std::time::Instant::now()))
     }
 
-    log::info!(
-        "Downloaded {} photos to {}",
-        photo_urls.len(),
-        temp_dir_name
-    );
+    /// 收集已经结束时取出需要取消置顶的状态消息
+    fn take_finished_status(&mut self) -> Option<MessageId> {
+        if self.is_collecting() {
+            return None;
+        }
+        self.status_message.take()
+    }
 
-    create_zip(&temp_dir, &zip_path)?;
-    log::info!("Created zip file: {}", zip_filename);
+    /// 会话持续的时间，从会话开始或最早的任务开始计算
+    fn age(&self) -> Option<Duration> {
+        self.jobs
+            .values()
+            .map(|job| job.started_at)
+            .chain(self.started_at)
+            .min()
+            .map(|started_at| started_at.elapsed())
+    }
 
-    // 4. 发送 ZIP 文件
-    bot.send_message(
-        chat_id,
-        format!(
-            "✅ 处理完成！共下载 {} 张图片，正在发送压缩包...",
-            photo_urls.len()
-        ),
-    )
-    .await?;
-    bot.send_document(chat_id, InputFile::file(&zip_path))
-        .await?;
-    log::info!("Sent zip file to chat {}", chat_id);
+    /// 根据任务来源取出要处理的内容
+    fn take(&mut self, source: BatchSource) -> Result<Batch, StopRejection> {
+        let batch = match source {
+            BatchSource::Stop => self.take_batch(false),
+            BatchSource::Pack => self.take_batch(true),
+            BatchSource::FullResolution => {
+                let mut batch = self.preview.take().ok_or(StopRejection::NoPreview)?;
+                batch.settings.fast = false;
+                Ok(batch)
+            }
+            BatchSource::Reply => {
+                if self.quick_packs.is_empty() {
+                    return Err(StopRejection::NoReplyTarget);
+                }
+                let batch = self.quick_packs.remove(0);
+                if batch.settings.fast {
+                    self.preview = Some(batch.clone());
+                }
+                Ok(batch)
+            }
+            BatchSource::Reprocess => {
+                // 过期的内容直接丢弃，不再占用内存
+                self.last_batch = self
+                    .last_batch
+                    .take()
+                    .filter(|(_, taken_at)| taken_at.elapsed() < REPROCESS_WINDOW);
+                let (batch, _) = self.last_batch.as_ref().ok_or(StopRejection::NoLastBatch)?;
+                // 保留文件名和分包序号，其余使用当前的设置
+                return Ok(Batch {
+                    settings: self.settings.clone(),
+                    resume: None,
+                    ..batch.clone()
+                });
+            }
+            BatchSource::Retry => {
+                // 过期的任务留给 `expire_failed_jobs` 删除临时目录
+                let now = std::time::Instant::now();
+                let failed = self
+                    .failed_job
+                    .take_if(|failed| failed.expires_at > now)
+                    .ok_or(StopRejection::NoFailedJob)?;
+                Ok(Batch {
+                    resume: Some(failed.job_id),
+                    ..failed.batch
+                })
+            }
+        }?;
+        self.last_batch = Some((batch.clone(), std::time::Instant::now()));
+        Ok(batch)
+    }
 
-    // 5. 清理临时文件和目录
-    tokio::fs::remove_dir_all(&temp_dir).await?;
-    tokio::fs::remove_file(&zip_path).await?;
-    log::info!("Cleaned up temporary files for chat {}", chat_id);
+    /// 取出收集到的消息准备处理
+    ///
+    /// `keep_collecting` 为 `true` 时（/pack）收集继续进行，文件名保留给之后的分包，
+    /// 否则结束收集。没有在收集时不会改动任何状态；收集为空时保留已设置的文件名。
+    fn take_batch(&mut self, keep_collecting: bool) -> Result<Batch, StopRejection> {
+        if !self.is_collecting() {
+            return Err(StopRejection::NotCollecting);
+        }
+        if !keep_collecting {
+            self.mode = SessionMode::Idle;
+        }
+        if self.messages.is_empty() {
+            return Err(if keep_collecting {
+                StopRejection::NothingToPack
+            } else {
+                StopRejection::Empty
+            });
+        }
 
-    Ok(())
+        // 打包过的会话，每一部分的文件名都带上序号
+        let overwrite_name = self.overwrite_file_name;
+        let (file_name, part) = if keep_collecting {
+            self.pack_count += 1;
+            (self.file_name.clone(), Some(self.pack_count))
+        } else {
+            let part = (self.pack_count > 0).then_some(self.pack_count + 1);
+            self.overwrite_file_name = false;
+            (self.file_name.take(), part)
+        };
+        let batch = Batch {
+            messages: std::mem::take(&mut self.messages),
+            file_name,
+            overwrite_name,
+            part,
+            settings: self.settings.clone(),
+            resume: None,
+        };
+        if batch.settings.fast {
+            self.preview = Some(batch.clone());
+        }
+        Ok(batch)
+    }
 }
 
-fn create_zip(src_dir: &Path, dst_file: &Path) -> zip::result::ZipResult<()> {
-    let file = File::create(dst_file)?;
-    let mut zip = ZipWriter::new(file);
-    let options = FileOptions::<()>::default()
-        .compression_method(zip::CompressionMethod::Deflated)
-        .unix_permissions(0o755);
+/// 打包任务的来源
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BatchSource {
+    /// /stopcollect，结束收集并处理
+    Stop,
+    /// /pack，处理已收集的图片，收集继续
+    Pack,
+    /// /full，以原图重新处理最近一次快速模式的图片
+    FullResolution,
+    /// 回复一条消息并 @机器人 zip，只打包被回复的消息
+    Reply,
+    /// /reprocess，用当前的设置重新处理最近一次打包的内容
+    Reprocess,
+    /// /retry，继续最近一次失败的任务
+    Retry,
+}
+
+/// 一次打包要处理的内容
+#[derive(Debug, Clone)]
+struct Batch {
+    messages: Vec<Message>,
+    /// 用户设置的文件名
+    file_name: Option<String>,
+    /// 会话用过这个文件名时沿用，而不是加上序号
+    overwrite_name: bool,
+    /// 同一次收集中的第几部分，只在使用过 /pack 时存在
+    part: Option<u32>,
+    settings: ChatSettings,
+    /// /retry 时为失败的任务id，沿用它的临时目录和已经下载的文件
+    resume: Option<Uuid>,
+}
 
-    let mut buffer = Vec::new();
-    for entry in std::fs::read_dir(src_dir)? {
-        let entry = entry?;
-        let path = entry.path();
-        let name = path.file_name().unwrap().to_str().unwrap();
+/// 无法结束收集并开始处理的原因
+#[derive(Debug, PartialEq, Eq)]
+enum StopRejection {
+    /// 没有正在进行的收集
+    NotCollecting,
+    /// 收集已开始，但没有收到任何消息
+    Empty,
+    /// 上次 /pack 之后没有收到新的消息
+    NothingToPack,
+    /// 没有可以获取原图的快速模式打包
+    NoPreview,
+    /// 没有等待处理的回复打包
+    NoReplyTarget,
+    /// 没有最近打包过的内容，或已经过期
+    NoLastBatch,
+    /// 没有失败的任务，或已经过期
+    NoFailedJob,
+}
 
-        if path.is_file() {
-            zip.start_file(name, options)?;
-            let mut f = File::open(path)?;
-            f.read_to_end(&mut buffer)?;
-            zip.write_all(&buffer)?;
-            buffer.clear();
+impl StopRejection {
+    fn message(&self) -> &'static str {
+        match self {
+            StopRejection::NotCollecting => {
+                "🤔 你还没有开始收集，请先发送 /startcollect 开始收集，然后再发送 /stopcollect。"
+            }
+            StopRejection::Empty => {
+                "ℹ️ 收集已结束，但你没有发送任何消息，无需处理。如需重新收集，请发送 /startcollect。"
+            }
+            StopRejection::NothingToPack => {
+                "ℹ️ 还没有收到新的消息，无需打包。收集仍在继续，完成后发送 /stopcollect。"
+            }
+            StopRejection::NoPreview => {
+                "🤔 没有可以获取原图的预览，请先用 /fast 开启快速模式并完成一次打包。"
+            }
+            StopRejection::NoReplyTarget => {
+                "🤔 没有找到要打包的消息，请回复一条包含图片的消息并 @我 zip。"
+            }
+            StopRejection::NoLastBatch => {
+                "🤔 没有可以重新处理的内容，只能重新处理 30 分钟内完成的打包。"
+            }
+            StopRejection::NoFailedJob => {
+                "🤔 没有可以重试的任务，失败的任务已经重试过或保留时间已过。"
+            }
         }
     }
-    zip.finish()?;
-    Ok(())
+}
+
+#[derive(BotCommands, Clone)]
+//如果不采用小写，telegram就无法注册命令
+#[command(rename_rule = "lowercase")]
+enum Command {
+    #[command(description = "显示此帮助信息")]
+    Start,
+    #[command(description = "显示此帮助信息")]
+    Help,
+    #[command(
+        description = "开始收集图片信息，也可以用 /sc 或 /collect",
+        alias = "collect"
+    )]
+    StartCollect,
+    #[command(
+        description = "停止收集并打包下载所有图片，也可以用 /done 或 /spc",
+        aliases = ["done", "spc"]
+    )]
+    StopCollect,
+    #[command(description = "打包已收集的图片，并继续收集")]
+    Pack,
+    #[command(description = "中止正在进行的打包任务")]
+    Abort,
+    #[command(description = "显示程序版本")]
+    Version,
+    #[command(description = "显示当前的设置和支持的内容")]
+    Settings,
+    #[command(description = "显示当前收集的用量和各项限制")]
+    Limits,
+    #[command(description = "显示你的用户id、用户名和语言")]
+    WhoAmI,
+    #[command(description = "显示当前会话的id、类型和名称")]
+    ChatInfo,
+    #[command(description = "向管理员发送反馈或问题，例如 /feedback 打包后缺少图片")]
+    Feedback(String),
+    #[command(
+        description = "设置zip名称，例如 /filename 旅行照片，也可以用 /name",
+        alias = "name"
+    )]
+    FileName(String),
+    #[command(description = "取消设置文件名或正在进行的收集")]
+    Cancel,
+    #[command(description = "恢复刚刚取消的收集")]
+    Restore,
+    #[command(
+        description = "切换到指定名称的收集，每个收集分别保存消息和文件名，例如 /use 旅行，不带参数时列出所有收集"
+    )]
+    Use(String),
+    #[command(
+        description = "设置输出方式：archive（压缩包）、album（相册）或 documents（原图文件）"
+    )]
+    Output(String),
+    #[command(description = "设置压缩方式：deflate（压缩）或 store（仅存储，打包更快）")]
+    Compression(String),
+    #[command(description = "设置压缩包的送达方式：telegram、sftp 或 both")]
+    Delivery(String),
+    #[command(description = "切换可复现打包：不写注释并将时间戳置零")]
+    Reproducible,
+    #[command(description = "切换是否在压缩包中附带记录来源信息的 README.txt")]
+    Readme,
+    #[command(
+        description = "切换是否在压缩包中按类型分文件夹：images/、audio/、stickers/、videos/"
+    )]
+    Folders,
+    #[command(description = "切换是否用图片的说明文字作为压缩包中的文件名")]
+    CaptionNames,
+    #[command(
+        description = "在压缩包中附带图片的说明文字：off、plain（纯文本）或 markdown（保留链接和格式）"
+    )]
+    Captions(String),
+    #[command(
+        description = "在压缩包中附带 Telegram Desktop 导出格式的 result.json：/exportformat telegram 或 off"
+    )]
+    ExportFormat(String),
+    #[command(description = "设置每个压缩包最多包含的图片数量，/chunk off 关闭")]
+    Chunk(String),
+    #[command(description = "设置图片顺序：received、date-asc、date-desc 或 shuffle")]
+    Order(String),
+    #[command(description = "收集期间置顶状态消息（需要置顶权限），/pinstatus on 或 off")]
+    PinStatus(String),
+    #[command(description = "交付结果后删除机器人的中间消息，/cleanchat on 或 off")]
+    CleanChat(String),
+    #[command(description = "跳过最长边小于指定像素的图片，例如 /minsize 200，/minsize off 关闭")]
+    MinSize(String),
+    #[command(description = "按 EXIF 方向标记旋转图片并去掉标记，/autorotate on 或 off")]
+    AutoRotate(String),
+    #[command(description = "是否收集贴纸，/stickers on 或 off")]
+    Stickers(String),
+    #[command(description = "是否收集语音和音频，/audio on 或 off")]
+    Audio(String),
+    #[command(description = "是否收集圆形的视频消息，/videonotes on 或 off")]
+    VideoNotes(String),
+    #[command(description = "在视频消息旁保存缩略图 videonote_1.thumb.jpg，/thumbnails on 或 off")]
+    Thumbnails(String),
+    #[command(description = "每张图片单独打包成一个压缩包，/perimage on 或 off")]
+    PerImage(String),
+    #[command(description = "下载网页链接的预览图，/previews on 或 off")]
+    Previews(String),
+    #[command(
+        description = "打包前给图片加上文字水印：/watermark 文字、/watermark corner 右下、/watermark opacity 60 或 /watermark off"
+    )]
+    Watermark(String),
+    #[command(
+        description = "在压缩包注释中写入一段文字，例如 /comment 2024 年春游，/comment off 清除"
+    )]
+    Comment(String),
+    #[command(
+        description = "同一组中同时有图片和原图文件时打包哪一种：document（原图文件）或 photo（压缩的图片）"
+    )]
+    Original(String),
+    #[command(description = "切换快速模式：下载较小的图片，快速生成预览")]
+    Fast,
+    #[command(description = "以原图重新打包最近一次快速模式的图片")]
+    Full,
+    #[command(description = "用当前的设置重新处理最近一次打包的图片，例如换一种输出方式")]
+    Reprocess,
+    #[command(description = "重试最近一次失败的任务，已经下载的文件不会重新下载")]
+    Retry,
+    #[command(
+        description = "打包头像：回复某人的消息发送 /avatar，或 /avatar 用户id、会话id或 @公开会话"
+    )]
+    Avatar(String),
+    #[command(
+        description = "发送一个示例压缩包，检查打包和上传是否正常（管理员）",
+        hide
+    )]
+    SelfTest,
+    #[command(description = "检查能否连接到文件下载服务器（管理员）", hide)]
+    CheckDownload,
+    #[command(description = "列出正在进行的会话（管理员）", hide)]
+    Sessions,
+    #[command(description = "重置指定会话并取消它的任务（管理员）", hide)]
+    ClearSession(String),
+    #[command(description = "显示最近的运行汇总（管理员）", hide)]
+    Report,
+    #[command(description = "显示临时文件和数据文件占用的空间（管理员）", hide)]
+    DiskUsage,
+}
+
+impl Command {
+    /// 是否只有管理员可以使用
+    fn is_admin_only(&self) -> bool {
+        matches!(
+            self,
+            Command::SelfTest
+                | Command::CheckDownload
+                | Command::Sessions
+                | Command::ClearSession(_)
+                | Command::Report
+                | Command::DiskUsage
+        )
+    }
+}
+
+/// /start 和 /help 的回复，也是第一次互动时的欢迎信息
+fn help_text(chat: &teloxide::types::Chat, me: &Me) -> String {
+    if chat.is_private() {
+        return HELP_TEXT.to_string();
+    }
+    format!(
+        "{}\n\n在群组中可以在命令后加上 @{}，例如 /startcollect@{}，避免和其他机器人冲突",
+        HELP_TEXT,
+        me.username(),
+        me.username()
+    )
+}
+
+const HELP_TEXT: &str = "你好！我是图片下载机器人。\n\n/startcollect - 开始收集图片\n/stopcollect - 停止并打包下载\n/pack - 打包已收集的图片并继续收集\n/abort - 中止正在进行的打包任务\n/filename 名称 - 设置文件名称\n/output - 设置输出方式（压缩包或相册）\n/settings - 查看当前设置";
+
+/// 可以收集的内容，/settings 中显示
+const SUPPORTED_MEDIA: &str = "支持的内容：\n· 图片（以图片形式发送的消息）\n· 以文件形式发送的图片，不会被telegram压缩，保留原图\n· 图片直链（http 或 https）\n· 网页链接的预览图（发送 /previews on 开启），按页面标题命名\n· telegraph 页面，会展开为页面中的所有图片\n· 语音和音频（发送 /audio on 开启），会和图片一起打包\n· 圆形的视频消息（发送 /videonotes on 开启），保存为 .mp4\n· 贴纸，静态、动态和视频贴纸都按原文件打包\n\n发送 /perimage on 可以让每张图片单独打包成一个压缩包";
+
+/// /settings 的回复，列出会话的设置和支持的内容
+fn describe_settings(user_state: &UserState) -> FormattedText {
+    let settings = &user_state.settings;
+    let on_off = |enabled: bool| if enabled { "开启" } else { "关闭" };
+    let resolution = if settings.fast {
+        format!("快速模式，最长边不超过 {} 像素", output::PREVIEW_MAX_SIDE)
+    } else {
+        "原图".to_string()
+    };
+    let chunk = match settings.chunk_size {
+        Some(size) => format!("每个压缩包最多 {} 张图片", size),
+        None => "只按大小分卷".to_string(),
+    };
+
+    let mut text = FormattedText::new().bold("当前设置").text("\n文件名：");
+    text = match &user_state.file_name {
+        Some(file_name) => text.code(format!("{}.zip", file_name)),
+        None => text.text("未设置，使用打包时间"),
+    };
+    text.text(format!(
+        "\n输出方式：{}\n送达方式：{}\n压缩方式：{}\n图片尺寸：{}\n图片顺序：{}\n分卷：{}\n附带 README.txt：{}\n按类型分文件夹：{}\n以说明文字命名：{}\n最小图片尺寸：{}\n按 EXIF 方向旋转：{}\n说明文字文件：{}\nTelegram 导出格式：{}\n收集贴纸：{}\n收集语音和音频：{}\n收集视频消息：{}\n视频缩略图：{}\n每张图片单独打包：{}\n网页预览图：{}\n水印：{}\n压缩包注释：{}\n图片和原图文件同时发送时：{}\n可复现打包：{}\n置顶状态消息：{}\n删除中间消息：{}\n\n{}",
+        settings.output_mode.describe(),
+        settings.delivery.describe(),
+        settings.compression.describe(),
+        resolution,
+        settings.order.describe(),
+        chunk,
+        on_off(settings.readme),
+        on_off(settings.folders),
+        on_off(settings.caption_names),
+        match settings.min_image_dimension {
+            0 => "不限制".to_string(),
+            min => format!("最长边至少 {} 像素", min),
+        },
+        on_off(settings.auto_rotate),
+        settings.caption_files.describe(),
+        on_off(settings.telegram_export),
+        on_off(settings.stickers),
+        on_off(settings.audio),
+        on_off(settings.video_notes),
+        on_off(settings.video_thumbnails),
+        on_off(settings.per_image),
+        on_off(settings.link_previews),
+        settings
+            .watermark
+            .as_ref()
+            .map_or("关闭".to_string(), |watermark| watermark.describe()),
+        settings.archive_comment.as_deref().unwrap_or("未设置"),
+        settings.photo_source.describe(),
+        on_off(settings.reproducible),
+        on_off(settings.pin_status),
+        on_off(settings.clean_chat),
+        SUPPORTED_MEDIA
+    ))
+}
+
+/// /limits 的回复：会话当前的用量和配置的各项限制
+async fn describe_limits(chat_id: ChatId, state: &AppState, config: &Config) -> FormattedText {
+    let active_sessions = state
+        .count_where(|user_state| user_state.is_collecting())
+        .await;
+    let (usage, chunk_size) = config
+        .update_session(state, chat_id, |user_state| {
+            let usage = format!(
+                "\n当前收集：{}，{}\n保存的收集：{}/{}\n正在处理的任务：{}",
+                user_state.collection_name(),
+                collection_summary(
+                    user_state.is_collecting(),
+                    user_state.messages.len(),
+                    user_state.file_name.as_deref()
+                ),
+                user_state.collections.len() + 1,
+                MAX_COLLECTIONS,
+                user_state.jobs.len()
+            );
+            (usage, user_state.settings.chunk_size)
+        })
+        .await;
+
+    let volume = match chunk_size {
+        Some(size) => format!(
+            "最大 {}，最多 {} 张图片",
+            format_size(archive::MAX_VOLUME_SIZE),
+            size
+        ),
+        None => format!("最大 {}", format_size(archive::MAX_VOLUME_SIZE)),
+    };
+    let sessions = match config.max_active_sessions {
+        0 => format!("{} 个，不限制", active_sessions),
+        max => format!("{}/{}", active_sessions, max),
+    };
+    let temp = match config.temp_quota {
+        0 => "不限制".to_string(),
+        quota => {
+            let (_, used) = workspace::usage(&config.temp_root).await;
+            format!("已用 {}/{}", format_size(used), format_size(quota))
+        }
+    };
+    FormattedText::new()
+        .bold("当前用量")
+        .text(usage)
+        .text("\n\n")
+        .bold("每个任务的限制")
+        .text(format!(
+            "\n单个文件：最大 {}\n每个压缩包：{}\n每张图片单独打包：最多 {} 个压缩包\n导入 zip：最大 {}，解压后合计最多 {}\n下载阶段超时：{}\n处理超时：{}",
+            format_size(config.file_limit()),
+            volume,
+            config.per_image_limit,
+            format_size(config.zip_import_max_bytes),
+            format_size(config.zip_import_max_extracted),
+            format_duration(config.job_timeout),
+            format_duration(config.process_timeout),
+        ))
+        .text("\n\n")
+        .bold("所有会话共享的限制")
+        .text(format!(
+            "\n正在收集的会话：{}\n同时处理的任务：{} 个，超出时排队\n服务器临时空间：{}",
+            sessions, config.max_concurrent_jobs, temp
+        ))
+}
+
+/// 自检时打包的示例图片
+const SELF_TEST_IMAGES: &[(&str, &[u8])] = &[
+    ("image_1.png", include_bytes!("../assets/selftest_1.png")),
+    ("image_2.png", include_bytes!("../assets/selftest_2.png")),
+];
+
+/// 消息处理函数
+/// 处理用户的收集消息，如果用户没有开启收集模式，则忽略。
+async fn handle_message(
+    bot: Bot,
+    msg: Message,
+    state: AppState,
+    config: Arc<Config>,
+    known_chats: Arc<KnownChats>,
+    albums: Arc<RecentAlbums>,
+    me: Me,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let chat_id = msg.chat.id;
+    let reply_to = msg.id;
+    // 相册无论是否在收集都要记录，之后回复它快速打包时需要
+    albums.record(&msg);
+
+    // 欢迎信息不影响后续的消息处理
+    let first_contact = known_chats.first_contact(chat_id).await;
+    if first_contact {
+        stats::record_new_chat();
+    }
+    if config.welcome_new_chats && first_contact {
+        markdown::send(&bot, chat_id, Some(reply_to), help_text(&msg.chat, &me)).await?;
+    }
+
+    // 在存储的操作中只修改状态，操作结束后再发送消息
+    let reply = config
+        .update_session(&state, chat_id, |user_state| {
+            match user_state.mode {
+                SessionMode::Collecting => {
+                    log::trace!(
+                        "用户 {} 有一个收集会话 {}，包含 {} 个链接",
+                        chat_id,
+                        msg.id,
+                        links::extract_urls(&msg).len()
+                    );
+                    user_state.messages.push(msg.clone());
+                    user_state.last_activity = Some(std::time::Instant::now());
+                    user_state.status_message.map(|status| {
+                        MessageReply::Status(status, collecting_status(user_state.messages.len()))
+                    })
+                }
+                SessionMode::AwaitingFileName(_) if user_state.is_set_file_name() => {
+                    log::trace!("用户 {} 有一个设置文件名会话 {}", chat_id, msg.id);
+                    Some(MessageReply::FileName(
+                        user_state
+                            .set_file_name(msg.text().unwrap_or_default())
+                            .map(str::to_string),
+                    ))
+                }
+                // 等待已经超时
+                SessionMode::AwaitingFileName(_) => {
+                    user_state.mode = SessionMode::Idle;
+                    None
+                }
+                SessionMode::Idle => None,
+            }
+        })
+        .await;
+
+    match reply {
+        Some(MessageReply::Status(status, text)) => {
+            if let Err(why) = bot.edit_message_text(chat_id, status, text).await {
+                log::debug!("无法更新会话 {} 的状态消息: {}", chat_id, why);
+            }
+        }
+        Some(MessageReply::FileName(Some(file_name))) => {
+            send_file_name_set(&bot, chat_id, reply_to, &config, &file_name).await?;
+        }
+        Some(MessageReply::FileName(None)) => {
+            let reply = "❌ 文件名不能为空，请重新发送，或发送 /cancel 取消";
+            markdown::send(&bot, chat_id, Some(reply_to), reply).await?;
+        }
+        None => {}
+    }
+    Ok(())
+}
+
+/// 收到普通消息后需要发送的回复
+enum MessageReply {
+    /// 更新收集的状态消息
+    Status(MessageId, String),
+    /// 设置了文件名，清理后为空时为 `None`
+    FileName(Option<String>),
+}
+
+/// @机器人 后可以触发打包的词
+const QUICK_PACK_VERBS: &[&str] = &["zip", "pack", "打包"];
+
+/// 是否是回复一条消息并 @机器人 zip 的打包请求
+fn is_quick_pack_request(msg: &Message, me: &Me) -> bool {
+    use teloxide::types::MessageEntityKind;
+
+    let (Some(text), Some(entities), Some(_)) =
+        (msg.text(), msg.parse_entities(), msg.reply_to_message())
+    else {
+        return false;
+    };
+    let mut rest = text.to_string();
+    let mut mentioned = false;
+    for entity in entities {
+        let is_me = match entity.kind() {
+            MessageEntityKind::Mention => entity
+                .text()
+                .trim_start_matches('@')
+                .eq_ignore_ascii_case(me.username()),
+            MessageEntityKind::TextMention { user } => user.id == me.id,
+            _ => false,
+        };
+        if is_me {
+            mentioned = true;
+            rest = rest.replacen(entity.text(), "", 1);
+        }
+    }
+    let verb = rest.trim().to_lowercase();
+    mentioned && QUICK_PACK_VERBS.contains(&verb.as_str())
+}
+
+/// 只打包被回复的消息，回复的是相册时打包整个相册，不需要开始收集
+#[allow(clippy::too_many_arguments)]
+async fn quick_pack(
+    bot: Bot,
+    msg: Message,
+    client: Client,
+    state: AppState,
+    config: Arc<Config>,
+    limiter: Arc<RateLimiter>,
+    queue: Arc<JobQueue>,
+    albums: Arc<RecentAlbums>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let chat_id = msg.chat.id;
+    let Some(target) = msg.reply_to_message() else {
+        return Ok(());
+    };
+    log::info!(
+        "Chat {} requested a quick pack of message {}",
+        chat_id,
+        target.id
+    );
+    // 回复的是刚刚发送的相册时，等其余的消息都到达
+    while let Some(wait) = albums.settle_time(target) {
+        tokio::time::sleep(wait).await;
+    }
+    let messages = albums.album(target);
+    config
+        .update_session(&state, chat_id, |user_state| {
+            let batch = Batch {
+                messages,
+                file_name: None,
+                overwrite_name: false,
+                part: None,
+                settings: user_state.settings.clone(),
+                resume: None,
+            };
+            user_state.quick_packs.push(batch);
+        })
+        .await;
+    tokio::spawn(stop_collecting_and_process(
+        Arc::new(bot),
+        chat_id,
+        msg.id,
+        state,
+        client,
+        config,
+        limiter,
+        queue,
+        BatchSource::Reply,
+    ));
+    Ok(())
+}
+
+/// 以 `/` 开头的消息中的命令名称，不包括 `@机器人名`
+fn command_name(msg: &Message) -> Option<&str> {
+    let text = msg.text()?.strip_prefix('/')?;
+    let name = text.split_whitespace().next()?;
+    let name = name.split('@').next()?;
+    (!name.is_empty()).then_some(name)
+}
+
+/// 是否是发给其他机器人的命令，例如群组中的 `/start@otherbot`
+fn is_addressed_elsewhere(msg: &Message, me: &Me) -> bool {
+    let Some(command) = msg
+        .text()
+        .and_then(|text| text.strip_prefix('/'))
+        .and_then(|text| text.split_whitespace().next())
+    else {
+        return false;
+    };
+    match command.split_once('@') {
+        Some((_, bot_name)) => !bot_name.eq_ignore_ascii_case(me.username()),
+        None => false,
+    }
+}
+
+/// 忽略发给其他机器人的命令，既不回复也不收集
+async fn ignore_command(msg: Message) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    log::trace!(
+        "Ignoring command for another bot in chat {}: {:?}",
+        msg.chat.id,
+        msg.text()
+    );
+    Ok(())
+}
+
+/// 过滤重复的命令和按钮点击，放在其他处理之前，重复的更新不会继续往下传递
+fn debounce_handler() -> UpdateHandler<Box<dyn std::error::Error + Send + Sync>> {
+    dptree::entry()
+        .branch(
+            Update::filter_message()
+                .filter(|msg: Message, debouncer: Arc<Debouncer>| {
+                    is_repeated_command(&msg, &debouncer)
+                })
+                .endpoint(ignore_repeated_command),
+        )
+        .branch(
+            Update::filter_callback_query()
+                .filter(|query: CallbackQuery, debouncer: Arc<Debouncer>| {
+                    is_repeated_click(&query, &debouncer)
+                })
+                .endpoint(ignore_repeated_click),
+        )
+}
+
+/// 是否是刚刚处理过的相同命令，例如网络不好时连续发送的 `/stop`
+fn is_repeated_command(msg: &Message, debouncer: &Debouncer) -> bool {
+    let Some(text) = msg.text().filter(|_| command_name(msg).is_some()) else {
+        return false;
+    };
+    debouncer.is_repeat(msg.chat.id, msg.from.as_ref().map(|user| user.id), text)
+}
+
+async fn ignore_repeated_command(
+    msg: Message,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    log::debug!(
+        "Ignoring repeated command in chat {}: {:?}",
+        msg.chat.id,
+        msg.text()
+    );
+    Ok(())
+}
+
+/// 是否是刚刚处理过的相同按钮点击
+fn is_repeated_click(query: &CallbackQuery, debouncer: &Debouncer) -> bool {
+    let Some(data) = query.data.as_deref() else {
+        return false;
+    };
+    let chat_id = query
+        .message
+        .as_ref()
+        .map_or(ChatId::from(query.from.id), |message| message.chat().id);
+    debouncer.is_repeat(chat_id, Some(query.from.id), data)
+}
+
+/// 重复的点击只回复提示，避免客户端一直显示加载中
+async fn ignore_repeated_click(
+    bot: Bot,
+    query: CallbackQuery,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    bot.answer_callback_query(query.id.clone())
+        .text("已收到，请勿重复点击")
+        .await?;
+    Ok(())
+}
+
+/// 解析消息中的命令，先把简写替换为完整的命令
+fn parse_command(msg: &Message, me: &Me, aliases: &aliases::CommandAliases) -> Option<Command> {
+    let text = msg.text()?;
+    Command::parse(&aliases.expand(text), me.username()).ok()
+}
+
+/// 处理无法识别的命令，提示最接近的已知命令，而不是当作普通消息收集
+async fn unknown_command(
+    bot: Bot,
+    msg: Message,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let name = command_name(&msg).unwrap_or_default();
+    let commands = Command::bot_commands();
+    let suggestion = suggest::closest(
+        name,
+        commands
+            .iter()
+            .map(|command| command.command.trim_start_matches('/')),
+    );
+    log::debug!(
+        "Unknown command /{} in chat {}, suggesting {:?}",
+        name,
+        msg.chat.id,
+        suggestion
+    );
+    let reply = match suggestion {
+        Some(command) => format!("你是不是想用 /{} ？", command),
+        None => "❓ 无法识别的命令，发送 /help 查看可用的命令".to_string(),
+    };
+    markdown::send(&bot, msg.chat.id, Some(msg.id), reply).await?;
+    Ok(())
+}
+
+/// 命令处理函数
+#[allow(clippy::too_many_arguments)]
+async fn command_handler(
+    bot: Bot,
+    msg: Message,
+    cmd: Command,
+    client: Client,
+    state: AppState,
+    config: Arc<Config>,
+    limiter: Arc<RateLimiter>,
+    known_chats: Arc<KnownChats>,
+    queue: Arc<JobQueue>,
+    me: Me,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let chat_id = msg.chat.id;
+    let reply_to = msg.id;
+    let bot = Arc::new(bot);
+
+    // /start 和 /help 本身就会回复帮助信息，不需要再欢迎一次
+    let first_contact = known_chats.first_contact(chat_id).await;
+    if first_contact {
+        stats::record_new_chat();
+    }
+    if config.welcome_new_chats && first_contact && !matches!(cmd, Command::Start | Command::Help) {
+        markdown::send(&bot, chat_id, Some(reply_to), help_text(&msg.chat, &me)).await?;
+    }
+
+    if cmd.is_admin_only() && !config.is_admin(&msg) {
+        markdown::send(&bot, chat_id, Some(reply_to), "⛔ 只有管理员可以使用此命令").await?;
+        return Ok(());
+    }
+
+    match cmd {
+        Command::Start | Command::Help => {
+            markdown::send(&bot, chat_id, Some(reply_to), help_text(&msg.chat, &me)).await?;
+        }
+        Command::StartCollect => {
+            start_collecting(bot, chat_id, reply_to, state, &config).await?;
+        }
+        Command::StopCollect
+        | Command::Pack
+        | Command::Full
+        | Command::Reprocess
+        | Command::Retry => {
+            let source = match cmd {
+                Command::Pack => BatchSource::Pack,
+                Command::Full => BatchSource::FullResolution,
+                Command::Reprocess => BatchSource::Reprocess,
+                Command::Retry => BatchSource::Retry,
+                _ => BatchSource::Stop,
+            };
+            // 耗时任务放入后台执行
+            tokio::spawn(stop_collecting_and_process(
+                bot, chat_id, reply_to, state, client, config, limiter, queue, source,
+            ));
+        }
+        Command::Abort => {
+            abort_jobs(bot, chat_id, reply_to, state).await?;
+        }
+        Command::Settings => {
+            // 只查看设置时不创建会话
+            let reply = match state.get(chat_id, describe_settings).await {
+                Some(reply) => reply,
+                None => describe_settings(&config.new_session()),
+            };
+            markdown::send(&bot, chat_id, Some(reply_to), reply).await?;
+        }
+        Command::Limits => {
+            let reply = describe_limits(chat_id, &state, &config).await;
+            markdown::send(&bot, chat_id, Some(reply_to), reply).await?;
+        }
+        Command::WhoAmI => {
+            markdown::send(&bot, chat_id, Some(reply_to), describe_sender(&msg)).await?;
+        }
+        Command::ChatInfo => {
+            markdown::send(&bot, chat_id, Some(reply_to), describe_chat_info(&msg.chat)).await?;
+        }
+        Command::Feedback(text) => {
+            send_feedback(bot, &msg, &config, &text).await?;
+        }
+        Command::Version => {
+            markdown::send(&bot, chat_id, Some(reply_to), build_info::BUILD.describe()).await?;
+        }
+        Command::FileName(name) => {
+            set_file_name(bot, chat_id, reply_to, state, &config, &name).await?;
+        }
+        Command::Cancel => {
+            cancel(bot, chat_id, reply_to, state).await?;
+        }
+        Command::Restore => {
+            restore_collection(bot, chat_id, reply_to, state).await?;
+        }
+        Command::Use(name) => {
+            use_collection(bot, chat_id, reply_to, state, &config, &name).await?;
+        }
+        Command::Output(mode) => {
+            set_output_mode(bot, chat_id, reply_to, state, &config, &mode).await?;
+        }
+        Command::Compression(arg) => {
+            set_compression(bot, chat_id, reply_to, state, &config, &arg).await?;
+        }
+        Command::Captions(arg) => {
+            set_caption_files(bot, chat_id, reply_to, state, &config, &arg).await?;
+        }
+        Command::Delivery(arg) => {
+            set_delivery(bot, chat_id, reply_to, state, &config, &arg).await?;
+        }
+        Command::Chunk(arg) => {
+            set_chunk_size(bot, chat_id, reply_to, state, &config, &arg).await?;
+        }
+        Command::Order(arg) => {
+            set_order(bot, chat_id, reply_to, state, &config, &arg).await?;
+        }
+        Command::PinStatus(arg) => {
+            set_pin_status(bot, chat_id, reply_to, state, &config, &arg).await?;
+        }
+        Command::CleanChat(arg) => {
+            set_clean_chat(bot, chat_id, reply_to, state, &config, &arg).await?;
+        }
+        Command::Stickers(arg) => {
+            set_stickers(bot, chat_id, reply_to, state, &config, &arg).await?;
+        }
+        Command::Audio(arg) => {
+            set_audio(bot, chat_id, reply_to, state, &config, &arg).await?;
+        }
+        Command::MinSize(arg) => {
+            set_min_image_dimension(bot, chat_id, reply_to, state, &config, &arg).await?;
+        }
+        Command::AutoRotate(arg) => {
+            set_auto_rotate(bot, chat_id, reply_to, state, &config, &arg).await?;
+        }
+        Command::VideoNotes(arg) => {
+            set_video_notes(bot, chat_id, reply_to, state, &config, &arg).await?;
+        }
+        Command::Thumbnails(arg) => {
+            set_video_thumbnails(bot, chat_id, reply_to, state, &config, &arg).await?;
+        }
+        Command::PerImage(arg) => {
+            set_per_image(bot, chat_id, reply_to, state, &config, &arg).await?;
+        }
+        Command::Previews(arg) => {
+            set_link_previews(bot, chat_id, reply_to, state, &config, &arg).await?;
+        }
+        Command::ExportFormat(arg) => {
+            set_export_format(bot, chat_id, reply_to, state, &config, &arg).await?;
+        }
+        Command::Watermark(arg) => {
+            set_watermark(bot, chat_id, reply_to, state, &config, &arg).await?;
+        }
+        Command::Comment(arg) => {
+            set_archive_comment(bot, chat_id, reply_to, state, &config, &arg).await?;
+        }
+        Command::Original(arg) => {
+            set_photo_source(bot, chat_id, reply_to, state, &config, &arg).await?;
+        }
+        Command::Fast => {
+            let fast = config
+                .update_session(&state, chat_id, |user_state| {
+                    user_state.settings.fast = !user_state.settings.fast;
+                    user_state.settings.fast
+                })
+                .await;
+            let reply = if fast {
+                format!(
+                    "✅已开启快速模式，只下载最长边不超过 {} 像素的图片，打包后可以发送 /full 获取原图",
+                    output::PREVIEW_MAX_SIDE
+                )
+            } else {
+                "✅已关闭快速模式，将下载原图".to_string()
+            };
+            markdown::send(&bot, chat_id, Some(reply_to), reply).await?;
+        }
+        Command::Readme => {
+            let readme = config
+                .update_session(&state, chat_id, |user_state| {
+                    user_state.settings.readme = !user_state.settings.readme;
+                    user_state.settings.readme
+                })
+                .await;
+            let reply = if readme {
+                "✅压缩包中将附带 README.txt，记录收集时间、会话、图片数量、大小和版本"
+            } else {
+                "✅压缩包中将不再附带 README.txt"
+            };
+            markdown::send(&bot, chat_id, Some(reply_to), reply).await?;
+        }
+        Command::Folders => {
+            let folders = config
+                .update_session(&state, chat_id, |user_state| {
+                    user_state.settings.folders = !user_state.settings.folders;
+                    user_state.settings.folders
+                })
+                .await;
+            let reply = if folders {
+                "✅压缩包中的图片、音频、贴纸和视频消息将分别放在 images/、audio/、stickers/ 和 videos/ 文件夹中"
+            } else {
+                "✅压缩包中的文件将不再分文件夹"
+            };
+            markdown::send(&bot, chat_id, Some(reply_to), reply).await?;
+        }
+        Command::CaptionNames => {
+            let caption_names = config
+                .update_session(&state, chat_id, |user_state| {
+                    user_state.settings.caption_names = !user_state.settings.caption_names;
+                    user_state.settings.caption_names
+                })
+                .await;
+            let reply = if caption_names {
+                "✅有说明文字的图片将以说明文字命名，没有说明文字的仍使用序号"
+            } else {
+                "✅图片将按序号命名"
+            };
+            markdown::send(&bot, chat_id, Some(reply_to), reply).await?;
+        }
+        Command::Reproducible => {
+            let reproducible = config
+                .update_session(&state, chat_id, |user_state| {
+                    user_state.settings.reproducible = !user_state.settings.reproducible;
+                    user_state.settings.reproducible
+                })
+                .await;
+            let reply = if reproducible {
+                "✅已开启可复现打包，相同的图片会得到完全相同的压缩包"
+            } else {
+                "✅已关闭可复现打包，压缩包将包含版本和打包时间等信息"
+            };
+            markdown::send(&bot, chat_id, Some(reply_to), reply).await?;
+        }
+        Command::SelfTest => {
+            tokio::spawn(self_test(bot, chat_id, reply_to, config.temp_root.clone()));
+        }
+        Command::Avatar(arg) => match avatar::Target::resolve(&msg, &arg) {
+            Some(target) => {
+                tokio::spawn(send_avatars(
+                    bot, chat_id, reply_to, client, limiter, config, target,
+                ));
+            }
+            None => {
+                let reply = "回复某人的消息发送 /avatar 打包对方的头像，也可以使用 /avatar 用户id、/avatar 会话id 或 /avatar @公开群组或频道";
+                markdown::send(&bot, chat_id, Some(reply_to), reply).await?;
+            }
+        },
+        Command::CheckDownload => {
+            tokio::spawn(check_download_host(bot, chat_id, reply_to, client));
+        }
+        Command::Sessions => {
+            list_sessions(bot, chat_id, reply_to, state).await?;
+        }
+        Command::ClearSession(arg) => {
+            clear_session(bot, chat_id, reply_to, state, &config, &arg).await?;
+        }
+        Command::DiskUsage => {
+            let reply = describe_disk_usage(&config).await;
+            markdown::send(&bot, chat_id, Some(reply_to), reply).await?;
+        }
+        Command::Report => {
+            let period = config
+                .report_schedule
+                .as_ref()
+                .map(|schedule| schedule.period)
+                .unwrap_or_default();
+            let text = report::summary(period, &config.temp_root).await;
+            markdown::send(&bot, chat_id, Some(reply_to), text).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// 取消正在设置的文件名；没有时取消正在进行的收集，收集的消息在一段时间内可以恢复
+async fn cancel(
+    bot: Arc<Bot>,
+    chat_id: ChatId,
+    reply_to: MessageId,
+    state: AppState,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// 取消的是什么
+    enum Cancelled {
+        Nothing,
+        FileName,
+        /// 收集的消息数量和需要取消置顶的状态消息
+        Collection(usize, Option<MessageId>),
+    }
+
+    let cancelled_at = std::time::Instant::now();
+    let cancelled = state
+        .update(chat_id, |user_state| {
+            if let SessionMode::AwaitingFileName(_) = user_state.mode {
+                user_state.mode = SessionMode::Idle;
+                return Cancelled::FileName;
+            }
+            if !user_state.is_collecting() {
+                return Cancelled::Nothing;
+            }
+            let count = user_state.cancel_collecting(cancelled_at);
+            Cancelled::Collection(count, user_state.status_message.take())
+        })
+        .await
+        .unwrap_or(Cancelled::Nothing);
+    let (count, status) = match cancelled {
+        Cancelled::Nothing => {
+            markdown::send(&bot, chat_id, Some(reply_to), "🤔 没有需要取消的操作").await?;
+            return Ok(());
+        }
+        Cancelled::FileName => {
+            markdown::send(&bot, chat_id, Some(reply_to), "✅已取消设置文件名").await?;
+            return Ok(());
+        }
+        Cancelled::Collection(count, status) => (count, status),
+    };
+    log::info!(
+        "Chat {} cancelled a collection of {} messages",
+        chat_id,
+        count
+    );
+
+    // 超过恢复时间后丢弃，期间重新取消过的收集由之后的任务清理
+    let purge_state = Arc::clone(&state);
+    tokio::spawn(async move {
+        tokio::time::sleep(RESTORE_WINDOW).await;
+        purge_state
+            .update(chat_id, |user_state| {
+                if user_state
+                    .cancelled
+                    .as_ref()
+                    .is_some_and(|cancelled| cancelled.cancelled_at == cancelled_at)
+                {
+                    user_state.cancelled = None;
+                    log::debug!("Purged the cancelled collection of chat {}", chat_id);
+                }
+            })
+            .await;
+    });
+
+    unpin_status(&bot, chat_id, status).await;
+    markdown::send(
+        &bot,
+        chat_id,
+        Some(reply_to),
+        format!(
+            "✅已取消收集，{} 条消息会保留 {}，期间发送 /restore 可以恢复",
+            count,
+            format_duration(RESTORE_WINDOW)
+        ),
+    )
+    .await?;
+    Ok(())
+}
+
+/// 恢复通过 /cancel 取消的收集，已经开始新的收集时合并到前面
+async fn restore_collection(
+    bot: Arc<Bot>,
+    chat_id: ChatId,
+    reply_to: MessageId,
+    state: AppState,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let restored = state
+        .update(chat_id, |user_state| {
+            let cancelled = user_state
+                .cancelled
+                .take()
+                .filter(|cancelled| cancelled.cancelled_at.elapsed() < RESTORE_WINDOW)?;
+            let count = cancelled.messages.len();
+            let mut messages = cancelled.messages;
+            messages.append(&mut user_state.messages);
+            user_state.messages = messages;
+            if user_state.file_name.is_none() {
+                user_state.file_name = cancelled.file_name;
+            }
+            if !user_state.is_collecting() {
+                user_state.start_collecting();
+                user_state.started_at = Some(std::time::Instant::now());
+            }
+            Some(count)
+        })
+        .await
+        .flatten();
+
+    let reply = match restored {
+        Some(count) => format!(
+            "✅已恢复 {} 条消息，收集继续进行，完成后发送 /stopcollect",
+            count
+        ),
+        None => format!(
+            "🤔 没有可以恢复的收集，取消超过 {} 的收集已被丢弃",
+            format_duration(RESTORE_WINDOW)
+        ),
+    };
+    markdown::send(&bot, chat_id, Some(reply_to), reply).await?;
+    Ok(())
+}
+
+/// 切换到名为 `name` 的收集，没有参数时列出会话的所有收集
+async fn use_collection(
+    bot: Arc<Bot>,
+    chat_id: ChatId,
+    reply_to: MessageId,
+    state: AppState,
+    config: &Config,
+    name: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let name = name.split_whitespace().collect::<Vec<_>>().join(" ");
+    // 回复和切换后需要取消置顶的状态消息
+    let (reply, status) = config
+        .update_session(&state, chat_id, |user_state| {
+            if name.is_empty() {
+                let mut lines = vec![format!(
+                    "▶️ {}：{}",
+                    user_state.collection_name(),
+                    collection_summary(
+                        user_state.is_collecting(),
+                        user_state.messages.len(),
+                        user_state.file_name.as_deref()
+                    )
+                )];
+                let mut names = user_state.collections.keys().collect::<Vec<_>>();
+                names.sort();
+                for name in names {
+                    let collection = &user_state.collections[name];
+                    lines.push(format!(
+                        "▫️ {}：{}",
+                        name,
+                        collection_summary(
+                            collection.collecting,
+                            collection.messages.len(),
+                            collection.file_name.as_deref()
+                        )
+                    ));
+                }
+                let reply = format!(
+                    "当前的收集：\n{}\n\n发送 /use 名称 切换或新建收集",
+                    lines.join("\n")
+                );
+                return (reply, None);
+            }
+
+            if name == user_state.collection_name() {
+                return (format!("🤔 已经在使用收集 {}", name), None);
+            }
+            let is_new = name != DEFAULT_COLLECTION && !user_state.collections.contains_key(&name);
+            // 当前的收集切换出去后也会占用一个名额
+            if is_new && user_state.collections.len() + 1 >= MAX_COLLECTIONS {
+                let reply = format!(
+                    "❌ 最多同时保存 {} 个收集，请先完成或取消其中一个",
+                    MAX_COLLECTIONS
+                );
+                return (reply, None);
+            }
+
+            // 切换后状态消息显示的不再是同一个收集
+            let status = user_state.status_message.take();
+            user_state.switch_collection(&name);
+            log::info!("Chat {} switched to collection {:?}", chat_id, name);
+            let reply = match is_new {
+                true => format!("✅已新建并切换到收集 {}，发送 /startcollect 开始收集", name),
+                false => format!(
+                    "✅已切换到收集 {}：{}",
+                    name,
+                    collection_summary(
+                        user_state.is_collecting(),
+                        user_state.messages.len(),
+                        user_state.file_name.as_deref(),
+                    )
+                ),
+            };
+            (reply, status)
+        })
+        .await;
+    unpin_status(&bot, chat_id, status).await;
+    markdown::send(&bot, chat_id, Some(reply_to), reply).await?;
+    Ok(())
+}
+
+/// 一个收集的状态、消息数量和文件名
+fn collection_summary(collecting: bool, count: usize, file_name: Option<&str>) -> String {
+    let mut summary = match collecting {
+        true => format!("收集中，{} 条消息", count),
+        false if count > 0 => format!("已暂停，{} 条消息", count),
+        false => "未开始".to_string(),
+    };
+    if let Some(file_name) = file_name {
+        summary.push_str(&format!("，文件名 {}", file_name));
+    }
+    summary
+}
+
+/// 设置压缩包名称，没有参数时等待用户在下一条消息中发送
+async fn set_file_name(
+    bot: Arc<Bot>,
+    chat: ChatId,
+    reply_to: MessageId,
+    state: AppState,
+    config: &Config,
+    name: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if !name.trim().is_empty() {
+        let file_name = config
+            .update_session(&state, chat, |user_state| {
+                user_state.set_file_name(name).map(str::to_string)
+            })
+            .await;
+        match file_name {
+            Some(file_name) => send_file_name_set(&bot, chat, reply_to, config, &file_name).await?,
+            None => {
+                markdown::send(&bot, chat, Some(reply_to), "❌ 文件名不能为空").await?;
+            }
+        }
+        return Ok(());
+    }
+
+    let prompted = config
+        .update_session(&state, chat, |user_state| {
+            let prompted = user_state.prompt_file_name();
+            if prompted {
+                user_state
+                    .started_at
+                    .get_or_insert_with(std::time::Instant::now);
+            }
+            prompted
+        })
+        .await;
+    // 收集期间发送的消息都会被收集，无法再等待文件名
+    let reply = if prompted {
+        "请在5分钟内将文件名发送给我，我会将其设置为压缩包名，发送 /cancel 取消。也可以直接发送 /filename 文件名"
+    } else {
+        "收集期间发送的消息都会被收集，请直接发送 /filename 文件名"
+    };
+    markdown::send(&bot, chat, Some(reply_to), reply).await?;
+    Ok(())
+}
+
+async fn set_output_mode(
+    bot: Arc<Bot>,
+    chat_id: ChatId,
+    reply_to: MessageId,
+    state: AppState,
+    config: &Config,
+    arg: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    const USAGE: &str = "/output archive - 打包成压缩包\n/output album - 以相册形式重新发送\n/output album caption - 以相册形式发送并保留说明文字\n/output documents - 逐个发送原图文件\n/output gallery - 打包成附带网页相册（index.html）的压缩包";
+    const TELEGRAPH_USAGE: &str = "\n/output telegraph - 发布为 telegraph 网页";
+    const UNKNOWN: &str = "❌ 无法识别的输出方式，可选 archive、album、documents 或 gallery";
+    let reply = config
+        .update_session(&state, chat_id, |user_state| {
+            if arg.trim().is_empty() {
+                let telegraph = if cfg!(feature = "telegraph") {
+                    TELEGRAPH_USAGE
+                } else {
+                    ""
+                };
+                let current = user_state.settings.output_mode.describe();
+                format!("当前输出方式：{}\n\n{}{}", current, USAGE, telegraph)
+            } else if let Some(mode) = OutputMode::parse(arg) {
+                user_state.settings.output_mode = mode;
+                format!("✅已将输出方式设置为{}", mode.describe())
+            } else {
+                UNKNOWN.to_string()
+            }
+        })
+        .await;
+    markdown::send(&bot, chat_id, Some(reply_to), reply).await?;
+    Ok(())
+}
+
+async fn set_compression(
+    bot: Arc<Bot>,
+    chat_id: ChatId,
+    reply_to: MessageId,
+    state: AppState,
+    config: &Config,
+    arg: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    const USAGE: &str =
+        "/compression deflate - 压缩，压缩包更小\n/compression store - 仅存储，打包更快";
+    let reply = config
+        .update_session(&state, chat_id, |user_state| {
+            if arg.trim().is_empty() {
+                let current = user_state.settings.compression.describe();
+                format!("当前压缩方式：{}\n\n{}", current, USAGE)
+            } else if let Some(compression) = Compression::parse(arg) {
+                user_state.settings.compression = compression;
+                format!("✅已将压缩方式设置为{}", compression.describe())
+            } else {
+                "❌ 无法识别的压缩方式，可选 deflate 或 store".to_string()
+            }
+        })
+        .await;
+    markdown::send(&bot, chat_id, Some(reply_to), reply).await?;
+    Ok(())
+}
+
+async fn set_caption_files(
+    bot: Arc<Bot>,
+    chat_id: ChatId,
+    reply_to: MessageId,
+    state: AppState,
+    config: &Config,
+    arg: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    const USAGE: &str = "/captions off - 不保存\n/captions plain - 每张图片附带同名的 .txt 文件\n/captions markdown - 附带同名的 .md 文件，保留链接和粗体等格式\n\n只在输出方式为压缩包时生效";
+    let reply = config
+        .update_session(&state, chat_id, |user_state| {
+            if arg.trim().is_empty() {
+                let current = user_state.settings.caption_files.describe();
+                format!("当前说明文字：{}\n\n{}", current, USAGE)
+            } else if let Some(caption_files) = captions::CaptionFiles::parse(arg) {
+                user_state.settings.caption_files = caption_files;
+                format!("✅说明文字：{}", caption_files.describe())
+            } else {
+                "❌ 无法识别的设置，可选 off、plain 或 markdown".to_string()
+            }
+        })
+        .await;
+    markdown::send(&bot, chat_id, Some(reply_to), reply).await?;
+    Ok(())
+}
+
+async fn set_audio(
+    bot: Arc<Bot>,
+    chat_id: ChatId,
+    reply_to: MessageId,
+    state: AppState,
+    config: &Config,
+    arg: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let reply = config
+        .update_session(&state, chat_id, |user_state| match arg.trim() {
+            "" => {
+                if user_state.settings.audio {
+                    "当前会收集语音和音频，发送 /audio off 关闭"
+                } else {
+                    "当前不会收集语音和音频，发送 /audio on 开启"
+                }
+            }
+            "on" => {
+                user_state.settings.audio = true;
+                "✅语音和音频将和图片一起打包，README.txt 中会记录它们的时长和大小"
+            }
+            "off" => {
+                user_state.settings.audio = false;
+                "✅收集时将忽略语音和音频"
+            }
+            _ => "❌ 请使用 /audio on 或 /audio off",
+        })
+        .await;
+    markdown::send(&bot, chat_id, Some(reply_to), reply).await?;
+    Ok(())
+}
+
+async fn set_auto_rotate(
+    bot: Arc<Bot>,
+    chat_id: ChatId,
+    reply_to: MessageId,
+    state: AppState,
+    config: &Config,
+    arg: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if !cfg!(feature = "imaging") {
+        let reply = "❌ 编译时没有启用 imaging 功能，无法旋转图片";
+        markdown::send(&bot, chat_id, Some(reply_to), reply).await?;
+        return Ok(());
+    }
+    const ENABLED: &str =
+        "✅带有方向标记的 JPEG、PNG 和 WebP 图片将旋转到正确的方向并重新编码，同时去掉 EXIF 信息";
+    let reply = config
+        .update_session(&state, chat_id, |user_state| match arg.trim() {
+            "" => {
+                if user_state.settings.auto_rotate {
+                    "当前会按 EXIF 方向标记旋转图片，发送 /autorotate off 关闭"
+                } else {
+                    "当前不旋转图片，发送 /autorotate on 按 EXIF 方向标记旋转"
+                }
+            }
+            "on" => {
+                user_state.settings.auto_rotate = true;
+                ENABLED
+            }
+            "off" => {
+                user_state.settings.auto_rotate = false;
+                "✅不再旋转图片，图片保持原样"
+            }
+            _ => "❌ 请使用 /autorotate on 或 /autorotate off",
+        })
+        .await;
+    markdown::send(&bot, chat_id, Some(reply_to), reply).await?;
+    Ok(())
+}
+
+async fn set_min_image_dimension(
+    bot: Arc<Bot>,
+    chat_id: ChatId,
+    reply_to: MessageId,
+    state: AppState,
+    config: &Config,
+    arg: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let reply = config
+        .update_session(&state, chat_id, |user_state| match arg.trim() {
+            "" => match user_state.settings.min_image_dimension {
+                0 => "当前不按尺寸跳过图片，发送 /minsize 200 跳过最长边小于200像素的图片"
+                    .to_string(),
+                min => format!(
+                    "当前会跳过最长边小于 {} 像素的图片，发送 /minsize off 关闭",
+                    min
+                ),
+            },
+            "off" | "0" => {
+                user_state.settings.min_image_dimension = 0;
+                "✅不再按尺寸跳过图片".to_string()
+            }
+            arg => match arg.parse::<u32>() {
+                Ok(min) => {
+                    user_state.settings.min_image_dimension = min;
+                    format!(
+                        "✅最长边小于 {} 像素的图片将被跳过，只对以图片形式发送的消息有效",
+                        min
+                    )
+                }
+                Err(_) => {
+                    "❌ 请使用 /minsize 像素数，例如 /minsize 200，或 /minsize off".to_string()
+                }
+            },
+        })
+        .await;
+    markdown::send(&bot, chat_id, Some(reply_to), reply).await?;
+    Ok(())
+}
+
+async fn set_video_notes(
+    bot: Arc<Bot>,
+    chat_id: ChatId,
+    reply_to: MessageId,
+    state: AppState,
+    config: &Config,
+    arg: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    const ENABLED: &str =
+        "✅圆形的视频消息将保存为 videonote_1.mp4 等文件，README.txt 中会记录它们的时长和尺寸";
+    let reply = config
+        .update_session(&state, chat_id, |user_state| match arg.trim() {
+            "" => {
+                if user_state.settings.video_notes {
+                    "当前会收集视频消息，发送 /videonotes off 关闭"
+                } else {
+                    "当前不会收集视频消息，发送 /videonotes on 开启"
+                }
+            }
+            "on" => {
+                user_state.settings.video_notes = true;
+                ENABLED
+            }
+            "off" => {
+                user_state.settings.video_notes = false;
+                "✅收集时将忽略视频消息"
+            }
+            _ => "❌ 请使用 /videonotes on 或 /videonotes off",
+        })
+        .await;
+    markdown::send(&bot, chat_id, Some(reply_to), reply).await?;
+    Ok(())
+}
+
+async fn set_video_thumbnails(
+    bot: Arc<Bot>,
+    chat_id: ChatId,
+    reply_to: MessageId,
+    state: AppState,
+    config: &Config,
+    arg: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let reply = config
+        .update_session(&state, chat_id, |user_state| match arg.trim() {
+            "" => {
+                if user_state.settings.video_thumbnails {
+                    "当前会在视频消息旁保存缩略图，发送 /thumbnails off 关闭"
+                } else {
+                    "当前不保存视频消息的缩略图，发送 /thumbnails on 开启"
+                }
+            }
+            "on" if !user_state.settings.video_notes => {
+                user_state.settings.video_thumbnails = true;
+                "✅已开启视频缩略图，当前不收集视频消息，发送 /videonotes on 开启后生效"
+            }
+            "on" => {
+                user_state.settings.video_thumbnails = true;
+                "✅视频消息旁将保存缩略图，例如 videonote_1.thumb.jpg，没有缩略图的视频会跳过"
+            }
+            "off" => {
+                user_state.settings.video_thumbnails = false;
+                "✅不再保存视频消息的缩略图"
+            }
+            _ => "❌ 请使用 /thumbnails on 或 /thumbnails off",
+        })
+        .await;
+    markdown::send(&bot, chat_id, Some(reply_to), reply).await?;
+    Ok(())
+}
+
+async fn set_per_image(
+    bot: Arc<Bot>,
+    chat_id: ChatId,
+    reply_to: MessageId,
+    state: AppState,
+    config: &Config,
+    arg: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let limit = config.per_image_limit;
+    let reply = config
+        .update_session(&state, chat_id, |user_state| match arg.trim() {
+            "" => {
+                if user_state.settings.per_image {
+                    "当前每张图片单独打包成一个压缩包，发送 /perimage off 关闭".to_string()
+                } else {
+                    "当前所有图片打包在同一个压缩包中，发送 /perimage on 改为每张图片单独打包"
+                        .to_string()
+                }
+            }
+            "on" => {
+                user_state.settings.per_image = true;
+                format!(
+                    "✅每张图片将单独打包成一个与图片同名的压缩包，最多 {limit} 个，超过时仍按普通方式打包"
+                )
+            }
+            "off" => {
+                user_state.settings.per_image = false;
+                "✅所有图片将打包在同一个压缩包中".to_string()
+            }
+            _ => "❌ 请使用 /perimage on 或 /perimage off".to_string(),
+        })
+        .await;
+    markdown::send(&bot, chat_id, Some(reply_to), reply).await?;
+    Ok(())
+}
+
+async fn set_export_format(
+    bot: Arc<Bot>,
+    chat_id: ChatId,
+    reply_to: MessageId,
+    state: AppState,
+    config: &Config,
+    arg: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    const CURRENT_ON: &str =
+        "当前压缩包中会附带 Telegram Desktop 导出格式的 result.json，发送 /exportformat off 关闭";
+    const CURRENT_OFF: &str = "当前不附带导出文件，发送 /exportformat telegram 在压缩包中附带 Telegram Desktop 导出格式的 result.json";
+    const ENABLED: &str =
+        "✅压缩包中将附带 result.json，格式与 Telegram Desktop 导出的会话相同，可以导入其他工具";
+    let reply = config
+        .update_session(&state, chat_id, |user_state| match arg.trim() {
+            "" => {
+                if user_state.settings.telegram_export {
+                    CURRENT_ON
+                } else {
+                    CURRENT_OFF
+                }
+            }
+            "telegram" => {
+                user_state.settings.telegram_export = true;
+                ENABLED
+            }
+            "off" => {
+                user_state.settings.telegram_export = false;
+                "✅压缩包中不再附带 result.json"
+            }
+            _ => "❌ 请使用 /exportformat telegram 或 /exportformat off",
+        })
+        .await;
+    markdown::send(&bot, chat_id, Some(reply_to), reply).await?;
+    Ok(())
+}
+
+async fn set_link_previews(
+    bot: Arc<Bot>,
+    chat_id: ChatId,
+    reply_to: MessageId,
+    state: AppState,
+    config: &Config,
+    arg: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let reply = config
+        .update_session(&state, chat_id, |user_state| match arg.trim() {
+            "" => {
+                if user_state.settings.link_previews {
+                    "当前会下载网页链接的预览图，发送 /previews off 关闭"
+                } else {
+                    "当前不是图片的链接会按图片直链下载，发送 /previews on 改为下载网页的预览图"
+                }
+            }
+            "on" => {
+                user_state.settings.link_previews = true;
+                "✅网页链接将下载页面的预览图（og:image），以页面标题命名；没有预览图的页面会被跳过"
+            }
+            "off" => {
+                user_state.settings.link_previews = false;
+                "✅所有链接将按图片直链下载"
+            }
+            _ => "❌ 请使用 /previews on 或 /previews off",
+        })
+        .await;
+    markdown::send(&bot, chat_id, Some(reply_to), reply).await?;
+    Ok(())
+}
+
+async fn set_archive_comment(
+    bot: Arc<Bot>,
+    chat_id: ChatId,
+    reply_to: MessageId,
+    state: AppState,
+    config: &Config,
+    arg: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let reply = config
+        .update_session(&state, chat_id, |user_state| {
+            let current = &mut user_state.settings.archive_comment;
+
+            let arg = arg.trim();
+            match arg {
+                "" => match current {
+                    Some(comment) => {
+                        format!("当前的压缩包注释：{}\n发送 /comment off 清除", comment)
+                    }
+                    None => "当前没有设置压缩包注释，发送 /comment 文字 设置".to_string(),
+                },
+                "off" => {
+                    *current = None;
+                    "✅之后的压缩包不再写入注释".to_string()
+                }
+                _ if arg.chars().count() > archive::MAX_NOTE_CHARS => {
+                    format!("❌ 压缩包注释最多 {} 个字符", archive::MAX_NOTE_CHARS)
+                }
+                _ => {
+                    *current = Some(arg.to_string());
+                    "✅之后的压缩包将写入这段注释，可以用解压软件查看".to_string()
+                }
+            }
+        })
+        .await;
+    markdown::send(&bot, chat_id, Some(reply_to), reply).await?;
+    Ok(())
+}
+
+async fn set_watermark(
+    bot: Arc<Bot>,
+    chat_id: ChatId,
+    reply_to: MessageId,
+    state: AppState,
+    config: &Config,
+    arg: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if config.watermark_font.is_none() {
+        let reply = "❌ 没有配置水印使用的字体（WATERMARK_FONT），无法添加水印";
+        markdown::send(&bot, chat_id, Some(reply_to), reply).await?;
+        return Ok(());
+    }
+    let reply = config
+        .update_session(&state, chat_id, |user_state| {
+            let current = &mut user_state.settings.watermark;
+
+            let arg = arg.trim();
+            let (option, value) = arg.split_once(char::is_whitespace).unwrap_or((arg, ""));
+            match (option, current.as_mut()) {
+                ("", None) => {
+                    "当前不加水印，发送 /watermark 文字 为之后打包的图片加上水印".to_string()
+                }
+                ("", Some(watermark)) => format!(
+                    "当前水印：{}，发送 /watermark off 关闭",
+                    watermark.describe()
+                ),
+                ("off", _) => {
+                    *current = None;
+                    "✅之后打包的图片不再加水印".to_string()
+                }
+                ("corner" | "opacity", None) => "❌ 请先发送 /watermark 文字 设置水印".to_string(),
+                ("corner", Some(watermark)) => match watermark::Corner::parse(value) {
+                    Some(corner) => {
+                        watermark.corner = corner;
+                        format!("✅水印将放在图片的{}", corner.describe())
+                    }
+                    None => "❌ 请使用 /watermark corner 左上、右上、左下或右下".to_string(),
+                },
+                ("opacity", Some(watermark)) => match value.trim().parse::<u8>() {
+                    Ok(opacity @ 1..=100) => {
+                        watermark.opacity = opacity;
+                        format!("✅水印的不透明度为 {}%", opacity)
+                    }
+                    _ => "❌ 不透明度需要在 1 到 100 之间".to_string(),
+                },
+                _ if arg.chars().count() > watermark::MAX_TEXT_CHARS => {
+                    format!("❌ 水印最多 {} 个字符", watermark::MAX_TEXT_CHARS)
+                }
+                _ => {
+                    // 更换文字时保留位置和不透明度
+                    let watermark = match current.take() {
+                        Some(watermark) => watermark::Watermark {
+                            text: arg.to_string(),
+                            ..watermark
+                        },
+                        None => watermark::Watermark::new(arg.to_string()),
+                    };
+                    let reply = format!(
+                        "✅之后打包的图片将加上水印：{}\n无法解码的文件和 GIF 保持原样",
+                        watermark.describe()
+                    );
+                    *current = Some(watermark);
+                    reply
+                }
+            }
+        })
+        .await;
+    markdown::send(&bot, chat_id, Some(reply_to), reply).await?;
+    Ok(())
+}
+
+async fn set_photo_source(
+    bot: Arc<Bot>,
+    chat_id: ChatId,
+    reply_to: MessageId,
+    state: AppState,
+    config: &Config,
+    arg: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    const USAGE: &str = "以图片形式发送时telegram会压缩图片，以文件形式发送才能保留原图。同一组消息中同时有图片和图片文件时，视为同一批图片各发送了一次，只打包其中一种：\n/original document - 打包原图文件\n/original photo - 打包压缩的图片";
+    let reply = config
+        .update_session(&state, chat_id, |user_state| {
+            if arg.trim().is_empty() {
+                let current = user_state.settings.photo_source.describe();
+                format!("当前：{}\n\n{}", current, USAGE)
+            } else if let Some(photo_source) = PhotoSource::parse(arg) {
+                user_state.settings.photo_source = photo_source;
+                format!(
+                    "✅同一组中同时有图片和原图文件时：{}",
+                    photo_source.describe()
+                )
+            } else {
+                "❌ 无法识别的设置，可选 document 或 photo".to_string()
+            }
+        })
+        .await;
+    markdown::send(&bot, chat_id, Some(reply_to), reply).await?;
+    Ok(())
+}
+
+async fn set_delivery(
+    bot: Arc<Bot>,
+    chat_id: ChatId,
+    reply_to: MessageId,
+    state: AppState,
+    config: &Config,
+    arg: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    const USAGE: &str = "/delivery telegram - 发送到会话\n/delivery sftp - 上传到 SFTP 服务器\n/delivery both - 两者都要\n\n只在输出方式为压缩包时生效";
+    let reply = config
+        .update_session(&state, chat_id, |user_state| {
+            if arg.trim().is_empty() {
+                let current = user_state.settings.delivery.describe();
+                format!("当前送达方式：{}\n\n{}", current, USAGE)
+            } else if let Some(delivery) = Delivery::parse(arg) {
+                if delivery.uploads_to_sftp() && !config.sftp_enabled() {
+                    "❌ 没有配置 SFTP 服务器，请联系管理员".to_string()
+                } else {
+                    user_state.settings.delivery = delivery;
+                    format!("✅已将送达方式设置为{}", delivery.describe())
+                }
+            } else {
+                "❌ 无法识别的送达方式，可选 telegram、sftp 或 both".to_string()
+            }
+        })
+        .await;
+    markdown::send(&bot, chat_id, Some(reply_to), reply).await?;
+    Ok(())
+}
+
+async fn set_chunk_size(
+    bot: Arc<Bot>,
+    chat_id: ChatId,
+    reply_to: MessageId,
+    state: AppState,
+    config: &Config,
+    arg: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let reply = config
+        .update_session(&state, chat_id, |user_state| match arg.trim() {
+            "" => match user_state.settings.chunk_size {
+                Some(size) => format!("当前每个压缩包最多 {} 张图片，发送 /chunk off 关闭", size),
+                None => "当前未按数量分卷，发送 /chunk 50 让每个压缩包最多包含50张图片".to_string(),
+            },
+            "off" => {
+                user_state.settings.chunk_size = None;
+                "✅已关闭按数量分卷".to_string()
+            }
+            arg => match arg.parse::<usize>() {
+                Ok(size) if size > 0 => {
+                    user_state.settings.chunk_size = Some(size);
+                    format!("✅每个压缩包最多包含 {} 张图片", size)
+                }
+                _ => "❌ 请输入大于0的数字，或使用 /chunk off 关闭".to_string(),
+            },
+        })
+        .await;
+    markdown::send(&bot, chat_id, Some(reply_to), reply).await?;
+    Ok(())
+}
+
+async fn set_order(
+    bot: Arc<Bot>,
+    chat_id: ChatId,
+    reply_to: MessageId,
+    state: AppState,
+    config: &Config,
+    arg: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    const USAGE: &str = "/order received - 按收到的顺序\n/order date-asc - 按时间从旧到新\n/order date-desc - 按时间从新到旧\n/order shuffle - 随机顺序";
+    let reply = config
+        .update_session(&state, chat_id, |user_state| {
+            if arg.trim().is_empty() {
+                let current = user_state.settings.order.describe();
+                format!("当前图片顺序：{}\n\n{}", current, USAGE)
+            } else if let Some(order) = Order::parse(arg) {
+                user_state.settings.order = order;
+                format!("✅已将图片顺序设置为{}", order.describe())
+            } else {
+                "❌ 无法识别的顺序，可选 received、date-asc、date-desc 或 shuffle".to_string()
+            }
+        })
+        .await;
+    markdown::send(&bot, chat_id, Some(reply_to), reply).await?;
+    Ok(())
+}
+
+async fn set_pin_status(
+    bot: Arc<Bot>,
+    chat_id: ChatId,
+    reply_to: MessageId,
+    state: AppState,
+    config: &Config,
+    arg: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let reply = config
+        .update_session(&state, chat_id, |user_state| match arg.trim() {
+            "" => {
+                if user_state.settings.pin_status {
+                    "当前收集期间会置顶状态消息，发送 /pinstatus off 关闭"
+                } else {
+                    "当前不会置顶状态消息，发送 /pinstatus on 开启，需要机器人有置顶消息的权限"
+                }
+            }
+            "on" => {
+                user_state.settings.pin_status = true;
+                "✅下次开始收集时会置顶状态消息，没有置顶权限时只更新消息"
+            }
+            "off" => {
+                user_state.settings.pin_status = false;
+                "✅已关闭置顶状态消息"
+            }
+            _ => "❌ 请使用 /pinstatus on 或 /pinstatus off",
+        })
+        .await;
+    markdown::send(&bot, chat_id, Some(reply_to), reply).await?;
+    Ok(())
+}
+
+async fn set_clean_chat(
+    bot: Arc<Bot>,
+    chat_id: ChatId,
+    reply_to: MessageId,
+    state: AppState,
+    config: &Config,
+    arg: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let reply = config
+        .update_session(&state, chat_id, |user_state| match arg.trim() {
+            "" => {
+                if user_state.settings.clean_chat {
+                    "当前交付结果后会删除中间消息，发送 /cleanchat off 关闭"
+                } else {
+                    "当前不会删除中间消息，发送 /cleanchat on 开启"
+                }
+            }
+            "on" => {
+                user_state.settings.clean_chat = true;
+                "✅交付结果后会删除“收集已开始”和处理进度等中间消息，只保留压缩包和结果"
+            }
+            "off" => {
+                user_state.settings.clean_chat = false;
+                user_state.interim_messages.clear();
+                "✅已关闭删除中间消息"
+            }
+            _ => "❌ 请使用 /cleanchat on 或 /cleanchat off",
+        })
+        .await;
+    markdown::send(&bot, chat_id, Some(reply_to), reply).await?;
+    Ok(())
+}
+
+async fn set_stickers(
+    bot: Arc<Bot>,
+    chat_id: ChatId,
+    reply_to: MessageId,
+    state: AppState,
+    config: &Config,
+    arg: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let reply = config
+        .update_session(&state, chat_id, |user_state| match arg.trim() {
+            "" => {
+                if user_state.settings.stickers {
+                    "当前会收集贴纸，发送 /stickers off 关闭"
+                } else {
+                    "当前不会收集贴纸，发送 /stickers on 开启"
+                }
+            }
+            "on" => {
+                user_state.settings.stickers = true;
+                "✅贴纸将按原文件打包：静态贴纸为 .webp，动态贴纸为 .tgs，视频贴纸为 .webm"
+            }
+            "off" => {
+                user_state.settings.stickers = false;
+                "✅收集时将忽略贴纸"
+            }
+            _ => "❌ 请使用 /stickers on 或 /stickers off",
+        })
+        .await;
+    markdown::send(&bot, chat_id, Some(reply_to), reply).await?;
+    Ok(())
+}
+
+/// 删除机器人的中间消息，消息太旧或没有权限时忽略
+async fn delete_interim_messages(bot: &Bot, chat_id: ChatId, messages: &[MessageId]) {
+    for &message_id in messages {
+        if let Err(why) = bot.delete_message(chat_id, message_id).await {
+            log::debug!("无法删除会话 {} 的消息 {}: {}", chat_id, message_id, why);
+        }
+    }
+}
+
+/// 设置文件名后的回复
+fn file_name_set(file_name: &str) -> FormattedText {
+    let file_name = format!("{}.zip", file_name);
+    match messages::custom("file_name_set", &[("filename", &file_name)]) {
+        Some(text) => text.into(),
+        None => FormattedText::new()
+            .text("✅已设置文件名为 ")
+            .code(file_name),
+    }
+}
+
+/// 文件名与会话用过的名称相同时，选择覆盖的按钮
+const FILE_NAME_OVERWRITE: &str = "filename:overwrite";
+/// 文件名与会话用过的名称相同时，选择自动加上序号的按钮
+const FILE_NAME_SUFFIX: &str = "filename:suffix";
+
+/// 回复设置好的文件名，会话用过这个名称时提醒，并提供覆盖和自动编号的按钮
+async fn send_file_name_set(
+    bot: &Bot,
+    chat_id: ChatId,
+    reply_to: MessageId,
+    config: &Config,
+    file_name: &str,
+) -> Result<(), teloxide::RequestError> {
+    let Some(suggested) = config.archive_names.suggest(chat_id, file_name).await else {
+        markdown::send(bot, chat_id, Some(reply_to), file_name_set(file_name)).await?;
+        return Ok(());
+    };
+    let text = file_name_set(file_name)
+        .text("\n\n⚠️ 这个会话已经用过这个名称，打包时会自动命名为 ")
+        .code(format!("{}.zip", suggested))
+        .text("，也可以选择沿用原来的名称");
+    let keyboard = InlineKeyboardMarkup::new([[
+        InlineKeyboardButton::callback("覆盖", FILE_NAME_OVERWRITE),
+        InlineKeyboardButton::callback("自动编号", FILE_NAME_SUFFIX),
+    ]]);
+    markdown::send_with_keyboard(bot, chat_id, Some(reply_to), text, Some(keyboard)).await?;
+    Ok(())
+}
+
+/// 处理文件名重复时的按钮
+async fn handle_callback(
+    bot: Bot,
+    query: CallbackQuery,
+    state: AppState,
+    config: Arc<Config>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let overwrite = match query.data.as_deref() {
+        Some(FILE_NAME_OVERWRITE) => true,
+        Some(FILE_NAME_SUFFIX) => false,
+        _ => {
+            bot.answer_callback_query(query.id.clone()).await?;
+            return Ok(());
+        }
+    };
+    let Some(message) = &query.message else {
+        bot.answer_callback_query(query.id.clone()).await?;
+        return Ok(());
+    };
+    let chat_id = message.chat().id;
+    let answer = config
+        .update_session(&state, chat_id, |user_state| match &user_state.file_name {
+            Some(file_name) => {
+                user_state.overwrite_file_name = overwrite;
+                match overwrite {
+                    true => format!("将沿用 {}.zip", file_name),
+                    false => "将自动加上序号".to_string(),
+                }
+            }
+            None => "文件名已经用过或被取消".to_string(),
+        })
+        .await;
+    bot.answer_callback_query(query.id.clone())
+        .text(answer)
+        .await?;
+    // 去掉按钮，结果已经在提示中说明
+    if let Err(why) = bot.edit_message_reply_markup(chat_id, message.id()).await {
+        log::debug!("无法移除会话 {} 的按钮: {}", chat_id, why);
+    }
+    Ok(())
+}
+
+/// 状态消息的内容，`count` 为已经收集的消息数量
+fn collecting_status(count: usize) -> String {
+    format!(
+        "{}\n\n📥 已收集 {} 条消息",
+        messages::text("collect_started", &[]),
+        count
+    )
+}
+
+/// 取消置顶收集的状态消息，失败时只记录日志
+async fn unpin_status(bot: &Bot, chat_id: ChatId, status: Option<MessageId>) {
+    let Some(status) = status else {
+        return;
+    };
+    if let Err(why) = bot.unpin_chat_message(chat_id).message_id(status).await {
+        log::debug!("无法取消置顶会话 {} 的状态消息: {}", chat_id, why);
+    }
+}
+
+/// `chat_id` 能否开始收集：正在收集的会话不超过 `max` 个，`max` 为0时不限制
+///
+/// 会话自己正在进行的收集重新开始时不占用新的名额。
+fn has_session_slot<'a>(
+    sessions: impl IntoIterator<Item = (&'a ChatId, &'a UserState)>,
+    chat_id: ChatId,
+    max: usize,
+) -> bool {
+    let active_sessions = sessions
+        .into_iter()
+        .filter(|(id, user_state)| **id != chat_id && user_state.is_collecting())
+        .count();
+    max == 0 || active_sessions < max
+}
+
+/// 检查闲置收集的间隔
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// 定期结束超过 `timeout` 没有收到新消息的收集，并通知所在的会话
+async fn expire_idle_sessions(bot: Bot, state: AppState, timeout: Duration) {
+    let mut interval = tokio::time::interval(IDLE_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        // 在存储的操作中只修改状态，操作结束后再发送消息
+        let expired = state
+            .filter_map(|&chat_id, user_state| {
+                let count = user_state.expire_idle(timeout)?;
+                Some((chat_id, count, user_state.status_message.take()))
+            })
+            .await;
+        for (chat_id, count, status) in expired {
+            log::info!("Chat {} collection expired after being idle", chat_id);
+            unpin_status(&bot, chat_id, status).await;
+            let reply = format!(
+                "⌛ 收集已经 {} 没有新消息，已自动结束。{} 条消息会保留 {}，期间发送 /restore 可以恢复",
+                format_duration(timeout),
+                count,
+                format_duration(RESTORE_WINDOW)
+            );
+            if let Err(why) = markdown::send(&bot, chat_id, None, reply).await {
+                log::error!("无法通知会话 {} 收集已自动结束: {}", chat_id, why);
+            }
+        }
+    }
+}
+
+async fn start_collecting(
+    bot: Arc<Bot>,
+    chat_id: ChatId,
+    reply_to: MessageId,
+    state: AppState,
+    config: &Config,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    // 会话不存在时先创建，检查名额和开始收集在同一次操作中完成
+    config.update_session(&state, chat_id, |_| ()).await;
+    let max = config.max_active_sessions;
+    let mut started = None;
+    state
+        .modify_all(Box::new(|sessions| {
+            let sessions = sessions.collect::<Vec<_>>();
+            let users = sessions.iter().map(|(id, user_state)| (*id, &**user_state));
+            if !has_session_slot(users, chat_id, max) {
+                return;
+            }
+            let Some((_, user_state)) = sessions.into_iter().find(|(id, _)| **id == chat_id) else {
+                return;
+            };
+            // 重新开始收集时，上一次的状态消息不再更新
+            let previous_status = user_state.status_message.take();
+            user_state.interim_messages.clear();
+            user_state.start_collecting();
+            user_state.messages.clear();
+            user_state.pack_count = 0;
+            let started_at = std::time::Instant::now();
+            user_state.started_at = Some(started_at);
+            user_state.last_activity = None;
+            started = Some((previous_status, started_at, user_state.settings.clone()));
+        }))
+        .await;
+    let Some((previous_status, started_at, settings)) = started else {
+        log::warn!(
+            "会话 {} 无法开始收集，已有 {} 个收集会话",
+            chat_id,
+            config.max_active_sessions
+        );
+        markdown::send(&bot, chat_id, Some(reply_to), "⏳ 服务繁忙，请稍后再试").await?;
+        return Ok(());
+    };
+    unpin_status(&bot, chat_id, previous_status).await;
+
+    log::info!("会话 {} 开启了一个收集任务", chat_id);
+    let text = match settings.pin_status {
+        true => collecting_status(0),
+        false => messages::text("collect_started", &[]),
+    };
+    let sent = markdown::send(&bot, chat_id, Some(reply_to), text).await?;
+    // 发送期间收集可能已经结束或重新开始，只记录到同一次收集中
+    let still_collecting = config
+        .update_session(&state, chat_id, |user_state| {
+            let same_session =
+                user_state.is_collecting() && user_state.started_at == Some(started_at);
+            if same_session {
+                if settings.clean_chat {
+                    user_state.interim_messages.push(sent.id);
+                }
+                if settings.pin_status {
+                    user_state.status_message = Some(sent.id);
+                }
+            }
+            same_session
+        })
+        .await;
+    if !settings.pin_status || !still_collecting {
+        return Ok(());
+    }
+
+    // 没有置顶权限时仍然更新状态消息
+    if let Err(why) = bot
+        .pin_chat_message(chat_id, sent.id)
+        .disable_notification(true)
+        .await
+    {
+        log::info!("无法置顶会话 {} 的状态消息: {}", chat_id, why);
+    }
+    Ok(())
+}
+
+/// 排队更新等待时间的间隔
+const QUEUE_UPDATE_INTERVAL: Duration = Duration::from_secs(10);
+
+#[allow(clippy::too_many_arguments)]
+async fn stop_collecting_and_process(
+    bot: Arc<Bot>,
+    chat_id: ChatId,
+    reply_to: MessageId,
+    state: AppState,
+    client: Client,
+    config: Arc<Config>,
+    limiter: Arc<RateLimiter>,
+    queue: Arc<JobQueue>,
+    source: BatchSource,
+) {
+    let cancel = CancellationToken::new();
+    let (job_id, batch, status, interim) = config
+        .update_session(&state, chat_id, |user_state| {
+            let batch = user_state.take(source);
+            // 重试时沿用失败任务的id，临时目录和文件的顺序都与上次相同
+            let job_id = batch
+                .as_ref()
+                .ok()
+                .and_then(|batch| batch.resume)
+                .unwrap_or_else(Uuid::new_v4);
+            if batch.is_ok() {
+                user_state.jobs.insert(
+                    job_id,
+                    RunningJob {
+                        cancel: cancel.clone(),
+                        started_at: std::time::Instant::now(),
+                    },
+                );
+            }
+            // 收集结束后，本次收集的中间消息在交付结果后删除
+            let interim = if user_state.is_collecting() {
+                Vec::new()
+            } else {
+                std::mem::take(&mut user_state.interim_messages)
+            };
+            (job_id, batch, user_state.take_finished_status(), interim)
+        })
+        .await;
+    // 收集结束后立即取消置顶，之后的处理是否成功都不影响
+    unpin_status(&bot, chat_id, status).await;
+    let batch = match batch {
+        Ok(batch) => batch,
+        Err(rejection) => {
+            log::info!("Chat {} cannot stop collecting: {:?}", chat_id, rejection);
+            let _ = markdown::send(&bot, chat_id, Some(reply_to), rejection.message()).await;
+            return;
+        }
+    };
+
+    config
+        .job_journal
+        .started(job_id, chat_id, batch.messages.len());
+    let created = std::time::Instant::now();
+    let items = batch.messages.len();
+    // 每个任务一条链路，收集时长从第一条消息算起
+    let collection_secs = batch
+        .messages
+        .iter()
+        .map(|msg| msg.date)
+        .min()
+        .map_or(0, |first| (chrono::Utc::now() - first).num_seconds());
+    let span = tracing::info_span!(
+        "job",
+        job_id = %job_id,
+        chat_id = chat_id.0,
+        items = batch.messages.len(),
+        collection_secs,
+        source = ?source,
+    );
+    // 失败时保留下来用于 /retry
+    let retry_batch = Batch {
+        resume: None,
+        ..batch.clone()
+    };
+    let result = tokio::select! {
+        permit = wait_in_queue(&bot, chat_id, reply_to, &queue) => {
+            let started = std::time::Instant::now();
+            let chat = TelegramChat {
+                bot: Arc::clone(&bot),
+                chat_id,
+                reply_to,
+            };
+            let result = run_job(
+                &chat,
+                chat_id,
+                job_id,
+                batch,
+                client,
+                Arc::clone(&config),
+                limiter,
+                &cancel,
+                source,
+            )
+            .instrument(span)
+            .await;
+            queue.record(started.elapsed());
+            drop(permit);
+            result
+        }
+        // 排队时被取消不需要清理任何文件
+        _ = cancel.cancelled() => {
+            log::info!("Job {} for chat {} was cancelled while queued", job_id, chat_id);
+            let report = job_report::ProcessingReport {
+                items,
+                ..Default::default()
+            };
+            let text = messages::text("aborted_queued", &[]);
+            match markdown::send(&bot, chat_id, Some(reply_to), text).await {
+                Ok(_) => Ok(report),
+                Err(why) => Err(JobError {
+                    error: why.into(),
+                    report,
+                }),
+            }
+        }
+    };
+    let (report, result) = match result {
+        Ok(report) => (report, Ok(())),
+        Err(JobError { error, report }) => (report, Err(error)),
+    };
+
+    state
+        .update(chat_id, |user_state| user_state.jobs.remove(&job_id))
+        .await;
+    config.job_journal.finished(job_id);
+    if result.is_ok() && !cancel.is_cancelled() {
+        delete_interim_messages(&bot, chat_id, &interim).await;
+    }
+
+    let status = match &result {
+        _ if cancel.is_cancelled() => stats::JobStatus::Cancelled,
+        Ok(_) if report.unfinished => stats::JobStatus::Failed,
+        Ok(_) => stats::JobStatus::Succeeded,
+        Err(_) => stats::JobStatus::Failed,
+    };
+    stats::record_job(job_id, status, report.downloaded, report.archive_size());
+    if let Some(webhook) = &config.notify_webhook {
+        let error = result
+            .as_ref()
+            .err()
+            .map(|why| download::redact_token(&why.to_string()));
+        webhook.send(notify::payload(
+            &report,
+            job_id,
+            chat_id,
+            status.as_str(),
+            created.elapsed(),
+            error.as_deref(),
+        ));
+    }
+
+    if let Err(e) = &result
+        && workspace::is_out_of_space(&**e)
+    {
+        log::error!(
+            "Job {} for chat {} ran out of disk space: {}",
+            job_id,
+            chat_id,
+            e
+        );
+        // 下载了一半的文件和没有写完的压缩包都没有用了
+        let temp_dir = workspace::job_dir(&config.temp_root, chat_id, job_id);
+        if let Err(why) = tokio::fs::remove_dir_all(&temp_dir).await
+            && why.kind() != std::io::ErrorKind::NotFound
+        {
+            log::error!("无法删除 {}: {}", temp_dir.display(), why);
+        }
+        let _ = markdown::send(
+            &bot,
+            chat_id,
+            Some(reply_to),
+            "💾 服务器空间不足，请稍后重试",
+        )
+        .await;
+        return;
+    }
+    if let Err(e) = result {
+        // 错误信息可能来自包含下载地址的请求，显示之前去掉其中的 bot token
+        let e = download::redact_token(&e.to_string());
+        log::error!("Error processing for chat {}: {}", chat_id, e);
+        let mut reply = FormattedText::new()
+            .text(messages::text("failed", &[("error_id", &job_id)]))
+            .text("\n")
+            .code_block(e);
+        if !cancel.is_cancelled() {
+            keep_for_retry(&state, &config, chat_id, job_id, retry_batch).await;
+            reply = reply.append(retry_hint(&config));
+        }
+        let _ = markdown::send(&bot, chat_id, Some(reply_to), reply).await;
+    } else if report.unfinished && !cancel.is_cancelled() {
+        // 已经在结果中提示了 /retry
+        keep_for_retry(&state, &config, chat_id, job_id, retry_batch).await;
+    }
+}
+
+/// 任务失败后提示可以重试
+fn retry_hint(config: &Config) -> FormattedText {
+    FormattedText::from(format!(
+        "\n发送 /retry 重试，已经下载的文件不需要重新下载，{} 内有效",
+        format_duration(config.retry_window)
+    ))
+}
+
+/// 保留失败任务的临时目录和内容，在 `RETRY_WINDOW` 内可以用 /retry 重试
+///
+/// 会话之前失败的任务不能再重试，删除它的临时目录。过期后由 [`expire_failed_jobs`] 删除。
+async fn keep_for_retry(
+    state: &AppState,
+    config: &Config,
+    chat_id: ChatId,
+    job_id: Uuid,
+    batch: Batch,
+) {
+    let expires_at = std::time::Instant::now() + config.retry_window;
+    let replaced = config
+        .update_session(state, chat_id, |user_state| {
+            user_state.failed_job.replace(FailedJob {
+                job_id,
+                batch,
+                expires_at,
+            })
+        })
+        .await;
+    if let Some(replaced) = replaced.filter(|replaced| replaced.job_id != job_id) {
+        remove_retry_dir(config, chat_id, replaced.job_id).await;
+    }
+}
+
+/// 检查过期的失败任务的间隔
+const RETRY_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// 定期删除超过 `RETRY_WINDOW` 没有重试的失败任务和它们的临时目录
+async fn expire_failed_jobs(state: AppState, config: Arc<Config>) {
+    let mut interval = tokio::time::interval(RETRY_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        // 在存储的操作中只修改状态，操作结束后再删除目录
+        let now = std::time::Instant::now();
+        let expired = state
+            .filter_map(|&chat_id, user_state| Some((chat_id, user_state.expire_failed_job(now)?)))
+            .await;
+        for (chat_id, job_id) in expired {
+            log::debug!("Failed job {} of chat {} expired", job_id, chat_id);
+            remove_retry_dir(&config, chat_id, job_id).await;
+        }
+    }
+}
+
+async fn remove_retry_dir(config: &Config, chat_id: ChatId, job_id: Uuid) {
+    let temp_dir = workspace::job_dir(&config.temp_root, chat_id, job_id);
+    if let Err(why) = tokio::fs::remove_dir_all(&temp_dir).await
+        && why.kind() != std::io::ErrorKind::NotFound
+    {
+        log::error!("无法删除 {}: {}", temp_dir.display(), why);
+    }
+}
+
+/// 排队等待处理，需要等待时告诉用户前面的任务数量和预计等待时间，并随队列前进更新
+async fn wait_in_queue(
+    bot: &Bot,
+    chat_id: ChatId,
+    reply_to: MessageId,
+    queue: &JobQueue,
+) -> OwnedSemaphorePermit {
+    let ticket = queue.join();
+    if let Some(permit) = queue.try_acquire(ticket) {
+        return permit;
+    }
+
+    let queue_status = || {
+        let ahead = queue.ahead(ticket);
+        let position = if ahead == 0 {
+            "🕒 正在排队，等待当前的任务完成".to_string()
+        } else {
+            format!("🕒 正在排队，前面还有 {} 个任务", ahead)
+        };
+        match queue.estimate(ahead) {
+            Some(wait) => format!(
+                "{}\n预计等待约 {}（根据最近的任务估算）",
+                position,
+                format_duration(wait)
+            ),
+            None => format!("{}\n暂无足够数据估算等待时间", position),
+        }
+    };
+    let mut text = queue_status();
+    log::info!("Chat {} is waiting in queue: {}", chat_id, text);
+    let status = markdown::send(bot, chat_id, Some(reply_to), &text)
+        .await
+        .ok();
+
+    let acquire = queue.acquire(ticket);
+    tokio::pin!(acquire);
+    let permit = loop {
+        tokio::select! {
+            permit = &mut acquire => break permit,
+            _ = tokio::time::sleep(QUEUE_UPDATE_INTERVAL) => {
+                let updated = queue_status();
+                // 内容没有变化时telegram会拒绝编辑
+                if let Some(status) = status.as_ref().filter(|_| updated != text) {
+                    let _ = bot.edit_message_text(chat_id, status.id, &updated).await;
+                }
+                text = updated;
+            }
+        }
+    };
+
+    if let Some(status) = status {
+        let _ = bot.delete_message(chat_id, status.id).await;
+    }
+    permit
+}
+
+/// 取消会话中所有正在排队或处理的任务，任务会自己清理并回复结果
+async fn abort_jobs(
+    bot: Arc<Bot>,
+    chat_id: ChatId,
+    reply_to: MessageId,
+    state: AppState,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let cancelled = state
+        .update(chat_id, |user_state| {
+            // 中止后不再清理本次收集的中间消息
+            user_state.interim_messages.clear();
+            user_state
+                .jobs
+                .values()
+                .filter(|job| !job.cancel.is_cancelled())
+                .inspect(|job| job.cancel.cancel())
+                .count()
+        })
+        .await
+        .unwrap_or_default();
+    log::info!("Chat {} aborted {} jobs", chat_id, cancelled);
+    if cancelled == 0 {
+        markdown::send(&bot, chat_id, Some(reply_to), "🤔 当前没有正在进行的任务").await?;
+    }
+    Ok(())
+}
+
+/// 会话的可读名称，例如 `@username (123456)`
+fn describe_chat(chat: &teloxide::types::Chat) -> String {
+    let name = chat
+        .username()
+        .map(|username| format!("@{}", username))
+        .or_else(|| chat.title().map(str::to_string))
+        .or_else(|| chat.first_name().map(str::to_string))
+        .unwrap_or_default();
+    format!("{} ({})", name, chat.id).trim_start().to_string()
+}
+
+/// /whoami 的回复，匿名管理员和以频道身份发送时显示发送者所代表的会话
+fn describe_sender(msg: &Message) -> FormattedText {
+    if let Some(sender_chat) = &msg.sender_chat {
+        return FormattedText::new()
+            .text("你正在以会话身份发送消息\n会话id：")
+            .code(sender_chat.id)
+            .text(format!("\n名称：{}", describe_chat(sender_chat)));
+    }
+    let Some(user) = &msg.from else {
+        return "🤔 无法识别消息的发送者".into();
+    };
+    FormattedText::new()
+        .text("用户id：")
+        .code(user.id)
+        .text(format!(
+            "\n用户名：{}\n名字：{}\n语言：{}",
+            user.username
+                .as_deref()
+                .map_or("未设置".to_string(), |username| format!("@{}", username)),
+            user.full_name(),
+            user.language_code.as_deref().unwrap_or("未知")
+        ))
+}
+
+/// 将 /feedback 的内容连同发送者的信息转发给所有管理员
+async fn send_feedback(
+    bot: Arc<Bot>,
+    msg: &Message,
+    config: &Config,
+    text: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let chat_id = msg.chat.id;
+    let text = text.trim();
+    if text.is_empty() {
+        markdown::send(
+            &bot,
+            chat_id,
+            Some(msg.id),
+            "请在命令后写上反馈的内容，例如 /feedback 打包后缺少图片",
+        )
+        .await?;
+        return Ok(());
+    }
+    let recipients = config.admin_recipients();
+    if recipients.is_empty() {
+        markdown::send(
+            &bot,
+            chat_id,
+            Some(msg.id),
+            "😢 机器人没有配置管理员，无法发送反馈",
+        )
+        .await?;
+        return Ok(());
+    }
+    let sender = match (&msg.sender_chat, &msg.from) {
+        (Some(sender_chat), _) => sender_chat.id,
+        (None, Some(user)) => ChatId::from(user.id),
+        (None, None) => chat_id,
+    };
+    if let Err(wait) = feedback::try_acquire(sender) {
+        markdown::send(
+            &bot,
+            chat_id,
+            Some(msg.id),
+            format!("⏳ 反馈发送得太频繁了，请 {} 后再试", format_duration(wait)),
+        )
+        .await?;
+        return Ok(());
+    }
+
+    let content: String = text.chars().take(feedback::MAX_LENGTH).collect();
+    let report = FormattedText::new()
+        .bold("📮 收到反馈")
+        .text("\n")
+        .append(describe_sender(msg))
+        .text("\n会话id：")
+        .code(chat_id)
+        .text("\n\n")
+        .text(content);
+    let mut delivered = 0;
+    for admin in recipients {
+        match markdown::send(&bot, admin, None, report.clone()).await {
+            Ok(_) => delivered += 1,
+            Err(why) => log::warn!("无法将 {} 的反馈发送给 {}: {}", sender, admin, why),
+        }
+    }
+    log::info!("Feedback from {} delivered to {} admins", sender, delivered);
+    let reply = if delivered > 0 {
+        "✅ 已收到你的反馈，谢谢！"
+    } else {
+        "😢 反馈发送失败，请稍后再试"
+    };
+    markdown::send(&bot, chat_id, Some(msg.id), reply).await?;
+    Ok(())
+}
+
+/// /chatinfo 的回复
+fn describe_chat_info(chat: &teloxide::types::Chat) -> FormattedText {
+    use teloxide::types::{ChatKind, ChatPublic, PublicChatKind, PublicChatSupergroup};
+
+    let kind = if chat.is_private() {
+        "私聊"
+    } else if chat.is_group() {
+        "群组"
+    } else if chat.is_supergroup() {
+        "超级群组"
+    } else {
+        "频道"
+    };
+    let is_forum = matches!(
+        &chat.kind,
+        ChatKind::Public(ChatPublic {
+            kind: PublicChatKind::Supergroup(PublicChatSupergroup { is_forum: true, .. }),
+            ..
+        })
+    );
+    FormattedText::new()
+        .text("会话id：")
+        .code(chat.id)
+        .text(format!(
+            "\n类型：{}\n名称：{}\n话题模式：{}",
+            kind,
+            describe_chat(chat),
+            if is_forum { "是" } else { "否" }
+        ))
+}
+
+/// 音频文件的扩展名，优先按 mime 类型，其次取自原文件名，都没有时使用 `mp3`
+fn audio_extension(audio: &teloxide::types::Audio) -> String {
+    let from_mime = audio
+        .mime_type
+        .as_ref()
+        .and_then(|mime| match mime.essence_str() {
+            "audio/mpeg" | "audio/mp3" => Some("mp3"),
+            "audio/mp4" | "audio/x-m4a" | "audio/m4a" => Some("m4a"),
+            "audio/ogg" => Some("ogg"),
+            "audio/flac" | "audio/x-flac" => Some("flac"),
+            "audio/wav" | "audio/x-wav" => Some("wav"),
+            _ => None,
+        });
+    if let Some(extension) = from_mime {
+        return extension.to_string();
+    }
+    audio
+        .file_name
+        .as_deref()
+        .and_then(|name| Path::new(name).extension())
+        .and_then(|extension| extension.to_str())
+        .filter(|extension| extension.chars().all(|c| c.is_ascii_alphanumeric()))
+        .map_or("mp3".to_string(), str::to_lowercase)
+}
+
+/// 音频在压缩包中的文件名，取自演唱者和标题，都没有时为 `None`
+fn audio_title_name(audio: &teloxide::types::Audio, extension: &str) -> Option<String> {
+    let title = match (audio.performer.as_deref(), audio.title.as_deref()) {
+        (Some(performer), Some(title)) => format!("{} - {}", performer, title),
+        (Some(name), None) | (None, Some(name)) => name.to_string(),
+        (None, None) => return None,
+    };
+    naming::caption_file_name(&title, extension)
+}
+
+/// 任务被取消后清理临时文件并告知用户
+async fn report_aborted(
+    chat: &impl JobChat,
+    temp_dir: Option<&Path>,
+    discarded: usize,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    // 提取链接时中止的任务可能还没有创建临时目录
+    if let Some(temp_dir) = temp_dir
+        && let Err(why) = tokio::fs::remove_dir_all(temp_dir).await
+        && why.kind() != std::io::ErrorKind::NotFound
+    {
+        return Err(why.into());
+    }
+    chat.reply(messages::text("aborted", &[("count", &discarded)]))
+        .await?;
+    Ok(())
+}
+
+/// /diskusage 的回复中最多列出的临时目录数量
+const DISK_USAGE_LISTED: usize = 20;
+
+/// /diskusage 的回复：临时目录的大小和时间，以及持久化的数据文件
+async fn describe_disk_usage(config: &Config) -> FormattedText {
+    let entries = workspace::list(&config.temp_root).await;
+    let total = entries.iter().map(|entry| entry.size).sum::<u64>();
+    let quota = if config.temp_quota > 0 {
+        format!("，配额 {}", format_size(config.temp_quota))
+    } else {
+        String::new()
+    };
+    let mut text = FormattedText::new().bold("临时文件").text(format!(
+        "\n{}：{} 个目录，共 {}{}",
+        config.temp_root.display(),
+        entries.len(),
+        format_size(total),
+        quota
+    ));
+    for entry in entries.iter().take(DISK_USAGE_LISTED) {
+        let age = entry.age.map_or("未知".to_string(), |age| {
+            format!("{}前", format_duration(age))
+        });
+        text = text.text("\n· ").code(&entry.name).text(format!(
+            " {}，{}",
+            format_size(entry.size),
+            age
+        ));
+    }
+    if entries.len() > DISK_USAGE_LISTED {
+        text = text.text(format!(
+            "\n· 以及其他 {} 个",
+            entries.len() - DISK_USAGE_LISTED
+        ));
+    }
+
+    text = text.text("\n\n").bold("数据文件");
+    #[cfg_attr(not(feature = "telegraph"), allow(unused_mut))]
+    let mut files = vec![
+        PathBuf::from(&config.known_chats_file),
+        config.archive_names.path().to_path_buf(),
+        config.job_journal.path().to_path_buf(),
+    ];
+    #[cfg(feature = "telegraph")]
+    files.push(config.telegraph_token_file.clone());
+    for file in files {
+        let size = match tokio::fs::metadata(&file).await {
+            Ok(metadata) => format_size(metadata.len()),
+            Err(_) => "不存在".to_string(),
+        };
+        text = text
+            .text("\n· ")
+            .code(file.display())
+            .text(format!(" {}", size));
+    }
+    text
+}
+
+/// 下载用户以文件形式发送的 HEIC/HEIF 图片，并在阻塞线程中转换为 JPEG
+///
+/// 返回可以打包的文件；无法转换时返回原文件和原因。
+#[allow(clippy::too_many_arguments)]
+async fn import_heif(
+    chat: &impl JobChat,
+    client: &Client,
+    limiter: &RateLimiter,
+    config: &Config,
+    file_id: &teloxide::types::FileId,
+    temp_dir: &Path,
+    position: usize,
+    extension: &str,
+) -> Result<(PathBuf, Result<(), String>), Box<dyn std::error::Error + Send + Sync>> {
+    let file = chat.get_file(file_id).await?;
+    let dir = temp_dir.join("import");
+    tokio::fs::create_dir_all(&dir).await?;
+    let original = dir.join(format!("heif_{}.{}", position, extension));
+    download::download_file(
+        client,
+        limiter,
+        &file.url,
+        &original,
+        config.download_timeout,
+    )
+    .await?;
+
+    let jpeg = original.with_extension("jpg");
+    let quality = config.heif_jpeg_quality;
+    let converted = {
+        let (original, jpeg) = (original.clone(), jpeg.clone());
+        tokio::task::spawn_blocking(move || heif::convert_to_jpeg(&original, &jpeg, quality))
+            .await?
+    };
+    match converted {
+        Ok(()) => {
+            let _ = tokio::fs::remove_file(&original).await;
+            Ok((jpeg, Ok(())))
+        }
+        Err(why) => {
+            log::warn!(
+                "Failed to convert HEIF in message {} to JPEG: {}",
+                position,
+                why
+            );
+            let _ = tokio::fs::remove_file(&jpeg).await;
+            Ok((original, Err(why.to_string())))
+        }
+    }
+}
+
+/// 提前下载动态贴纸，并在阻塞线程中将第一帧渲染为 PNG
+///
+/// 返回贴纸文件和渲染出的预览图，渲染失败时只返回贴纸文件。
+async fn import_animated_sticker(
+    client: &Client,
+    limiter: &RateLimiter,
+    config: &Config,
+    url: &download::FileUrl,
+    temp_dir: &Path,
+    position: usize,
+) -> Result<(PathBuf, Option<PathBuf>), Box<dyn std::error::Error + Send + Sync>> {
+    let dir = temp_dir.join("import");
+    tokio::fs::create_dir_all(&dir).await?;
+    let tgs = dir.join(format!("sticker_{}.tgs", position));
+    download::download_file(client, limiter, url, &tgs, config.download_timeout).await?;
+
+    let png = tgs.with_extension("png");
+    let rendered = {
+        let (tgs, png) = (tgs.clone(), png.clone());
+        tokio::task::spawn_blocking(move || stickers::render_first_frame(&tgs, &png)).await?
+    };
+    match rendered {
+        Ok(()) => Ok((tgs, Some(png))),
+        Err(why) => {
+            log::warn!(
+                "Failed to render the sticker in message {}: {}",
+                position,
+                why
+            );
+            let _ = tokio::fs::remove_file(&png).await;
+            Ok((tgs, None))
+        }
+    }
+}
+
+/// 下载用户发送的 zip 压缩包，将其中的图片解压到 `temp_dir` 中
+///
+/// `position` 为压缩包所在的消息序号，用于区分同一个任务中的多个压缩包。
+async fn import_zip(
+    chat: &impl JobChat,
+    client: &Client,
+    limiter: &RateLimiter,
+    config: &Config,
+    file_id: &teloxide::types::FileId,
+    temp_dir: &Path,
+    position: usize,
+) -> Result<import::Imported, Box<dyn std::error::Error + Send + Sync>> {
+    let file = chat.get_file(file_id).await?;
+    let dir = temp_dir.join("import");
+    tokio::fs::create_dir_all(&dir).await?;
+    let archive = dir.join(format!("upload_{}.zip", position));
+    download::download_file(
+        client,
+        limiter,
+        &file.url,
+        &archive,
+        config.download_timeout,
+    )
+    .await?;
+
+    let max_extracted = config.zip_import_max_extracted;
+    let imported = tokio::task::spawn_blocking(move || {
+        let result = import::extract_images(
+            &archive,
+            archive.parent().unwrap(),
+            &format!("message{}", position),
+            max_extracted,
+        );
+        let _ = std::fs::remove_file(&archive);
+        result
+    })
+    .await??;
+    log::info!(
+        "Imported {} images from zip in message {}, skipped {} entries",
+        imported.images.len(),
+        position,
+        imported.skipped
+    );
+    Ok(imported)
+}
+
+/// 列出正在进行的会话：会话id、消息数量、状态和持续时间
+async fn list_sessions(
+    bot: Arc<Bot>,
+    chat_id: ChatId,
+    reply_to: MessageId,
+    state: AppState,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut sessions = state
+        .filter_map(|&id, user_state| {
+            if !user_state.is_active() {
+                return None;
+            }
+            let mut states = Vec::new();
+            if user_state.is_collecting() {
+                states.push("收集中".to_string());
+            }
+            if user_state.is_set_file_name() {
+                states.push("设置文件名".to_string());
+            }
+            if !user_state.jobs.is_empty() {
+                states.push(format!("{} 个任务处理中", user_state.jobs.len()));
+            }
+            let age = user_state
+                .age()
+                .map(format_duration)
+                .unwrap_or_else(|| "未知".to_string());
+            let line = format!(
+                "{}：{}，{} 条消息，已持续 {}",
+                id,
+                states.join("、"),
+                user_state.messages.len(),
+                age
+            );
+            Some((id, line))
+        })
+        .await;
+    sessions.sort_by_key(|(id, _)| id.0);
+    let lines = sessions
+        .into_iter()
+        .map(|(_, line)| line)
+        .collect::<Vec<_>>();
+
+    let reply = if lines.is_empty() {
+        "当前没有正在进行的会话".to_string()
+    } else {
+        format!(
+            "共 {} 个会话：\n\n{}\n\n使用 /clearsession <会话id> 重置会话",
+            lines.len(),
+            lines.join("\n")
+        )
+    };
+    markdown::send(&bot, chat_id, Some(reply_to), reply).await?;
+    Ok(())
+}
+
+/// 重置指定会话：清空收集状态、取消它的任务并清理临时文件，然后通知该会话
+///
+/// 会话的设置会保留。
+async fn clear_session(
+    bot: Arc<Bot>,
+    chat_id: ChatId,
+    reply_to: MessageId,
+    state: AppState,
+    config: &Config,
+    arg: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let Ok(target) = arg.trim().parse().map(ChatId) else {
+        markdown::send(
+            &bot,
+            chat_id,
+            Some(reply_to),
+            "❌ 用法：/clearsession <会话id>",
+        )
+        .await?;
+        return Ok(());
+    };
+
+    let Some(removed) = state.remove(target).await else {
+        markdown::send(
+            &bot,
+            chat_id,
+            Some(reply_to),
+            format!("🤔 没有找到会话 {}", target),
+        )
+        .await?;
+        return Ok(());
+    };
+    // 重置后只保留会话的设置
+    let UserState {
+        settings,
+        jobs,
+        status_message: status,
+        ..
+    } = removed;
+    state
+        .insert(
+            target,
+            UserState {
+                settings,
+                ..Default::default()
+            },
+        )
+        .await;
+    unpin_status(&bot, target, status).await;
+
+    // 正在运行的任务会在取消后自己清理临时文件，没有任务时清理崩溃等原因遗留的目录
+    for job in jobs.values() {
+        job.cancel.cancel();
+    }
+    let removed = if jobs.is_empty() {
+        workspace::remove_chat_dirs(&config.temp_root, target).await
+    } else {
+        0
+    };
+    log::warn!(
+        "Session {} cleared by admin in chat {}: cancelled {} jobs, removed {} temp dirs",
+        target,
+        chat_id,
+        jobs.len(),
+        removed
+    );
+
+    let notified = markdown::send(
+        &bot,
+        target,
+        None,
+        "⚠️ 管理员重置了你的会话，正在进行的收集和任务已取消。如需继续，请重新发送 /startcollect。",
+    )
+    .await;
+    let mut reply = format!(
+        "✅ 已重置会话 {}，取消了 {} 个任务，清理了 {} 个临时目录",
+        target,
+        jobs.len(),
+        removed
+    );
+    if let Err(why) = notified {
+        reply.push_str(&format!("\n⚠️ 无法通知该会话: {}", why));
+    }
+    markdown::send(&bot, chat_id, Some(reply_to), reply).await?;
+    Ok(())
+}
+
+/// 分别检查 Bot API 和文件下载服务器的连通性和延迟
+///
+/// 下载走的是共享的下载客户端（包括代理设置），可以区分是下载失败还是 Bot API 失败。
+async fn check_download_host(bot: Arc<Bot>, chat_id: ChatId, reply_to: MessageId, client: Client) {
+    let started = std::time::Instant::now();
+    let bot_api = match bot.get_me().await {
+        Ok(_) => format!("✅ 可访问，耗时 {} ms", started.elapsed().as_millis()),
+        Err(why) => format!("❌ 无法访问: {}", why),
+    };
+
+    let started = std::time::Instant::now();
+    let file_host = match client.head(download::TELEGRAM_FILE_URL).send().await {
+        // 不带token的请求会返回错误状态码，但能收到响应就说明服务器可以访问
+        Ok(response) => format!(
+            "✅ 可访问（HTTP {}），耗时 {} ms",
+            response.status().as_u16(),
+            started.elapsed().as_millis()
+        ),
+        Err(why) => format!("❌ 无法访问: {}", why.without_url()),
+    };
+
+    log::info!(
+        "Download host check for chat {}: bot api {}, file host {}",
+        chat_id,
+        bot_api,
+        file_host
+    );
+    let _ = markdown::send(
+        &bot,
+        chat_id,
+        Some(reply_to),
+        format!(
+            "🔍 连通性检查\n\nBot API：{}\n文件下载（{}）：{}",
+            bot_api,
+            download::TELEGRAM_FILE_URL,
+            file_host
+        ),
+    )
+    .await;
+}
+
+/// 自检：用内置的示例图片走一遍打包、发送和清理流程
+async fn self_test(bot: Arc<Bot>, chat_id: ChatId, reply_to: MessageId, temp_root: PathBuf) {
+    let temp_dir = workspace::self_test_dir(&temp_root, Uuid::new_v4());
+    let zip_path = temp_dir.join("selftest.zip");
+
+    let result = self_test_inner(&bot, chat_id, reply_to, &temp_dir, &zip_path).await;
+
+    // 无论成功与否都要清理临时文件
+    let _ = tokio::fs::remove_dir_all(&temp_dir).await;
+
+    match result {
+        Ok(()) => log::info!("Self test passed for chat {}", chat_id),
+        Err(e) => {
+            log::error!("Self test failed for chat {}: {}", chat_id, e);
+            let reply = FormattedText::new().text("❌ 自检失败：\n").code_block(e);
+            let _ = markdown::send(&bot, chat_id, Some(reply_to), reply).await;
+        }
+    }
+}
+
+/// 下载头像并打包发送，不经过收集会话
+async fn send_avatars(
+    bot: Arc<Bot>,
+    chat_id: ChatId,
+    reply_to: MessageId,
+    client: Client,
+    limiter: Arc<RateLimiter>,
+    config: Arc<Config>,
+    target: avatar::Target,
+) {
+    let temp_dir = workspace::avatar_dir(&config.temp_root, Uuid::new_v4());
+    let result = send_avatars_inner(
+        &bot, chat_id, reply_to, &client, &limiter, &config, target, &temp_dir,
+    )
+    .await;
+    let _ = tokio::fs::remove_dir_all(&temp_dir).await;
+
+    if let Err(why) = result {
+        let why = download::redact_token(&why.to_string());
+        log::warn!("Failed to send avatars to chat {}: {}", chat_id, why);
+        let reply = FormattedText::new()
+            .text("❌ 无法获取头像：\n")
+            .code_block(why);
+        let _ = markdown::send(&bot, chat_id, Some(reply_to), reply).await;
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn send_avatars_inner(
+    bot: &Bot,
+    chat_id: ChatId,
+    reply_to: MessageId,
+    client: &Client,
+    limiter: &RateLimiter,
+    config: &Config,
+    target: avatar::Target,
+    temp_dir: &Path,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let (name, file_ids) = avatar::collect(bot, target, config.avatar_limit).await?;
+    if file_ids.is_empty() {
+        markdown::send(bot, chat_id, Some(reply_to), "该用户未公开头像或没有头像").await?;
+        return Ok(());
+    }
+
+    tokio::fs::create_dir_all(temp_dir).await?;
+    let mut files = Vec::with_capacity(file_ids.len());
+    for (i, file_id) in file_ids.into_iter().enumerate() {
+        let file = bot.get_file(file_id).await?;
+        let path = temp_dir.join(naming::image_file_name(i + 1, files.capacity(), "jpg"));
+        download::download_file(
+            client,
+            limiter,
+            &download::telegram_file_url(bot.token(), &file.path),
+            &path,
+            config.download_timeout,
+        )
+        .await?;
+        files.push(path);
+    }
+
+    let file_name = format!(
+        "{}_头像.zip",
+        naming::sanitize_file_name(&name).unwrap_or_else(|| "avatar".to_string())
+    );
+    let zip_path = temp_dir.join(&file_name);
+    let metadata = ArchiveMetadata::for_job(false, Uuid::new_v4(), chat_id, None);
+    let (archive_files, dst) = (files.clone(), zip_path.clone());
+    tokio::task::spawn_blocking(move || {
+        archive::create_zip(
+            &archive_files,
+            &[],
+            &dst,
+            metadata,
+            Compression::default(),
+            |_| None,
+            || {},
+        )
+    })
+    .await??;
+    bot.send_document(chat_id, InputFile::file(&zip_path).file_name(file_name))
+        .reply_parameters(markdown::reply_parameters(reply_to))
+        .caption(format!("🖼 {} 的 {} 张头像", name, files.len()))
+        .await?;
+    log::info!("Sent {} avatars to chat {}", files.len(), chat_id);
+    Ok(())
+}
+
+async fn self_test_inner(
+    bot: &Bot,
+    chat_id: ChatId,
+    reply_to: MessageId,
+    temp_dir: &Path,
+    zip_path: &Path,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    tokio::fs::create_dir_all(temp_dir).await?;
+    let mut files = Vec::with_capacity(SELF_TEST_IMAGES.len());
+    for (name, bytes) in SELF_TEST_IMAGES {
+        let path = temp_dir.join(name);
+        tokio::fs::write(&path, bytes).await?;
+        files.push(path);
+    }
+
+    // 打包是阻塞的文件读写和压缩，放到阻塞线程中执行，不占用处理其他会话的运行时线程
+    let metadata = ArchiveMetadata::for_job(false, Uuid::new_v4(), chat_id, None);
+    let dst = zip_path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        archive::create_zip(
+            &files,
+            &[],
+            &dst,
+            metadata,
+            Compression::default(),
+            |_| None,
+            || {},
+        )
+    })
+    .await??;
+    bot.send_document(chat_id, InputFile::file(zip_path))
+        .reply_parameters(markdown::reply_parameters(reply_to))
+        .caption(format!(
+            "✅ 自检完成，压缩包内应有 {} 张示例图片",
+            SELF_TEST_IMAGES.len()
+        ))
+        .await?;
+    Ok(())
+}
+
+/// 打包分卷需要的数据，可以移动到阻塞线程中并发打包多个分卷
+struct VolumeBuilder {
+    temp_dir: PathBuf,
+    archive_name: String,
+    settings: ChatSettings,
+    job_id: Uuid,
+    chat_id: ChatId,
+    chat_label: String,
+    chat_info: chat_export::ChatInfo,
+    collected: (
+        chrono::DateTime<chrono::Local>,
+        chrono::DateTime<chrono::Local>,
+    ),
+    is_group: bool,
+    file_sizes: HashMap<PathBuf, u64>,
+    file_contributors: HashMap<PathBuf, String>,
+    file_kinds: HashMap<PathBuf, MediaKind>,
+    file_caption_files: HashMap<PathBuf, String>,
+    file_origins: HashMap<PathBuf, chat_export::Origin>,
+    /// 语音、音频和视频消息的时长，单位为秒
+    file_durations: HashMap<PathBuf, u32>,
+    /// 视频消息的边长，单位为像素
+    file_video_note_lengths: HashMap<PathBuf, u32>,
+    sticker_sets: Vec<String>,
+    /// 每张图片单独打包时各压缩包的文件名
+    single_file_names: Option<Vec<String>>,
+    progress: Arc<Progress>,
+}
+
+/// 正在打包的分卷，完成后返回打包耗时
+type VolumeBuild =
+    tokio::task::JoinHandle<Result<Duration, Box<dyn std::error::Error + Send + Sync>>>;
+
+impl VolumeBuilder {
+    /// 第 `index` 卷（共 `total` 卷）的文件名
+    fn zip_name(&self, index: usize, total: usize) -> String {
+        match &self.single_file_names {
+            Some(names) => names[index].clone(),
+            None => archive::volume_name(&self.archive_name, index, total),
+        }
+    }
+
+    /// 在后台打包一个分卷，同时打包的分卷数量受 `slots` 限制
+    fn spawn(
+        self: &Arc<Self>,
+        slots: &Arc<tokio::sync::Semaphore>,
+        volume: Vec<PathBuf>,
+        index: usize,
+        total: usize,
+    ) -> VolumeBuild {
+        let builder = Arc::clone(self);
+        let slots = Arc::clone(slots);
+        let zip_filename = self.zip_name(index, total);
+        let span = tracing::info_span!("archive", file = %zip_filename, files = volume.len());
+        tokio::spawn(async move {
+            let _permit = slots.acquire_owned().await?;
+            let elapsed = tokio::task::spawn_blocking(move || {
+                let started = std::time::Instant::now();
+                span.in_scope(|| builder.build(&volume, index, total))?;
+                Ok::<_, zip::result::ZipError>(started.elapsed())
+            })
+            .await??;
+            log::info!("Created zip file: {} in {:?}", zip_filename, elapsed);
+            Ok(elapsed)
+        })
+    }
+
+    /// 分卷中 `kind` 类型的录音或视频消息：文件名、时长、大小，视频消息还有尺寸，
+    /// 每行一个，没有时为 `None`
+    fn describe_recordings(&self, volume: &[PathBuf], kind: MediaKind) -> Option<String> {
+        let lines = volume
+            .iter()
+            .filter(|path| self.file_kinds[*path] == kind)
+            .filter_map(|path| {
+                let seconds = self.file_durations.get(path)?;
+                let mut line = format!(
+                    "  {}  {}:{:02}  {}",
+                    path.file_name()?.to_string_lossy(),
+                    seconds / 60,
+                    seconds % 60,
+                    format_size(self.file_sizes[path])
+                );
+                if let Some(length) = self.file_video_note_lengths.get(path) {
+                    line.push_str(&format!("  {}x{}", length, length));
+                }
+                Some(line)
+            })
+            .collect::<Vec<_>>();
+        (!lines.is_empty()).then(|| lines.join("\n"))
+    }
+
+    /// 同步打包，需要在阻塞线程中调用，直接在异步任务中调用会在压缩大文件时阻塞运行时线程
+    fn build(&self, volume: &[PathBuf], index: usize, total: usize) -> zip::result::ZipResult<()> {
+        let settings = &self.settings;
+        let readme = settings.readme.then(|| {
+            archive::ReadmeInfo {
+                chat: &self.chat_label,
+                collected: self.collected,
+                image_count: volume.len(),
+                total_size: format_size(volume.iter().map(|path| self.file_sizes[path]).sum()),
+                part: (total > 1).then_some((index + 1, total)),
+                contributors: self.is_group.then(|| {
+                    credits::format_leaderboard(&credits::leaderboard(
+                        volume
+                            .iter()
+                            .map(|path| self.file_contributors[path].as_str()),
+                    ))
+                }),
+                audio: self.describe_recordings(volume, MediaKind::Audio),
+                video_notes: self.describe_recordings(volume, MediaKind::VideoNote),
+                sticker_sets: (!self.sticker_sets.is_empty()).then(|| {
+                    self.sticker_sets
+                        .iter()
+                        .map(|name| stickers::set_link(name))
+                        .collect::<Vec<_>>()
+                        .join("，")
+                }),
+            }
+            .render()
+        });
+        // 说明文字文件与图片放在同一个文件夹中
+        let caption_entries = volume
+            .iter()
+            .filter_map(|path| {
+                let text = self.file_caption_files.get(path)?;
+                let image_name = path.file_name()?.to_str()?;
+                let name = captions::file_name(image_name, settings.caption_files);
+                let name = match settings.folders {
+                    true => format!("{}/{}", self.file_kinds[path].folder(), name),
+                    false => name,
+                };
+                Some((name, text))
+            })
+            .collect::<Vec<_>>();
+        let export = settings.telegram_export.then(|| {
+            let files = volume
+                .iter()
+                .filter_map(|path| {
+                    let kind = self.file_kinds[path];
+                    let name = path.file_name()?.to_str()?;
+                    let name = match settings.folders {
+                        true => format!("{}/{}", kind.folder(), name),
+                        false => name.to_string(),
+                    };
+                    Some((self.file_origins.get(path)?, name, kind))
+                })
+                .collect::<Vec<_>>();
+            chat_export::render(&self.chat_info, &files)
+        });
+        // 网页相册和缩略图，缩略图在这里生成，不会留在临时目录中
+        let mut thumbs = Vec::new();
+        let gallery_index = (settings.output_mode == OutputMode::Gallery).then(|| {
+            let items = volume
+                .iter()
+                .filter_map(|path| {
+                    let kind = self.file_kinds[path];
+                    let name = path.file_name()?.to_str()?;
+                    let is_image = kind == MediaKind::Image;
+                    let thumb = is_image
+                        .then(|| gallery::thumbnail(path))
+                        .flatten()
+                        .map(|thumb| {
+                            thumbs.push((gallery::thumb_path(name), thumb));
+                            gallery::thumb_path(name)
+                        });
+                    let origin = self.file_origins.get(path);
+                    Some(gallery::Item {
+                        path: match settings.folders {
+                            true => format!("{}/{}", kind.folder(), name),
+                            false => name.to_string(),
+                        },
+                        thumb,
+                        is_image,
+                        caption: origin.and_then(|origin| origin.text()),
+                        date: origin.map(|origin| origin.date().with_timezone(&chrono::Local)),
+                    })
+                })
+                .collect::<Vec<_>>();
+            gallery::render(&self.archive_name, &items)
+        });
+        let entries = readme
+            .iter()
+            .map(|text| (archive::README_NAME, text.as_bytes()))
+            .chain(
+                gallery_index
+                    .iter()
+                    .map(|text| (gallery::INDEX_NAME, text.as_bytes())),
+            )
+            .chain(
+                thumbs
+                    .iter()
+                    .map(|(name, thumb)| (name.as_str(), thumb.as_slice())),
+            )
+            .chain(
+                export
+                    .iter()
+                    .map(|text| (chat_export::RESULT_NAME, text.as_bytes())),
+            )
+            .chain(
+                caption_entries
+                    .iter()
+                    .map(|(name, text)| (name.as_str(), text.as_bytes())),
+            )
+            .collect::<Vec<_>>();
+        archive::create_zip(
+            volume,
+            &entries,
+            &self.temp_dir.join(self.zip_name(index, total)),
+            ArchiveMetadata::for_job(
+                settings.reproducible,
+                self.job_id,
+                self.chat_id,
+                settings.archive_comment.as_deref(),
+            ),
+            settings.compression,
+            |path| settings.folders.then(|| self.file_kinds[path].folder()),
+            || self.progress.finish_compressing_file(),
+        )
+    }
+
+    /// 等待还没有用到的分卷打包结束并删除，用于取消任务或重新分卷
+    async fn discard(&self, builds: &mut HashMap<usize, VolumeBuild>, total: usize) {
+        for (index, build) in builds.drain() {
+            if let Ok(Ok(_)) = build.await {
+                let zip_path = self.temp_dir.join(self.zip_name(index, total));
+                if let Err(why) = tokio::fs::remove_file(&zip_path).await {
+                    log::warn!("Failed to remove {}: {}", zip_path.display(), why);
+                }
+            }
+        }
+    }
+}
+
+/// 将压缩包上传到配置的 SFTP 服务器，返回远程路径
+///
+/// 详细的错误只记录在日志中，返回给用户的信息不包含登录凭据。
+#[cfg(feature = "sftp")]
+async fn deliver_sftp(config: &Config, chat_id: ChatId, zip_path: &Path) -> Result<String, String> {
+    let Some(sftp) = &config.sftp else {
+        return Err("没有配置 SFTP 服务器".to_string());
+    };
+    sftp.upload(zip_path, chat_id).await.map_err(|why| {
+        log::error!("Failed to upload {} to sftp: {}", zip_path.display(), why);
+        why.to_string()
+    })
+}
+
+#[cfg(not(feature = "sftp"))]
+async fn deliver_sftp(
+    _config: &Config,
+    _chat_id: ChatId,
+    _zip_path: &Path,
+) -> Result<String, String> {
+    Err("编译时没有启用 sftp 功能".to_string())
+}
+
+/// 没有设置文件名时的压缩包名称，不含 Windows 文件名中不允许的 `:`
+fn default_archive_name(now: chrono::DateTime<chrono::Local>, chat_id: ChatId) -> String {
+    format!("images_{}_{}", now.format("%Y-%m-%d-%H-%M"), chat_id.0)
+}
+
+/// 任务出错，保留出错前已经得到的结果，用于统计和通知
+struct JobError {
+    error: Box<dyn std::error::Error + Send + Sync>,
+    report: job_report::ProcessingReport,
+}
+
+/// 处理一次打包任务，`cancel` 被触发时在下一个检查点停止并清理临时文件
+///
+/// 不直接连接 telegram，获取文件和发送消息都通过 `chat`。
+#[allow(clippy::too_many_arguments)]
+async fn run_job(
+    chat: &impl JobChat,
+    chat_id: ChatId,
+    job_id: Uuid,
+    batch: Batch,
+    client: Client,
+    config: Arc<Config>,
+    limiter: Arc<RateLimiter>,
+    cancel: &CancellationToken,
+    source: BatchSource,
+) -> Result<job_report::ProcessingReport, JobError> {
+    let mut report = job_report::ProcessingReport {
+        items: batch.messages.len(),
+        ..Default::default()
+    };
+    let result = process_batch(
+        chat,
+        chat_id,
+        job_id,
+        batch,
+        client,
+        config,
+        limiter,
+        cancel,
+        source,
+        &mut report,
+    )
+    .await;
+    match result {
+        Ok(()) => Ok(report),
+        Err(error) => Err(JobError { error, report }),
+    }
+}
+
+/// [`run_job`] 的各个步骤，处理过程中逐步填写 `report`
+#[allow(clippy::too_many_arguments)]
+async fn process_batch(
+    chat: &impl JobChat,
+    chat_id: ChatId,
+    job_id: Uuid,
+    batch: Batch,
+    client: Client,
+    config: Arc<Config>,
+    limiter: Arc<RateLimiter>,
+    cancel: &CancellationToken,
+    source: BatchSource,
+    report: &mut job_report::ProcessingReport,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let Batch {
+        messages: mut messages_to_process,
+        file_name,
+        overwrite_name,
+        part,
+        settings,
+        resume,
+    } = batch;
+    log::info!(
+        "{:?} for chat {}. Processing {} messages.",
+        source,
+        chat_id,
+        messages_to_process.len()
+    );
+
+    let started = std::time::Instant::now();
+    let deadline = tokio::time::Instant::now() + config.process_timeout;
+    let status_text = match source {
+        BatchSource::Stop => messages::text("processing", &[]),
+        BatchSource::Pack => "⏳ 正在打包已收集的图片，收集仍在继续...".to_string(),
+        BatchSource::FullResolution => "⏳ 正在以原图重新打包，请稍候...".to_string(),
+        BatchSource::Reply => "⏳ 正在打包被回复的消息，请稍候...".to_string(),
+        BatchSource::Reprocess => "⏳ 正在用当前的设置重新处理，请稍候...".to_string(),
+        BatchSource::Retry => {
+            "⏳ 正在重试上次失败的任务，已经下载的文件不会重新下载...".to_string()
+        }
+    };
+    let status = chat.reply(status_text).await?;
+    // 开启 /cleanchat 时交付结果后删除的消息
+    let mut transient = vec![status];
+
+    // 先确定顺序，之后的编号都以此为准
+    let (seed_high, seed_low) = job_id.as_u64_pair();
+    ordering::sort_messages(
+        &mut messages_to_process,
+        settings.order,
+        seed_high ^ seed_low,
+    );
+
+    if let OutputMode::Album { captions } = settings.output_mode {
+        let sent = tokio::select! {
+            sent = chat.send_albums(&messages_to_process, captions) => sent?,
+            _ = cancel.cancelled() => return report_aborted(chat, None, 0).await,
+        };
+        report.sent = sent;
+        let reply = if sent == 0 {
+            messages::text("no_images", &[]).into()
+        } else {
+            FormattedText::new()
+                .text("✅ 处理完成！共发送 ")
+                .bold(sent)
+                .text(" 张图片")
+        };
+        chat.reply(reply).await?;
+        if settings.clean_chat {
+            chat.delete_messages(&[status]).await;
+        }
+        return Ok(());
+    }
+
+    // 提取到的文件，按提取的顺序
+    let mut items = Vec::new();
+    let mut total_size = 0u64;
+    // 链接中的图片在下载前不知道大小
+    let mut sizes_known = true;
+    let file_limit = config.file_limit();
+    let exceeds_limit = |size: u32| download::exceeds_limit(size, file_limit);
+    // 超过 `PROCESS_TIMEOUT` 时还没有处理的消息数量
+    let mut unprocessed = 0;
+    // 导入 zip 压缩包时已经需要临时目录
+    let temp_dir = workspace::job_dir(&config.temp_root, chat_id, job_id);
+    let skips = &mut report.skips;
+    // 用户发送的链接都通过这个客户端访问
+    let external_client = config.external_client();
+    // 贴纸所在的贴纸包，按第一次出现的顺序
+    let mut sticker_sets = Vec::new();
+    let mut voices = 0;
+    let mut video_notes = 0;
+    // 同时有图片和原图文件的消息组，以及因此没有打包的图片或文件数量
+    let paired_groups = originals::paired_groups(&messages_to_process);
+
+    // 1. 提取所有图片的下载链接
+    for (position, msg) in messages_to_process.iter().enumerate() {
+        if cancel.is_cancelled() {
+            return report_aborted(chat, Some(&temp_dir), 0).await;
+        }
+        if tokio::time::Instant::now() >= deadline {
+            unprocessed = messages_to_process.len() - position;
+            break;
+        }
+
+        // 同一组中同时有图片和原图文件时只保留设置的一种
+        let paired = msg
+            .media_group_id()
+            .is_some_and(|group| paired_groups.contains(group));
+        let skip_photo = paired && settings.photo_source == PhotoSource::Document;
+        let skip_document = paired && settings.photo_source == PhotoSource::Photo;
+
+        // 获取最高分辨率的图片，快速模式下获取较小的预览图
+        let photo = if settings.fast {
+            output::preview_photo(msg)
+        } else {
+            output::largest_photo(msg)
+        };
+        // 按最高分辨率判断尺寸，快速模式下也一样
+        let undersized_photo = output::largest_photo(msg)
+            .filter(|largest| output::below_min_dimension(largest, settings.min_image_dimension));
+        if photo.is_some() && skip_photo {
+            log::debug!(
+                "Skipping photo in message {} in favor of the original",
+                msg.id
+            );
+            skips.paired += 1;
+        } else if let Some(largest) = undersized_photo {
+            log::debug!(
+                "Skipping photo in message {}: {}x{}",
+                msg.id,
+                largest.width,
+                largest.height
+            );
+            skips
+                .undersized
+                .push((position + 1, largest.width, largest.height));
+        } else if let Some(photo) = photo {
+            let file = chat.get_file(&photo.file.id).await?;
+            if exceeds_limit(file.size) {
+                log::info!("Skipping photo in message {}: {} bytes", msg.id, file.size);
+                skips.too_large.push((position + 1, file.size));
+            } else {
+                items.push(
+                    CollectedItem::new(msg, file.url, MediaKind::Image, "jpg")
+                        .with_caption(msg, settings.caption_files),
+                );
+                total_size += u64::from(file.size);
+            }
+        }
+
+        // 以文件形式发送的图片保留了原图，和图片一样打包
+        if let Some((document, extension)) = originals::image_document(msg) {
+            if skip_document {
+                log::debug!(
+                    "Skipping document in message {} in favor of the photo",
+                    msg.id
+                );
+                skips.paired += 1;
+            } else if exceeds_limit(document.file.size) {
+                log::info!(
+                    "Skipping document in message {}: {} bytes",
+                    msg.id,
+                    document.file.size
+                );
+                skips.too_large.push((position + 1, document.file.size));
+            } else {
+                let file = chat.get_file(&document.file.id).await?;
+                items.push(
+                    CollectedItem::new(msg, file.url, MediaKind::Image, extension)
+                        .with_caption(msg, settings.caption_files),
+                );
+                total_size += u64::from(file.size);
+            }
+        }
+
+        // 开启 /audio 时语音和音频与图片一起打包，音频以演唱者和标题命名，语音按顺序命名
+        let audio = msg
+            .voice()
+            .map(|voice| (&voice.file, "ogg".to_string(), voice.duration, None))
+            .or_else(|| {
+                msg.audio().map(|audio| {
+                    let extension = audio_extension(audio);
+                    let name = audio_title_name(audio, &extension);
+                    (&audio.file, extension, audio.duration, name)
+                })
+            })
+            .filter(|_| settings.audio);
+        if let Some((audio, extension, duration, name)) = audio {
+            if exceeds_limit(audio.size) {
+                log::info!("Skipping audio in message {}: {} bytes", msg.id, audio.size);
+                skips.too_large.push((position + 1, audio.size));
+            } else {
+                let file = chat.get_file(&audio.id).await?;
+                let name = name.or_else(|| {
+                    msg.voice().map(|_| {
+                        voices += 1;
+                        format!("voice_{}.{}", voices, extension)
+                    })
+                });
+                let mut item = CollectedItem::new(msg, file.url, MediaKind::Audio, extension)
+                    .with_caption(msg, settings.caption_files);
+                item.name = name;
+                item.duration = Some(duration.seconds());
+                items.push(item);
+                total_size += u64::from(file.size);
+            }
+        }
+
+        // 开启 /videonotes 时圆形的视频消息按顺序命名为 videonote_N.mp4
+        if let Some(video_note) = msg.video_note().filter(|_| settings.video_notes) {
+            if exceeds_limit(video_note.file.size) {
+                log::info!(
+                    "Skipping video note in message {}: {} bytes",
+                    msg.id,
+                    video_note.file.size
+                );
+                skips.too_large.push((position + 1, video_note.file.size));
+            } else {
+                let file = chat.get_file(&video_note.file.id).await?;
+                video_notes += 1;
+                let mut item = CollectedItem::new(msg, file.url, MediaKind::VideoNote, "mp4")
+                    .named(format!("videonote_{}.mp4", video_notes));
+                item.duration = Some(video_note.duration.seconds());
+                item.video_note_length = Some(video_note.length);
+                items.push(item);
+                total_size += u64::from(file.size);
+
+                // 缩略图紧跟在视频消息之后，放在同一个文件夹中
+                let thumbnail = match video_note.thumbnail.as_ref() {
+                    Some(thumbnail) if settings.video_thumbnails => {
+                        match chat.get_file(&thumbnail.file.id).await {
+                            Ok(thumbnail) => Some(thumbnail),
+                            Err(why) => {
+                                log::warn!(
+                                    "Skipping thumbnail of video note in message {}: {}",
+                                    msg.id,
+                                    why
+                                );
+                                None
+                            }
+                        }
+                    }
+                    _ => None,
+                };
+                if let Some(thumbnail) = thumbnail {
+                    items.push(
+                        CollectedItem::new(msg, thumbnail.url, MediaKind::VideoNote, "jpg")
+                            .named(format!("videonote_{}.thumb.jpg", video_notes)),
+                    );
+                    total_size += u64::from(thumbnail.size);
+                }
+            }
+        }
+
+        // 贴纸按原文件打包，记录所在的贴纸包
+        if let Some(sticker) = msg.sticker().filter(|_| settings.stickers) {
+            let file = chat.get_file(&sticker.file.id).await?;
+            if exceeds_limit(file.size) {
+                log::info!(
+                    "Skipping sticker in message {}: {} bytes",
+                    msg.id,
+                    file.size
+                );
+                skips.too_large.push((position + 1, file.size));
+            } else {
+                if let Some(set_name) = &sticker.set_name
+                    && !sticker_sets.contains(set_name)
+                {
+                    sticker_sets.push(set_name.clone());
+                }
+                let extension = stickers::extension(sticker);
+                let mut url = file.url;
+                let mut preview = None;
+                // 启用 lottie 时提前下载动态贴纸，渲染第一帧作为预览
+                if cfg!(feature = "lottie") && extension == "tgs" {
+                    let imported = tokio::select! {
+                        imported = import_animated_sticker(&client, &limiter, &config, &url, &temp_dir, position + 1) => imported,
+                        _ = cancel.cancelled() => return report_aborted(chat, Some(&temp_dir), 0).await,
+                    };
+                    match imported {
+                        Ok((tgs, png)) => {
+                            url = download::FileUrl::Local(tgs);
+                            preview = png;
+                        }
+                        // 留给下载阶段重试并报告失败原因
+                        Err(why) => {
+                            log::warn!("无法提前下载第 {} 条消息中的贴纸: {}", position + 1, why)
+                        }
+                    }
+                }
+                items.push(CollectedItem::new(msg, url, MediaKind::Sticker, extension));
+                total_size += u64::from(file.size);
+                if let Some(png) = preview {
+                    total_size += tokio::fs::metadata(&png).await?.len();
+                    let url = download::FileUrl::Local(png);
+                    items.push(CollectedItem::new(msg, url, MediaKind::Sticker, "png"));
+                }
+            }
+        }
+
+        // 用户发送的图片链接和telegraph页面
+        for link in links::extract_urls(msg) {
+            let resolved = tokio::select! {
+                resolved = links::resolve_image_urls(&external_client, link.clone()) => resolved,
+                _ = cancel.cancelled() => return report_aborted(chat, Some(&temp_dir), 0).await,
+            };
+            let urls = match resolved {
+                Ok(urls) => urls,
+                Err(why) => {
+                    // 保留原链接，让下载阶段把失败原因告诉用户
+                    log::warn!("无法解析链接 {}: {}", link, why);
+                    vec![link]
+                }
+            };
+            for url in urls {
+                // 不像图片直链的链接当作网页，下载其中的预览图
+                if settings.link_previews && !previews::looks_like_image(&url) {
+                    let page = tokio::select! {
+                        page = previews::fetch(&external_client, url.clone()) => page,
+                        _ = cancel.cancelled() => return report_aborted(chat, Some(&temp_dir), 0).await,
+                    };
+                    match page {
+                        Ok(previews::Page::Image) => {}
+                        Ok(previews::Page::Preview { image, title }) => {
+                            skips.previews.found += 1;
+                            let extension = previews::image_extension(&image);
+                            let url = download::FileUrl::from(image);
+                            let mut item =
+                                CollectedItem::new(msg, url, MediaKind::Image, extension);
+                            item.name = title
+                                .and_then(|title| naming::caption_file_name(&title, extension));
+                            items.push(item);
+                            sizes_known = false;
+                            continue;
+                        }
+                        Err(
+                            previews::PreviewError::NoPreview | previews::PreviewError::NotHtml(_),
+                        ) => {
+                            log::info!("No preview image in {}", url);
+                            skips.previews.without_image += 1;
+                            continue;
+                        }
+                        Err(why) => {
+                            log::warn!("无法读取网页 {}: {}", url, why);
+                            skips.previews.failed.push((
+                                position + 1,
+                                url.to_string(),
+                                why.to_string(),
+                            ));
+                            continue;
+                        }
+                    }
+                }
+
+                let url = download::FileUrl::from(url);
+                items.push(CollectedItem::new(msg, url, MediaKind::Image, "jpg"));
+                sizes_known = false;
+            }
+        }
+
+        // 用户发送的 zip 压缩包，其中的图片按顺序加入
+        if let Some(document) = msg.document().filter(|_| import::is_zip(msg)) {
+            let size = u64::from(document.file.size);
+            let imported = if size > config.zip_import_max_bytes {
+                log::info!("Skipping zip in message {}: {} bytes", msg.id, size);
+                Err(format!(
+                    "超过了 {} 的大小限制",
+                    format_size(config.zip_import_max_bytes)
+                )
+                .into())
+            } else {
+                tokio::select! {
+                imported = import_zip(chat, &client, &limiter, &config, &document.file.id, &temp_dir, position + 1) => imported,
+                    _ = cancel.cancelled() => return report_aborted(chat, Some(&temp_dir), 0).await,
+                }
+            };
+            match imported {
+                Ok(imported) => {
+                    skips.imports.archives += 1;
+                    skips.imports.skipped += imported.skipped;
+                    for (path, extension, size) in imported.images {
+                        let url = download::FileUrl::Local(path);
+                        items.push(CollectedItem::new(msg, url, MediaKind::Image, extension));
+                        total_size += size;
+                        skips.imports.images += 1;
+                    }
+                }
+                Err(why) => {
+                    log::warn!("无法导入第 {} 条消息中的压缩包: {}", position + 1, why);
+                    skips.imports.failed.push((position + 1, why.to_string()));
+                }
+            }
+        }
+
+        // 以文件形式发送的 HEIC/HEIF 图片转换为 JPEG 后打包，保留原文件名
+        if let Some(document) = msg.document().filter(|_| heif::is_heif(msg)) {
+            if exceeds_limit(document.file.size) {
+                log::info!(
+                    "Skipping HEIF in message {}: {} bytes",
+                    msg.id,
+                    document.file.size
+                );
+                skips.too_large.push((position + 1, document.file.size));
+            } else {
+                let name = document
+                    .file_name
+                    .as_deref()
+                    .and_then(naming::sanitize_file_name)
+                    .unwrap_or_else(|| format!("message{}.heic", position + 1));
+                let extension = name
+                    .rsplit_once('.')
+                    .map(|(_, extension)| extension.to_lowercase())
+                    .filter(|extension| extension.chars().all(|c| c.is_ascii_alphanumeric()))
+                    .unwrap_or_else(|| "heic".to_string());
+                let imported = tokio::select! {
+                    imported = import_heif(chat, &client, &limiter, &config, &document.file.id, &temp_dir, position + 1, &extension) => imported,
+                    _ = cancel.cancelled() => return report_aborted(chat, Some(&temp_dir), 0).await,
+                };
+                match imported {
+                    Ok((path, converted)) => {
+                        let (name, extension) = match converted {
+                            Ok(()) => {
+                                skips.heifs.converted += 1;
+                                (heif::jpeg_name(&name), "jpg".to_string())
+                            }
+                            Err(why) => {
+                                skips.heifs.kept.push((position + 1, why));
+                                (name, extension)
+                            }
+                        };
+                        let url = download::FileUrl::Local(path);
+                        items.push(
+                            CollectedItem::new(msg, url, MediaKind::Image, extension)
+                                .with_caption(msg, settings.caption_files)
+                                .named(name),
+                        );
+                        total_size += u64::from(document.file.size);
+                    }
+                    Err(why) => {
+                        log::warn!("无法下载第 {} 条消息中的 HEIC 文件: {}", position + 1, why);
+                        skips.heifs.failed.push((position + 1, why.to_string()));
+                    }
+                }
+            }
+        }
+    }
+
+    let skipped_report = skips.describe(
+        file_limit,
+        settings.photo_source.describe(),
+        settings.min_image_dimension,
+    );
+
+    let mut process_timed_out = unprocessed > 0;
+    if items.is_empty() {
+        // 导入失败的压缩包可能留下了文件
+        let _ = tokio::fs::remove_dir_all(&temp_dir).await;
+        let reply = if process_timed_out {
+            log::warn!(
+                "Job {} for chat {} exceeded PROCESS_TIMEOUT of {:?} before any download",
+                job_id,
+                chat_id,
+                config.process_timeout
+            );
+            FormattedText::from(format!(
+                "⏰ 处理超时（超过 {}），还没有可以发送的文件，任务已中止。",
+                format_duration(config.process_timeout)
+            ))
+        } else if !skips.explains_empty() {
+            FormattedText::from(messages::text("no_images", &[]))
+        } else {
+            FormattedText::from("🤷‍♀️ 没有可以下载的文件。").append(skipped_report)
+        };
+        chat.reply(reply).await?;
+        return Ok(());
+    }
+
+    // 下载的文件和生成的压缩包同时存在，按文件大小的两倍估算
+    let estimate = total_size.saturating_mul(2);
+    if let Err(used) = workspace::ensure_quota(
+        &config.temp_root,
+        config.temp_quota,
+        estimate,
+        config.temp_max_age,
+    )
+    .await
+    {
+        log::warn!(
+            "Job {} for chat {} refused: temp usage {} bytes, estimate {} bytes, quota {} bytes",
+            job_id,
+            chat_id,
+            used,
+            estimate,
+            config.temp_quota
+        );
+        let _ = tokio::fs::remove_dir_all(&temp_dir).await;
+        chat.reply("💾 服务器的临时空间不足，暂时无法处理这个任务，请稍后再试。")
+            .await?;
+        return Ok(());
+    }
+
+    if let Some(eta) = limiter.estimate(total_size) {
+        let sent = chat
+            .reply(format!(
+                "📥 共 {} 张图片（约 {}），当前限速 {}，预计需要 {}",
+                items.len(),
+                format_size(total_size),
+                format_speed(limiter.rate() as f64),
+                format_duration(eta)
+            ))
+            .await?;
+        transient.push(sent);
+    }
+
+    // 2. 创建临时目录并下载图片
+    let mut archive_name =
+        file_name.unwrap_or_else(|| default_archive_name(chrono::Local::now(), chat_id));
+    if let Some(part) = part {
+        archive_name = format!("{}_part{}", archive_name, part);
+    }
+    if settings.fast {
+        archive_name.push_str("_preview");
+    }
+    report.archive_name = Some(archive_name.clone());
+    // 快速模式的图片分辨率较低，在结果中说明
+    let fast_report = if settings.fast {
+        FormattedText::from(format!(
+            "\n\n⚡ 快速模式：图片最长边不超过 {} 像素，链接中的图片不受影响。发送 /full 获取原图",
+            output::PREVIEW_MAX_SIDE
+        ))
+    } else {
+        FormattedText::new()
+    };
+
+    tokio::fs::create_dir_all(&temp_dir).await?;
+    let file_paths = pipeline::file_names(&items, settings.caption_names)
+        .into_iter()
+        .map(|name| temp_dir.join(name))
+        .collect::<Vec<_>>();
+
+    // 在处理中的消息上显示进度，任务结束时停止更新
+    let progress = Arc::new(Progress::new(
+        items.len(),
+        sizes_known.then_some(total_size),
+    ));
+    let stop_report = CancellationToken::new();
+    let _stop_report_on_return = stop_report.clone().drop_guard();
+    chat.show_progress(status, Arc::clone(&progress), stop_report);
+
+    // 记录下载完成的文件，任务失败后重试时跳过这些文件
+    let manifest = {
+        let temp_dir = temp_dir.clone();
+        Arc::new(tokio::task::spawn_blocking(move || resume::Manifest::load(&temp_dir)).await?)
+    };
+    if resume.is_some() {
+        log::info!(
+            "Job {}: resuming with {} files downloaded before",
+            job_id,
+            manifest.len()
+        );
+    }
+    let resumed = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+
+    // 整个下载阶段超时或任务被取消时通过它取消剩余的下载
+    let download_cancel = cancel.child_token();
+    let failures = {
+        let mut downloads = Vec::with_capacity(items.len());
+
+        for (i, (item, file_path)) in items.iter().zip(&file_paths).enumerate() {
+            let client = client.clone();
+            let external_client = external_client.clone();
+            let limiter = Arc::clone(&limiter);
+            let progress = Arc::clone(&progress);
+            let cancel = download_cancel.clone();
+            let timeout = config.download_timeout;
+            let manifest = Arc::clone(&manifest);
+            let resumed = Arc::clone(&resumed);
+            let resuming = resume.is_some();
+            // 下载链接中包含 bot token，不记录在 span 中
+            let span = tracing::info_span!(
+                "download",
+                index = i + 1,
+                kind = ?item.kind,
+                bytes = tracing::field::Empty,
+            );
+            downloads.push(
+                async move {
+                    let download = async {
+                        download::download_image(
+                            &client,
+                            &external_client,
+                            &limiter,
+                            &progress,
+                            &cancel,
+                            &item.url,
+                            file_path,
+                            item.kind,
+                            timeout,
+                        )
+                        .await?;
+                        if let Ok(metadata) = tokio::fs::metadata(file_path).await {
+                            tracing::Span::current().record("bytes", metadata.len());
+                        }
+                        Ok(())
+                    };
+                    let result =
+                        resume::download_once(manifest, file_path, resuming, download).await;
+                    progress.finish_download();
+                    if let Ok(true) = result {
+                        resumed.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                    }
+                    result.map(|_| ()).map_err(|why| (i + 1, why))
+                }
+                .instrument(span),
+            );
+        }
+
+        let downloads = futures::future::join_all(downloads);
+        tokio::pin!(downloads);
+        let results = tokio::select! {
+            results = &mut downloads => results,
+            _ = tokio::time::sleep(config.job_timeout) => {
+                // 取消后未完成的下载会立即返回，已完成的结果仍然保留
+                download_cancel.cancel();
+                downloads.await
+            }
+            _ = tokio::time::sleep_until(deadline) => {
+                // 整个任务超时时不中止，发送已经完成的文件
+                process_timed_out = true;
+                download_cancel.cancel();
+                downloads.await
+            }
+        };
+        results
+            .into_iter()
+            .filter_map(Result::err)
+            .collect::<Vec<_>>()
+    };
+    // 磁盘已满时其余的文件也无法写入，整个任务失败，由调用方清理并告知用户
+    if let Some((index, why)) = failures.iter().find(|(_, why)| {
+        matches!(why, download::DownloadError::Io(why) if workspace::is_out_of_space(why))
+    }) {
+        log::error!(
+            "Job {}: out of disk space while downloading file {}: {}",
+            job_id,
+            index,
+            why
+        );
+        return Err(std::io::Error::from(std::io::ErrorKind::StorageFull).into());
+    }
+    let failed = failures.iter().map(|(index, _)| *index).collect::<Vec<_>>();
+    let summary = pipeline::DownloadSummary::new(&items, &failed);
+    let downloaded = summary.downloaded;
+    report.downloaded = downloaded;
+    let resume_report = match resumed.load(std::sync::atomic::Ordering::Relaxed) {
+        0 => FormattedText::new(),
+        resumed => FormattedText::from(format!(
+            "\n\n♻️ {} 个文件在上次失败前已经下载完成，没有重新下载",
+            resumed
+        )),
+    };
+    report.failures = failures
+        .iter()
+        .map(|(index, why)| (*index, why.to_string()))
+        .collect();
+    let audio_report = summary.describe_media();
+    let is_group = !messages_to_process[0].chat.is_private();
+    let credits_report = summary.describe_credits(is_group);
+    let stats_report = FormattedText::from(format!(
+        "（{}，下载用时 {}）",
+        format_size(progress.bytes()),
+        format_duration(started.elapsed())
+    ));
+
+    log::info!(
+        "Downloaded {}/{} photos to {}",
+        downloaded,
+        items.len(),
+        temp_dir.display()
+    );
+
+    if process_timed_out {
+        log::warn!(
+            "Job {} for chat {} exceeded PROCESS_TIMEOUT of {:?}, sending {}/{} files",
+            job_id,
+            chat_id,
+            config.process_timeout,
+            downloaded,
+            items.len()
+        );
+    }
+    let timeout_report = if process_timed_out {
+        let mut report = FormattedText::from(format!(
+            "\n\n⏰ 处理时间超过 {}，剩余的下载已中止，只发送已完成的文件",
+            format_duration(config.process_timeout)
+        ));
+        if unprocessed > 0 {
+            report = report.text(format!("，还有 {} 条消息没有处理", unprocessed));
+        }
+        report
+    } else {
+        FormattedText::new()
+    };
+
+    // 失败的图片不会被发送，逐条列出原因
+    let failure_report = timeout_report
+        .append(report.describe_failures())
+        .append(skipped_report);
+
+    if cancel.is_cancelled() {
+        log::warn!("Job {} for chat {} was cancelled", job_id, chat_id);
+        return report_aborted(chat, Some(&temp_dir), downloaded).await;
+    }
+
+    if download_cancel.is_cancelled() && !process_timed_out {
+        log::warn!(
+            "Download for chat {} timed out after {:?}",
+            chat_id,
+            config.job_timeout
+        );
+        // 已经下载的文件保留给 /retry
+        report.unfinished = true;
+        chat.reply(
+            FormattedText::from(format!(
+                "⏰ 下载超时（超过 {} 秒），任务已中止。已完成 {}/{} 张图片。",
+                config.job_timeout.as_secs(),
+                downloaded,
+                items.len()
+            ))
+            .append(retry_hint(&config)),
+        )
+        .await?;
+        return Ok(());
+    }
+
+    if downloaded == 0 {
+        report.unfinished = true;
+        chat.reply(
+            FormattedText::from(messages::text("all_failed", &[("count", &failures.len())]))
+                .append(failure_report)
+                .append(retry_hint(&config)),
+        )
+        .await?;
+        return Ok(());
+    }
+
+    // 先按方向标记旋转，水印才会加在显示时的角落
+    let rotate_report = match settings.auto_rotate {
+        true => {
+            let images = (1..=items.len())
+                .filter(|index| !failures.iter().any(|(failed, _)| failed == index))
+                .filter(|index| items[index - 1].kind == MediaKind::Image)
+                .map(|index| file_paths[index - 1].clone())
+                .filter(|path| orientation::supports(path))
+                .collect::<Vec<_>>();
+            let rotated = tokio::task::spawn_blocking(move || {
+                let mut rotated = 0;
+                for path in &images {
+                    match orientation::apply(path) {
+                        Ok(true) => rotated += 1,
+                        Ok(false) => {}
+                        Err(why) => log::warn!("Failed to rotate {}: {}", path.display(), why),
+                    }
+                }
+                rotated
+            })
+            .await?;
+            log::info!("Job {}: rotated {} images", job_id, rotated);
+            match rotated {
+                0 => FormattedText::new(),
+                rotated => {
+                    FormattedText::from(format!("\n\n🔄 已按 EXIF 方向标记旋转 {} 张图片", rotated))
+                }
+            }
+        }
+        false => FormattedText::new(),
+    };
+
+    // 打包或发送前加水印，无法解码的图片保持原样
+    let watermark_report = match (&settings.watermark, &config.watermark_font) {
+        (Some(watermark), Some(font)) => {
+            let images = (1..=items.len())
+                .filter(|index| !failures.iter().any(|(failed, _)| failed == index))
+                .filter(|index| items[index - 1].kind == MediaKind::Image)
+                .map(|index| file_paths[index - 1].clone())
+                .filter(|path| watermark::supports(path))
+                .collect::<Vec<_>>();
+            let (watermark, font) = (watermark.clone(), font.clone());
+            let (marked, unchanged) = tokio::task::spawn_blocking(move || {
+                let mut marked = 0;
+                for path in &images {
+                    match watermark::apply(path, &watermark, &font) {
+                        Ok(()) => marked += 1,
+                        Err(why) => log::warn!("Failed to watermark {}: {}", path.display(), why),
+                    }
+                }
+                (marked, images.len() - marked)
+            })
+            .await?;
+            log::info!("Job {}: watermarked {} images", job_id, marked);
+            let mut text = FormattedText::from(format!("\n\n💧 已为 {} 张图片添加水印", marked));
+            if unchanged > 0 {
+                text = text.text(format!("，{} 张无法解码，保持原样", unchanged));
+            }
+            text
+        }
+        _ => FormattedText::new(),
+    };
+    if cancel.is_cancelled() {
+        log::warn!("Job {} for chat {} was cancelled", job_id, chat_id);
+        return report_aborted(chat, Some(&temp_dir), downloaded).await;
+    }
+
+    if settings.output_mode == OutputMode::Documents {
+        let files = (1..=items.len())
+            .filter(|index| !failures.iter().any(|(failed, _)| failed == index))
+            .map(|index| {
+                let path = file_paths[index - 1].clone();
+                (index, path, items[index - 1].caption.clone())
+            })
+            .collect::<Vec<_>>();
+        let send_failures = tokio::select! {
+            failures = chat.send_documents(&files) => failures,
+            _ = cancel.cancelled() => {
+                return report_aborted(chat, Some(&temp_dir), downloaded).await;
+            }
+        };
+        tokio::fs::remove_dir_all(&temp_dir).await?;
+        log::info!("Cleaned up temporary files for chat {}", chat_id);
+        report.sent = files.len() - send_failures.len();
+
+        let mut reply = FormattedText::new()
+            .text("✅ 处理完成！共发送 ")
+            .bold(report.sent)
+            .text(" 张图片")
+            .append(audio_report)
+            .append(stats_report)
+            .append(credits_report)
+            .append(resume_report)
+            .append(rotate_report)
+            .append(watermark_report)
+            .append(failure_report);
+        if !send_failures.is_empty() {
+            reply = reply
+                .text("\n\n⚠️ 以下 ")
+                .bold(send_failures.len())
+                .text(" 张图片发送失败：");
+            for (index, why) in &send_failures {
+                reply = reply.text(format!("\n第 {} 张：{}", index, why));
+            }
+        }
+        let reply = reply.append(fast_report);
+        chat.reply(reply).await?;
+        return Ok(());
+    }
+
+    // 发布到 telegraph 失败时改为打包发送，原因附在打包的结果中
+    #[cfg_attr(not(feature = "telegraph"), allow(unused_mut))]
+    let mut telegraph_report = FormattedText::new();
+    #[cfg(feature = "telegraph")]
+    if settings.output_mode == OutputMode::Telegraph {
+        let telegraph = telegraph::Telegraph::new(client.clone(), &config.telegraph_token_file);
+        // telegraph 页面只能包含图片
+        let images = (1..=items.len())
+            .filter(|index| !failures.iter().any(|(failed, _)| failed == index))
+            .filter(|index| items[index - 1].kind == MediaKind::Image)
+            .collect::<Vec<_>>();
+        let mut uploaded = Vec::with_capacity(images.len());
+        let mut upload_failures = Vec::new();
+        for (i, &index) in images.iter().enumerate() {
+            if cancel.is_cancelled() {
+                return report_aborted(chat, Some(&temp_dir), downloaded).await;
+            }
+            progress.start_uploading(format!("📤 上传到 telegraph {}/{}", i + 1, images.len()));
+            match telegraph.upload_image(&file_paths[index - 1]).await {
+                Ok(src) => uploaded.push((src, items[index - 1].caption.clone())),
+                Err(why) => {
+                    log::warn!(
+                        "Job {}: failed to upload image {} to telegraph: {}",
+                        job_id,
+                        index,
+                        why
+                    );
+                    upload_failures.push((index, why));
+                }
+            }
+        }
+
+        let mut upload_report = FormattedText::new();
+        if !upload_failures.is_empty() {
+            upload_report = upload_report
+                .text("\n\n⚠️ 以下 ")
+                .bold(upload_failures.len())
+                .text(" 张图片上传失败：");
+            for (index, why) in &upload_failures {
+                upload_report = upload_report.text(format!("\n第 {} 张：{}", index, why));
+            }
+        }
+        let published = match uploaded.is_empty() {
+            true => Err("没有图片上传到 telegraph".to_string()),
+            false => telegraph
+                .create_page(&archive_name, &uploaded)
+                .await
+                .map_err(|why| format!("无法发布 telegraph 页面：{}", why)),
+        };
+        match published {
+            Ok(url) => {
+                tokio::fs::remove_dir_all(&temp_dir).await?;
+                log::info!("Cleaned up temporary files for chat {}", chat_id);
+                log::info!("Job {}: published telegraph page {}", job_id, url);
+                report.published_url = Some(url.clone());
+                let reply = FormattedText::new()
+                    .text("✅ 已发布到 telegraph，共 ")
+                    .bold(uploaded.len())
+                    .text(" 张图片")
+                    .append(stats_report)
+                    .text(format!("\n{}", url))
+                    .append(credits_report)
+                    .append(resume_report)
+                    .append(rotate_report)
+                    .append(watermark_report)
+                    .append(upload_report)
+                    .append(failure_report)
+                    .append(fast_report);
+                chat.reply(reply).await?;
+                return Ok(());
+            }
+            Err(why) => {
+                log::warn!("Job {}: {}, falling back to zip", job_id, why);
+                telegraph_report = FormattedText::from(format!("\n\n⚠️ {}，已改为打包发送", why))
+                    .append(upload_report);
+            }
+        }
+    }
+
+    // 会话用过这个名称时加上序号，除非用户选择了覆盖
+    let requested_name = archive_name;
+    let archive_name = config
+        .archive_names
+        .claim(chat_id, &requested_name, overwrite_name)
+        .await;
+    report.archive_name = Some(archive_name.clone());
+    let rename_report = if archive_name != requested_name {
+        FormattedText::new()
+            .text("\n\nℹ️ 这个会话已经用过 ")
+            .code(&requested_name)
+            .text("，本次命名为 ")
+            .code(&archive_name)
+    } else {
+        FormattedText::new()
+    };
+
+    // 3. 按数量和大小分卷打包
+    let mut files = Vec::with_capacity(downloaded);
+    for index in 1..=items.len() {
+        if failures.iter().any(|(failed, _)| *failed == index) {
+            continue;
+        }
+        let path = &file_paths[index - 1];
+        let size = tokio::fs::metadata(path).await?.len();
+        files.push((path.clone(), size));
+    }
+    // 每张图片单独打包时每卷只有一个文件，数量超过上限时按普通方式打包，避免刷屏
+    let per_image = settings.per_image && files.len() <= config.per_image_limit;
+    let per_image_report = if settings.per_image && !per_image {
+        FormattedText::from(format!(
+            "\n\nℹ️ 共 {} 个文件，超过了单独打包 {} 个的上限，已按普通方式打包",
+            files.len(),
+            config.per_image_limit
+        ))
+    } else {
+        FormattedText::new()
+    };
+    let chunk_size = if per_image {
+        Some(1)
+    } else {
+        settings.chunk_size
+    };
+    let mut volumes = archive::split_volumes(&files, chunk_size, archive::MAX_VOLUME_SIZE);
+    let collected = {
+        let dates = messages_to_process.iter().map(|msg| msg.date);
+        let first = dates.clone().min().unwrap_or_default();
+        let last = dates.max().unwrap_or_default();
+        (
+            first.with_timezone(&chrono::Local),
+            last.with_timezone(&chrono::Local),
+        )
+    };
+    let builder = Arc::new(VolumeBuilder {
+        temp_dir: temp_dir.clone(),
+        archive_name: archive_name.clone(),
+        settings: settings.clone(),
+        job_id,
+        chat_id,
+        chat_label: describe_chat(&messages_to_process[0].chat),
+        chat_info: chat_export::ChatInfo::of(&messages_to_process[0].chat),
+        collected,
+        is_group,
+        file_sizes: files.iter().cloned().collect(),
+        file_contributors: file_paths
+            .iter()
+            .cloned()
+            .zip(items.iter().map(|item| item.contributor.clone()))
+            .collect(),
+        file_kinds: file_paths
+            .iter()
+            .cloned()
+            .zip(items.iter().map(|item| item.kind))
+            .collect(),
+        file_caption_files: file_paths
+            .iter()
+            .zip(&items)
+            .filter_map(|(path, item)| Some((path.clone(), item.caption_file.clone()?)))
+            .collect(),
+        file_origins: file_paths
+            .iter()
+            .cloned()
+            .zip(&items)
+            .map(|(path, item)| (path, item.origin.clone().with_text(item.caption.clone())))
+            .collect(),
+        file_durations: file_paths
+            .iter()
+            .zip(&items)
+            .filter_map(|(path, item)| Some((path.clone(), item.duration?)))
+            .collect(),
+        file_video_note_lengths: file_paths
+            .iter()
+            .zip(&items)
+            .filter_map(|(path, item)| Some((path.clone(), item.video_note_length?)))
+            .collect(),
+        sticker_sets,
+        single_file_names: per_image.then(|| archive::single_file_zip_names(&volumes)),
+        progress: Arc::clone(&progress),
+    });
+
+    // 4. 逐个发送 ZIP 文件，某一卷发送失败时继续发送其余的，最后统一报告
+    //
+    // 发送当前分卷时后面的分卷已经在后台打包，最多提前 `ZIP_CONCURRENCY` 卷，
+    // 避免同时在磁盘上留下太多还没发送的压缩包
+    progress.start_compressing();
+    let mut builds = HashMap::new();
+    // 所有分卷打包耗时的总和，以及发送流程实际等待打包的时间
+    let mut build_time = Duration::ZERO;
+    let mut build_wait = Duration::ZERO;
+    let mut i = 0;
+    while i < volumes.len() {
+        for j in i..volumes.len().min(i + config.zip_concurrency) {
+            builds.entry(j).or_insert_with(|| {
+                builder.spawn(&config.zip_slots, volumes[j].clone(), j, volumes.len())
+            });
+        }
+        let volume = &volumes[i];
+        if cancel.is_cancelled() {
+            log::warn!("Job {} for chat {} was cancelled", job_id, chat_id);
+            builder.discard(&mut builds, volumes.len()).await;
+            return report_aborted(chat, Some(&temp_dir), downloaded).await;
+        }
+
+        // 压缩包也放在临时目录中，随临时目录一起清理
+        let zip_filename = builder.zip_name(i, volumes.len());
+        let zip_path = temp_dir.join(&zip_filename);
+        let waiting_since = std::time::Instant::now();
+        let built = builds.remove(&i).expect("volume build was spawned").await?;
+        build_wait += waiting_since.elapsed();
+        match built {
+            Ok(elapsed) => build_time += elapsed,
+            Err(why) => {
+                builder.discard(&mut builds, volumes.len()).await;
+                return Err(why);
+            }
+        }
+        let zip_size = tokio::fs::metadata(&zip_path).await?.len();
+
+        // 上传前告知压缩包的实际大小；单张图片就超过分卷上限时压缩包可能无法上传
+        let mut too_large = false;
+        let mut errors = Vec::new();
+        let mut delivered = false;
+        if settings.delivery.sends_to_chat() {
+            too_large = zip_size > archive::UPLOAD_LIMIT;
+            let sent = if too_large {
+                Err(format!(
+                    "压缩包大小 {} 超过了telegram {} 的上传限制",
+                    format_size(zip_size),
+                    format_size(archive::UPLOAD_LIMIT)
+                ))
+            } else {
+                progress.start_uploading(format!(
+                    "📤 上传中 {}/{} · {} · {}",
+                    i + 1,
+                    volumes.len(),
+                    zip_filename,
+                    format_size(zip_size)
+                ));
+                // 分卷按顺序逐个回复同一条消息，并在说明中标明是同一组的第几卷
+                let caption = match per_image {
+                    true => Some(format!(
+                        "📦 {} · 第 {}/{} 张",
+                        archive_name,
+                        i + 1,
+                        volumes.len()
+                    )),
+                    false => (volumes.len() > 1).then(|| {
+                        format!("📦 {} · 第 {}/{} 卷", archive_name, i + 1, volumes.len())
+                    }),
+                };
+                chat.send_archive(&zip_path, caption.as_deref(), job_id)
+                    .instrument(tracing::info_span!(
+                        "send",
+                        file = %zip_filename,
+                        bytes = zip_size
+                    ))
+                    .await
+                    .map_err(|why| {
+                        too_large = output::is_too_large(&why);
+                        why.to_string()
+                    })
+            };
+            match sent {
+                Ok(_) => {
+                    log::info!("Sent zip file {} to chat {}", zip_filename, chat_id);
+                    delivered = true;
+                }
+                Err(why) => {
+                    log::warn!(
+                        "Failed to send zip file {} to chat {}: {}",
+                        zip_filename,
+                        chat_id,
+                        why
+                    );
+                    errors.push(why);
+                }
+            }
+        }
+
+        // 太大的分卷拆成两半重新打包上传
+        if too_large && volume.len() > 1 {
+            tokio::fs::remove_file(&zip_path).await?;
+            log::warn!(
+                "Job {}: {} is too large to upload, splitting it",
+                job_id,
+                zip_filename
+            );
+            // 之后分卷的编号和总数都会变化，已经打包好的需要重新打包
+            builder.discard(&mut builds, volumes.len()).await;
+            let half = volume.len() / 2;
+            let second_half = volumes[i].split_off(half);
+            volumes.insert(i + 1, second_half);
+            continue;
+        }
+
+        let mut remote_path = None;
+        if settings.delivery.uploads_to_sftp() {
+            progress.start_uploading(format!(
+                "📤 上传到 SFTP {}/{} · {} · {}",
+                i + 1,
+                volumes.len(),
+                zip_filename,
+                format_size(zip_size)
+            ));
+            match deliver_sftp(&config, chat_id, &zip_path).await {
+                Ok(path) => {
+                    log::info!("Job {}: uploaded {} to sftp {}", job_id, zip_filename, path);
+                    remote_path = Some(path);
+                    delivered = true;
+                }
+                Err(why) => errors.push(format!("上传到 SFTP 失败：{}", why)),
+            }
+        }
+        tokio::fs::remove_file(&zip_path).await?;
+
+        report.archives.push(job_report::ArchivePart {
+            name: zip_filename,
+            count: volume.len(),
+            size: zip_size,
+            remote_path,
+            errors,
+            delivered,
+        });
+        i += 1;
+    }
+    if volumes.len() > 1 {
+        log::info!(
+            "Job {}: built {} volumes in {:?} with up to {} at a time, waited {:?} for them ({:.1}x)",
+            job_id,
+            volumes.len(),
+            build_time,
+            config.zip_concurrency,
+            build_wait,
+            build_time.as_secs_f64() / build_wait.as_secs_f64().max(0.001)
+        );
+    }
+
+    // 只有一个压缩包时没能送达就是整个任务失败，下载的文件保留给 /retry
+    if let Some(why) = report.single_archive_error() {
+        return Err(why.into());
+    }
+
+    // 5. 清理临时文件和目录
+    tokio::fs::remove_dir_all(&temp_dir).await?;
+    log::info!("Cleaned up temporary files for chat {}", chat_id);
+
+    let failed_parts = report.failed_archives();
+    let headline = if failed_parts == 0 {
+        let size = format_size(report.archives.iter().map(|part| part.size).sum());
+        messages::text(
+            "archive_done",
+            &[
+                ("count", &downloaded),
+                ("size", &size),
+                ("filename", &archive_name),
+            ],
+        )
+    } else {
+        format!("⚠️ 处理完成，但有 {} 个压缩包没能全部送达。", failed_parts)
+    };
+    let reply = FormattedText::new()
+        .text(headline)
+        .text("共打包 ")
+        .bold(downloaded)
+        .text(" 张图片")
+        .append(audio_report)
+        .append(stats_report)
+        .append(credits_report)
+        .append(resume_report)
+        .append(rotate_report)
+        .append(watermark_report)
+        .append(report.describe_archives())
+        .append(rename_report)
+        .append(per_image_report)
+        .append(telegraph_report)
+        .append(failure_report)
+        .append(fast_report);
+    chat.reply(reply).await?;
+    if settings.clean_chat {
+        chat.delete_messages(&transient).await;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn collecting(started: Duration) -> UserState {
+        let mut user_state = UserState::default();
+        user_state.start_collecting();
+        user_state.started_at = Some(std::time::Instant::now() - started);
+        user_state
+    }
+
+    #[test]
+    fn session_limit_boundary() {
+        const MAX: usize = 3;
+        let mut sessions = HashMap::new();
+        for id in 0..MAX as i64 - 1 {
+            sessions.insert(ChatId(id), collecting(Duration::ZERO));
+        }
+        // 第N个会话可以开始
+        assert!(has_session_slot(&sessions, ChatId(10), MAX));
+        sessions.insert(ChatId(10), collecting(Duration::ZERO));
+        // 第N+1个会话被拒绝
+        assert!(!has_session_slot(&sessions, ChatId(11), MAX));
+        // 已经在收集的会话重新开始不占用新的名额
+        assert!(has_session_slot(&sessions, ChatId(10), MAX));
+        // 没有在收集的会话不占用名额
+        sessions.insert(ChatId(12), UserState::default());
+        assert!(!has_session_slot(&sessions, ChatId(11), MAX));
+        assert!(has_session_slot(&sessions, ChatId(11), 0));
+    }
+
+    #[test]
+    fn idle_session_releases_slot() {
+        let timeout = Duration::from_secs(60 * 60);
+        let mut sessions = HashMap::new();
+        sessions.insert(ChatId(1), collecting(Duration::from_secs(2 * 60 * 60)));
+        sessions.insert(ChatId(2), collecting(Duration::from_secs(2 * 60 * 60)));
+        sessions.get_mut(&ChatId(2)).unwrap().last_activity = Some(std::time::Instant::now());
+        assert!(!has_session_slot(&sessions, ChatId(3), 2));
+
+        let expired = sessions
+            .values_mut()
+            .filter_map(|user_state| user_state.expire_idle(timeout))
+            .count();
+        assert_eq!(expired, 1);
+        let idle = &sessions[&ChatId(1)];
+        assert!(!idle.is_collecting());
+        assert!(idle.cancelled.is_some());
+        assert!(sessions[&ChatId(2)].is_collecting());
+        assert!(has_session_slot(&sessions, ChatId(3), 2));
+    }
+
+    #[test]
+    fn stop_without_collecting_keeps_state() {
+        let mut user_state = UserState {
+            file_name: Some("trip".into()),
+            pack_count: 2,
+            ..Default::default()
+        };
+        let rejection = user_state.take(BatchSource::Stop).unwrap_err();
+        assert!(matches!(rejection, StopRejection::NotCollecting));
+        assert_eq!(user_state.mode, SessionMode::Idle);
+        assert_eq!(user_state.file_name.as_deref(), Some("trip"));
+        assert_eq!(user_state.pack_count, 2);
+        assert!(user_state.last_batch.is_none());
+    }
+
+    #[test]
+    fn stop_empty_collection_ends_it_and_keeps_file_name() {
+        let mut user_state = collecting(Duration::ZERO);
+        user_state.file_name = Some("trip".into());
+        let rejection = user_state.take(BatchSource::Stop).unwrap_err();
+        assert!(matches!(rejection, StopRejection::Empty));
+        assert!(!user_state.is_collecting());
+        assert_eq!(user_state.file_name.as_deref(), Some("trip"));
+        assert_ne!(
+            StopRejection::Empty.message(),
+            StopRejection::NotCollecting.message()
+        );
+    }
+
+    #[test]
+    fn pack_empty_collection_keeps_collecting() {
+        let mut user_state = collecting(Duration::ZERO);
+        let rejection = user_state.take(BatchSource::Pack).unwrap_err();
+        assert!(matches!(rejection, StopRejection::NothingToPack));
+        assert!(user_state.is_collecting());
+    }
+
+    fn parse(text: &str) -> Option<Command> {
+        let aliases = aliases::CommandAliases::default();
+        parse_command(&test_util::text(1, text), &test_util::me(), &aliases)
+    }
+
+    fn parse_file_name(text: &str) -> String {
+        match parse(text) {
+            Some(Command::FileName(name)) => name,
+            _ => panic!("{:?} is not a /filename command", text),
+        }
+    }
+
+    #[test]
+    fn file_name_argument_keeps_spaces_and_emoji() {
+        assert_eq!(
+            parse_file_name("/filename my trip photos"),
+            "my trip photos"
+        );
+        assert_eq!(parse_file_name("/name 🏖️ 海边 2024"), "🏖️ 海边 2024");
+        assert_eq!(parse_file_name("/filename"), "");
+        assert_eq!(
+            parse_file_name(&format!("/filename@{} a b", test_util::BOT_USERNAME)),
+            "a b"
+        );
+    }
+
+    #[test]
+    fn inline_file_name_is_sanitized() {
+        let name = parse_file_name("/filename /etc/passwd");
+        assert_eq!(name, "/etc/passwd");
+        let mut user_state = UserState::default();
+        assert_eq!(user_state.set_file_name(&name), Some("_etc_passwd"));
+        assert_eq!(user_state.set_file_name("  my trip  "), Some("my trip"));
+        assert_eq!(user_state.set_file_name("..."), None);
+        assert_eq!(user_state.file_name.as_deref(), Some("my trip"));
+    }
+
+    #[test]
+    fn file_name_prompt_expires() {
+        let mut user_state = UserState::default();
+        assert!(user_state.prompt_file_name());
+        assert!(user_state.is_set_file_name());
+        user_state.mode =
+            SessionMode::AwaitingFileName(std::time::Instant::now() - FILE_NAME_PROMPT_TIMEOUT);
+        assert!(!user_state.is_set_file_name());
+        // 设置文件名后不再等待
+        assert!(user_state.prompt_file_name());
+        user_state.set_file_name("trip");
+        assert_eq!(user_state.mode, SessionMode::Idle);
+    }
+
+    #[test]
+    fn prompt_and_collecting_are_exclusive() {
+        let mut user_state = UserState::default();
+        assert!(!user_state.is_active());
+        assert!(user_state.prompt_file_name());
+        assert!(user_state.is_active());
+        // 开始收集后不再等待文件名
+        user_state.start_collecting();
+        assert_eq!(user_state.mode, SessionMode::Collecting);
+        assert!(!user_state.is_set_file_name());
+        // 收集中不能等待文件名，/filename 带参数时直接设置，收集继续
+        assert!(!user_state.prompt_file_name());
+        assert_eq!(user_state.mode, SessionMode::Collecting);
+        user_state.set_file_name("trip");
+        assert_eq!(user_state.mode, SessionMode::Collecting);
+        assert_eq!(user_state.file_name.as_deref(), Some("trip"));
+    }
+
+    #[test]
+    fn collecting_ends_in_idle() {
+        let mut user_state = collecting(Duration::ZERO);
+        user_state.messages.push(test_util::text(1, "a"));
+        // /pack 之后继续收集
+        user_state.take(BatchSource::Pack).unwrap();
+        assert_eq!(user_state.mode, SessionMode::Collecting);
+        user_state.messages.push(test_util::text(2, "b"));
+        user_state.take(BatchSource::Stop).unwrap();
+        assert_eq!(user_state.mode, SessionMode::Idle);
+        // 结束后可以等待文件名
+        assert!(user_state.prompt_file_name());
+
+        let mut user_state = collecting(Duration::ZERO);
+        user_state.messages.push(test_util::text(1, "a"));
+        assert_eq!(user_state.cancel_collecting(std::time::Instant::now()), 1);
+        assert_eq!(user_state.mode, SessionMode::Idle);
+
+        let mut user_state = collecting(Duration::from_secs(60));
+        assert_eq!(user_state.expire_idle(Duration::from_secs(30)), Some(0));
+        assert_eq!(user_state.mode, SessionMode::Idle);
+    }
+
+    #[test]
+    fn switching_collections_restores_mode() {
+        let mut user_state = UserState::default();
+        assert!(user_state.prompt_file_name());
+        // 切换收集时不再等待文件名
+        user_state.switch_collection("work");
+        assert_eq!(user_state.mode, SessionMode::Idle);
+        user_state.start_collecting();
+        user_state.switch_collection(DEFAULT_COLLECTION);
+        assert_eq!(user_state.mode, SessionMode::Idle);
+        user_state.switch_collection("work");
+        assert_eq!(user_state.mode, SessionMode::Collecting);
+    }
+
+    #[test]
+    fn accepts_bare_commands() {
+        let msg = test_util::group_text(1, "/startcollect");
+        assert!(!is_addressed_elsewhere(&msg, &test_util::me()));
+        assert!(matches!(
+            parse("/startcollect"),
+            Some(Command::StartCollect)
+        ));
+    }
+
+    #[test]
+    fn accepts_commands_addressed_to_this_bot() {
+        let text = format!("/startcollect@{}", test_util::BOT_USERNAME.to_uppercase());
+        let msg = test_util::group_text(1, &text);
+        assert!(!is_addressed_elsewhere(&msg, &test_util::me()));
+        let text = format!("/startcollect@{}", test_util::BOT_USERNAME);
+        assert!(matches!(parse(&text), Some(Command::StartCollect)));
+    }
+
+    #[test]
+    fn ignores_commands_addressed_to_other_bots() {
+        let msg = test_util::group_text(1, "/startcollect@other_bot");
+        assert!(is_addressed_elsewhere(&msg, &test_util::me()));
+        assert!(parse("/startcollect@other_bot").is_none());
+        // 普通消息不是命令
+        let msg = test_util::group_text(2, "hello @other_bot");
+        assert!(!is_addressed_elsewhere(&msg, &test_util::me()));
+    }
+
+    #[test]
+    fn help_mentions_bot_name_only_in_groups() {
+        let me = test_util::me();
+        let private = test_util::text(1, "/help");
+        assert_eq!(help_text(&private.chat, &me), HELP_TEXT);
+        let group = test_util::group_text(1, "/help");
+        let help = help_text(&group.chat, &me);
+        assert!(help.contains(&format!("/startcollect@{}", test_util::BOT_USERNAME)));
+    }
+
+    fn failed_job(expires_in: Duration, now: std::time::Instant) -> FailedJob {
+        FailedJob {
+            job_id: Uuid::new_v4(),
+            batch: Batch {
+                messages: vec![test_util::text(1, "photo")],
+                file_name: Some("holiday".to_string()),
+                overwrite_name: false,
+                part: None,
+                settings: ChatSettings::default(),
+                resume: None,
+            },
+            expires_at: now + expires_in,
+        }
+    }
+
+    #[test]
+    fn retry_resumes_failed_job() {
+        let now = std::time::Instant::now();
+        let mut user_state = UserState::default();
+        let failed = failed_job(Duration::from_secs(60), now);
+        let job_id = failed.job_id;
+        user_state.failed_job = Some(failed);
+
+        assert_eq!(user_state.expire_failed_job(now), None);
+        let batch = user_state.take(BatchSource::Retry).unwrap();
+        assert_eq!(batch.resume, Some(job_id));
+        assert_eq!(batch.file_name.as_deref(), Some("holiday"));
+        // 重试后不能再次重试同一个任务
+        assert!(matches!(
+            user_state.take(BatchSource::Retry),
+            Err(StopRejection::NoFailedJob)
+        ));
+    }
+
+    #[test]
+    fn expired_failed_job_is_left_for_janitor() {
+        let now = std::time::Instant::now();
+        let mut user_state = UserState::default();
+        let failed = failed_job(Duration::ZERO, now);
+        let job_id = failed.job_id;
+        user_state.failed_job = Some(failed);
+
+        assert!(matches!(
+            user_state.take(BatchSource::Retry),
+            Err(StopRejection::NoFailedJob)
+        ));
+        // 过期后仍然保留，由定期检查删除临时目录
+        assert_eq!(user_state.expire_failed_job(now), Some(job_id));
+        assert_eq!(user_state.expire_failed_job(now), None);
+    }
+
+    #[tokio::test]
+    async fn cancelling_mid_download_discards_workspace() {
+        use wiremock::matchers::{body_partial_json, method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/slow.jpg"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_bytes(vec![0; 1024])
+                    .set_delay(Duration::from_secs(5)),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(body_partial_json(serde_json::json!({
+                "chat_id": test_util::CHAT_ID,
+                "text": "⏹ 已中止，已下载的 2 个文件被丢弃",
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "ok": true,
+                "result": {
+                    "message_id": 2,
+                    "date": 0,
+                    "chat": {"id": test_util::CHAT_ID, "type": "private", "first_name": "Alice"},
+                    "text": "⏹",
+                },
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let bot = Bot::new("123:token").set_api_url(server.uri().parse().unwrap());
+
+        // 已经下载了两个文件，第三个正在下载
+        let root = tempfile::tempdir().unwrap();
+        let chat_id = ChatId(test_util::CHAT_ID);
+        let temp_dir = workspace::job_dir(root.path(), chat_id, Uuid::new_v4());
+        std::fs::create_dir_all(&temp_dir).unwrap();
+        std::fs::write(temp_dir.join("image_1.jpg"), b"one").unwrap();
+        std::fs::write(temp_dir.join("image_2.jpg"), b"two").unwrap();
+        let cancel = CancellationToken::new();
+        let abort = {
+            let cancel = cancel.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                cancel.cancel();
+            })
+        };
+        let client = Client::new();
+        let started = std::time::Instant::now();
+        let downloaded = download::download_image(
+            &client,
+            &client,
+            &RateLimiter::new(0),
+            &Progress::new(3, None),
+            &cancel,
+            &download::FileUrl::Remote(format!("{}/slow.jpg", server.uri())),
+            &temp_dir.join("image_3.jpg"),
+            MediaKind::Sticker,
+            Duration::from_secs(30),
+        )
+        .await;
+        abort.await.unwrap();
+        assert!(matches!(
+            downloaded,
+            Err(download::DownloadError::Cancelled)
+        ));
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let chat = TelegramChat {
+            bot: Arc::new(bot),
+            chat_id,
+            reply_to: MessageId(1),
+        };
+        report_aborted(&chat, Some(&temp_dir), 2).await.unwrap();
+        assert!(!temp_dir.exists());
+        // 其他任务的目录不受影响
+        assert!(root.path().exists());
+    }
+
+    fn update(id: u32, kind: teloxide::types::UpdateKind) -> Update {
+        Update {
+            id: teloxide::types::UpdateId(id),
+            kind,
+        }
+    }
+
+    fn click(id: u32, data: &str) -> Update {
+        let query = serde_json::from_value(serde_json::json!({
+            "id": format!("query_{}", id),
+            "from": {"id": test_util::CHAT_ID, "is_bot": false, "first_name": "Alice"},
+            "chat_instance": "instance",
+            "data": data,
+        }))
+        .unwrap();
+        update(id, teloxide::types::UpdateKind::CallbackQuery(query))
+    }
+
+    #[tokio::test]
+    async fn rapid_duplicates_are_handled_once() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use wiremock::matchers::body_partial_json;
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(body_partial_json(serde_json::json!({
+            "text": "已收到，请勿重复点击",
+        })))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(serde_json::json!({"ok": true, "result": true})),
+        )
+        .expect(2)
+        .mount(&server)
+        .await;
+        let bot = Bot::new("123:token").set_api_url(server.uri().parse().unwrap());
+
+        let window = Duration::from_millis(300);
+        let debouncer = Arc::new(Debouncer::new(window));
+        let handled = Arc::new(AtomicUsize::new(0));
+        let handler = dptree::entry()
+            .branch(debounce_handler())
+            .branch(dptree::endpoint(|handled: Arc<AtomicUsize>| async move {
+                handled.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            }));
+        let feed = async |update: Update| {
+            let deps = dptree::deps![
+                update,
+                bot.clone(),
+                Arc::clone(&debouncer),
+                Arc::clone(&handled)
+            ];
+            let _ = handler.dispatch(deps).await;
+            handled.load(Ordering::SeqCst)
+        };
+        let message = |id: u32, msg: Message| update(id, teloxide::types::UpdateKind::Message(msg));
+
+        // 连续发送的相同命令只处理第一次，多余的空白不影响比较
+        assert_eq!(
+            feed(message(1, test_util::text(1, "/startcollect"))).await,
+            1
+        );
+        assert_eq!(
+            feed(message(2, test_util::text(2, "/startcollect"))).await,
+            1
+        );
+        assert_eq!(
+            feed(message(3, test_util::text(3, "/startcollect  "))).await,
+            1
+        );
+        // 不同的命令、其他会话中的相同命令照常处理
+        assert_eq!(
+            feed(message(4, test_util::text(4, "/stopcollect"))).await,
+            2
+        );
+        assert_eq!(
+            feed(message(5, test_util::group_text(5, "/startcollect"))).await,
+            3
+        );
+        // 普通消息不去重
+        assert_eq!(feed(message(6, test_util::text(6, "hello"))).await, 4);
+        assert_eq!(feed(message(7, test_util::text(7, "hello"))).await, 5);
+
+        // 重复的点击只回复提示
+        assert_eq!(feed(click(8, "stop")).await, 6);
+        assert_eq!(feed(click(9, "stop")).await, 6);
+        assert_eq!(feed(click(10, "stop")).await, 6);
+        assert_eq!(feed(click(11, "cancel")).await, 7);
+
+        // 超过窗口后再次处理
+        tokio::time::sleep(window).await;
+        assert_eq!(
+            feed(message(12, test_util::text(12, "/startcollect"))).await,
+            8
+        );
+        assert_eq!(feed(click(13, "stop")).await, 9);
+    }
+
+    #[tokio::test]
+    async fn interrupted_jobs_are_cleaned_and_reported() {
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(|request: &wiremock::Request| request.url.path().ends_with("/SendMessage"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "ok": true,
+                "result": {
+                    "message_id": 1,
+                    "date": 0,
+                    "chat": {"id": test_util::CHAT_ID, "type": "private", "first_name": "Alice"},
+                    "text": "⚠️",
+                },
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let bot = Bot::new("123:token").set_api_url(server.uri().parse().unwrap());
+
+        // 上次运行留下的记录：一个任务被中断，一个正常结束
+        let root = tempfile::tempdir().unwrap();
+        let journal = JobJournal::new(root.path().join("jobs.log"));
+        let chat_id = ChatId(test_util::CHAT_ID);
+        let (interrupted, finished) = (Uuid::new_v4(), Uuid::new_v4());
+        journal.started(interrupted, chat_id, 3);
+        journal.started(finished, chat_id, 5);
+        journal.finished(finished);
+        let leftover = workspace::job_dir(root.path(), chat_id, interrupted);
+        std::fs::create_dir_all(&leftover).unwrap();
+        std::fs::write(leftover.join("image_1.jpg"), b"partial").unwrap();
+        let other = workspace::job_dir(root.path(), chat_id, finished);
+        std::fs::create_dir_all(&other).unwrap();
+
+        recover_interrupted_jobs(&bot, &journal, root.path()).await;
+        assert!(!leftover.exists());
+        assert!(other.exists());
+        let requests = server.received_requests().await.unwrap();
+        let body: serde_json::Value = requests[0].body_json().unwrap();
+        assert_eq!(body["chat_id"], test_util::CHAT_ID);
+        let text = body["text"].as_str().unwrap().replace('\\', "");
+        assert!(text.contains("3 条消息"), "{}", text);
+        assert!(text.contains("/startcollect"), "{}", text);
+        assert!(text.contains(&interrupted.to_string()), "{}", text);
+
+        // 记录已经清空，再次启动时不会重复通知
+        recover_interrupted_jobs(&bot, &journal, root.path()).await;
+        assert_eq!(server.received_requests().await.unwrap().len(), 1);
+    }
+
+    /// 测试任务使用的配置，所有文件都放在 `root` 中
+    fn test_config(root: &Path) -> Config {
+        Config {
+            bot_token: BotToken("123:token".to_string()),
+            proxy: None,
+            admin_ids: Vec::new(),
+            admin_chats: Vec::new(),
+            user_agent: "test".to_string(),
+            link_user_agent: "test link-fetcher".to_string(),
+            download_headers: reqwest::header::HeaderMap::new(),
+            max_file_bytes: 0,
+            zip_import_max_bytes: 20 * 1024 * 1024,
+            zip_import_max_extracted: 200 * 1024 * 1024,
+            heif_jpeg_quality: 90,
+            watermark_font: None,
+            avatar_limit: 10,
+            download_timeout: Duration::from_secs(10),
+            job_timeout: Duration::from_secs(60),
+            process_timeout: Duration::from_secs(60),
+            max_download_rate: 0,
+            max_active_sessions: 0,
+            collect_idle_timeout: Duration::ZERO,
+            max_concurrent_jobs: 1,
+            zip_concurrency: 1,
+            zip_slots: Arc::new(tokio::sync::Semaphore::new(1)),
+            per_image_limit: 20,
+            welcome_new_chats: false,
+            known_chats_file: root.join("known_chats.txt").display().to_string(),
+            archive_names: Arc::new(ArchiveNames::load(root.join("archive_names.txt"))),
+            job_journal: Arc::new(JobJournal::new(root.join("jobs.txt"))),
+            temp_root: root.join("temp"),
+            temp_max_age: Duration::from_secs(60 * 60),
+            temp_quota: 0,
+            retry_window: Duration::from_secs(60 * 60),
+            #[cfg(feature = "telegraph")]
+            telegraph_token_file: root.join("telegraph_token.txt"),
+            #[cfg(feature = "sftp")]
+            sftp: None,
+            report_schedule: None,
+            update_check: false,
+            notify_webhook: None,
+            default_settings: ChatSettings::default(),
+            command_aliases: aliases::CommandAliases::default(),
+        }
+    }
+
+    /// 不连接 telegram 的会话，记录发送的消息和每个压缩包中的文件
+    struct FakeChat {
+        /// 文件都从这里下载，地址为 `<server>/<file_id>`
+        server: String,
+        /// 发送压缩包时返回的错误
+        archive_error: Option<String>,
+        replies: std::sync::Mutex<Vec<String>>,
+        archives: std::sync::Mutex<Vec<Vec<String>>>,
+    }
+
+    impl FakeChat {
+        fn new(server: &wiremock::MockServer) -> Self {
+            FakeChat {
+                server: server.uri(),
+                archive_error: None,
+                replies: Default::default(),
+                archives: Default::default(),
+            }
+        }
+
+        fn replies(&self) -> Vec<String> {
+            self.replies.lock().unwrap().clone()
+        }
+
+        fn archives(&self) -> Vec<Vec<String>> {
+            self.archives.lock().unwrap().clone()
+        }
+    }
+
+    impl JobChat for FakeChat {
+        async fn get_file(
+            &self,
+            file_id: &teloxide::types::FileId,
+        ) -> Result<job_chat::RemoteFile, teloxide::RequestError> {
+            Ok(job_chat::RemoteFile {
+                url: download::FileUrl::Remote(format!("{}/{}", self.server, file_id.0)),
+                size: 1024,
+            })
+        }
+
+        async fn reply(
+            &self,
+            text: impl Into<FormattedText> + Send,
+        ) -> Result<MessageId, teloxide::RequestError> {
+            let mut replies = self.replies.lock().unwrap();
+            replies.push(text.into().plain().to_string());
+            Ok(MessageId(100 + replies.len() as i32))
+        }
+
+        fn show_progress(&self, _: MessageId, _: Arc<Progress>, _: CancellationToken) {}
+
+        async fn send_albums(
+            &self,
+            messages: &[Message],
+            _: bool,
+        ) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+            Ok(messages.len())
+        }
+
+        async fn send_documents(
+            &self,
+            _: &[(usize, PathBuf, Option<String>)],
+        ) -> Vec<(usize, teloxide::RequestError)> {
+            Vec::new()
+        }
+
+        async fn send_archive(
+            &self,
+            path: &Path,
+            _: Option<&str>,
+            _: Uuid,
+        ) -> Result<(), teloxide::RequestError> {
+            if let Some(why) = &self.archive_error {
+                return Err(teloxide::RequestError::Api(teloxide::ApiError::Unknown(
+                    why.clone(),
+                )));
+            }
+            let zip = zip::ZipArchive::new(std::fs::File::open(path).unwrap()).unwrap();
+            let mut names = zip.file_names().map(str::to_string).collect::<Vec<_>>();
+            names.sort();
+            self.archives.lock().unwrap().push(names);
+            Ok(())
+        }
+
+        async fn delete_messages(&self, _: &[MessageId]) {}
+    }
+
+    const GIF: &[u8] = b"GIF89a\x01\x00\x01\x00\x80\x00\x00\xff\xff\xff\x00\x00\x00!\xf9\x04\x01\x00\x00\x00\x00,\x00\x00\x00\x00\x01\x00\x01\x00\x00\x02\x02D\x01\x00;";
+
+    /// 在 `server` 上提供 `file_id` 对应的图片
+    async fn serve_image(server: &wiremock::MockServer, file_id: &str) {
+        use wiremock::matchers::path;
+        use wiremock::{Mock, ResponseTemplate};
+
+        Mock::given(path(format!("/{}", file_id)))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(GIF))
+            .mount(server)
+            .await;
+    }
+
+    fn batch(messages: Vec<Message>) -> Batch {
+        Batch {
+            messages,
+            file_name: Some("trip".to_string()),
+            overwrite_name: false,
+            part: None,
+            settings: ChatSettings::default(),
+            resume: None,
+        }
+    }
+
+    async fn run_test_job(
+        chat: &FakeChat,
+        config: &Arc<Config>,
+        job_id: Uuid,
+        batch: Batch,
+    ) -> Result<job_report::ProcessingReport, JobError> {
+        run_job(
+            chat,
+            ChatId(test_util::CHAT_ID),
+            job_id,
+            batch,
+            Client::new(),
+            Arc::clone(config),
+            Arc::new(RateLimiter::new(0)),
+            &CancellationToken::new(),
+            BatchSource::Stop,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn job_archives_downloaded_files() {
+        let server = wiremock::MockServer::start().await;
+        serve_image(&server, "photo_1_0").await;
+        serve_image(&server, "photo_2_0").await;
+        let chat = FakeChat::new(&server);
+        let root = tempfile::tempdir().unwrap();
+        let config = Arc::new(test_config(root.path()));
+        let job_id = Uuid::new_v4();
+
+        // 第三张图片无法下载
+        let messages = vec![
+            test_util::photo(1, 100, &[(800, 600)]),
+            test_util::photo(2, 200, &[(800, 600)]),
+            test_util::photo(3, 300, &[(800, 600)]),
+        ];
+        let report = run_test_job(&chat, &config, job_id, batch(messages))
+            .await
+            .unwrap_or_else(|failure| panic!("{}", failure.error));
+
+        assert_eq!(report.items, 3);
+        assert_eq!(report.downloaded, 2);
+        assert_eq!(report.failures.len(), 1);
+        assert_eq!(report.failures[0].0, 3);
+        assert_eq!(report.archive_name.as_deref(), Some("trip"));
+        assert_eq!(report.archives.len(), 1);
+        assert!(report.archives[0].delivered);
+        assert_eq!(report.archives[0].count, 2);
+        assert_eq!(chat.archives(), [["image_1.jpg", "image_2.jpg"]]);
+
+        let replies = chat.replies();
+        assert_eq!(replies[0], messages::text("processing", &[]));
+        let result = replies.last().unwrap();
+        assert!(result.contains("共打包 2 张图片"), "{}", result);
+        assert!(result.contains("第 3 张"), "{}", result);
+        // 交付后删除临时目录
+        let chat_id = ChatId(test_util::CHAT_ID);
+        assert!(!workspace::job_dir(&config.temp_root, chat_id, job_id).exists());
+    }
+
+    #[tokio::test]
+    async fn job_without_downloads_keeps_workspace_for_retry() {
+        let server = wiremock::MockServer::start().await;
+        let chat = FakeChat::new(&server);
+        let root = tempfile::tempdir().unwrap();
+        let config = Arc::new(test_config(root.path()));
+        let job_id = Uuid::new_v4();
+
+        let messages = vec![test_util::photo(1, 100, &[(800, 600)])];
+        let report = run_test_job(&chat, &config, job_id, batch(messages))
+            .await
+            .unwrap_or_else(|failure| panic!("{}", failure.error));
+
+        assert!(report.unfinished);
+        assert_eq!(report.downloaded, 0);
+        assert!(report.archives.is_empty());
+        assert!(chat.archives().is_empty());
+        let result = chat.replies().pop().unwrap();
+        assert!(result.contains("/retry"), "{}", result);
+        let chat_id = ChatId(test_util::CHAT_ID);
+        assert!(workspace::job_dir(&config.temp_root, chat_id, job_id).exists());
+    }
+
+    #[tokio::test]
+    async fn failed_job_keeps_partial_report() {
+        let server = wiremock::MockServer::start().await;
+        serve_image(&server, "photo_1_0").await;
+        let mut chat = FakeChat::new(&server);
+        chat.archive_error = Some("Bad Request".to_string());
+        let root = tempfile::tempdir().unwrap();
+        let config = Arc::new(test_config(root.path()));
+
+        let messages = vec![test_util::photo(1, 100, &[(800, 600)])];
+        let Err(failure) = run_test_job(&chat, &config, Uuid::new_v4(), batch(messages)).await
+        else {
+            panic!("sending the only archive failed");
+        };
+
+        assert!(failure.error.to_string().contains("Bad Request"));
+        // 出错前的下载结果仍然用于统计和通知
+        assert_eq!(failure.report.downloaded, 1);
+        assert_eq!(failure.report.archives.len(), 1);
+        assert!(!failure.report.archives[0].delivered);
+    }
+
+    #[test]
+    fn default_archive_name_is_a_valid_file_name() {
+        use chrono::TimeZone;
+
+        let now = chrono::Local
+            .with_ymd_and_hms(2024, 3, 9, 14, 5, 0)
+            .unwrap();
+        let name = default_archive_name(now, ChatId(test_util::GROUP_ID));
+        assert_eq!(name, "images_2024-03-09-14-05_-1001000");
+        assert_eq!(
+            naming::sanitize_file_name(&name).as_deref(),
+            Some(name.as_str())
+        );
+    }
+
+    #[tokio::test]
+    async fn oversized_heif_keeps_links_in_caption() {
+        let server = wiremock::MockServer::start().await;
+        let chat = FakeChat::new(&server);
+        let root = tempfile::tempdir().unwrap();
+        let mut config = test_config(root.path());
+        config.max_file_bytes = 100;
+        let config = Arc::new(config);
+
+        // 超过大小限制的 HEIC 文件，说明中还带着一个图片链接
+        let link = format!("{}/cover.gif", server.uri());
+        let message = test_util::message(
+            1,
+            0,
+            serde_json::json!({
+                "document": {
+                    "file_id": "document_1",
+                    "file_unique_id": "unique_1",
+                    "file_name": "big.heic",
+                    "mime_type": "image/heic",
+                    "file_size": 1024,
+                },
+                "caption": link,
+                "caption_entities": [{
+                    "type": "url",
+                    "offset": 0,
+                    "length": link.encode_utf16().count(),
+                }],
+            }),
+        );
+        let report = run_test_job(&chat, &config, Uuid::new_v4(), batch(vec![message]))
+            .await
+            .unwrap_or_else(|failure| panic!("{}", failure.error));
+
+        assert_eq!(report.skips.too_large, [(1, 1024)]);
+        assert_eq!(report.items, 1);
+    }
 }
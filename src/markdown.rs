@@ -0,0 +1,234 @@
+use std::fmt::Display;
+use teloxide::prelude::*;
+use teloxide::requests::HasPayload;
+use teloxide::types::{InlineKeyboardMarkup, MessageId, ParseMode, ReplyParameters};
+use teloxide::{ApiError, RequestError};
+
+/// MarkdownV2 中需要转义的字符
+const SPECIAL_CHARS: &[char] = &[
+    '\\', '_', '*', '[', ']', '(', ')', '~', '`', '>', '#', '+', '-', '=', '|', '{', '}', '.', '!',
+];
+
+/// 转义普通文本，使其可以原样出现在 MarkdownV2 消息中
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if SPECIAL_CHARS.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// 转义代码和代码块中的文本，其中只有 `` ` `` 和 `\` 需要转义
+pub fn escape_code(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '`' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// 带格式的消息，同时保存 MarkdownV2 和纯文本两种形式
+///
+/// 格式化的消息被telegram拒绝时，可以改为发送纯文本。
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FormattedText {
+    markdown: String,
+    plain: String,
+}
+
+impl FormattedText {
+    pub fn new() -> Self {
+        FormattedText::default()
+    }
+
+    /// 普通文本
+    pub fn text(mut self, text: impl Display) -> Self {
+        let text = text.to_string();
+        self.markdown.push_str(&escape(&text));
+        self.plain.push_str(&text);
+        self
+    }
+
+    /// 粗体，用于数量等需要突出的内容
+    pub fn bold(mut self, text: impl Display) -> Self {
+        let text = text.to_string();
+        self.markdown.push_str(&format!("*{}*", escape(&text)));
+        self.plain.push_str(&text);
+        self
+    }
+
+    /// 等宽文本，用于文件名
+    pub fn code(mut self, text: impl Display) -> Self {
+        let text = text.to_string();
+        self.markdown.push_str(&format!("`{}`", escape_code(&text)));
+        self.plain.push_str(&text);
+        self
+    }
+
+    /// 代码块，用于错误信息
+    pub fn code_block(mut self, text: impl Display) -> Self {
+        let text = text.to_string();
+        self.markdown
+            .push_str(&format!("```\n{}\n```", escape_code(&text)));
+        self.plain.push_str(&text);
+        self
+    }
+
+    /// 追加另一段带格式的消息
+    pub fn append(mut self, other: FormattedText) -> Self {
+        self.markdown.push_str(&other.markdown);
+        self.plain.push_str(&other.plain);
+        self
+    }
+
+    /// 不带格式的内容
+    #[cfg(test)]
+    pub fn plain(&self) -> &str {
+        &self.plain
+    }
+}
+
+impl From<&str> for FormattedText {
+    fn from(text: &str) -> Self {
+        FormattedText::new().text(text)
+    }
+}
+
+impl From<&String> for FormattedText {
+    fn from(text: &String) -> Self {
+        FormattedText::new().text(text)
+    }
+}
+
+impl From<String> for FormattedText {
+    fn from(text: String) -> Self {
+        FormattedText::new().text(text)
+    }
+}
+
+/// 以 MarkdownV2 格式发送消息，格式无法解析时改为发送纯文本
+pub async fn send(
+    bot: &Bot,
+    chat_id: ChatId,
+    reply_to: Option<MessageId>,
+    text: impl Into<FormattedText>,
+) -> Result<Message, RequestError> {
+    send_with_keyboard(bot, chat_id, reply_to, text, None).await
+}
+
+/// 与 [`send`] 相同，消息下方带有按钮
+pub async fn send_with_keyboard(
+    bot: &Bot,
+    chat_id: ChatId,
+    reply_to: Option<MessageId>,
+    text: impl Into<FormattedText>,
+    keyboard: Option<InlineKeyboardMarkup>,
+) -> Result<Message, RequestError> {
+    let text = text.into();
+    let mut request = bot
+        .send_message(chat_id, &text.markdown)
+        .parse_mode(ParseMode::MarkdownV2);
+    request.payload_mut().reply_parameters = reply_to.map(reply_parameters);
+    request.payload_mut().reply_markup = keyboard.clone().map(Into::into);
+    let sent = request.await;
+    match sent {
+        Err(RequestError::Api(ApiError::CantParseEntities(why))) => {
+            log::warn!("无法解析消息格式，改为发送纯文本: {}", why);
+            let mut request = bot.send_message(chat_id, text.plain);
+            request.payload_mut().reply_parameters = reply_to.map(reply_parameters);
+            request.payload_mut().reply_markup = keyboard.map(Into::into);
+            request.await
+        }
+        sent => sent,
+    }
+}
+
+/// 回复 `message_id` 对应的消息，原消息被删除时仍然正常发送
+pub fn reply_parameters(message_id: MessageId) -> ReplyParameters {
+    ReplyParameters::new(message_id).allow_sending_without_reply()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::matchers::{body_partial_json, method};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    const ALL_SPECIAL: &str = "\\_*[]()~`>#+-=|{}.!";
+
+    #[test]
+    fn escapes_every_special_character() {
+        assert_eq!(
+            escape(ALL_SPECIAL),
+            "\\\\\\_\\*\\[\\]\\(\\)\\~\\`\\>\\#\\+\\-\\=\\|\\{\\}\\.\\!"
+        );
+        for c in SPECIAL_CHARS {
+            assert_eq!(escape(&c.to_string()), format!("\\{}", c));
+        }
+        assert_eq!(escape("普通文本 abc 123"), "普通文本 abc 123");
+    }
+
+    #[test]
+    fn escapes_only_backtick_and_backslash_in_code() {
+        assert_eq!(escape_code(ALL_SPECIAL), "\\\\_*[]()~\\`>#+-=|{}.!");
+    }
+
+    #[test]
+    fn builds_markdown_and_plain_text() {
+        let text = FormattedText::new()
+            .text("已打包 ")
+            .bold(12)
+            .text(" 张图片：")
+            .code("my_trip (1).zip")
+            .append(FormattedText::from("\n错误："))
+            .code_block("a`b");
+        assert_eq!(
+            text.markdown,
+            "已打包 *12* 张图片：`my_trip (1).zip`\n错误：```\na\\`b\n```"
+        );
+        assert_eq!(text.plain, "已打包 12 张图片：my_trip (1).zip\n错误：a`b");
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_plain_text_when_rejected() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(body_partial_json(
+                serde_json::json!({"parse_mode": "MarkdownV2"}),
+            ))
+            .respond_with(ResponseTemplate::new(400).set_body_json(serde_json::json!({
+                "ok": false,
+                "error_code": 400,
+                "description": "Bad Request: can't parse entities: Character '.' is reserved",
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(body_partial_json(serde_json::json!({"text": "a.b"})))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "ok": true,
+                "result": {
+                    "message_id": 7,
+                    "date": 0,
+                    "chat": {"id": 1, "type": "private", "first_name": "Alice"},
+                    "text": "a.b",
+                },
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let bot = Bot::new("123:token").set_api_url(server.uri().parse().unwrap());
+
+        let sent = send(&bot, ChatId(1), None, FormattedText::new().bold("a.b"))
+            .await
+            .unwrap();
+        assert_eq!(sent.id, MessageId(7));
+    }
+}
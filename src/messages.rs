@@ -0,0 +1,291 @@
+//! 可自定义的回复文字
+//!
+//! `MESSAGES_FILE` 指向的文件中 `[messages]` 一节可以覆盖内置的回复，没有覆盖的使用内置文字，例如：
+//!
+//! ```text
+//! [messages]
+//! no_images = "没有找到图片哦"
+//! aborted = "已停止，丢弃了 {count} 个文件"
+//! ```
+//!
+//! 每条回复只能使用自己支持的占位符，启动时检查，写错的键或占位符会让程序直接退出。
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::path::Path;
+use std::sync::OnceLock;
+
+/// 内置的回复：键、文字和支持的占位符
+const BUILTIN: &[(&str, &str, &[&str])] = &[
+    (
+        "collect_started",
+        "✅收集已开始，请发送图片、图片链接或包含图片的消息。完成后，发送/stopcollect以结束收集",
+        &[],
+    ),
+    ("processing", "⏳ 正在处理，请稍候...", &[]),
+    ("no_images", "🤷‍♀️ 在你发送的消息中没有找到任何图片。", &[]),
+    ("all_failed", "❌ 所有图片都下载失败了。", &["count"]),
+    (
+        "file_name_set",
+        "✅已设置文件名为 {filename}",
+        &["filename"],
+    ),
+    (
+        "archive_done",
+        "✅ 处理完成！",
+        &["count", "size", "filename"],
+    ),
+    (
+        "aborted",
+        "⏹ 已中止，已下载的 {count} 个文件被丢弃",
+        &["count"],
+    ),
+    ("aborted_queued", "⏹ 已中止，任务还没有开始处理", &[]),
+    ("failed", "❌ 处理失败：", &["error_id"]),
+];
+
+/// 启动时读取的自定义文字
+static OVERRIDES: OnceLock<HashMap<&'static str, String>> = OnceLock::new();
+
+/// 读取并检查自定义文字，返回覆盖的数量
+pub fn init(path: Option<&Path>) -> Result<usize, String> {
+    let overrides = match path {
+        Some(path) => {
+            let content = std::fs::read_to_string(path)
+                .map_err(|why| format!("无法读取 {}: {}", path.display(), why))?;
+            parse(&content)?
+        }
+        None => HashMap::new(),
+    };
+    let count = overrides.len();
+    let _ = OVERRIDES.set(overrides);
+    Ok(count)
+}
+
+/// 键对应的回复，替换其中的占位符
+pub fn text(key: &str, args: &[(&str, &(dyn Display + Sync))]) -> String {
+    match custom(key, args) {
+        Some(text) => text,
+        None => render(builtin(key).map_or(key, |(_, text, _)| text), args),
+    }
+}
+
+/// 只在设置了自定义文字时返回，内置的回复带有格式时使用
+pub fn custom(key: &str, args: &[(&str, &(dyn Display + Sync))]) -> Option<String> {
+    let template = OVERRIDES.get()?.get(key)?;
+    Some(render(template, args))
+}
+
+fn builtin(key: &str) -> Option<&'static (&'static str, &'static str, &'static [&'static str])> {
+    BUILTIN.iter().find(|(builtin, _, _)| *builtin == key)
+}
+
+/// 解析 `[messages]` 一节中的 `键 = "文字"`，其他节和 `#` 开头的注释会被忽略
+fn parse(content: &str) -> Result<HashMap<&'static str, String>, String> {
+    let mut overrides = HashMap::new();
+    let mut in_messages = false;
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with('[') {
+            in_messages = line == "[messages]";
+            continue;
+        }
+        if !in_messages {
+            continue;
+        }
+
+        let error = |why: String| format!("第 {} 行：{}", number + 1, why);
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| error("应为 `键 = \"文字\"`".to_string()))?;
+        let key = key.trim();
+        let &(key, _, allowed) =
+            builtin(key).ok_or_else(|| error(format!("未知的回复 `{}`", key)))?;
+        let value = unquote(value.trim()).map_err(error)?;
+        for placeholder in placeholders(&value).map_err(error)? {
+            if !allowed.contains(&placeholder) {
+                return Err(error(format!(
+                    "`{}` 不支持占位符 {{{}}}，可用的有：{}",
+                    key,
+                    placeholder,
+                    describe_placeholders(allowed)
+                )));
+            }
+        }
+        if overrides.insert(key, value).is_some() {
+            return Err(error(format!("重复设置了 `{}`", key)));
+        }
+    }
+    Ok(overrides)
+}
+
+fn describe_placeholders(allowed: &[&str]) -> String {
+    if allowed.is_empty() {
+        return "无".to_string();
+    }
+    allowed
+        .iter()
+        .map(|name| format!("{{{}}}", name))
+        .collect::<Vec<_>>()
+        .join("、")
+}
+
+/// 去掉引号并处理 `\n`、`\"` 和 `\\`，没有引号时原样使用
+fn unquote(value: &str) -> Result<String, String> {
+    let Some(inner) = value.strip_prefix('"') else {
+        return Ok(value.to_string());
+    };
+    let inner = inner
+        .strip_suffix('"')
+        .ok_or_else(|| "缺少结尾的引号".to_string())?;
+    let mut text = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            text.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => text.push('\n'),
+            Some('"') => text.push('"'),
+            Some('\\') => text.push('\\'),
+            other => return Err(format!("无法识别的转义 \\{}", other.unwrap_or(' '))),
+        }
+    }
+    Ok(text)
+}
+
+/// 模板中的占位符名称，`{{` 和 `}}` 表示花括号本身
+fn placeholders(template: &str) -> Result<Vec<&str>, String> {
+    let mut names = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find(['{', '}']) {
+        let after = &rest[start + 1..];
+        if rest[start..].starts_with("{{") || rest[start..].starts_with("}}") {
+            rest = &after[1..];
+            continue;
+        }
+        if rest[start..].starts_with('}') {
+            return Err("多余的 `}`，花括号本身请写成 `}}`".to_string());
+        }
+        let end = after
+            .find('}')
+            .ok_or_else(|| "占位符缺少结尾的 `}`".to_string())?;
+        names.push(&after[..end]);
+        rest = &after[end + 1..];
+    }
+    Ok(names)
+}
+
+/// 将模板中的 `{名称}` 替换为 `args` 中对应的值，没有对应值的占位符保持原样
+pub fn render(template: &str, args: &[(&str, &(dyn Display + Sync))]) -> String {
+    let mut text = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find(['{', '}']) {
+        text.push_str(&rest[..start]);
+        let tail = &rest[start..];
+        if tail.starts_with("{{") || tail.starts_with("}}") {
+            text.push_str(&tail[..1]);
+            rest = &tail[2..];
+            continue;
+        }
+        let placeholder = tail
+            .strip_prefix('{')
+            .and_then(|inner| inner.split_once('}'));
+        match placeholder {
+            Some((name, after)) => {
+                match args.iter().find(|(arg, _)| *arg == name) {
+                    Some((_, value)) => text.push_str(&value.to_string()),
+                    None => text.push_str(&tail[..name.len() + 2]),
+                }
+                rest = after;
+            }
+            None => {
+                text.push_str(&tail[..1]);
+                rest = &tail[1..];
+            }
+        }
+    }
+    text.push_str(rest);
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_placeholders_and_braces() {
+        assert_eq!(
+            render("已下载 {count} 个，{{原样}}", &[("count", &3)]),
+            "已下载 3 个，{原样}"
+        );
+        // 没有对应值或不完整的占位符保持原样
+        assert_eq!(
+            render("{missing} {count", &[("count", &3)]),
+            "{missing} {count"
+        );
+        assert_eq!(render("没有占位符", &[]), "没有占位符");
+    }
+
+    #[test]
+    fn finds_placeholders() {
+        assert_eq!(
+            placeholders("{count} 张，{{x}} {size}").unwrap(),
+            ["count", "size"]
+        );
+        assert!(placeholders("多余的 }").is_err());
+        assert!(placeholders("{count").is_err());
+    }
+
+    #[test]
+    fn parses_messages_section() {
+        let content = r#"
+# 注释
+[other]
+no_images = "不会读取"
+
+[messages]
+no_images = "没有找到图片哦"
+aborted = "已停止，丢弃了 {count} 个文件\n下次见"
+processing = 处理中
+"#;
+        let overrides = parse(content).unwrap();
+        assert_eq!(overrides.len(), 3);
+        assert_eq!(overrides["no_images"], "没有找到图片哦");
+        assert_eq!(
+            overrides["aborted"],
+            "已停止，丢弃了 {count} 个文件\n下次见"
+        );
+        assert_eq!(overrides["processing"], "处理中");
+    }
+
+    #[test]
+    fn rejects_invalid_overrides() {
+        let error = |line: &str| parse(&format!("[messages]\n{}", line)).unwrap_err();
+        assert!(error("unknown = \"x\"").contains("未知的回复"));
+        assert!(error("aborted = \"{size}\"").contains("不支持占位符 {size}"));
+        assert!(error("processing = \"{count}\"").contains("可用的有：无"));
+        assert!(error("no_images = \"x").contains("缺少结尾的引号"));
+        assert!(error("no_images = \"\\t\"").contains("无法识别的转义"));
+        assert!(error("no_images").contains("第 2 行"));
+        assert!(error("no_images = a\nno_images = b").contains("重复设置"));
+    }
+
+    #[test]
+    fn every_builtin_uses_only_its_placeholders() {
+        for (key, text, allowed) in BUILTIN {
+            for placeholder in placeholders(text).unwrap() {
+                assert!(
+                    allowed.contains(&placeholder),
+                    "{} uses {}",
+                    key,
+                    placeholder
+                );
+            }
+        }
+    }
+}
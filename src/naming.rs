@@ -0,0 +1,150 @@
+/// Windows 保留的设备名，不区分大小写，带扩展名也无法创建
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// 清理用户输入的文件名，使其可以安全地用于创建文件
+///
+/// 路径分隔符和各系统不允许的字符会被替换为 `_`，
+/// 与保留设备名同名时追加 `_` 后缀。清理后为空时返回 `None`。
+pub fn sanitize_file_name(name: &str) -> Option<String> {
+    let cleaned: String = name
+        .trim()
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    // Windows 会忽略结尾的点和空格，".." 之类的名字也不能使用
+    let mut cleaned = cleaned.trim_end_matches(['.', ' ']).to_string();
+    if cleaned.is_empty() {
+        return None;
+    }
+
+    if is_reserved_name(&cleaned) {
+        cleaned.insert(stem_len(&cleaned), '_');
+    }
+    Some(cleaned)
+}
+
+/// 文件名（忽略扩展名）是否是保留的设备名
+fn is_reserved_name(name: &str) -> bool {
+    let stem = &name[..stem_len(name)];
+    RESERVED_NAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(stem.trim_end()))
+}
+
+/// 第一个 `.` 之前的部分的长度
+fn stem_len(name: &str) -> usize {
+    name.find('.').unwrap_or(name.len())
+}
+
+/// 第 `index` 张图片的文件名，序号按总数补零，保证按文件名排序与发送顺序一致
+///
+/// 例如共有120张图片时，第1张为 `image_001.jpg`。
+pub fn image_file_name(index: usize, total: usize, extension: &str) -> String {
+    let width = total.to_string().len();
+    format!("image_{:0width$}.{}", index, extension)
+}
+
+/// 由说明文字生成的文件名主干的最大字符数
+const CAPTION_NAME_LIMIT: usize = 60;
+
+/// 用图片的说明文字作为文件名，只使用第一行，清理后为空时返回 `None`
+///
+/// 例如说明文字为 `海边/日落\n第二天` 时为 `海边_日落.jpg`。
+pub fn caption_file_name(caption: &str, extension: &str) -> Option<String> {
+    let line = caption.lines().find(|line| !line.trim().is_empty())?;
+    let stem: String = line.trim().chars().take(CAPTION_NAME_LIMIT).collect();
+    // 清理时会去掉结尾的点，带上扩展名一起清理可能会吞掉扩展名前的点
+    let stem = sanitize_file_name(&stem)?;
+    Some(format!("{}.{}", stem, extension))
+}
+
+/// `name` 已经被使用时在扩展名前追加序号，例如 `日落.jpg` 变为 `日落_2.jpg`
+pub fn unique_file_name(name: String, used: &mut std::collections::HashSet<String>) -> String {
+    if used.insert(name.clone()) {
+        return name;
+    }
+    let (stem, extension) = name.rsplit_once('.').unwrap_or((&name, ""));
+    let name = (2..)
+        .map(|n| match extension {
+            "" => format!("{}_{}", stem, n),
+            extension => format!("{}_{}.{}", stem, n, extension),
+        })
+        .find(|candidate| !used.contains(candidate))
+        .expect("there is always an unused suffix");
+    used.insert(name.clone());
+    name
+}
+
+/// 第 `index` 个语音或音频的文件名，与图片共用序号，例如 `audio_007.ogg`
+pub fn audio_file_name(index: usize, total: usize, extension: &str) -> String {
+    let width = total.to_string().len();
+    format!("audio_{:0width$}.{}", index, extension)
+}
+
+/// 贴纸在压缩包中的文件名，与图片的编号方式相同，例如 `sticker_03.webp`
+pub fn sticker_file_name(index: usize, total: usize, extension: &str) -> String {
+    let width = total.to_string().len();
+    format!("sticker_{:0width$}.{}", index, extension)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reserved_names_get_suffix() {
+        for reserved in RESERVED_NAMES {
+            let expected = format!("{}_", reserved);
+            assert_eq!(sanitize_file_name(reserved), Some(expected.clone()));
+            let lower = reserved.to_lowercase();
+            assert_eq!(sanitize_file_name(&lower), Some(format!("{}_", lower)));
+            assert_eq!(
+                sanitize_file_name(&format!("{}.zip", reserved)),
+                Some(format!("{}.zip", expected))
+            );
+        }
+    }
+
+    #[test]
+    fn reserved_name_with_multiple_extensions() {
+        assert_eq!(sanitize_file_name("nul.tar.gz"), Some("nul_.tar.gz".into()));
+        assert_eq!(sanitize_file_name("Aux .txt"), Some("Aux _.txt".into()));
+    }
+
+    #[test]
+    fn similar_names_are_not_reserved() {
+        for name in ["CONSOLE", "COM10", "LPT", "NULL.zip", "my con", "prn_1"] {
+            assert_eq!(sanitize_file_name(name), Some(name.to_string()));
+        }
+    }
+
+    #[test]
+    fn invalid_characters_are_replaced() {
+        assert_eq!(sanitize_file_name("a/b\\c:d*"), Some("a_b_c_d_".into()));
+        assert_eq!(sanitize_file_name(" trip... "), Some("trip".into()));
+        assert_eq!(sanitize_file_name(".."), None);
+        assert_eq!(sanitize_file_name("   "), None);
+    }
+
+    #[test]
+    fn padded_names_sort_in_send_order() {
+        let total = 120;
+        let names = (1..=total)
+            .map(|index| image_file_name(index, total, "jpg"))
+            .collect::<Vec<_>>();
+        assert_eq!(names[0], "image_001.jpg");
+        assert_eq!(names[total - 1], "image_120.jpg");
+        let mut sorted = names.clone();
+        sorted.sort();
+        assert_eq!(sorted, names);
+        assert_eq!(audio_file_name(7, 100, "ogg"), "audio_007.ogg");
+        assert_eq!(sticker_file_name(3, 12, "webp"), "sticker_03.webp");
+    }
+}
@@ -0,0 +1,279 @@
+//! 任务结束后向 `NOTIFY_WEBHOOK_URL` 发送通知，例如通知家庭自动化系统压缩包已经准备好
+
+use crate::job_report::ProcessingReport;
+use hmac::{Hmac, Mac};
+use reqwest::{Client, Url};
+use serde_json::{Value, json};
+use sha2::Sha256;
+use std::fmt;
+use std::time::Duration;
+use teloxide::types::ChatId;
+use uuid::Uuid;
+
+/// 签名所在的请求头，值为 `sha256=<十六进制的 HMAC-SHA256>`
+pub const SIGNATURE_HEADER: &str = "X-Signature-256";
+/// 单次请求的超时
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// 发送失败时最多重试的次数
+const RETRIES: u32 = 2;
+/// 重试的初始等待时间，每次翻倍
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// 通知的内容，`status` 为 `succeeded`、`failed` 或 `cancelled`
+pub fn payload(
+    report: &ProcessingReport,
+    job_id: Uuid,
+    chat_id: ChatId,
+    status: &str,
+    duration: Duration,
+    error: Option<&str>,
+) -> Value {
+    json!({
+        "job_id": job_id.to_string(),
+        "chat_id": chat_id.0,
+        "status": status,
+        "items": {
+            "collected": report.items,
+            "downloaded": report.downloaded,
+            "failed": report.failed(),
+        },
+        "archive": {
+            "name": report.archive_name,
+            "size": report.archive_size(),
+            "volumes": report.archives.iter().map(|part| json!({
+                "name": part.name,
+                "files": part.count,
+                "size": part.size,
+                "remote_path": part.remote_path,
+                "delivered": part.delivered,
+            })).collect::<Vec<_>>(),
+        },
+        "sent": report.sent,
+        "url": report.published_url,
+        "duration_secs": duration.as_secs_f64(),
+        "error": error,
+    })
+}
+
+/// 通知的地址和签名密钥
+#[derive(Clone)]
+pub struct Webhook {
+    client: Client,
+    url: Url,
+    secret: Option<String>,
+}
+
+// 不输出密钥，避免出现在日志中
+impl fmt::Debug for Webhook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Webhook")
+            .field("url", &self.url.as_str())
+            .field("signed", &self.secret.is_some())
+            .finish()
+    }
+}
+
+impl Webhook {
+    /// 读取 `NOTIFY_WEBHOOK_URL` 和 `NOTIFY_WEBHOOK_SECRET`，没有设置地址时返回 `None`
+    pub fn from_env() -> Option<Self> {
+        let url = std::env::var("NOTIFY_WEBHOOK_URL")
+            .ok()
+            .filter(|url| !url.is_empty())?;
+        Some(Webhook {
+            client: Client::builder()
+                .timeout(TIMEOUT)
+                .build()
+                .expect("Client creation failed"),
+            url: url.parse().expect("NOTIFY_WEBHOOK_URL is invalid"),
+            secret: std::env::var("NOTIFY_WEBHOOK_SECRET")
+                .ok()
+                .filter(|secret| !secret.is_empty()),
+        })
+    }
+
+    /// 在后台发送通知，不等待结果，失败只记录日志
+    pub fn send(&self, payload: Value) {
+        let webhook = self.clone();
+        tokio::spawn(async move { webhook.deliver(payload.to_string().into_bytes()).await });
+    }
+
+    async fn deliver(&self, body: Vec<u8>) {
+        let mut delay = RETRY_DELAY;
+        for attempt in 0..=RETRIES {
+            if attempt > 0 {
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+            let mut request = self
+                .client
+                .post(self.url.clone())
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body.clone());
+            if let Some(secret) = &self.secret {
+                request = request.header(SIGNATURE_HEADER, sign(secret, &body));
+            }
+            match request
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+            {
+                Ok(_) => return,
+                Err(why) => log::warn!(
+                    "Failed to notify webhook (attempt {}/{}): {}",
+                    attempt + 1,
+                    RETRIES + 1,
+                    why
+                ),
+            }
+        }
+        log::error!("Giving up notifying webhook after {} attempts", RETRIES + 1);
+    }
+}
+
+/// 用 `secret` 对 `body` 计算 HMAC-SHA256，例如 `sha256=3f2a...`
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(body);
+    let digest = mac.finalize().into_bytes();
+    let hex = digest
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+    format!("sha256={}", hex)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::job_report::ArchivePart;
+    use wiremock::matchers::{body_json, header, method};
+    use wiremock::{Mock, MockServer, Request, ResponseTemplate};
+
+    fn webhook(server: &MockServer, secret: Option<&str>) -> Webhook {
+        Webhook {
+            client: Client::new(),
+            url: server.uri().parse().unwrap(),
+            secret: secret.map(str::to_string),
+        }
+    }
+
+    fn finished_report() -> ProcessingReport {
+        ProcessingReport {
+            items: 3,
+            downloaded: 2,
+            failures: vec![(3, "超时".to_string())],
+            archive_name: Some("holiday".to_string()),
+            archives: vec![ArchivePart {
+                name: "holiday.zip".to_string(),
+                count: 2,
+                size: 2048,
+                remote_path: None,
+                errors: Vec::new(),
+                delivered: true,
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn signs_with_hmac_sha256() {
+        // RFC 4231 的第二组测试数据
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn payload_shape() {
+        let job_id = Uuid::new_v4();
+        let payload = payload(
+            &finished_report(),
+            job_id,
+            ChatId(42),
+            "succeeded",
+            Duration::from_millis(1500),
+            None,
+        );
+        assert_eq!(
+            payload,
+            json!({
+                "job_id": job_id.to_string(),
+                "chat_id": 42,
+                "status": "succeeded",
+                "items": {"collected": 3, "downloaded": 2, "failed": 1},
+                "archive": {
+                    "name": "holiday",
+                    "size": 2048,
+                    "volumes": [{
+                        "name": "holiday.zip",
+                        "files": 2,
+                        "size": 2048,
+                        "remote_path": null,
+                        "delivered": true,
+                    }],
+                },
+                "sent": 0,
+                "url": null,
+                "duration_secs": 1.5,
+                "error": null,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn delivers_signed_payload() {
+        let server = MockServer::start().await;
+        let payload = payload(
+            &ProcessingReport::default(),
+            Uuid::new_v4(),
+            ChatId(1),
+            "failed",
+            Duration::ZERO,
+            Some("磁盘已满"),
+        );
+        let signature = sign("secret", payload.to_string().as_bytes());
+        Mock::given(method("POST"))
+            .and(header("content-type", "application/json"))
+            .and(header(SIGNATURE_HEADER, signature.as_str()))
+            .and(body_json(&payload))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        webhook(&server, Some("secret"))
+            .deliver(payload.to_string().into_bytes())
+            .await;
+    }
+
+    #[tokio::test]
+    async fn unsigned_without_secret() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(|request: &Request| !request.headers.contains_key(SIGNATURE_HEADER))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+        webhook(&server, None).deliver(b"{}".to_vec()).await;
+    }
+
+    #[tokio::test]
+    async fn retries_failed_delivery() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(500))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+        webhook(&server, None).deliver(b"{}".to_vec()).await;
+    }
+}
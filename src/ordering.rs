@@ -0,0 +1,127 @@
+use chrono::{DateTime, Utc};
+use std::cmp::Reverse;
+use teloxide::types::Message;
+
+/// 图片在压缩包中的排列顺序
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Order {
+    /// 按收到消息的顺序
+    #[default]
+    Received,
+    /// 按发送时间从旧到新
+    DateAsc,
+    /// 按发送时间从新到旧
+    DateDesc,
+    /// 随机打乱，同一个任务的结果是固定的
+    Shuffle,
+}
+
+impl Order {
+    pub fn parse(arg: &str) -> Option<Self> {
+        match arg.trim().to_lowercase().as_str() {
+            "received" => Some(Order::Received),
+            "date-asc" => Some(Order::DateAsc),
+            "date-desc" => Some(Order::DateDesc),
+            "shuffle" => Some(Order::Shuffle),
+            _ => None,
+        }
+    }
+
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Order::Received => "按收到的顺序",
+            Order::DateAsc => "按时间从旧到新",
+            Order::DateDesc => "按时间从新到旧",
+            Order::Shuffle => "随机顺序",
+        }
+    }
+}
+
+/// 按 `order` 重新排列消息，随机顺序由 `seed` 决定
+pub fn sort_messages(messages: &mut [Message], order: Order, seed: u64) {
+    match order {
+        Order::Received => {}
+        Order::DateAsc => messages.sort_by_key(original_date),
+        Order::DateDesc => messages.sort_by_key(|msg| Reverse(original_date(msg))),
+        Order::Shuffle => shuffle(messages, seed),
+    }
+}
+
+/// 消息最初发送的时间，转发的消息使用转发来源的时间
+fn original_date(msg: &Message) -> DateTime<Utc> {
+    msg.forward_date().unwrap_or(msg.date)
+}
+
+/// 使用 splitmix64 作为随机数来源的 Fisher-Yates 洗牌
+fn shuffle<T>(items: &mut [T], seed: u64) {
+    let mut state = seed;
+    let mut next = move || {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    };
+    for i in (1..items.len()).rev() {
+        let j = (next() % (i as u64 + 1)) as usize;
+        items.swap(i, j);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+
+    /// 收到的顺序为 1、2、3、4，原消息的发送时间为 3、1、4、2
+    fn messages() -> Vec<Message> {
+        [(1, 300), (2, 100), (3, 400), (4, 200)]
+            .into_iter()
+            .map(|(id, original)| test_util::forwarded(id, 1000 + id as i64, original))
+            .collect()
+    }
+
+    fn sorted(order: Order, seed: u64) -> Vec<i32> {
+        let mut messages = messages();
+        sort_messages(&mut messages, order, seed);
+        messages.iter().map(|msg| msg.id.0).collect()
+    }
+
+    #[test]
+    fn received_keeps_order() {
+        assert_eq!(sorted(Order::Received, 0), [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn date_orders_use_forward_date() {
+        assert_eq!(sorted(Order::DateAsc, 0), [2, 4, 1, 3]);
+        assert_eq!(sorted(Order::DateDesc, 0), [3, 1, 4, 2]);
+    }
+
+    #[test]
+    fn date_falls_back_to_message_date() {
+        let mut messages = vec![
+            test_util::message(1, 500, serde_json::json!({"text": "a"})),
+            test_util::forwarded(2, 600, 100),
+        ];
+        sort_messages(&mut messages, Order::DateAsc, 0);
+        assert_eq!(messages[0].id.0, 2);
+    }
+
+    #[test]
+    fn shuffle_is_reproducible_per_seed() {
+        let first = sorted(Order::Shuffle, 42);
+        assert_eq!(first, sorted(Order::Shuffle, 42));
+        let mut ids = first.clone();
+        ids.sort();
+        assert_eq!(ids, [1, 2, 3, 4]);
+        // 不同的种子中至少有一个得到不同的顺序
+        assert!((0..16).any(|seed| sorted(Order::Shuffle, seed) != first));
+    }
+
+    #[test]
+    fn parses_order_names() {
+        assert_eq!(Order::parse(" Date-Desc "), Some(Order::DateDesc));
+        assert_eq!(Order::parse("random"), None);
+    }
+}
@@ -0,0 +1,53 @@
+//! 按 EXIF 方向旋转图片
+//!
+//! 手机拍摄的照片通常按传感器的方向保存像素，再用 EXIF 中的方向标记告诉看图软件如何旋转，
+//! 不读取这个标记的软件会把照片显示成横的或倒的。开启 `/autorotate` 后，带有方向标记的图片
+//! 会按标记旋转后重新编码，重新编码的图片不再带有 EXIF，也就去掉了方向标记。
+//! 需要启用 `imaging` 特性，没有方向标记或方向正常的图片保持原样。
+
+use std::path::Path;
+
+/// 可能带有方向标记的图片扩展名
+pub fn supports(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            matches!(
+                extension.to_ascii_lowercase().as_str(),
+                "jpg" | "jpeg" | "png" | "webp"
+            )
+        })
+}
+
+/// 按方向标记旋转 `path` 的图片并按原格式写回，返回是否旋转了
+///
+/// 同步执行，需要在阻塞线程中调用。
+#[cfg(feature = "imaging")]
+pub fn apply(path: &Path) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+    use image::metadata::Orientation;
+    use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader};
+
+    let format = ImageFormat::from_path(path)?;
+    let mut decoder = ImageReader::open(path)?
+        .with_guessed_format()?
+        .into_decoder()?;
+    let orientation = decoder.orientation()?;
+    if orientation == Orientation::NoTransforms {
+        return Ok(false);
+    }
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+
+    if format == ImageFormat::Jpeg {
+        let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        image::codecs::jpeg::JpegEncoder::new_with_quality(file, 90).encode_image(&image)?;
+    } else {
+        image.save_with_format(path, format)?;
+    }
+    Ok(true)
+}
+
+#[cfg(not(feature = "imaging"))]
+pub fn apply(_path: &Path) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+    Err("编译时没有启用 imaging 功能".into())
+}
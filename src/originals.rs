@@ -0,0 +1,79 @@
+//! 以文件形式发送的原图
+//!
+//! 以图片形式发送时telegram会重新压缩，只有以文件形式发送才能保留原图。
+//! 同一组消息中同时有压缩的图片和图片文件时，通常是同一批图片各发了一次，
+//! 按 [`PhotoSource`] 只打包其中一种。
+
+use std::collections::HashSet;
+use teloxide::types::{Document, MediaGroupId, Message};
+
+/// 同一组中同时有图片和原图文件时打包哪一种
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PhotoSource {
+    /// 原图文件，画质更好
+    #[default]
+    Document,
+    /// telegram压缩过的图片
+    Photo,
+}
+
+impl PhotoSource {
+    /// 解析 `/original` 的参数
+    pub fn parse(arg: &str) -> Option<Self> {
+        match arg.trim().to_lowercase().as_str() {
+            "document" | "file" | "original" => Some(PhotoSource::Document),
+            "photo" | "compressed" => Some(PhotoSource::Photo),
+            _ => None,
+        }
+    }
+
+    pub fn describe(&self) -> &'static str {
+        match self {
+            PhotoSource::Document => "打包原图文件",
+            PhotoSource::Photo => "打包压缩的图片",
+        }
+    }
+}
+
+/// 消息中以文件形式发送的图片和扩展名，HEIC/HEIF 由 [`crate::heif`] 处理
+pub fn image_document(msg: &Message) -> Option<(&Document, &'static str)> {
+    let document = msg.document()?;
+    let from_mime = document
+        .mime_type
+        .as_ref()
+        .and_then(|mime| match mime.essence_str() {
+            "image/jpeg" => Some("jpg"),
+            "image/png" => Some("png"),
+            "image/gif" => Some("gif"),
+            "image/webp" => Some("webp"),
+            "image/bmp" => Some("bmp"),
+            _ => None,
+        });
+    let from_name = || {
+        let name = document.file_name.as_deref()?.to_lowercase();
+        let (_, extension) = name.rsplit_once('.')?;
+        match extension {
+            "jpg" | "jpeg" => Some("jpg"),
+            "png" => Some("png"),
+            "gif" => Some("gif"),
+            "webp" => Some("webp"),
+            "bmp" => Some("bmp"),
+            _ => None,
+        }
+    };
+    Some((document, from_mime.or_else(from_name)?))
+}
+
+/// 同时包含图片和原图文件的消息组
+pub fn paired_groups(messages: &[Message]) -> HashSet<MediaGroupId> {
+    let groups_with = |matches: fn(&Message) -> bool| {
+        messages
+            .iter()
+            .filter(|msg| matches(msg))
+            .filter_map(|msg| msg.media_group_id().cloned())
+            .collect::<HashSet<_>>()
+    };
+    let photos = groups_with(|msg| msg.photo().is_some());
+    let documents = groups_with(|msg| image_document(msg).is_some());
+    photos.intersection(&documents).cloned().collect()
+}
@@ -0,0 +1,350 @@
+use crate::markdown;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use teloxide::prelude::*;
+use teloxide::types::{InputFile, InputMedia, InputMediaPhoto, MessageId, PhotoSize};
+use teloxide::{ApiError, RequestError};
+
+/// 一组相册最多包含的图片数量
+const ALBUM_SIZE: usize = 10;
+/// 图片说明文字的最大长度
+const CAPTION_LIMIT: usize = 1024;
+/// 逐个发送文件时的间隔
+const DOCUMENT_INTERVAL: Duration = Duration::from_millis(500);
+/// 触发频率限制后最多重试的次数
+const FLOOD_RETRIES: usize = 3;
+/// 上传压缩包遇到网络错误时最多重试的次数
+const ARCHIVE_RETRIES: u32 = 4;
+/// 上传压缩包重试的初始等待时间，每次翻倍
+const ARCHIVE_RETRY_DELAY: Duration = Duration::from_secs(2);
+/// 上传压缩包重试的最长等待时间
+const ARCHIVE_RETRY_MAX_DELAY: Duration = Duration::from_secs(30);
+/// 快速模式下图片最长边的上限，对应telegram生成的中等尺寸
+pub const PREVIEW_MAX_SIDE: u32 = 800;
+
+/// 收集结束后的输出方式
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OutputMode {
+    /// 下载后打包成zip压缩包
+    #[default]
+    Archive,
+    /// 直接以相册的形式重新发送，`captions` 表示是否保留说明文字
+    Album { captions: bool },
+    /// 下载后逐个以文件形式发送原图
+    Documents,
+    /// 下载后打包成附带 index.html 网页相册的压缩包
+    Gallery,
+    /// 下载后上传到 telegraph，发布为网页
+    #[cfg(feature = "telegraph")]
+    Telegraph,
+}
+
+impl OutputMode {
+    /// 解析 `/output` 的参数，例如 `archive`、`album`、`album caption`
+    pub fn parse(arg: &str) -> Option<Self> {
+        let mut words = arg.split_whitespace().map(str::to_lowercase);
+        let mode = match words.next()?.as_str() {
+            "archive" | "zip" => OutputMode::Archive,
+            "album" => OutputMode::Album { captions: false },
+            "documents" | "document" | "files" => OutputMode::Documents,
+            "gallery" | "html" => OutputMode::Gallery,
+            #[cfg(feature = "telegraph")]
+            "telegraph" => OutputMode::Telegraph,
+            _ => return None,
+        };
+        match (mode, words.next().as_deref()) {
+            (_, None) => Some(mode),
+            (OutputMode::Album { .. }, Some("caption" | "captions")) => {
+                Some(OutputMode::Album { captions: true })
+            }
+            _ => None,
+        }
+    }
+
+    pub fn describe(&self) -> &'static str {
+        match self {
+            OutputMode::Archive => "压缩包",
+            OutputMode::Album { captions: false } => "相册",
+            OutputMode::Album { captions: true } => "相册（保留说明文字）",
+            OutputMode::Documents => "逐个发送原图文件",
+            OutputMode::Gallery => "附带网页相册的压缩包",
+            #[cfg(feature = "telegraph")]
+            OutputMode::Telegraph => "telegraph 网页",
+        }
+    }
+}
+
+/// 压缩包的送达方式，只在输出方式为压缩包或网页相册时生效
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    /// 发送到会话中
+    #[default]
+    Telegram,
+    /// 上传到 SFTP 服务器，回复远程路径
+    Sftp,
+    /// 同时发送到会话和上传到 SFTP 服务器
+    Both,
+}
+
+impl Delivery {
+    /// 解析 `/delivery` 的参数
+    pub fn parse(arg: &str) -> Option<Self> {
+        match arg.trim().to_lowercase().as_str() {
+            "telegram" | "chat" => Some(Delivery::Telegram),
+            "sftp" => Some(Delivery::Sftp),
+            "both" => Some(Delivery::Both),
+            _ => None,
+        }
+    }
+
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Delivery::Telegram => "发送到会话",
+            Delivery::Sftp => "上传到 SFTP 服务器",
+            Delivery::Both => "发送到会话并上传到 SFTP 服务器",
+        }
+    }
+
+    /// 是否需要通过telegram发送压缩包
+    pub fn sends_to_chat(&self) -> bool {
+        matches!(self, Delivery::Telegram | Delivery::Both)
+    }
+
+    /// 是否需要上传到 SFTP 服务器
+    pub fn uploads_to_sftp(&self) -> bool {
+        matches!(self, Delivery::Sftp | Delivery::Both)
+    }
+}
+
+/// 获取消息中分辨率最高的图片
+pub fn largest_photo(msg: &Message) -> Option<&PhotoSize> {
+    msg.photo()?.iter().max_by_key(|p| p.height * p.width)
+}
+
+/// 图片的最长边是否小于 `min` 像素，`min` 为0时不限制
+pub fn below_min_dimension(photo: &PhotoSize, min: u32) -> bool {
+    photo.width.max(photo.height) < min
+}
+
+/// 获取消息中用于快速预览的图片
+///
+/// 选择最长边不超过 [`PREVIEW_MAX_SIDE`] 的最大尺寸，没有时选择最小的尺寸。
+pub fn preview_photo(msg: &Message) -> Option<&PhotoSize> {
+    let photos = msg.photo()?;
+    photos
+        .iter()
+        .filter(|p| p.width.max(p.height) <= PREVIEW_MAX_SIDE)
+        .max_by_key(|p| p.height * p.width)
+        .or_else(|| photos.iter().min_by_key(|p| p.height * p.width))
+}
+
+/// 将收集到的图片以相册的形式重新发送
+///
+/// 直接使用图片的 file_id，不需要下载，每组都回复 `reply_to`。返回发送的图片数量。
+pub async fn send_as_albums(
+    bot: &Bot,
+    chat_id: ChatId,
+    reply_to: MessageId,
+    messages: &[Message],
+    captions: bool,
+) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+    let photos: Vec<_> = messages
+        .iter()
+        .filter_map(|msg| Some((largest_photo(msg)?, msg.caption())))
+        .collect();
+
+    for (i, group) in photos.chunks(ALBUM_SIZE).enumerate() {
+        if i > 0 {
+            // 避免连续发送触发telegram的频率限制
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+
+        // 说明文字放在每组的第一张图片上
+        let caption = captions
+            .then(|| {
+                let text = group
+                    .iter()
+                    .filter_map(|(_, caption)| *caption)
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                text.chars().take(CAPTION_LIMIT).collect::<String>()
+            })
+            .filter(|text| !text.is_empty());
+
+        // 相册至少需要两张图片，只剩一张时单独发送
+        if let [(photo, _)] = group {
+            let mut request = bot
+                .send_photo(chat_id, InputFile::file_id(photo.file.id.clone()))
+                .reply_parameters(markdown::reply_parameters(reply_to));
+            if let Some(caption) = caption {
+                request = request.caption(caption);
+            }
+            request.await?;
+            continue;
+        }
+
+        let media = group
+            .iter()
+            .enumerate()
+            .map(|(j, (photo, _))| {
+                let mut media = InputMediaPhoto::new(InputFile::file_id(photo.file.id.clone()));
+                if let (0, Some(caption)) = (j, &caption) {
+                    media = media.caption(caption);
+                }
+                InputMedia::Photo(media)
+            })
+            .collect::<Vec<_>>();
+        bot.send_media_group(chat_id, media)
+            .reply_parameters(markdown::reply_parameters(reply_to))
+            .await?;
+    }
+
+    Ok(photos.len())
+}
+
+/// 逐个以文件的形式发送图片，避免被telegram压缩
+///
+/// `files` 为图片序号、文件路径和说明文字，返回发送失败的序号和原因。
+pub async fn send_as_documents(
+    bot: &Bot,
+    chat_id: ChatId,
+    reply_to: MessageId,
+    files: &[(usize, PathBuf, Option<String>)],
+) -> Vec<(usize, RequestError)> {
+    let mut failures = Vec::new();
+    for (i, (index, path, caption)) in files.iter().enumerate() {
+        if i > 0 {
+            tokio::time::sleep(DOCUMENT_INTERVAL).await;
+        }
+        if let Err(why) =
+            send_document_with_retry(bot, chat_id, reply_to, path, caption.as_deref()).await
+        {
+            log::warn!("发送第 {} 张图片到 {} 失败: {}", index, chat_id, why);
+            failures.push((*index, why));
+        }
+    }
+    failures
+}
+
+/// 发送文件，触发频率限制时按telegram要求的时间等待后重试
+async fn send_document_with_retry(
+    bot: &Bot,
+    chat_id: ChatId,
+    reply_to: MessageId,
+    path: &Path,
+    caption: Option<&str>,
+) -> Result<(), RequestError> {
+    let mut retries = 0;
+    loop {
+        let mut request = bot
+            .send_document(chat_id, InputFile::file(path))
+            .reply_parameters(markdown::reply_parameters(reply_to));
+        if let Some(caption) = caption {
+            request = request.caption(caption.chars().take(CAPTION_LIMIT).collect::<String>());
+        }
+        match request.await {
+            Err(RequestError::RetryAfter(secs)) if retries < FLOOD_RETRIES => {
+                retries += 1;
+                log::warn!("触发频率限制，{} 秒后重试", secs.seconds());
+                tokio::time::sleep(secs.duration()).await;
+            }
+            result => return result.map(|_| ()),
+        }
+    }
+}
+
+/// 上传压缩包，网络错误时按指数退避重试，触发频率限制时按telegram要求的时间等待
+///
+/// 文件过大的错误不会重试，由调用方判断是否需要拆分后重新上传。
+/// `caption` 为分卷时的说明，例如 `第 2/3 卷`；`job_id` 只用于在日志中关联同一个任务。
+pub async fn send_archive_with_retry(
+    bot: &Bot,
+    chat_id: ChatId,
+    reply_to: MessageId,
+    path: &Path,
+    caption: Option<&str>,
+    job_id: uuid::Uuid,
+) -> Result<Message, RequestError> {
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        log::info!(
+            "Job {}: uploading {} (attempt {})",
+            job_id,
+            path.display(),
+            attempt
+        );
+        let mut request = bot
+            .send_document(chat_id, InputFile::file(path))
+            .reply_parameters(markdown::reply_parameters(reply_to));
+        if let Some(caption) = caption {
+            request = request.caption(caption);
+        }
+        let result = request.await;
+        let delay = match &result {
+            Err(RequestError::RetryAfter(secs)) => secs.duration(),
+            Err(RequestError::Network(_) | RequestError::Io(_)) => {
+                (ARCHIVE_RETRY_DELAY * 2u32.pow(attempt - 1)).min(ARCHIVE_RETRY_MAX_DELAY)
+            }
+            _ => return result,
+        };
+        if attempt > ARCHIVE_RETRIES {
+            return result;
+        }
+        log::warn!(
+            "Job {}: upload attempt {} failed: {}, retrying in {:?}",
+            job_id,
+            attempt,
+            result.unwrap_err(),
+            delay
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// 错误是否是因为文件超过了telegram的上传限制
+pub fn is_too_large(error: &RequestError) -> bool {
+    matches!(error, RequestError::Api(ApiError::RequestEntityTooLarge))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+
+    #[test]
+    fn min_dimension_boundary() {
+        // 最大尺寸的最长边为 200
+        let msg = test_util::photo(1, 0, &[(90, 50), (200, 112), (120, 67)]);
+        let largest = largest_photo(&msg).unwrap();
+        assert_eq!((largest.width, largest.height), (200, 112));
+        assert!(!below_min_dimension(largest, 0));
+        assert!(!below_min_dimension(largest, 199));
+        assert!(!below_min_dimension(largest, 200));
+        assert!(below_min_dimension(largest, 201));
+
+        // 竖向图片按高判断
+        let tall = test_util::photo(2, 0, &[(112, 200)]);
+        let largest = largest_photo(&tall).unwrap();
+        assert!(!below_min_dimension(largest, 200));
+        assert!(below_min_dimension(largest, 201));
+        assert!(largest_photo(&test_util::text(3, "hi")).is_none());
+    }
+
+    #[test]
+    fn preview_prefers_largest_within_limit() {
+        let msg = test_util::photo(
+            1,
+            0,
+            &[
+                (90, 60),
+                (PREVIEW_MAX_SIDE, 600),
+                (PREVIEW_MAX_SIDE + 1, 900),
+            ],
+        );
+        assert_eq!(preview_photo(&msg).unwrap().width, PREVIEW_MAX_SIDE);
+        // 都超过时选择最小的尺寸
+        let msg = test_util::photo(2, 0, &[(4000, 3000), (2000, 1500)]);
+        assert_eq!(preview_photo(&msg).unwrap().width, 2000);
+    }
+}
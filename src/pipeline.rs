@@ -0,0 +1,301 @@
+//! 打包任务中不需要连接 telegram 的步骤
+//!
+//! `run_job` 负责下载、打包等 I/O，文件的命名和下载结果的汇总都在这里计算，
+//! 得到的结果再由 `run_job` 写成给用户的回复。
+
+use crate::captions::CaptionFiles;
+use crate::chat_export;
+use crate::credits;
+use crate::download::{FileUrl, MediaKind};
+use crate::markdown::FormattedText;
+use crate::naming;
+use crate::stickers::StickerCounts;
+use std::collections::HashSet;
+use teloxide::types::Message;
+
+/// 提取下载链接时收集到的一个文件
+#[derive(Debug, Clone)]
+pub struct CollectedItem {
+    pub url: FileUrl,
+    pub kind: MediaKind,
+    pub extension: String,
+    /// 提取时已经确定的文件名，例如音频的标题、视频消息和网页标题
+    pub name: Option<String>,
+    /// 消息的说明文字，用于以说明文字命名和逐个发送
+    pub caption: Option<String>,
+    /// 在压缩包中附带的说明文字文件的内容
+    pub caption_file: Option<String>,
+    /// 发送者，群组会话中用于致谢
+    pub contributor: String,
+    /// 来自的消息，用于 Telegram 导出格式
+    pub origin: chat_export::Origin,
+    /// 语音、音频和视频消息的时长，秒
+    pub duration: Option<u32>,
+    /// 视频消息的边长，像素
+    pub video_note_length: Option<u32>,
+}
+
+impl CollectedItem {
+    /// `msg` 中的一个文件，不带说明文字
+    pub fn new(msg: &Message, url: FileUrl, kind: MediaKind, extension: impl Into<String>) -> Self {
+        CollectedItem {
+            url,
+            kind,
+            extension: extension.into(),
+            name: None,
+            caption: None,
+            caption_file: None,
+            contributor: credits::contributor(msg),
+            origin: chat_export::Origin::of(msg),
+            duration: None,
+            video_note_length: None,
+        }
+    }
+
+    /// 带上 `msg` 的说明文字，`caption_files` 为 `/captions` 的设置
+    pub fn with_caption(mut self, msg: &Message, caption_files: CaptionFiles) -> Self {
+        self.caption = msg.caption().map(str::to_string);
+        self.caption_file = caption_files.render(msg);
+        self
+    }
+
+    /// 使用提取时已经确定的文件名
+    pub fn named(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+}
+
+/// 每个文件在临时目录中的文件名，按提取的顺序
+///
+/// 提取时已经确定文件名的文件（音频的标题、视频消息、网页标题等）使用该名称，
+/// 开启 `/captionnames` 时图片以说明文字命名。重名时加上序号。
+pub fn file_names(items: &[CollectedItem], caption_names: bool) -> Vec<String> {
+    let total = items.len();
+    // 已经使用的文件名，以说明文字命名时可能重名
+    let mut used_names = HashSet::new();
+    items
+        .iter()
+        .enumerate()
+        .map(|(i, item)| {
+            let extension = &item.extension;
+            let name = match (&item.name, item.kind) {
+                (Some(name), _) => name.clone(),
+                (None, MediaKind::Image) => caption_names
+                    .then(|| naming::caption_file_name(item.caption.as_deref()?, extension))
+                    .flatten()
+                    .unwrap_or_else(|| naming::image_file_name(i + 1, total, extension)),
+                (None, MediaKind::Audio) => naming::audio_file_name(i + 1, total, extension),
+                // 动态贴纸的预览图紧跟在贴纸之后，使用贴纸的编号
+                (None, MediaKind::Sticker) if extension == "png" && i > 0 => {
+                    naming::sticker_file_name(i, total, extension)
+                }
+                (None, MediaKind::Sticker) => naming::sticker_file_name(i + 1, total, extension),
+                // 视频消息都在收集时命名
+                (None, MediaKind::VideoNote) => format!("videonote_{}.{}", i + 1, extension),
+            };
+            naming::unique_file_name(name, &mut used_names)
+        })
+        .collect()
+}
+
+/// 下载完成后按类型和发送者汇总的数量，只统计下载成功的文件
+#[derive(Debug, Default)]
+pub struct DownloadSummary {
+    pub downloaded: usize,
+    /// 语音和音频的数量
+    pub audio: usize,
+    /// 视频消息的数量，缩略图不单独计数
+    pub video_notes: usize,
+    pub stickers: StickerCounts,
+    /// 每个发送者贡献的数量，按数量从多到少
+    pub contributors: Vec<(String, usize)>,
+}
+
+impl DownloadSummary {
+    /// `failed` 为下载失败的文件编号（从1开始）
+    pub fn new(items: &[CollectedItem], failed: &[usize]) -> Self {
+        let succeeded = |i: usize| !failed.contains(&(i + 1));
+        let mut summary = DownloadSummary::default();
+        for (i, item) in items.iter().enumerate() {
+            if !succeeded(i) {
+                continue;
+            }
+            summary.downloaded += 1;
+            match item.kind {
+                MediaKind::Audio => summary.audio += 1,
+                MediaKind::VideoNote if item.extension != "jpg" => summary.video_notes += 1,
+                MediaKind::Sticker => summary.stickers.record(&item.extension),
+                _ => {}
+            }
+        }
+        summary.contributors = credits::leaderboard(
+            items
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| succeeded(*i))
+                .map(|(_, item)| item.contributor.as_str()),
+        )
+        .into_iter()
+        .map(|(name, count)| (name.to_string(), count))
+        .collect();
+        summary
+    }
+
+    /// 接在数量后面的说明，例如“，其中 2 个语音或音频，1 个视频消息”
+    pub fn describe_media(&self) -> FormattedText {
+        let mut text = FormattedText::new();
+        if self.audio > 0 {
+            text = text.text(format!("，其中 {} 个语音或音频", self.audio));
+        }
+        if self.video_notes > 0 {
+            text = text.text(format!("，{} 个视频消息", self.video_notes));
+        }
+        if let Some(stickers) = self.stickers.describe() {
+            text = text.text(format!("，{}", stickers));
+        }
+        text
+    }
+
+    /// 群组会话中列出每个人贡献的数量，私聊时为空
+    pub fn describe_credits(&self, is_group: bool) -> FormattedText {
+        if !is_group || self.contributors.is_empty() {
+            return FormattedText::new();
+        }
+        let counts = self
+            .contributors
+            .iter()
+            .map(|(name, count)| (name.as_str(), *count))
+            .collect::<Vec<_>>();
+        FormattedText::from(format!(
+            "\n\n👥 贡献者：{}",
+            credits::format_leaderboard(&counts)
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+    use std::path::PathBuf;
+
+    fn items(kinds: &[(MediaKind, &str)]) -> Vec<CollectedItem> {
+        let msg = test_util::text(1, "");
+        kinds
+            .iter()
+            .map(|(kind, extension)| {
+                CollectedItem::new(&msg, FileUrl::Local(PathBuf::new()), *kind, *extension)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn names_follow_kind_and_position() {
+        let mut items = items(&[
+            (MediaKind::Image, "jpg"),
+            (MediaKind::Audio, "mp3"),
+            (MediaKind::Sticker, "tgs"),
+            (MediaKind::Sticker, "png"),
+            (MediaKind::VideoNote, "mp4"),
+            (MediaKind::Image, "png"),
+            (MediaKind::Audio, "ogg"),
+            (MediaKind::Image, "jpg"),
+            (MediaKind::Image, "jpg"),
+            (MediaKind::Image, "jpg"),
+        ]);
+        items[4].name = Some("videonote_1.mp4".to_string());
+        items[6].name = Some("voice_1.ogg".to_string());
+        let names = file_names(&items, false);
+        assert_eq!(
+            names,
+            [
+                "image_01.jpg",
+                "audio_02.mp3",
+                "sticker_03.tgs",
+                // 预览图和贴纸使用同一个编号
+                "sticker_03.png",
+                "videonote_1.mp4",
+                "image_06.png",
+                "voice_1.ogg",
+                "image_08.jpg",
+                "image_09.jpg",
+                "image_10.jpg",
+            ]
+        );
+    }
+
+    #[test]
+    fn caption_names_are_unique() {
+        let mut items = items(&[
+            (MediaKind::Image, "jpg"),
+            (MediaKind::Image, "jpg"),
+            (MediaKind::Image, "jpg"),
+            (MediaKind::Audio, "mp3"),
+        ]);
+        items[0].caption = Some("日落".to_string());
+        items[1].caption = Some("日落\n第二天".to_string());
+        items[3].caption = Some("日落".to_string());
+        assert_eq!(
+            file_names(&items, true),
+            ["日落.jpg", "日落_2.jpg", "image_3.jpg", "audio_4.mp3"]
+        );
+        // 关闭时不使用说明文字
+        assert_eq!(file_names(&items, false)[0], "image_1.jpg");
+    }
+
+    #[test]
+    fn summary_counts_only_downloaded_files() {
+        let mut items = items(&[
+            (MediaKind::Image, "jpg"),
+            (MediaKind::Audio, "ogg"),
+            (MediaKind::Audio, "mp3"),
+            (MediaKind::VideoNote, "mp4"),
+            (MediaKind::VideoNote, "jpg"),
+            (MediaKind::Sticker, "webp"),
+            (MediaKind::Sticker, "tgs"),
+            (MediaKind::Image, "jpg"),
+        ]);
+        let contributors = [
+            "Alice", "Bob", "Alice", "Bob", "Bob", "Carol", "Alice", "Bob",
+        ];
+        for (item, contributor) in items.iter_mut().zip(contributors) {
+            item.contributor = contributor.to_string();
+        }
+        let summary = DownloadSummary::new(&items, &[3, 7]);
+
+        assert_eq!(summary.downloaded, 6);
+        assert_eq!(summary.audio, 1);
+        assert_eq!(summary.video_notes, 1);
+        assert_eq!(summary.stickers.still, 1);
+        assert_eq!(summary.stickers.animated, 0);
+        assert_eq!(
+            summary.contributors,
+            [
+                ("Bob".to_string(), 4),
+                ("Alice".to_string(), 1),
+                ("Carol".to_string(), 1)
+            ]
+        );
+        assert_eq!(
+            summary.describe_media(),
+            FormattedText::from(format!(
+                "，其中 1 个语音或音频，1 个视频消息，{}",
+                summary.stickers.describe().unwrap()
+            ))
+        );
+        assert_eq!(
+            summary.describe_credits(true),
+            FormattedText::from("\n\n👥 贡献者：Bob 4 张、Alice 1 张、Carol 1 张")
+        );
+        assert_eq!(summary.describe_credits(false), FormattedText::new());
+    }
+
+    #[test]
+    fn summary_of_plain_images_has_no_details() {
+        let items = items(&[(MediaKind::Image, "jpg"), (MediaKind::Image, "jpg")]);
+        let summary = DownloadSummary::new(&items, &[]);
+        assert_eq!(summary.downloaded, 2);
+        assert_eq!(summary.describe_media(), FormattedText::new());
+    }
+}
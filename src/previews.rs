@@ -0,0 +1,407 @@
+//! 网页链接的预览图
+//!
+//! 开启 `/previews` 后，不像图片直链的链接会被当作网页：读取页面开头的一部分，
+//! 从 `og:image`、`twitter:image` 等 meta 标签中找出预览图，按页面标题命名后打包。
+//! 只接受 `text/html`，每个页面最多读取 [`MAX_PAGE_BYTES`]。页面和预览图都通过 [`crate::external`]
+//! 访问，跳转次数有限，指向内网、回环等地址的链接会被拒绝。
+
+use crate::external::{self, ExternalError};
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Client, Response, Url};
+use std::fmt;
+
+/// 每个页面最多读取的字节数，预览图的 meta 标签都在 `<head>` 中
+pub const MAX_PAGE_BYTES: usize = 512 * 1024;
+/// 按优先级排列的预览图标签
+const IMAGE_KEYS: &[&str] = &[
+    "og:image:secure_url",
+    "og:image",
+    "og:image:url",
+    "twitter:image",
+    "twitter:image:src",
+];
+/// 按优先级排列的标题标签，都没有时使用 `<title>`
+const TITLE_KEYS: &[&str] = &["og:title", "twitter:title"];
+
+#[derive(Debug)]
+pub enum PreviewError {
+    /// 不是网页，保存响应的类型
+    NotHtml(String),
+    /// 页面中没有预览图
+    NoPreview,
+    /// 链接无法访问或被拒绝
+    External(ExternalError),
+}
+
+impl fmt::Display for PreviewError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PreviewError::NotHtml(content_type) => write!(f, "不是网页（{}）", content_type),
+            PreviewError::NoPreview => write!(f, "页面中没有预览图"),
+            PreviewError::External(why) => write!(f, "{}", why),
+        }
+    }
+}
+
+impl std::error::Error for PreviewError {}
+
+impl From<ExternalError> for PreviewError {
+    fn from(why: ExternalError) -> Self {
+        PreviewError::External(why)
+    }
+}
+
+/// 链接指向的内容
+#[derive(Debug)]
+pub enum Page {
+    /// 链接本身就是图片，只是地址看不出来
+    Image,
+    /// 网页的预览图和标题
+    Preview { image: Url, title: Option<String> },
+}
+
+/// 链接的路径是否以图片的扩展名结尾，这样的链接直接下载
+pub fn looks_like_image(url: &Url) -> bool {
+    let path = url.path().to_ascii_lowercase();
+    [".jpg", ".jpeg", ".png", ".gif", ".webp", ".bmp"]
+        .iter()
+        .any(|extension| path.ends_with(extension))
+}
+
+/// 预览图地址中的扩展名，看不出来时为 `jpg`
+pub fn image_extension(url: &Url) -> &'static str {
+    let path = url.path().to_ascii_lowercase();
+    match path.rsplit_once('.').map(|(_, extension)| extension) {
+        Some("png") => "png",
+        Some("gif") => "gif",
+        Some("webp") => "webp",
+        Some("bmp") => "bmp",
+        _ => "jpg",
+    }
+}
+
+/// 读取链接指向的页面，找出其中的预览图
+///
+/// `client` 必须由 [`external::client_builder`] 创建。
+pub async fn fetch(client: &Client, url: Url) -> Result<Page, PreviewError> {
+    read_page(external::fetch(client, url).await?).await
+}
+
+/// 根据响应的类型和页面内容判断链接指向的内容
+async fn read_page(response: Response) -> Result<Page, PreviewError> {
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    if content_type.starts_with("image/") {
+        return Ok(Page::Image);
+    }
+    if !(content_type.starts_with("text/html") || content_type.starts_with("application/xhtml+xml"))
+    {
+        return Err(PreviewError::NotHtml(content_type));
+    }
+
+    let url = response.url().clone();
+    let body = external::read_prefix(response, MAX_PAGE_BYTES).await?;
+    let html = String::from_utf8_lossy(&body);
+    let image = preview_image(&html)
+        .and_then(|src| url.join(&src).ok())
+        .filter(|image| matches!(image.scheme(), "http" | "https"))
+        .ok_or(PreviewError::NoPreview)?;
+    Ok(Page::Preview {
+        image,
+        title: page_title(&html),
+    })
+}
+
+/// 页面中预览图的地址，可能是相对地址
+pub fn preview_image(html: &str) -> Option<String> {
+    meta_value(html, IMAGE_KEYS)
+}
+
+/// 页面的标题，优先使用 `og:title`
+pub fn page_title(html: &str) -> Option<String> {
+    meta_value(html, TITLE_KEYS)
+        .or_else(|| {
+            let lower = html.to_ascii_lowercase();
+            let start = lower.find("<title")?;
+            let start = start + lower[start..].find('>')? + 1;
+            let end = start + lower[start..].find("</title")?;
+            Some(decode_entities(&html[start..end]))
+        })
+        .map(|title| title.split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|title| !title.is_empty())
+}
+
+/// 按 `keys` 的顺序找出第一个存在的 meta 标签的 `content`
+fn meta_value(html: &str, keys: &[&str]) -> Option<String> {
+    let tags = meta_tags(html);
+    keys.iter().find_map(|key| {
+        tags.iter()
+            .find(|(name, content)| name == key && !content.is_empty())
+            .map(|(_, content)| content.clone())
+    })
+}
+
+/// 所有 meta 标签的 `property` 或 `name`（小写）和 `content`
+fn meta_tags(html: &str) -> Vec<(String, String)> {
+    // 只转换 ASCII，字节位置与原文相同
+    let lower = html.to_ascii_lowercase();
+    let mut tags = Vec::new();
+    let mut offset = 0;
+    while let Some(start) = lower[offset..].find("<meta") {
+        let start = offset + start + "<meta".len();
+        let Some(end) = lower[start..].find('>') else {
+            break;
+        };
+        let end = start + end;
+        offset = end;
+        let attributes = attributes(&html[start..end]);
+        let value = |name: &str| {
+            attributes
+                .iter()
+                .find(|(attribute, _)| attribute == name)
+                .map(|(_, value)| value.as_str())
+        };
+        if let (Some(key), Some(content)) = (
+            value("property").or_else(|| value("name")),
+            value("content"),
+        ) {
+            tags.push((key.to_ascii_lowercase(), decode_entities(content.trim())));
+        }
+    }
+    tags
+}
+
+/// 解析标签中的属性，名称转为小写，值可以用单引号、双引号或不加引号
+fn attributes(tag: &str) -> Vec<(String, String)> {
+    let mut attributes = Vec::new();
+    let mut rest = tag;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == '/');
+        let Some(first) = rest.chars().next() else {
+            break;
+        };
+        let name_end = rest
+            .find(|c: char| c.is_whitespace() || c == '=' || c == '/')
+            .unwrap_or(rest.len());
+        if name_end == 0 {
+            // 没有名称的 `=`，跳过
+            rest = &rest[first.len_utf8()..];
+            continue;
+        }
+        let name = rest[..name_end].to_ascii_lowercase();
+        rest = rest[name_end..].trim_start();
+        let value = match rest.strip_prefix('=') {
+            Some(after) => {
+                let after = after.trim_start();
+                match after.chars().next() {
+                    Some(quote @ ('"' | '\'')) => {
+                        let quoted = &after[1..];
+                        let end = quoted.find(quote).unwrap_or(quoted.len());
+                        rest = quoted.get(end + 1..).unwrap_or_default();
+                        &quoted[..end]
+                    }
+                    _ => {
+                        let end = after.find(char::is_whitespace).unwrap_or(after.len());
+                        rest = &after[end..];
+                        &after[..end]
+                    }
+                }
+            }
+            None => "",
+        };
+        attributes.push((name, value.to_string()));
+    }
+    attributes
+}
+
+/// 还原常见的 HTML 实体，例如地址中的 `&amp;`
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let entity = rest[1..]
+            .find(';')
+            .filter(|end| *end <= 10)
+            .map(|end| &rest[1..end + 1]);
+        let character = entity.and_then(|entity| match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            _ => {
+                let code = match entity
+                    .strip_prefix("#x")
+                    .or_else(|| entity.strip_prefix("#X"))
+                {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                    None => entity.strip_prefix('#')?.parse().ok()?,
+                };
+                char::from_u32(code)
+            }
+        });
+        match (entity, character) {
+            (Some(entity), Some(character)) => {
+                decoded.push(character);
+                rest = &rest[entity.len() + 2..];
+            }
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::matchers::path;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    const ARTICLE: &str = r#"<!DOCTYPE html>
+<html><head>
+<title>
+  Fallback   title
+</title>
+<META name="twitter:image" content="https://cdn.example.com/twitter.png">
+<meta property='og:image' content='/images/cover.webp?w=1200&amp;h=630' />
+<meta content="Sunset &amp; sea &#x1F305;" property="og:title">
+<meta property="og:image:secure_url" content="">
+</head><body></body></html>"#;
+
+    #[test]
+    fn scanner_prefers_og_tags() {
+        assert_eq!(
+            preview_image(ARTICLE).as_deref(),
+            Some("/images/cover.webp?w=1200&h=630")
+        );
+        assert_eq!(page_title(ARTICLE).as_deref(), Some("Sunset & sea 🌅"));
+    }
+
+    #[test]
+    fn scanner_falls_back_to_twitter_and_title() {
+        let html = r#"<head><title>  A
+            page </title><meta name=twitter:image:src content=https://x.example/a.jpg></head>"#;
+        assert_eq!(
+            preview_image(html).as_deref(),
+            Some("https://x.example/a.jpg")
+        );
+        assert_eq!(page_title(html).as_deref(), Some("A page"));
+    }
+
+    #[test]
+    fn scanner_tolerates_broken_markup() {
+        assert_eq!(preview_image("<p>no tags</p>"), None);
+        assert_eq!(
+            preview_image("<meta property=\"og:image\" content=\"a.jpg"),
+            None
+        );
+        assert_eq!(
+            preview_image("<meta = property=og:image content=\"b.jpg\">").as_deref(),
+            Some("b.jpg")
+        );
+        assert_eq!(page_title("<title></title>"), None);
+        assert_eq!(
+            decode_entities("a &unknown; &amp b &#99999999;"),
+            "a &unknown; &amp b &#99999999;"
+        );
+    }
+
+    #[test]
+    fn image_urls() {
+        let url = |url: &str| Url::parse(url).unwrap();
+        assert!(looks_like_image(&url("https://example.com/a/B.JPEG")));
+        assert!(!looks_like_image(&url("https://example.com/article.html")));
+        assert!(!looks_like_image(&url("https://example.com/?file=a.png")));
+        assert_eq!(image_extension(&url("https://example.com/a.PNG")), "png");
+        assert_eq!(image_extension(&url("https://example.com/image")), "jpg");
+    }
+
+    /// 从测试服务器读取 `body` 并交给 [`read_page`]
+    async fn page(content_type: &str, body: impl Into<Vec<u8>>) -> Result<Page, PreviewError> {
+        let server = MockServer::start().await;
+        Mock::given(path("/article"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(body, content_type))
+            .mount(&server)
+            .await;
+        let response = Client::new()
+            .get(format!("{}/article", server.uri()))
+            .send()
+            .await
+            .unwrap();
+        read_page(response).await
+    }
+
+    #[tokio::test]
+    async fn preview_is_resolved_against_page() {
+        let page = page("text/html; charset=utf-8", ARTICLE).await.unwrap();
+        let Page::Preview { image, title } = page else {
+            panic!("expected a preview: {:?}", page);
+        };
+        assert_eq!(image.path(), "/images/cover.webp");
+        assert_eq!(image.query(), Some("w=1200&h=630"));
+        assert_eq!(title.as_deref(), Some("Sunset & sea 🌅"));
+    }
+
+    #[tokio::test]
+    async fn only_html_pages_are_read() {
+        assert!(matches!(page("image/png", "png").await, Ok(Page::Image)));
+        assert!(matches!(
+            page("application/json", ARTICLE).await,
+            Err(PreviewError::NotHtml(content_type)) if content_type == "application/json"
+        ));
+        assert!(matches!(
+            page("text/html", "<html></html>").await,
+            Err(PreviewError::NoPreview)
+        ));
+        // 不是 http(s) 的预览图地址视为没有预览图
+        assert!(matches!(
+            page(
+                "text/html",
+                r#"<meta property="og:image" content="javascript:alert(1)">"#
+            )
+            .await,
+            Err(PreviewError::NoPreview)
+        ));
+    }
+
+    #[tokio::test]
+    async fn tags_after_size_limit_are_ignored() {
+        let mut body = "<html><head>".to_string();
+        body.push_str(&" ".repeat(MAX_PAGE_BYTES));
+        body.push_str(r#"<meta property="og:image" content="/late.jpg"></head>"#);
+        assert!(matches!(
+            page("text/html", body).await,
+            Err(PreviewError::NoPreview)
+        ));
+    }
+
+    #[tokio::test]
+    async fn private_pages_are_not_fetched() {
+        let server = MockServer::start().await;
+        let client = external::client_builder("test", Default::default())
+            .build()
+            .unwrap();
+        let url = Url::parse(&format!("{}/article", server.uri())).unwrap();
+        assert!(matches!(
+            fetch(&client, url).await,
+            Err(PreviewError::External(ExternalError::Blocked(_)))
+        ));
+        let metadata = Url::parse("http://169.254.169.254/latest/meta-data/").unwrap();
+        assert!(matches!(
+            fetch(&client, metadata).await,
+            Err(PreviewError::External(ExternalError::Blocked(_)))
+        ));
+        assert!(server.received_requests().await.unwrap().is_empty());
+    }
+}
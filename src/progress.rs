@@ -0,0 +1,239 @@
+use crate::units::{format_duration, format_size, format_speed};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use teloxide::prelude::*;
+use teloxide::types::MessageId;
+use tokio_util::sync::CancellationToken;
+
+/// 进度消息的更新间隔，太频繁会触发telegram的频率限制
+const UPDATE_INTERVAL: Duration = Duration::from_secs(3);
+/// 计算下载速度时使用最近这段时间的数据
+const SPEED_WINDOW: Duration = Duration::from_secs(10);
+
+/// 一次打包任务的进度，由下载任务更新，进度消息读取
+#[derive(Debug)]
+pub struct Progress {
+    total_files: usize,
+    /// 预计的总字节数，包含大小未知的链接时为 `None`
+    total_bytes: Option<u64>,
+    /// 已经结束下载的文件数量，包括失败的
+    downloaded: AtomicUsize,
+    /// 已经接收的字节数
+    bytes: AtomicU64,
+    /// 是否已经进入压缩阶段
+    compressing: AtomicBool,
+    /// 已经写入压缩包的文件数量
+    compressed: AtomicUsize,
+    /// 正在上传的文件的说明，开始上传后才有值
+    uploading: Mutex<Option<String>>,
+}
+
+impl Progress {
+    pub fn new(total_files: usize, total_bytes: Option<u64>) -> Self {
+        Progress {
+            total_files,
+            total_bytes,
+            downloaded: AtomicUsize::new(0),
+            bytes: AtomicU64::new(0),
+            compressing: AtomicBool::new(false),
+            compressed: AtomicUsize::new(0),
+            uploading: Mutex::new(None),
+        }
+    }
+
+    pub fn add_bytes(&self, bytes: u64) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// 撤销一次失败下载接收的字节，重试时会重新计入
+    pub fn discard_bytes(&self, bytes: u64) {
+        self.bytes.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    pub fn finish_download(&self) {
+        self.downloaded.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn start_compressing(&self) {
+        self.compressing.store(true, Ordering::Relaxed);
+    }
+
+    pub fn finish_compressing_file(&self) {
+        self.compressed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// 开始上传文件，`description` 会显示在进度消息中
+    pub fn start_uploading(&self, description: String) {
+        *self.uploading.lock().unwrap() = Some(description);
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    /// 进度消息的内容，`speed` 为最近的下载速度（字节每秒）
+    ///
+    /// 例如 `下载中 12/40 · 34.2 MB / 118.0 MB · 5.1 MB/s · 约 16 秒剩余`。
+    pub fn render(&self, speed: Option<f64>) -> String {
+        if let Some(uploading) = self.uploading.lock().unwrap().as_ref() {
+            return uploading.clone();
+        }
+        if self.compressing.load(Ordering::Relaxed) {
+            return format!(
+                "📦 压缩中 {}/{}",
+                self.compressed.load(Ordering::Relaxed),
+                self.total_files
+            );
+        }
+
+        let bytes = self.bytes();
+        let mut parts = vec![format!(
+            "📥 下载中 {}/{}",
+            self.downloaded.load(Ordering::Relaxed),
+            self.total_files
+        )];
+        parts.push(match self.total_bytes {
+            Some(total) => format!("{} / {}", format_size(bytes), format_size(total)),
+            None => format_size(bytes),
+        });
+        if let Some(speed) = speed.filter(|speed| *speed > 0.0) {
+            parts.push(format_speed(speed));
+            if let Some(total) = self.total_bytes {
+                let remaining = total.saturating_sub(bytes) as f64 / speed;
+                parts.push(format!(
+                    "约 {}剩余",
+                    format_duration(Duration::from_secs_f64(remaining))
+                ));
+            }
+        }
+        parts.join(" · ")
+    }
+}
+
+/// 根据最近一段时间接收的字节数计算滚动平均速度
+#[derive(Debug, Default)]
+pub struct SpeedMeter {
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl SpeedMeter {
+    /// 记录 `now` 时已接收的字节数，返回窗口内的平均速度（字节每秒）
+    pub fn sample(&mut self, now: Instant, bytes: u64) -> Option<f64> {
+        self.samples.push_back((now, bytes));
+        while let Some(&(time, _)) = self.samples.front() {
+            if now.duration_since(time) <= SPEED_WINDOW || self.samples.len() <= 2 {
+                break;
+            }
+            self.samples.pop_front();
+        }
+
+        let &(first_time, first_bytes) = self.samples.front()?;
+        let elapsed = now.duration_since(first_time).as_secs_f64();
+        (elapsed > 0.0).then(|| bytes.saturating_sub(first_bytes) as f64 / elapsed)
+    }
+}
+
+/// 定时将进度写入 `message_id` 对应的消息，直到 `stop` 被触发
+pub async fn report(
+    bot: Bot,
+    chat_id: ChatId,
+    message_id: MessageId,
+    progress: std::sync::Arc<Progress>,
+    stop: CancellationToken,
+) {
+    let mut meter = SpeedMeter::default();
+    let mut last_text = String::new();
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(UPDATE_INTERVAL) => {}
+            _ = stop.cancelled() => return,
+        }
+
+        let speed = meter.sample(Instant::now(), progress.bytes());
+        let text = progress.render(speed);
+        // 内容没有变化时telegram会拒绝编辑
+        if text == last_text {
+            continue;
+        }
+        if let Err(why) = bot.edit_message_text(chat_id, message_id, &text).await {
+            log::debug!("无法更新进度消息: {}", why);
+        }
+        last_text = text;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MB: u64 = 1024 * 1024;
+
+    #[test]
+    fn renders_download_progress_with_eta() {
+        let progress = Progress::new(40, Some(118 * MB));
+        for _ in 0..12 {
+            progress.finish_download();
+        }
+        progress.add_bytes(34 * MB);
+        assert_eq!(
+            progress.render(Some(6.0 * MB as f64)),
+            "📥 下载中 12/40 · 34.0 MB / 118.0 MB · 6.0 MB/s · 约 14 秒剩余"
+        );
+        // 还没有速度时不显示速度和剩余时间
+        assert_eq!(
+            progress.render(None),
+            "📥 下载中 12/40 · 34.0 MB / 118.0 MB"
+        );
+    }
+
+    #[test]
+    fn unknown_total_has_no_eta() {
+        let progress = Progress::new(3, None);
+        progress.add_bytes(2048);
+        assert_eq!(
+            progress.render(Some(1024.0)),
+            "📥 下载中 0/3 · 2.0 KB · 1.0 KB/s"
+        );
+    }
+
+    #[test]
+    fn failed_attempts_are_discarded() {
+        let progress = Progress::new(1, None);
+        progress.add_bytes(100);
+        progress.discard_bytes(40);
+        assert_eq!(progress.bytes(), 60);
+    }
+
+    #[test]
+    fn compressing_and_uploading_replace_download_progress() {
+        let progress = Progress::new(5, None);
+        progress.start_compressing();
+        progress.finish_compressing_file();
+        progress.finish_compressing_file();
+        assert_eq!(progress.render(Some(1.0)), "📦 压缩中 2/5");
+        progress.start_uploading("📤 上传中".to_string());
+        assert_eq!(progress.render(None), "📤 上传中");
+    }
+
+    #[test]
+    fn speed_is_averaged_over_window() {
+        let start = Instant::now();
+        let mut meter = SpeedMeter::default();
+        assert_eq!(meter.sample(start, 0), None);
+        assert_eq!(
+            meter.sample(start + Duration::from_secs(2), 2000),
+            Some(1000.0)
+        );
+        assert_eq!(
+            meter.sample(start + Duration::from_secs(4), 6000),
+            Some(1500.0)
+        );
+        // 超出窗口的旧数据不再参与计算
+        let speed = meter
+            .sample(start + Duration::from_secs(14), 16000)
+            .unwrap();
+        assert_eq!(speed, 1000.0);
+    }
+}
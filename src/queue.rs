@@ -0,0 +1,97 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// 计算任务耗时移动平均时，最新一次任务所占的权重
+const AVERAGE_WEIGHT: f64 = 0.3;
+
+/// 打包任务队列，限制同时运行的任务数量，并根据最近的任务耗时估算等待时间
+#[derive(Debug)]
+pub struct JobQueue {
+    /// 同时运行的任务上限
+    concurrency: usize,
+    /// tokio 的信号量按申请顺序分配，排队顺序与 `waiting` 一致
+    permits: Arc<Semaphore>,
+    /// 正在排队的任务
+    waiting: Mutex<VecDeque<u64>>,
+    next_ticket: AtomicU64,
+    /// 最近任务耗时的移动平均，还没有任务完成时为 `None`
+    average: Mutex<Option<Duration>>,
+}
+
+/// 排队凭证
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ticket(u64);
+
+impl JobQueue {
+    pub fn new(concurrency: usize) -> Self {
+        let concurrency = concurrency.max(1);
+        JobQueue {
+            concurrency,
+            permits: Arc::new(Semaphore::new(concurrency)),
+            waiting: Mutex::new(VecDeque::new()),
+            next_ticket: AtomicU64::new(0),
+            average: Mutex::new(None),
+        }
+    }
+
+    /// 加入队列
+    pub fn join(&self) -> Ticket {
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        self.waiting.lock().unwrap().push_back(ticket);
+        Ticket(ticket)
+    }
+
+    /// 排在前面的任务数量
+    pub fn ahead(&self, ticket: Ticket) -> usize {
+        let waiting = self.waiting.lock().unwrap();
+        waiting
+            .iter()
+            .position(|&t| t == ticket.0)
+            .unwrap_or(waiting.len())
+    }
+
+    /// 估算前面有 `ahead` 个任务排队时需要等待的时间，还没有历史数据时返回 `None`
+    ///
+    /// 需要等正在运行的任务结束，再等前面的任务按并发数分批完成。
+    pub fn estimate(&self, ahead: usize) -> Option<Duration> {
+        let average = (*self.average.lock().unwrap())?;
+        let rounds = (ahead / self.concurrency + 1) as u32;
+        Some(average * rounds)
+    }
+
+    /// 有空闲名额时立即获得运行许可
+    pub fn try_acquire(&self, ticket: Ticket) -> Option<OwnedSemaphorePermit> {
+        let permit = Arc::clone(&self.permits).try_acquire_owned().ok()?;
+        self.leave(ticket);
+        Some(permit)
+    }
+
+    /// 等待轮到自己，返回运行许可，许可释放后下一个任务才能开始
+    pub async fn acquire(&self, ticket: Ticket) -> OwnedSemaphorePermit {
+        let permit = Arc::clone(&self.permits)
+            .acquire_owned()
+            .await
+            .expect("job queue semaphore closed");
+        self.leave(ticket);
+        permit
+    }
+
+    /// 离开队列
+    fn leave(&self, ticket: Ticket) {
+        self.waiting.lock().unwrap().retain(|&t| t != ticket.0);
+    }
+
+    /// 记录一次任务的耗时
+    pub fn record(&self, elapsed: Duration) {
+        let mut average = self.average.lock().unwrap();
+        *average = Some(match *average {
+            Some(previous) => {
+                previous.mul_f64(1.0 - AVERAGE_WEIGHT) + elapsed.mul_f64(AVERAGE_WEIGHT)
+            }
+            None => elapsed,
+        });
+    }
+}
@@ -0,0 +1,134 @@
+//! 定期发给管理员的运行汇总
+//!
+//! 设置 `REPORT_TIME`（例如 `23:30`）后，每天在该时间把最近 24 小时的汇总发给 `ADMIN_IDS` 中的所有id；
+//! `REPORT_PERIOD=weekly` 时改为每周一发送最近 7 天的汇总。时间按 `REPORT_TIMEZONE`（例如
+//! `Asia/Shanghai`，默认 UTC）计算。
+
+use crate::{markdown, stats, workspace};
+use chrono::{DateTime, Datelike, Days, NaiveTime, TimeZone, Utc, Weekday};
+use chrono_tz::Tz;
+use std::path::PathBuf;
+use std::time::Duration;
+use teloxide::prelude::*;
+
+/// 报告的周期
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Period {
+    /// 每天发送最近 24 小时的汇总
+    #[default]
+    Daily,
+    /// 每周一发送最近 7 天的汇总
+    Weekly,
+}
+
+impl Period {
+    /// 汇总覆盖的时间
+    pub fn window(&self) -> Duration {
+        match self {
+            Period::Daily => Duration::from_secs(24 * 60 * 60),
+            Period::Weekly => Duration::from_secs(7 * 24 * 60 * 60),
+        }
+    }
+
+    /// 报告中显示的时间范围
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Period::Daily => "24 小时",
+            Period::Weekly => "7 天",
+        }
+    }
+}
+
+/// 发送报告的时间
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    time: NaiveTime,
+    timezone: Tz,
+    pub period: Period,
+}
+
+impl Schedule {
+    /// 读取 `REPORT_TIME`、`REPORT_TIMEZONE` 和 `REPORT_PERIOD`，没有设置 `REPORT_TIME` 时返回 `None`
+    pub fn from_env() -> Option<Self> {
+        let time = std::env::var("REPORT_TIME")
+            .ok()
+            .filter(|time| !time.trim().is_empty())?;
+        let time = NaiveTime::parse_from_str(time.trim(), "%H:%M")
+            .unwrap_or_else(|_| panic!("REPORT_TIME is invalid: {:?}, expected HH:MM", time));
+        let timezone = match std::env::var("REPORT_TIMEZONE") {
+            Ok(timezone) => timezone.trim().parse().unwrap_or_else(|_| {
+                panic!(
+                    "REPORT_TIMEZONE is invalid: {:?}, expected a name like Asia/Shanghai",
+                    timezone
+                )
+            }),
+            Err(_) => Tz::UTC,
+        };
+        let period = match std::env::var("REPORT_PERIOD")
+            .unwrap_or_default()
+            .trim()
+            .to_lowercase()
+            .as_str()
+        {
+            "" | "daily" => Period::Daily,
+            "weekly" => Period::Weekly,
+            other => panic!(
+                "REPORT_PERIOD is invalid: {:?}, expected daily or weekly",
+                other
+            ),
+        };
+        Some(Schedule {
+            time,
+            timezone,
+            period,
+        })
+    }
+
+    /// `now` 之后下一次发送的时间
+    fn next_run(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let today = now.with_timezone(&self.timezone).date_naive();
+        (0..=7)
+            .filter_map(|days| today.checked_add_days(Days::new(days)))
+            .filter(|date| self.period == Period::Daily || date.weekday() == Weekday::Mon)
+            // 夏令时跳过的时间没有对应的时刻，这一天不发送
+            .filter_map(|date| {
+                self.timezone
+                    .from_local_datetime(&date.and_time(self.time))
+                    .earliest()
+            })
+            .map(|time| time.with_timezone(&Utc))
+            .find(|time| *time > now)
+            .unwrap_or(now + chrono::Duration::days(1))
+    }
+
+    pub fn describe(&self) -> String {
+        let day = match self.period {
+            Period::Daily => "每天",
+            Period::Weekly => "每周一",
+        };
+        format!("{} {} ({})", day, self.time.format("%H:%M"), self.timezone)
+    }
+}
+
+/// 最近一个周期的汇总
+pub async fn summary(period: Period, temp_root: &std::path::Path) -> String {
+    let usage = workspace::usage(temp_root).await;
+    stats::summary(period.window(), period.describe(), usage)
+}
+
+/// 按计划发送汇总，直到程序退出
+pub async fn run(bot: Bot, schedule: Schedule, recipients: Vec<ChatId>, temp_root: PathBuf) {
+    log::info!("将在{}向管理员发送运行汇总", schedule.describe());
+    loop {
+        let now = Utc::now();
+        let next = schedule.next_run(now);
+        tokio::time::sleep((next - now).to_std().unwrap_or_default()).await;
+
+        let text = summary(schedule.period, &temp_root).await;
+        for &chat_id in &recipients {
+            if let Err(why) = markdown::send(&bot, chat_id, None, text.as_str()).await {
+                log::warn!("无法向 {} 发送运行汇总: {}", chat_id, why);
+            }
+        }
+    }
+}
@@ -0,0 +1,229 @@
+//! 失败任务的断点续传
+//!
+//! 打包任务失败后临时目录会保留一段时间，其中的 [`MANIFEST_NAME`] 记录了已经下载完成的文件：
+//! 每行为文件名、大小和 SHA-256，用制表符分隔。发送 /retry 重试时使用同一个临时目录，
+//! 大小和哈希都与记录一致的文件直接使用，缺少或内容变化的文件（例如下载后加了水印）重新下载。
+
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// 临时目录中记录已完成文件的文件名，以点开头，不会和下载的文件重名
+pub const MANIFEST_NAME: &str = ".manifest";
+
+/// 一个任务临时目录中已经下载完成的文件
+#[derive(Debug)]
+pub struct Manifest {
+    path: PathBuf,
+    /// 文件名对应的大小和十六进制的 SHA-256
+    entries: Mutex<HashMap<String, (u64, String)>>,
+}
+
+impl Manifest {
+    /// 读取临时目录中的记录，没有记录时从空记录开始
+    pub fn load(dir: &Path) -> Self {
+        let path = dir.join(MANIFEST_NAME);
+        let mut entries = HashMap::new();
+        for line in std::fs::read_to_string(&path).unwrap_or_default().lines() {
+            let mut fields = line.split('\t');
+            if let (Some(name), Some(Ok(size)), Some(hash)) = (
+                fields.next(),
+                fields.next().map(str::parse::<u64>),
+                fields.next(),
+            ) {
+                entries.insert(name.to_string(), (size, hash.to_string()));
+            }
+        }
+        Manifest {
+            path,
+            entries: Mutex::new(entries),
+        }
+    }
+
+    /// 已经记录的文件数量
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    /// `path` 是否已经完整下载：有记录，并且现在的大小和哈希都与记录一致
+    ///
+    /// 需要读取整个文件，同步执行，需要在阻塞线程中调用。
+    pub fn is_complete(&self, path: &Path) -> bool {
+        let Some(name) = file_name(path) else {
+            return false;
+        };
+        let Some((size, hash)) = self.entries.lock().unwrap().get(name).cloned() else {
+            return false;
+        };
+        std::fs::metadata(path).is_ok_and(|metadata| metadata.len() == size)
+            && sha256(path).is_ok_and(|actual| actual == hash)
+    }
+
+    /// 记录下载完成的文件
+    ///
+    /// 需要读取整个文件，同步执行，需要在阻塞线程中调用。
+    pub fn record(&self, path: &Path) -> std::io::Result<()> {
+        let name = file_name(path).ok_or(std::io::ErrorKind::InvalidInput)?;
+        let size = std::fs::metadata(path)?.len();
+        let hash = sha256(path)?;
+        let mut entries = self.entries.lock().unwrap();
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| writeln!(file, "{}\t{}\t{}", name, size, hash))?;
+        entries.insert(name.to_string(), (size, hash));
+        Ok(())
+    }
+}
+
+fn file_name(path: &Path) -> Option<&str> {
+    path.file_name()?.to_str()
+}
+
+/// 文件内容的十六进制 SHA-256
+fn sha256(path: &Path) -> std::io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+/// 下载一个文件并记录到 `manifest`；`resuming` 时已经完整下载的文件直接使用
+///
+/// 返回文件是否是之前下载的。记录失败只影响下次重试，不影响这次的结果。
+pub async fn download_once<E>(
+    manifest: Arc<Manifest>,
+    path: &Path,
+    resuming: bool,
+    download: impl Future<Output = Result<(), E>>,
+) -> Result<bool, E> {
+    if resuming {
+        let (manifest, path) = (Arc::clone(&manifest), path.to_path_buf());
+        let complete = tokio::task::spawn_blocking(move || manifest.is_complete(&path))
+            .await
+            .unwrap_or(false);
+        if complete {
+            return Ok(true);
+        }
+    }
+    download.await?;
+    let recorded = {
+        let path = path.to_path_buf();
+        tokio::task::spawn_blocking(move || manifest.record(&path)).await
+    };
+    if let Ok(Err(why)) = recorded {
+        log::warn!("Failed to record {} for retry: {}", path.display(), why);
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// 模拟下载：写入 `content` 并计数
+    async fn fetch(path: &Path, content: &str, calls: &AtomicUsize) -> Result<(), std::io::Error> {
+        calls.fetch_add(1, Ordering::SeqCst);
+        tokio::fs::write(path, content).await
+    }
+
+    #[tokio::test]
+    async fn resume_skips_downloaded_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (first, second) = (
+            dir.path().join("image_1.jpg"),
+            dir.path().join("image_2.jpg"),
+        );
+        let calls = AtomicUsize::new(0);
+
+        // 第一次只下载了第一个文件就失败了
+        let manifest = Arc::new(Manifest::load(dir.path()));
+        let resumed = download_once(manifest, &first, false, fetch(&first, "one", &calls)).await;
+        assert!(!resumed.unwrap());
+        let failed: Result<bool, std::io::Error> = download_once(
+            Arc::new(Manifest::load(dir.path())),
+            &second,
+            false,
+            async { Err(std::io::ErrorKind::TimedOut.into()) },
+        )
+        .await;
+        assert!(failed.is_err());
+
+        // 重试时重新读取记录，只下载缺少的文件
+        let manifest = Arc::new(Manifest::load(dir.path()));
+        assert_eq!(manifest.len(), 1);
+        let resumed = download_once(
+            Arc::clone(&manifest),
+            &first,
+            true,
+            fetch(&first, "one", &calls),
+        )
+        .await;
+        assert!(resumed.unwrap());
+        let resumed = download_once(
+            Arc::clone(&manifest),
+            &second,
+            true,
+            fetch(&second, "two", &calls),
+        )
+        .await;
+        assert!(!resumed.unwrap());
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!(Manifest::load(dir.path()).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn changed_files_are_downloaded_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("image_1.jpg");
+        let calls = AtomicUsize::new(0);
+        let manifest = Arc::new(Manifest::load(dir.path()));
+        download_once(manifest, &path, false, fetch(&path, "one", &calls))
+            .await
+            .unwrap();
+
+        // 例如下载后加了水印
+        std::fs::write(&path, "marked").unwrap();
+        let manifest = Arc::new(Manifest::load(dir.path()));
+        let resumed = download_once(manifest, &path, true, fetch(&path, "one", &calls)).await;
+        assert!(!resumed.unwrap());
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "one");
+    }
+
+    #[tokio::test]
+    async fn fresh_jobs_always_download() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("image_1.jpg");
+        let calls = AtomicUsize::new(0);
+        let manifest = Arc::new(Manifest::load(dir.path()));
+        download_once(
+            Arc::clone(&manifest),
+            &path,
+            false,
+            fetch(&path, "one", &calls),
+        )
+        .await
+        .unwrap();
+        // 不是重试时即使有记录也重新下载
+        download_once(manifest, &path, false, fetch(&path, "one", &calls))
+            .await
+            .unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,291 @@
+//! 将压缩包上传到 SFTP 服务器
+
+use ssh2::{CheckResult, HashType, KnownHostFileKind, Session};
+use std::fmt;
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use teloxide::types::ChatId;
+
+/// 连接 SFTP 服务器的超时
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// SSH 登录方式
+#[derive(Clone)]
+pub enum Auth {
+    /// 私钥文件，可以带密码
+    Key {
+        path: PathBuf,
+        passphrase: Option<String>,
+    },
+    Password(String),
+}
+
+// 不输出密码，避免出现在日志中
+impl fmt::Debug for Auth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Auth::Key { path, .. } => write!(f, "Key({})", path.display()),
+            Auth::Password(_) => write!(f, "Password(***)"),
+        }
+    }
+}
+
+/// 如何验证服务器的主机密钥
+#[derive(Debug, Clone)]
+pub enum HostKeyCheck {
+    /// 在 OpenSSH 格式的 known_hosts 文件中查找
+    KnownHosts(PathBuf),
+    /// 与 `ssh-keygen -l` 显示的 `SHA256:...` 指纹比较
+    Fingerprint(String),
+}
+
+/// SFTP 上传失败的原因，不包含任何登录凭据
+#[derive(Debug)]
+pub enum SftpError {
+    Io(std::io::Error),
+    Ssh(ssh2::Error),
+    /// 主机密钥无法验证，可能连接到了错误的服务器
+    HostKey(String),
+    /// 用户名、密钥或密码不正确
+    Auth,
+    /// 上传后远程文件的大小与本地不一致
+    SizeMismatch {
+        expected: u64,
+        actual: u64,
+    },
+}
+
+impl fmt::Display for SftpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SftpError::Io(why) => write!(f, "连接或读取文件失败: {}", why),
+            SftpError::Ssh(why) => write!(f, "SSH 错误: {}", why.message()),
+            SftpError::HostKey(why) => write!(f, "主机密钥验证失败: {}", why),
+            SftpError::Auth => write!(
+                f,
+                "SSH 登录失败，请管理员检查 SFTP_USERNAME 和密钥或密码的配置"
+            ),
+            SftpError::SizeMismatch { expected, actual } => write!(
+                f,
+                "上传后远程文件大小为 {} 字节，本地为 {} 字节",
+                actual, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SftpError {}
+
+impl From<std::io::Error> for SftpError {
+    fn from(why: std::io::Error) -> Self {
+        SftpError::Io(why)
+    }
+}
+
+impl From<ssh2::Error> for SftpError {
+    fn from(why: ssh2::Error) -> Self {
+        SftpError::Ssh(why)
+    }
+}
+
+/// SFTP 服务器的配置，来自 `SFTP_*` 环境变量
+#[derive(Debug, Clone)]
+pub struct SftpConfig {
+    host: String,
+    port: u16,
+    username: String,
+    auth: Auth,
+    host_key: HostKeyCheck,
+    /// 远程目录的模板，`{date}` 替换为打包日期，`{chat}` 替换为会话id
+    remote_dir: String,
+}
+
+impl SftpConfig {
+    /// 读取 `SFTP_*` 环境变量，没有设置 `SFTP_HOST` 时返回 `None`，配置不完整时直接退出
+    pub fn from_env() -> Option<Self> {
+        let host = std::env::var("SFTP_HOST")
+            .ok()
+            .filter(|host| !host.is_empty())?;
+        let var = |key: &str| std::env::var(key).ok().filter(|value| !value.is_empty());
+
+        let auth = match (var("SFTP_KEY_PATH"), var("SFTP_PASSWORD")) {
+            (Some(path), _) => Auth::Key {
+                path: PathBuf::from(path),
+                passphrase: var("SFTP_KEY_PASSPHRASE"),
+            },
+            (None, Some(password)) => Auth::Password(password),
+            (None, None) => {
+                log::error!("设置了 SFTP_HOST 时必须设置 SFTP_KEY_PATH 或 SFTP_PASSWORD");
+                crate::telemetry::exit(1);
+            }
+        };
+        let host_key = match (var("SFTP_HOST_FINGERPRINT"), var("SFTP_KNOWN_HOSTS")) {
+            (Some(fingerprint), _) => HostKeyCheck::Fingerprint(fingerprint),
+            (None, Some(path)) => HostKeyCheck::KnownHosts(PathBuf::from(path)),
+            (None, None) => match std::env::var("HOME") {
+                Ok(home) => HostKeyCheck::KnownHosts(Path::new(&home).join(".ssh/known_hosts")),
+                Err(_) => {
+                    log::error!(
+                        "设置了 SFTP_HOST 时必须设置 SFTP_KNOWN_HOSTS 或 SFTP_HOST_FINGERPRINT"
+                    );
+                    crate::telemetry::exit(1);
+                }
+            },
+        };
+        Some(SftpConfig {
+            host,
+            port: var("SFTP_PORT").map_or(22, |port| {
+                port.parse()
+                    .unwrap_or_else(|_| panic!("SFTP_PORT is invalid"))
+            }),
+            username: var("SFTP_USERNAME").unwrap_or_else(|| {
+                log::error!("设置了 SFTP_HOST 时必须设置 SFTP_USERNAME");
+                crate::telemetry::exit(1);
+            }),
+            auth,
+            host_key,
+            remote_dir: var("SFTP_REMOTE_DIR").unwrap_or_else(|| "{date}/{chat}".to_string()),
+        })
+    }
+
+    /// 会话的压缩包上传到的远程目录
+    fn remote_dir(&self, chat_id: ChatId) -> String {
+        self.remote_dir
+            .replace(
+                "{date}",
+                &chrono::Local::now().format("%Y-%m-%d").to_string(),
+            )
+            .replace("{chat}", &chat_id.to_string())
+            .trim_end_matches('/')
+            .to_string()
+    }
+
+    /// 上传 `local` 到会话的远程目录，返回远程路径
+    pub async fn upload(&self, local: &Path, chat_id: ChatId) -> Result<String, SftpError> {
+        let config = self.clone();
+        let local = local.to_path_buf();
+        let remote_dir = self.remote_dir(chat_id);
+        tokio::task::spawn_blocking(move || config.upload_blocking(&local, &remote_dir))
+            .await
+            .map_err(|why| SftpError::Io(std::io::Error::other(why)))?
+    }
+
+    fn upload_blocking(&self, local: &Path, remote_dir: &str) -> Result<String, SftpError> {
+        let address = std::net::ToSocketAddrs::to_socket_addrs(&(self.host.as_str(), self.port))?
+            .next()
+            .ok_or_else(|| std::io::Error::other(format!("无法解析 {}", self.host)))?;
+        let tcp = TcpStream::connect_timeout(&address, CONNECT_TIMEOUT)?;
+        let mut session = Session::new()?;
+        session.set_tcp_stream(tcp);
+        session.handshake()?;
+        self.verify_host_key(&session)?;
+        self.authenticate(&session)?;
+
+        let sftp = session.sftp()?;
+        // 逐级创建远程目录，已经存在的目录会创建失败，忽略即可
+        let mut dir = PathBuf::new();
+        for component in Path::new(remote_dir).components() {
+            dir.push(component);
+            if sftp.stat(&dir).is_err() {
+                sftp.mkdir(&dir, 0o755)?;
+            }
+        }
+
+        let remote = Path::new(remote_dir).join(local.file_name().unwrap_or_default());
+        let expected = std::fs::metadata(local)?.len();
+        let mut file = std::fs::File::open(local)?;
+        let mut remote_file = sftp.create(&remote)?;
+        std::io::copy(&mut file, &mut remote_file)?;
+        drop(remote_file);
+
+        let actual = sftp.stat(&remote)?.size.unwrap_or_default();
+        if actual != expected {
+            return Err(SftpError::SizeMismatch { expected, actual });
+        }
+        Ok(remote.display().to_string())
+    }
+
+    fn verify_host_key(&self, session: &Session) -> Result<(), SftpError> {
+        let (key, _) = session
+            .host_key()
+            .ok_or_else(|| SftpError::HostKey("服务器没有提供主机密钥".to_string()))?;
+        match &self.host_key {
+            HostKeyCheck::KnownHosts(path) => {
+                let mut known_hosts = session.known_hosts()?;
+                known_hosts
+                    .read_file(path, KnownHostFileKind::OpenSSH)
+                    .map_err(|why| {
+                        SftpError::HostKey(format!(
+                            "无法读取 {}: {}",
+                            path.display(),
+                            why.message()
+                        ))
+                    })?;
+                match known_hosts.check_port(&self.host, self.port, key) {
+                    CheckResult::Match => Ok(()),
+                    CheckResult::Mismatch => Err(SftpError::HostKey(format!(
+                        "{} 的主机密钥与 {} 中记录的不一致",
+                        self.host,
+                        path.display()
+                    ))),
+                    CheckResult::NotFound => Err(SftpError::HostKey(format!(
+                        "{} 中没有 {} 的记录",
+                        path.display(),
+                        self.host
+                    ))),
+                    CheckResult::Failure => {
+                        Err(SftpError::HostKey("检查 known_hosts 时出错".to_string()))
+                    }
+                }
+            }
+            HostKeyCheck::Fingerprint(expected) => {
+                let hash = session
+                    .host_key_hash(HashType::Sha256)
+                    .ok_or_else(|| SftpError::HostKey("无法计算主机密钥的指纹".to_string()))?;
+                let actual = format!("SHA256:{}", base64_no_pad(hash));
+                if actual == expected.trim() {
+                    Ok(())
+                } else {
+                    Err(SftpError::HostKey(format!(
+                        "{} 的指纹为 {}，与配置的不一致",
+                        self.host, actual
+                    )))
+                }
+            }
+        }
+    }
+
+    fn authenticate(&self, session: &Session) -> Result<(), SftpError> {
+        let result = match &self.auth {
+            Auth::Key { path, passphrase } => {
+                session.userauth_pubkey_file(&self.username, None, path, passphrase.as_deref())
+            }
+            Auth::Password(password) => session.userauth_password(&self.username, password),
+        };
+        // 错误信息可能包含认证细节，只记录在日志中
+        if let Err(why) = result {
+            log::debug!("SFTP authentication failed: {}", why.message());
+        }
+        if !session.authenticated() {
+            return Err(SftpError::Auth);
+        }
+        Ok(())
+    }
+}
+
+/// 与 OpenSSH 显示指纹的方式相同，不带填充的标准 base64
+fn base64_no_pad(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut text = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &byte)| n | (byte as u32) << (16 - 8 * i));
+        for i in 0..=chunk.len() {
+            text.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+        }
+    }
+    text
+}
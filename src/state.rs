@@ -0,0 +1,267 @@
+//! 会话状态的存储
+//!
+//! 处理函数只通过 [`StateStore`] 访问会话状态，默认使用内存中的 [`MemoryStore`]，
+//! 以后可以换成持久化的实现而不需要修改处理逻辑。
+
+use futures::future::BoxFuture;
+use std::collections::HashMap;
+use std::hash::Hash;
+use tokio::sync::Mutex;
+
+/// 在存储内部修改一个会话的函数，参数为会话当前的状态，不存在时为 `None`
+///
+/// 调用结束后为 `None` 表示删除这个会话。
+pub type Modify<'a, V> = Box<dyn FnOnce(&mut Option<V>) + Send + 'a>;
+
+/// 在存储内部查看或修改所有会话的函数，参数依次给出每个会话，不能新建或删除会话
+pub type ModifyAll<'a, K, V> =
+    Box<dyn for<'s> FnOnce(&mut dyn Iterator<Item = (&'s K, &'s mut V)>) + Send + 'a>;
+
+/// 按会话保存状态的存储
+///
+/// 会话状态包含正在运行的任务，无法按值读写，所以读写都通过在存储内部执行的函数完成，
+/// 同一个存储上的操作依次执行。只涉及一个会话时使用 [`get`](Self::get)、
+/// [`update`](Self::update) 等操作，需要同时查看或修改多个会话时（例如统计正在收集的会话）
+/// 才使用 [`count_where`](Self::count_where)、[`filter_map`](Self::filter_map)
+/// 或 [`modify_all`](StateStore::modify_all)。
+pub trait StateStore<K, V>: Send + Sync {
+    /// 修改一个会话的状态，可以创建、修改或删除这个会话
+    fn modify<'a>(&'a self, key: K, f: Modify<'a, V>) -> BoxFuture<'a, ()>;
+
+    /// 在一次操作中查看或修改所有会话，期间其他操作需要等待
+    fn modify_all<'a>(&'a self, f: ModifyAll<'a, K, V>) -> BoxFuture<'a, ()>;
+}
+
+impl<'s, K, V> dyn StateStore<K, V> + 's
+where
+    K: Send + 'static,
+    V: Send + 'static,
+{
+    /// 读取一个会话的状态，会话不存在时返回 `None`
+    pub async fn get<R: Send>(&self, key: K, f: impl FnOnce(&V) -> R + Send) -> Option<R> {
+        let mut result = None;
+        self.modify(key, Box::new(|value| result = value.as_ref().map(f)))
+            .await;
+        result
+    }
+
+    /// 修改已有会话的状态，会话不存在时不做任何事并返回 `None`
+    pub async fn update<R: Send>(&self, key: K, f: impl FnOnce(&mut V) -> R + Send) -> Option<R> {
+        let mut result = None;
+        self.modify(key, Box::new(|value| result = value.as_mut().map(f)))
+            .await;
+        result
+    }
+
+    /// 修改会话的状态，会话不存在时先用 `init` 创建
+    pub async fn update_or_insert<R: Send>(
+        &self,
+        key: K,
+        init: impl FnOnce() -> V + Send,
+        f: impl FnOnce(&mut V) -> R + Send,
+    ) -> R {
+        let mut result = None;
+        self.modify(
+            key,
+            Box::new(|value| result = Some(f(value.get_or_insert_with(init)))),
+        )
+        .await;
+        result.expect("modify 没有调用修改函数")
+    }
+
+    /// 保存会话的状态，返回之前的状态
+    pub async fn insert(&self, key: K, value: V) -> Option<V> {
+        let mut previous = None;
+        self.modify(key, Box::new(|current| previous = current.replace(value)))
+            .await;
+        previous
+    }
+
+    /// 满足 `predicate` 的会话数量
+    pub async fn count_where(&self, predicate: impl Fn(&V) -> bool + Send + Sync) -> usize {
+        let mut count = 0;
+        self.modify_all(Box::new(|sessions| {
+            count = sessions.filter(|(_, value)| predicate(value)).count()
+        }))
+        .await;
+        count
+    }
+
+    /// 依次对每个会话调用 `f`，返回其中不为 `None` 的结果
+    pub async fn filter_map<R: Send>(
+        &self,
+        mut f: impl FnMut(&K, &mut V) -> Option<R> + Send,
+    ) -> Vec<R> {
+        let mut results = Vec::new();
+        self.modify_all(Box::new(|sessions| {
+            results = sessions.filter_map(|(key, value)| f(key, value)).collect()
+        }))
+        .await;
+        results
+    }
+
+    /// 删除会话，返回删除前的状态
+    pub async fn remove(&self, key: K) -> Option<V> {
+        let mut removed = None;
+        self.modify(key, Box::new(|current| removed = current.take()))
+            .await;
+        removed
+    }
+}
+
+/// 保存在内存中的状态，程序退出后丢失
+#[derive(Debug, Default)]
+pub struct MemoryStore<K, V> {
+    sessions: Mutex<HashMap<K, V>>,
+}
+
+impl<K, V> MemoryStore<K, V> {
+    pub fn new() -> Self {
+        MemoryStore {
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<K, V> StateStore<K, V> for MemoryStore<K, V>
+where
+    K: Eq + Hash + Send,
+    V: Send,
+{
+    fn modify<'a>(&'a self, key: K, f: Modify<'a, V>) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            let mut sessions = self.sessions.lock().await;
+            let mut value = sessions.remove(&key);
+            f(&mut value);
+            if let Some(value) = value {
+                sessions.insert(key, value);
+            }
+        })
+    }
+
+    fn modify_all<'a>(&'a self, f: ModifyAll<'a, K, V>) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            f(&mut self.sessions.lock().await.iter_mut());
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// 记录每个操作次数的存储，用来确认操作只经过 [`StateStore::modify`]
+    #[derive(Default)]
+    struct FakeStore {
+        sessions: Mutex<HashMap<u32, String>>,
+        modifies: AtomicUsize,
+        modify_alls: AtomicUsize,
+    }
+
+    impl StateStore<u32, String> for FakeStore {
+        fn modify<'a>(&'a self, key: u32, f: Modify<'a, String>) -> BoxFuture<'a, ()> {
+            self.modifies.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async move {
+                let mut sessions = self.sessions.lock().await;
+                let mut value = sessions.get(&key).cloned();
+                f(&mut value);
+                match value {
+                    Some(value) => sessions.insert(key, value),
+                    None => sessions.remove(&key),
+                };
+            })
+        }
+
+        fn modify_all<'a>(&'a self, f: ModifyAll<'a, u32, String>) -> BoxFuture<'a, ()> {
+            self.modify_alls.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async move {
+                // 与持久化的实现一样，先读出所有会话，修改后再写回
+                let mut sessions = self.sessions.lock().await;
+                let mut values = sessions.clone().into_iter().collect::<Vec<_>>();
+                f(&mut values.iter_mut().map(|(key, value)| (&*key, value)));
+                sessions.extend(values);
+            })
+        }
+    }
+
+    /// 对任意实现执行同一组操作
+    async fn exercise(store: &dyn StateStore<u32, String>) {
+        assert_eq!(store.get(1, |value| value.clone()).await, None);
+        assert_eq!(store.update(1, |value| value.push('!')).await, None);
+        assert_eq!(store.get(1, |value| value.clone()).await, None);
+
+        let len = store
+            .update_or_insert(
+                1,
+                || "a".to_string(),
+                |value| {
+                    value.push('b');
+                    value.len()
+                },
+            )
+            .await;
+        assert_eq!(len, 2);
+        // 已经存在时不再调用 init
+        store
+            .update_or_insert(1, || unreachable!(), |value| value.push('c'))
+            .await;
+        assert_eq!(
+            store.get(1, |value| value.clone()).await.as_deref(),
+            Some("abc")
+        );
+
+        assert_eq!(store.insert(2, "x".to_string()).await, None);
+        assert_eq!(store.insert(2, "y".to_string()).await.as_deref(), Some("x"));
+        assert_eq!(store.update(2, |value| value.len()).await, Some(1));
+
+        assert_eq!(store.remove(1).await.as_deref(), Some("abc"));
+        assert_eq!(store.remove(1).await, None);
+        assert_eq!(store.filter_map(|key, _| Some(*key)).await, [2]);
+
+        store.insert(3, "zz".to_string()).await;
+        assert_eq!(store.count_where(|value| value.len() == 2).await, 1);
+        // 修改所有会话
+        let mut lengths = store
+            .filter_map(|key, value| {
+                value.push('!');
+                (*key == 3).then_some(value.len())
+            })
+            .await;
+        lengths.sort();
+        assert_eq!(lengths, [3]);
+        assert_eq!(
+            store.get(2, |value| value.clone()).await.as_deref(),
+            Some("y!")
+        );
+
+        // 在一次操作中根据其他会话修改某个会话
+        store
+            .modify_all(Box::new(|sessions| {
+                let mut sessions = sessions.collect::<Vec<_>>();
+                let total = sessions.iter().map(|(_, value)| value.len()).sum::<usize>();
+                if let Some((_, value)) = sessions.iter_mut().find(|(key, _)| **key == 2) {
+                    value.push_str(&total.to_string());
+                }
+            }))
+            .await;
+        assert_eq!(
+            store.get(2, |value| value.clone()).await.as_deref(),
+            Some("y!5")
+        );
+    }
+
+    #[tokio::test]
+    async fn operations_on_fake() {
+        let store = Arc::new(FakeStore::default());
+        exercise(store.as_ref()).await;
+        assert_eq!(store.modifies.load(Ordering::SeqCst), 14);
+        assert_eq!(store.modify_alls.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn operations_on_memory_store() {
+        exercise(&MemoryStore::new()).await;
+    }
+}
@@ -0,0 +1,139 @@
+//! 运行统计，用于定期发给管理员的汇总报告和 /report
+//!
+//! 只保存在内存中，重启后从零开始，超过 [`RETENTION`] 的记录会被丢弃。
+
+use crate::units::format_size;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// 记录保留的时间，覆盖最长的报告周期
+const RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// 报告中最多列出的失败任务数量
+const FAILURES_LISTED: usize = 10;
+
+/// 任务的结束状态
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobStatus {
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+impl JobStatus {
+    /// 通知中使用的名称
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobStatus::Succeeded => "succeeded",
+            JobStatus::Failed => "failed",
+            JobStatus::Cancelled => "cancelled",
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Event {
+    Job {
+        id: Uuid,
+        status: JobStatus,
+        /// 下载成功的文件数量
+        images: usize,
+        /// 送达的压缩包总大小
+        bytes: u64,
+    },
+    NewChat,
+}
+
+static EVENTS: Mutex<Vec<(Instant, Event)>> = Mutex::new(Vec::new());
+
+fn record(event: Event) {
+    let mut events = EVENTS.lock().unwrap();
+    events.retain(|(at, _)| at.elapsed() < RETENTION);
+    events.push((Instant::now(), event));
+}
+
+/// 记录一个结束的任务
+pub fn record_job(id: Uuid, status: JobStatus, images: usize, bytes: u64) {
+    record(Event::Job {
+        id,
+        status,
+        images,
+        bytes,
+    });
+}
+
+/// 记录第一次互动的会话
+pub fn record_new_chat() {
+    record(Event::NewChat);
+}
+
+/// 最近 `window` 内的汇总，`period` 为报告中显示的时间范围，例如 `24 小时`
+///
+/// `temp_usage` 为临时目录的数量和总大小。没有任何任务时只返回一行。
+pub fn summary(window: Duration, period: &str, temp_usage: (usize, u64)) -> String {
+    let events = EVENTS.lock().unwrap();
+    let recent = events
+        .iter()
+        .filter(|(at, _)| at.elapsed() < window)
+        .map(|(_, event)| event);
+
+    let (mut succeeded, mut cancelled, mut images, mut bytes, mut new_chats) = (0, 0, 0, 0, 0);
+    let mut failures = Vec::new();
+    for event in recent {
+        match event {
+            Event::Job {
+                id,
+                status,
+                images: job_images,
+                bytes: job_bytes,
+            } => {
+                match status {
+                    JobStatus::Succeeded => succeeded += 1,
+                    JobStatus::Failed => failures.push(*id),
+                    JobStatus::Cancelled => cancelled += 1,
+                }
+                images += job_images;
+                bytes += job_bytes;
+            }
+            Event::NewChat => new_chats += 1,
+        }
+    }
+
+    let (temp_dirs, temp_bytes) = temp_usage;
+    let jobs = succeeded + cancelled + failures.len();
+    if jobs == 0 {
+        return format!(
+            "📊 过去 {} 没有任务，新会话 {} 个，临时文件占用 {}",
+            period,
+            new_chats,
+            format_size(temp_bytes)
+        );
+    }
+
+    let mut text = format!(
+        "📊 过去 {} 的运行情况\n任务：{} 个（成功 {}，失败 {}，取消 {}）\n打包的文件：{} 个\n发送的压缩包：{}\n新会话：{} 个\n临时文件：{} 个目录，共 {}",
+        period,
+        jobs,
+        succeeded,
+        failures.len(),
+        cancelled,
+        images,
+        format_size(bytes),
+        new_chats,
+        temp_dirs,
+        format_size(temp_bytes)
+    );
+    if !failures.is_empty() {
+        text.push_str("\n\n失败的任务：");
+        for id in failures.iter().rev().take(FAILURES_LISTED) {
+            text.push_str(&format!("\n· {}", id));
+        }
+        if failures.len() > FAILURES_LISTED {
+            text.push_str(&format!(
+                "\n· 以及更早的 {} 个",
+                failures.len() - FAILURES_LISTED
+            ));
+        }
+    }
+    text
+}
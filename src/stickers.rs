@@ -0,0 +1,112 @@
+//! 贴纸的收集
+//!
+//! 三种贴纸都按原文件打包：静态贴纸为 `.webp`，视频贴纸为 `.webm`，
+//! 动态贴纸为 gzip 压缩的 Lottie JSON（`.tgs`）。启用 `lottie` 特性时
+//! 还会把动态贴纸的第一帧渲染为 `.png`，方便没有 Lottie 播放器时预览。
+
+use std::path::Path;
+use teloxide::types::{Sticker, StickerFormat};
+
+/// 贴纸文件的扩展名
+pub fn extension(sticker: &Sticker) -> &'static str {
+    match sticker.format() {
+        StickerFormat::Static => "webp",
+        StickerFormat::Animated => "tgs",
+        StickerFormat::Video => "webm",
+    }
+}
+
+/// 一个任务中各类贴纸的数量
+#[derive(Debug, Default)]
+pub struct StickerCounts {
+    pub still: usize,
+    pub animated: usize,
+    pub video: usize,
+}
+
+impl StickerCounts {
+    /// 按扩展名计数，动态贴纸渲染出的预览图不计入
+    pub fn record(&mut self, extension: &str) {
+        match extension {
+            "webp" => self.still += 1,
+            "tgs" => self.animated += 1,
+            "webm" => self.video += 1,
+            _ => {}
+        }
+    }
+
+    /// 结果中的说明，没有贴纸时为 `None`
+    pub fn describe(&self) -> Option<String> {
+        let kinds = [
+            ("静态", self.still),
+            ("动态", self.animated),
+            ("视频", self.video),
+        ];
+        let total = kinds.iter().map(|(_, count)| count).sum::<usize>();
+        if total == 0 {
+            return None;
+        }
+        let kinds = kinds
+            .iter()
+            .filter(|(_, count)| *count > 0)
+            .map(|(name, count)| format!("{} {}", name, count))
+            .collect::<Vec<_>>()
+            .join("，");
+        Some(format!("{} 个贴纸（{}）", total, kinds))
+    }
+}
+
+/// 贴纸包的链接，记录在 README.txt 中
+pub fn set_link(set_name: &str) -> String {
+    format!("https://t.me/addstickers/{}", set_name)
+}
+
+/// 将动态贴纸 `tgs` 的第一帧渲染为 PNG 并写入 `png`
+///
+/// 同步执行，需要在阻塞线程中调用。
+#[cfg(feature = "lottie")]
+pub fn render_first_frame(
+    tgs: &Path,
+    png: &Path,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    use std::io::Read;
+
+    let mut json = Vec::new();
+    flate2::read::GzDecoder::new(std::fs::File::open(tgs)?).read_to_end(&mut json)?;
+    let mut animation =
+        rlottie::Animation::from_data(json, "", "").ok_or("无法解析 Lottie 动画")?;
+    let size = animation.size();
+    let mut surface = rlottie::Surface::new(size);
+    animation.render(0, &mut surface);
+
+    // rlottie 输出预乘 alpha 的 BGRA，转换为普通的 RGBA
+    let mut pixels = Vec::with_capacity(size.width * size.height * 4);
+    for pixel in surface.data() {
+        let unmultiply = |channel: u8| match pixel.a {
+            0 => 0,
+            alpha => (u16::from(channel) * 255 / u16::from(alpha)).min(255) as u8,
+        };
+        pixels.extend_from_slice(&[
+            unmultiply(pixel.r),
+            unmultiply(pixel.g),
+            unmultiply(pixel.b),
+            pixel.a,
+        ]);
+    }
+    image::save_buffer(
+        png,
+        &pixels,
+        size.width as u32,
+        size.height as u32,
+        image::ExtendedColorType::Rgba8,
+    )?;
+    Ok(())
+}
+
+#[cfg(not(feature = "lottie"))]
+pub fn render_first_frame(
+    _tgs: &Path,
+    _png: &Path,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    Err("编译时没有启用 lottie 功能".into())
+}
@@ -0,0 +1,228 @@
+//! 将收集到的图片发布为 telegraph 网页
+
+use reqwest::Client;
+use reqwest::multipart::{Form, Part};
+use serde_json::{Value, json};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// telegraph 的 API 地址
+const API_URL: &str = "https://api.telegra.ph";
+/// telegraph 的图片上传地址
+const UPLOAD_URL: &str = "https://telegra.ph/upload";
+/// telegraph 接受的单张图片大小上限
+pub const MAX_IMAGE_SIZE: usize = 5 * 1024 * 1024;
+/// 页面标题的最大长度
+const TITLE_LIMIT: usize = 256;
+
+/// telegraph 请求失败的原因
+#[derive(Debug)]
+pub enum TelegraphError {
+    Request(reqwest::Error),
+    Io(std::io::Error),
+    /// telegraph 返回了错误或无法解析的内容
+    Api(String),
+    /// 图片超过了大小上限，且无法缩小
+    TooLarge(usize),
+}
+
+impl fmt::Display for TelegraphError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TelegraphError::Request(why) => write!(f, "网络请求失败: {}", why),
+            TelegraphError::Io(why) => write!(f, "读写文件失败: {}", why),
+            TelegraphError::Api(why) => write!(f, "telegraph 返回错误: {}", why),
+            TelegraphError::TooLarge(size) => write!(
+                f,
+                "图片大小 {} 超过了 telegraph {} 的限制",
+                crate::units::format_size(*size as u64),
+                crate::units::format_size(MAX_IMAGE_SIZE as u64)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TelegraphError {}
+
+impl From<reqwest::Error> for TelegraphError {
+    fn from(why: reqwest::Error) -> Self {
+        TelegraphError::Request(why)
+    }
+}
+
+impl From<std::io::Error> for TelegraphError {
+    fn from(why: std::io::Error) -> Self {
+        TelegraphError::Io(why)
+    }
+}
+
+/// telegraph 客户端，账号的 access token 保存在 `token_file` 中，第一次使用时创建账号
+#[derive(Debug)]
+pub struct Telegraph {
+    client: Client,
+    token_file: PathBuf,
+}
+
+impl Telegraph {
+    pub fn new(client: Client, token_file: impl Into<PathBuf>) -> Self {
+        Telegraph {
+            client,
+            token_file: token_file.into(),
+        }
+    }
+
+    /// 读取保存的 access token，没有时创建账号并保存
+    async fn access_token(&self) -> Result<String, TelegraphError> {
+        if let Ok(token) = tokio::fs::read_to_string(&self.token_file).await {
+            let token = token.trim();
+            if !token.is_empty() {
+                return Ok(token.to_string());
+            }
+        }
+
+        let result = self
+            .call(
+                "createAccount",
+                &[
+                    ("short_name", "images-bot"),
+                    ("author_name", "telegram-images-bot"),
+                ],
+            )
+            .await?;
+        let token = result["access_token"]
+            .as_str()
+            .ok_or_else(|| TelegraphError::Api("没有返回 access_token".to_string()))?
+            .to_string();
+        tokio::fs::write(&self.token_file, &token).await?;
+        log::info!(
+            "已创建 telegraph 账号，token 保存在 {}",
+            self.token_file.display()
+        );
+        Ok(token)
+    }
+
+    /// 调用 telegraph API，返回 `result` 字段
+    async fn call(&self, method: &str, params: &[(&str, &str)]) -> Result<Value, TelegraphError> {
+        let bytes = self
+            .client
+            .post(format!("{}/{}", API_URL, method))
+            .form(params)
+            .send()
+            .await?
+            .bytes()
+            .await?;
+        let mut response: Value =
+            serde_json::from_slice(&bytes).map_err(|why| TelegraphError::Api(why.to_string()))?;
+        if response["ok"].as_bool() != Some(true) {
+            let error = response["error"].as_str().unwrap_or("未知错误");
+            return Err(TelegraphError::Api(error.to_string()));
+        }
+        Ok(response["result"].take())
+    }
+
+    /// 上传一张图片，返回图片在 telegraph 上的地址
+    ///
+    /// 超过 [`MAX_IMAGE_SIZE`] 的图片会先缩小，未启用 `imaging` 特性时无法缩小。
+    pub async fn upload_image(&self, path: &Path) -> Result<String, TelegraphError> {
+        let bytes = fit_size(tokio::fs::read(path).await?)?;
+        let file_name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("image.jpg")
+            .to_string();
+        let form = Form::new().part("file", Part::bytes(bytes).file_name(file_name));
+        let bytes = self
+            .client
+            .post(UPLOAD_URL)
+            .multipart(form)
+            .send()
+            .await?
+            .bytes()
+            .await?;
+
+        // 成功时返回 `[{"src": "/file/xxx.jpg"}]`，失败时返回 `{"error": "..."}`
+        let response: Value =
+            serde_json::from_slice(&bytes).map_err(|why| TelegraphError::Api(why.to_string()))?;
+        match response[0]["src"].as_str() {
+            Some(src) => Ok(format!("https://telegra.ph{}", src)),
+            None => Err(TelegraphError::Api(
+                response["error"].as_str().unwrap_or("未知错误").to_string(),
+            )),
+        }
+    }
+
+    /// 按顺序创建包含 `images`（图片地址和说明文字）的页面，返回页面地址
+    pub async fn create_page(
+        &self,
+        title: &str,
+        images: &[(String, Option<String>)],
+    ) -> Result<String, TelegraphError> {
+        let content = images
+            .iter()
+            .map(|(src, caption)| {
+                let mut children = vec![json!({ "tag": "img", "attrs": { "src": src } })];
+                if let Some(caption) = caption {
+                    children.push(json!({ "tag": "figcaption", "children": [caption] }));
+                }
+                json!({ "tag": "figure", "children": children })
+            })
+            .collect::<Vec<_>>();
+        let title = title.chars().take(TITLE_LIMIT).collect::<String>();
+        let token = self.access_token().await?;
+        let result = self
+            .call(
+                "createPage",
+                &[
+                    ("access_token", &token),
+                    ("title", &title),
+                    ("content", &Value::Array(content).to_string()),
+                ],
+            )
+            .await?;
+        result["url"]
+            .as_str()
+            .map(str::to_string)
+            .ok_or_else(|| TelegraphError::Api("没有返回页面地址".to_string()))
+    }
+}
+
+/// 保证图片不超过 [`MAX_IMAGE_SIZE`]，超过时逐步缩小并重新编码为 JPEG
+#[cfg(feature = "imaging")]
+fn fit_size(bytes: Vec<u8>) -> Result<Vec<u8>, TelegraphError> {
+    if bytes.len() <= MAX_IMAGE_SIZE {
+        return Ok(bytes);
+    }
+    let original_size = bytes.len();
+    let mut image = image::load_from_memory(&bytes)
+        .map_err(|why| TelegraphError::Api(format!("无法解析图片: {}", why)))?;
+    let mut size = original_size;
+    // 编码后的大小大致与像素数成正比，每次按比例缩小，最多尝试几次
+    for _ in 0..4 {
+        let scale = (MAX_IMAGE_SIZE as f64 / size as f64).sqrt() * 0.9;
+        let width = ((image.width() as f64 * scale) as u32).max(1);
+        let height = ((image.height() as f64 * scale) as u32).max(1);
+        image = image.resize(width, height, image::imageops::FilterType::Triangle);
+
+        let mut encoded = Vec::new();
+        image
+            .to_rgb8()
+            .write_to(
+                &mut std::io::Cursor::new(&mut encoded),
+                image::ImageFormat::Jpeg,
+            )
+            .map_err(|why| TelegraphError::Api(format!("无法编码图片: {}", why)))?;
+        if encoded.len() <= MAX_IMAGE_SIZE {
+            return Ok(encoded);
+        }
+        size = encoded.len();
+    }
+    Err(TelegraphError::TooLarge(original_size))
+}
+
+#[cfg(not(feature = "imaging"))]
+fn fit_size(bytes: Vec<u8>) -> Result<Vec<u8>, TelegraphError> {
+    if bytes.len() > MAX_IMAGE_SIZE {
+        return Err(TelegraphError::TooLarge(bytes.len()));
+    }
+    Ok(bytes)
+}
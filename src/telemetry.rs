@@ -0,0 +1,170 @@
+//! 日志和链路追踪的初始化
+//!
+//! 设置了 `LOG_DIR` 时日志同时写入该目录，按 `LOG_ROTATION` 轮换，保留 `LOG_RETENTION` 个文件。
+//!
+//! 启用 `otel` 特性并设置了 `OTEL_EXPORTER_OTLP_ENDPOINT` 时，每个任务的 span 会导出到
+//! OTLP collector，同一个任务的下载、打包和发送都在以任务id区分的同一条链路中。
+
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+/// 需要持有到程序结束，drop 时导出还没有发送的追踪数据
+/// 默认的日志级别：本程序输出调试信息，依赖库只输出警告，设置 `RUST_LOG` 时以它为准
+const DEFAULT_LOG_FILTER: &str =
+    "info,telegram_images_bot=debug,reqwest=warn,hyper=warn,hyper_util=warn,h2=warn,rustls=warn";
+
+/// 日志文件的后台写入线程，drop 时写完剩余的日志
+static LOG_FILE: Mutex<Option<WorkerGuard>> = Mutex::new(None);
+
+#[must_use]
+pub struct Telemetry {
+    #[cfg(feature = "otel")]
+    provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+/// 初始化日志输出，以及配置了 OTLP collector 时的追踪导出
+pub fn init() -> Telemetry {
+    let (filter, directives) = match std::env::var("RUST_LOG") {
+        Ok(directives) if !directives.trim().is_empty() => (
+            EnvFilter::try_new(&directives).expect("RUST_LOG is invalid"),
+            directives,
+        ),
+        _ => (
+            EnvFilter::new(DEFAULT_LOG_FILTER),
+            DEFAULT_LOG_FILTER.to_string(),
+        ),
+    };
+    let log_dir = std::env::var("LOG_DIR")
+        .ok()
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from);
+    let (file_layer, log_file) = match &log_dir {
+        Some(dir) => {
+            let (writer, guard) = tracing_appender::non_blocking(log_file_appender(dir));
+            let layer = tracing_subscriber::fmt::layer()
+                .with_ansi(false)
+                .with_writer(writer);
+            (Some(layer), Some(guard))
+        }
+        None => (None, None),
+    };
+    *LOG_FILE.lock().unwrap() = log_file;
+    let registry = tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer())
+        .with(file_layer);
+
+    #[cfg(feature = "otel")]
+    {
+        use opentelemetry::trace::TracerProvider;
+        let provider = otlp_provider();
+        let layer = provider.as_ref().map(|provider| {
+            tracing_opentelemetry::layer().with_tracer(provider.tracer("telegram-images-bot"))
+        });
+        registry.with(layer).init();
+        log_destination(log_dir.as_deref(), &directives);
+        if provider.is_some() {
+            log::info!("已启用链路追踪导出");
+        }
+        Telemetry { provider }
+    }
+    #[cfg(not(feature = "otel"))]
+    {
+        registry.init();
+        log_destination(log_dir.as_deref(), &directives);
+        Telemetry {}
+    }
+}
+
+/// 日志文件名为 `telegram-images-bot.<日期>.log`，超过保留数量时删除最旧的
+fn log_file_appender(dir: &std::path::Path) -> RollingFileAppender {
+    let rotation = match std::env::var("LOG_ROTATION")
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase()
+        .as_str()
+    {
+        "" | "daily" => Rotation::DAILY,
+        "hourly" => Rotation::HOURLY,
+        "never" => Rotation::NEVER,
+        other => panic!(
+            "LOG_ROTATION is invalid: {:?}, expected daily, hourly or never",
+            other
+        ),
+    };
+    let retention = match std::env::var("LOG_RETENTION") {
+        Ok(count) => count.trim().parse().expect("LOG_RETENTION is invalid"),
+        Err(_) => 7,
+    };
+    RollingFileAppender::builder()
+        .rotation(rotation)
+        .filename_prefix("telegram-images-bot")
+        .filename_suffix("log")
+        .max_log_files(retention)
+        .build(dir)
+        .unwrap_or_else(|why| panic!("无法在 {} 中创建日志文件: {}", dir.display(), why))
+}
+
+fn log_destination(log_dir: Option<&std::path::Path>, directives: &str) {
+    log::info!("日志级别：{}，可以通过 RUST_LOG 修改", directives);
+    match log_dir {
+        Some(dir) => log::info!(
+            "日志输出到标准输出和 {}/telegram-images-bot.<日期>.log",
+            dir.display()
+        ),
+        None => log::info!("日志只输出到标准输出，设置 LOG_DIR 可以同时写入文件"),
+    }
+}
+
+/// 根据 `OTEL_EXPORTER_OTLP_ENDPOINT` 创建导出器，没有设置时返回 `None`
+#[cfg(feature = "otel")]
+fn otlp_provider() -> Option<opentelemetry_sdk::trace::SdkTracerProvider> {
+    use opentelemetry_otlp::WithExportConfig;
+
+    let endpoint = std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT")
+        .ok()
+        .filter(|endpoint| !endpoint.is_empty())?;
+    // 端点来自环境变量时导出器会自动加上 `/v1/traces`
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_protocol(opentelemetry_otlp::Protocol::HttpBinary)
+        .build()
+        .unwrap_or_else(|why| {
+            panic!(
+                "OTEL_EXPORTER_OTLP_ENDPOINT {} is invalid: {}",
+                endpoint, why
+            )
+        });
+    let resource = opentelemetry_sdk::Resource::builder()
+        .with_service_name("telegram-images-bot")
+        .build();
+    Some(
+        opentelemetry_sdk::trace::SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(resource)
+            .build(),
+    )
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        #[cfg(feature = "otel")]
+        if let Some(provider) = self.provider.take()
+            && let Err(why) = provider.shutdown()
+        {
+            log::warn!("导出剩余的追踪数据失败: {}", why);
+        }
+        LOG_FILE.lock().unwrap().take();
+    }
+}
+
+/// 写完日志文件后退出，`std::process::exit` 不会运行析构函数，直接调用会丢失最后的日志
+pub fn exit(code: i32) -> ! {
+    LOG_FILE.lock().unwrap().take();
+    std::process::exit(code)
+}
@@ -0,0 +1,108 @@
+//! 测试中使用的telegram消息，通过 Bot API 的 JSON 格式构造
+
+use serde_json::{Value, json};
+use teloxide::types::{Me, Message};
+
+/// 测试消息所在的私聊会话和发送者
+pub const CHAT_ID: i64 = 1000;
+
+/// 发送时间为 `date`（unix 时间戳）的消息，`fields` 中的字段会覆盖默认值
+pub fn message(id: i32, date: i64, fields: Value) -> Message {
+    let mut message = json!({
+        "message_id": id,
+        "date": date,
+        "chat": {"id": CHAT_ID, "type": "private", "first_name": "Alice"},
+        "from": {"id": CHAT_ID, "is_bot": false, "first_name": "Alice"},
+    });
+    if let (Value::Object(message), Value::Object(fields)) = (&mut message, fields) {
+        message.extend(fields);
+    }
+    serde_json::from_value(message).expect("test message should deserialize")
+}
+
+/// 转发的消息，`original_date` 为原消息的发送时间
+pub fn forwarded(id: i32, date: i64, original_date: i64) -> Message {
+    message(
+        id,
+        date,
+        json!({
+            "text": format!("message {}", id),
+            "forward_origin": {
+                "type": "hidden_user",
+                "sender_user_name": "Bob",
+                "date": original_date,
+            },
+        }),
+    )
+}
+
+/// 测试机器人的用户名
+pub const BOT_USERNAME: &str = "images_bot";
+
+/// 测试机器人自己的信息，与 getMe 的返回相同
+pub fn me() -> Me {
+    serde_json::from_value(json!({
+        "id": 42,
+        "is_bot": true,
+        "first_name": "Images",
+        "username": BOT_USERNAME,
+        "can_join_groups": true,
+        "can_read_all_group_messages": false,
+        "supports_inline_queries": false,
+        "can_connect_to_business": false,
+        "has_main_web_app": false,
+    }))
+    .expect("test bot should deserialize")
+}
+
+/// 文字消息
+pub fn text(id: i32, text: &str) -> Message {
+    message(id, 0, json!({ "text": text }))
+}
+
+/// 测试群组的id
+pub const GROUP_ID: i64 = -1001000;
+
+/// 群组中的文字消息
+pub fn group_text(id: i32, text: &str) -> Message {
+    message(
+        id,
+        0,
+        json!({
+            "text": text,
+            "chat": {"id": GROUP_ID, "type": "supergroup", "title": "Group"},
+        }),
+    )
+}
+
+/// 以文件形式发送的消息，`mime_type` 为 `None` 时不带类型
+pub fn document(id: i32, file_name: &str, mime_type: Option<&str>) -> Message {
+    let mut document = json!({
+        "file_id": format!("document_{}", id),
+        "file_unique_id": format!("unique_{}", id),
+        "file_name": file_name,
+        "file_size": 1024,
+    });
+    if let Some(mime_type) = mime_type {
+        document["mime_type"] = json!(mime_type);
+    }
+    message(id, 0, json!({ "document": document }))
+}
+
+/// 包含一张图片的消息，`sizes` 为各个尺寸的宽和高
+pub fn photo(id: i32, date: i64, sizes: &[(u32, u32)]) -> Message {
+    let sizes = sizes
+        .iter()
+        .enumerate()
+        .map(|(index, (width, height))| {
+            json!({
+                "file_id": format!("photo_{}_{}", id, index),
+                "file_unique_id": format!("unique_{}_{}", id, index),
+                "width": width,
+                "height": height,
+                "file_size": width * height,
+            })
+        })
+        .collect::<Vec<_>>();
+    message(id, date, json!({ "photo": sizes }))
+}
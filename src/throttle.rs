@@ -0,0 +1,143 @@
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// 所有下载共享的令牌桶限速器
+///
+/// 每写入一块数据前先预定同样数量的令牌，令牌不足时等待补充。
+/// 速率为0时不限速。
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// 每秒允许的字节数
+    rate: u64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// 当前可用的令牌，为负数时表示已经预支
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Bucket {
+    /// 预定 `bytes` 个令牌，返回需要等待的时间
+    ///
+    /// 桶的容量为一秒的速率。令牌不足时先预支，调用者需要等到欠下的令牌补回为止。
+    fn reserve(&mut self, bytes: u64, rate: u64, now: Instant) -> Duration {
+        if rate == 0 {
+            return Duration::ZERO;
+        }
+        let rate = rate as f64;
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(rate);
+        self.last_refill = now;
+
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate)
+        }
+    }
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64) -> Self {
+        RateLimiter {
+            rate,
+            bucket: Mutex::new(Bucket {
+                tokens: rate as f64,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// 每秒允许的字节数，0表示不限速
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    /// 预定写入 `bytes` 字节，返回写入前需要等待的时间
+    pub fn reserve(&self, bytes: usize) -> Duration {
+        if self.rate == 0 {
+            return Duration::ZERO;
+        }
+        self.bucket
+            .lock()
+            .unwrap()
+            .reserve(bytes as u64, self.rate, Instant::now())
+    }
+
+    /// 在限速下传输 `bytes` 字节大约需要的时间，不限速时返回 `None`
+    pub fn estimate(&self, bytes: u64) -> Option<Duration> {
+        (self.rate > 0).then(|| Duration::from_secs_f64(bytes as f64 / self.rate as f64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u64 = 1000;
+
+    fn full_bucket(now: Instant) -> Bucket {
+        Bucket {
+            tokens: RATE as f64,
+            last_refill: now,
+        }
+    }
+
+    #[test]
+    fn burst_up_to_one_second_is_free() {
+        let now = Instant::now();
+        let mut bucket = full_bucket(now);
+        assert_eq!(bucket.reserve(600, RATE, now), Duration::ZERO);
+        assert_eq!(bucket.reserve(400, RATE, now), Duration::ZERO);
+        assert_eq!(bucket.reserve(500, RATE, now), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn debt_is_repaid_over_time() {
+        let now = Instant::now();
+        let mut bucket = full_bucket(now);
+        // 预支了2000字节，需要等两秒才能补回
+        assert_eq!(bucket.reserve(3000, RATE, now), Duration::from_secs(2));
+        // 一秒后还欠1000字节，再写100字节需要等1.1秒
+        let later = now + Duration::from_secs(1);
+        assert_eq!(
+            bucket.reserve(100, RATE, later),
+            Duration::from_millis(1100)
+        );
+        // 债务还清后恢复到无需等待
+        let repaid = later + Duration::from_millis(1100);
+        assert_eq!(bucket.reserve(0, RATE, repaid), Duration::ZERO);
+    }
+
+    #[test]
+    fn idle_time_does_not_exceed_capacity() {
+        let now = Instant::now();
+        let mut bucket = full_bucket(now);
+        let later = now + Duration::from_secs(60);
+        assert_eq!(bucket.reserve(1000, RATE, later), Duration::ZERO);
+        assert_eq!(bucket.reserve(1000, RATE, later), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn zero_rate_never_waits() {
+        let now = Instant::now();
+        let mut bucket = full_bucket(now);
+        assert_eq!(bucket.reserve(u64::MAX, 0, now), Duration::ZERO);
+        let limiter = RateLimiter::new(0);
+        assert_eq!(limiter.reserve(usize::MAX), Duration::ZERO);
+        assert_eq!(limiter.estimate(1000), None);
+    }
+
+    #[test]
+    fn estimate_uses_rate() {
+        let limiter = RateLimiter::new(RATE);
+        assert_eq!(limiter.estimate(2500), Some(Duration::from_millis(2500)));
+    }
+}
@@ -0,0 +1,64 @@
+//! 大小、时长和速度的格式化，保证进度消息和结果中的写法一致
+
+use std::time::Duration;
+
+/// 将字节数格式化为便于阅读的大小
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// 将时长格式化为便于阅读的分钟和秒
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs().max(1);
+    if secs < 60 {
+        format!("{} 秒", secs)
+    } else {
+        format!("{} 分 {} 秒", secs / 60, secs % 60)
+    }
+}
+
+/// 将每秒字节数格式化为速度
+pub fn format_speed(bytes_per_sec: f64) -> String {
+    format!("{}/s", format_size(bytes_per_sec as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(1024), "1.0 KB");
+        assert_eq!(format_size(35_861_709), "34.2 MB");
+        assert_eq!(format_size(5 * 1024 * 1024 * 1024), "5.0 GB");
+        // 没有更大的单位时继续使用 GB
+        assert_eq!(format_size(2048 * 1024 * 1024 * 1024), "2048.0 GB");
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(format_duration(Duration::ZERO), "1 秒");
+        assert_eq!(format_duration(Duration::from_millis(16_400)), "16 秒");
+        assert_eq!(format_duration(Duration::from_secs(60)), "1 分 0 秒");
+        assert_eq!(format_duration(Duration::from_secs(3725)), "62 分 5 秒");
+    }
+
+    #[test]
+    fn speeds() {
+        assert_eq!(format_speed(5.1 * 1024.0 * 1024.0), "5.1 MB/s");
+        assert_eq!(format_speed(512.0), "512 B/s");
+    }
+}
@@ -0,0 +1,118 @@
+//! 检查是否有新版本
+//!
+//! 设置 `UPDATE_CHECK=true` 后每天查询一次 GitHub 上最新的 release，版本比当前运行的
+//! [`crate::VERSION`] 新时通知管理员，同一个版本只通知一次。网络错误只记录在 debug 日志中，
+//! 不会影响用户。最近一次检查的结果保存在内存中，/version 会显示可用的新版本。
+
+use crate::markdown;
+use reqwest::Client;
+use serde_json::Value;
+use std::sync::Mutex;
+use std::time::Duration;
+use teloxide::Bot;
+use teloxide::types::ChatId;
+
+/// 查询最新 release 的接口
+const LATEST_RELEASE_URL: &str =
+    "https://api.github.com/repos/ChengCY-2254/telegram-images-bot/releases/latest";
+/// 两次检查之间的间隔
+const CHECK_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// 比当前版本新的最新 release
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Release {
+    pub tag: String,
+    /// release 页面，包含更新说明
+    pub url: String,
+}
+
+/// 最近一次检查发现的新版本
+static AVAILABLE: Mutex<Option<Release>> = Mutex::new(None);
+
+/// 最近一次检查发现的新版本，没有检查过或已经是最新版本时为 `None`
+pub fn available() -> Option<Release> {
+    AVAILABLE.lock().unwrap().clone()
+}
+
+/// 每天检查一次新版本，发现新版本时通知 `recipients`
+pub async fn run(bot: Bot, client: Client, recipients: Vec<ChatId>) {
+    log::info!("将每天检查一次新版本");
+    let mut notified: Option<String> = None;
+    loop {
+        match latest_release(&client).await {
+            Ok(release) => {
+                let newer = is_newer(&release.tag, crate::VERSION);
+                *AVAILABLE.lock().unwrap() = newer.then(|| release.clone());
+                if newer && notified.as_ref() != Some(&release.tag) {
+                    log::info!("发现新版本 {}", release.tag);
+                    let text = format!(
+                        "🆕 telegram-images-bot 有新版本 {} 可用，当前版本 {}\n更新说明：{}",
+                        release.tag,
+                        crate::VERSION.trim(),
+                        release.url
+                    );
+                    for &chat_id in &recipients {
+                        if let Err(why) = markdown::send(&bot, chat_id, None, text.as_str()).await {
+                            log::warn!("无法向 {} 发送新版本通知: {}", chat_id, why);
+                        }
+                    }
+                    notified = Some(release.tag);
+                }
+            }
+            Err(why) => log::debug!("无法检查新版本: {}", why),
+        }
+        tokio::time::sleep(CHECK_INTERVAL).await;
+    }
+}
+
+async fn latest_release(
+    client: &Client,
+) -> Result<Release, Box<dyn std::error::Error + Send + Sync>> {
+    let response: Value = client
+        .get(LATEST_RELEASE_URL)
+        .header(reqwest::header::ACCEPT, "application/vnd.github+json")
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    let tag = response["tag_name"]
+        .as_str()
+        .ok_or("release 中没有 tag_name")?;
+    let url = response["html_url"].as_str().unwrap_or_default();
+    Ok(Release {
+        tag: tag.to_string(),
+        url: url.to_string(),
+    })
+}
+
+/// `tag` 是否比当前版本 `current` 新
+///
+/// `current` 为Cargo包的版本号加上 `git describe` 的输出，例如 `0.1.0-v0.2.0-3-gabc1234`，
+/// 取其中能解析出的最高版本。无法解析 `tag` 时视为不是新版本。
+pub fn is_newer(tag: &str, current: &str) -> bool {
+    let Some(latest) = parse_version(tag) else {
+        return false;
+    };
+    let current = current
+        .trim()
+        .split('-')
+        .filter_map(parse_version)
+        .max()
+        .unwrap_or_default();
+    latest > current
+}
+
+/// 解析 `v1.2.3`、`1.2` 这样的版本号，至少需要主版本号和次版本号
+fn parse_version(text: &str) -> Option<(u64, u64, u64)> {
+    let text = text.trim().trim_start_matches(['v', 'V']);
+    let text = text.split(['-', '+']).next()?;
+    let mut parts = text.split('.').map(|part| part.parse::<u64>());
+    let major = parts.next()?.ok()?;
+    let minor = parts.next()?.ok()?;
+    let patch = match parts.next() {
+        Some(patch) => patch.ok()?,
+        None => 0,
+    };
+    Some((major, minor, patch))
+}
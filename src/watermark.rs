@@ -0,0 +1,187 @@
+//! 打包前给图片加上文字水印
+//!
+//! 需要启用 `watermark` 特性，并用 `WATERMARK_FONT` 指定字体文件，中文水印需要支持中文的字体。
+//! 水印为带深色阴影的白色文字，放在图片的一角，大小随图片的短边变化。
+//! 无法解码的文件保持原样，GIF 加水印会丢失动画，不做处理。
+
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+/// 水印文字的最大字符数
+pub const MAX_TEXT_CHARS: usize = 64;
+/// 默认的不透明度
+pub const DEFAULT_OPACITY: u8 = 60;
+
+/// 水印所在的角落
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Corner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    #[default]
+    BottomRight,
+}
+
+impl Corner {
+    /// 解析 `/watermark corner` 的参数
+    pub fn parse(arg: &str) -> Option<Self> {
+        match arg.trim().to_lowercase().as_str() {
+            "top-left" | "tl" | "左上" => Some(Corner::TopLeft),
+            "top-right" | "tr" | "右上" => Some(Corner::TopRight),
+            "bottom-left" | "bl" | "左下" => Some(Corner::BottomLeft),
+            "bottom-right" | "br" | "右下" => Some(Corner::BottomRight),
+            _ => None,
+        }
+    }
+
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Corner::TopLeft => "左上角",
+            Corner::TopRight => "右上角",
+            Corner::BottomLeft => "左下角",
+            Corner::BottomRight => "右下角",
+        }
+    }
+}
+
+/// 会话的水印设置
+#[derive(Debug, Clone)]
+pub struct Watermark {
+    pub text: String,
+    pub corner: Corner,
+    /// 不透明度，1-100
+    pub opacity: u8,
+}
+
+impl Watermark {
+    pub fn new(text: String) -> Self {
+        Watermark {
+            text,
+            corner: Corner::default(),
+            opacity: DEFAULT_OPACITY,
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        format!(
+            "「{}」，{}，不透明度 {}%",
+            self.text,
+            self.corner.describe(),
+            self.opacity
+        )
+    }
+}
+
+/// 可以加水印的图片扩展名
+pub fn supports(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            matches!(
+                extension.to_ascii_lowercase().as_str(),
+                "jpg" | "jpeg" | "png" | "webp" | "bmp"
+            )
+        })
+}
+
+/// 水印使用的字体文件，`Debug` 时只输出大小
+#[derive(Clone)]
+pub struct Font(Arc<[u8]>);
+
+impl Font {
+    /// 读取并检查字体文件，启动时调用
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let data = std::fs::read(path)?;
+        check_font(&data)?;
+        Ok(Font(data.into()))
+    }
+}
+
+impl fmt::Debug for Font {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Font({} bytes)", self.0.len())
+    }
+}
+
+#[cfg(feature = "watermark")]
+fn check_font(font: &[u8]) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    ab_glyph::FontRef::try_from_slice(font)?;
+    Ok(())
+}
+
+#[cfg(not(feature = "watermark"))]
+fn check_font(_font: &[u8]) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    Err("编译时没有启用 watermark 功能".into())
+}
+
+/// 给 `path` 的图片加上水印并按原格式写回
+///
+/// 同步执行，需要在阻塞线程中调用。
+#[cfg(feature = "watermark")]
+pub fn apply(
+    path: &Path,
+    watermark: &Watermark,
+    font: &Font,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    use ab_glyph::{FontRef, PxScale};
+    use image::{DynamicImage, GrayImage, ImageFormat, Luma};
+
+    let font = FontRef::try_from_slice(&font.0)?;
+    let format = ImageFormat::from_path(path)?;
+    let image = image::open(path)?;
+    let has_alpha = image.color().has_alpha();
+    let mut canvas = image.to_rgba8();
+    let (width, height) = canvas.dimensions();
+
+    let scale = PxScale::from((width.min(height) as f32 / 20.0).max(12.0));
+    let (text_width, text_height) = imageproc::drawing::text_size(scale, &font, &watermark.text);
+    let margin = (scale.y / 2.0) as u32;
+    let shadow = ((scale.y / 16.0) as u32).max(1);
+    // 文字先画在遮罩上，再按不透明度把阴影和文字叠加到图片上
+    let mut mask = GrayImage::new(text_width + shadow, text_height + shadow);
+    imageproc::drawing::draw_text_mut(&mut mask, Luma([255]), 0, 0, scale, &font, &watermark.text);
+    let left = match watermark.corner {
+        Corner::TopLeft | Corner::BottomLeft => margin,
+        Corner::TopRight | Corner::BottomRight => width.saturating_sub(mask.width() + margin),
+    };
+    let top = match watermark.corner {
+        Corner::TopLeft | Corner::TopRight => margin,
+        Corner::BottomLeft | Corner::BottomRight => height.saturating_sub(mask.height() + margin),
+    };
+    let opacity = f32::from(watermark.opacity.clamp(1, 100)) / 100.0;
+    for (offset, color) in [(shadow, 0.0), (0, 255.0)] {
+        for (x, y, Luma([coverage])) in mask.enumerate_pixels() {
+            let (x, y) = (left + x + offset, top + y + offset);
+            if *coverage == 0 || x >= width || y >= height {
+                continue;
+            }
+            let weight = f32::from(*coverage) / 255.0 * opacity;
+            let pixel = canvas.get_pixel_mut(x, y);
+            for channel in &mut pixel.0[..3] {
+                *channel = (f32::from(*channel) * (1.0 - weight) + color * weight).round() as u8;
+            }
+        }
+    }
+
+    let image = match has_alpha {
+        true => DynamicImage::ImageRgba8(canvas),
+        false => DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(canvas).to_rgb8()),
+    };
+    if format == ImageFormat::Jpeg {
+        let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        image::codecs::jpeg::JpegEncoder::new_with_quality(file, 90).encode_image(&image)?;
+    } else {
+        image.save_with_format(path, format)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "watermark"))]
+pub fn apply(
+    _path: &Path,
+    _watermark: &Watermark,
+    _font: &Font,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    Err("编译时没有启用 watermark 功能".into())
+}
@@ -0,0 +1,281 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use teloxide::types::ChatId;
+use uuid::Uuid;
+
+/// 所有临时目录名称的前缀
+const TEMP_PREFIX: &str = "temp_";
+
+/// 创建存放临时目录的位置并确认可以写入，启动时调用
+///
+/// 目录中有用户发送的文件，在 unix 上权限设置为 0700。
+pub async fn prepare_root(root: &Path) -> std::io::Result<()> {
+    tokio::fs::create_dir_all(root).await?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        tokio::fs::set_permissions(root, std::fs::Permissions::from_mode(0o700)).await?;
+    }
+    // 只读的文件系统上创建目录可能成功（目录已经存在），需要实际写入一次
+    let probe = root.join(format!(".write_test_{}", Uuid::new_v4()));
+    tokio::fs::write(&probe, b"").await?;
+    tokio::fs::remove_file(&probe).await
+}
+
+/// 打包任务下载图片和生成压缩包使用的临时目录
+pub fn job_dir(root: &Path, chat_id: ChatId, job_id: Uuid) -> PathBuf {
+    root.join(format!("{}{}_{}", TEMP_PREFIX, chat_id.0, job_id))
+}
+
+/// 自检使用的临时目录
+pub fn self_test_dir(root: &Path, id: Uuid) -> PathBuf {
+    root.join(format!("{}selftest_{}", TEMP_PREFIX, id))
+}
+
+/// /avatar 使用的临时目录
+pub fn avatar_dir(root: &Path, id: Uuid) -> PathBuf {
+    root.join(format!("{}avatar_{}", TEMP_PREFIX, id))
+}
+
+/// 删除 `root` 中所有临时目录里至少 `min_age` 没有修改过的，返回删除的数量
+///
+/// 用于启动时清理之前崩溃遗留的目录。
+pub async fn remove_stale(root: &Path, min_age: Duration) -> usize {
+    remove_matching(root, TEMP_PREFIX, min_age).await
+}
+
+/// 删除会话遗留的所有临时目录，返回删除的数量
+pub async fn remove_chat_dirs(root: &Path, chat_id: ChatId) -> usize {
+    let prefix = format!("{}{}_", TEMP_PREFIX, chat_id.0);
+    remove_matching(root, &prefix, Duration::ZERO).await
+}
+
+/// 错误是否由磁盘空间不足（ENOSPC）或超出磁盘配额（EDQUOT）引起，沿着 `source` 逐层查找
+pub fn is_out_of_space(error: &(dyn std::error::Error + 'static)) -> bool {
+    let mut current = Some(error);
+    while let Some(error) = current {
+        if let Some(why) = error.downcast_ref::<std::io::Error>()
+            && matches!(
+                why.kind(),
+                std::io::ErrorKind::StorageFull | std::io::ErrorKind::QuotaExceeded
+            )
+        {
+            return true;
+        }
+        current = error.source();
+    }
+    false
+}
+
+/// 一个临时目录的大小和最后修改至今的时间
+#[derive(Debug)]
+pub struct TempEntry {
+    pub name: String,
+    pub size: u64,
+    /// 无法获取修改时间时为 `None`
+    pub age: Option<Duration>,
+}
+
+/// 列出 `root` 中的所有临时目录，按大小从大到小排列
+///
+/// 遍历目录树可能较慢，在阻塞线程中进行，不会占用异步运行时。
+pub async fn list(root: &Path) -> Vec<TempEntry> {
+    let root = root.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let Ok(entries) = std::fs::read_dir(&root) else {
+            return Vec::new();
+        };
+        let mut entries = entries
+            .flatten()
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with(TEMP_PREFIX))
+            .map(|entry| TempEntry {
+                name: entry.file_name().to_string_lossy().into_owned(),
+                size: tree_size(&entry.path()),
+                age: entry
+                    .metadata()
+                    .and_then(|metadata| metadata.modified())
+                    .ok()
+                    .and_then(|modified| modified.elapsed().ok()),
+            })
+            .collect::<Vec<_>>();
+        entries.sort_by_key(|entry| std::cmp::Reverse(entry.size));
+        entries
+    })
+    .await
+    .unwrap_or_default()
+}
+
+/// `root` 中所有临时目录的数量和总大小
+pub async fn usage(root: &Path) -> (usize, u64) {
+    let entries = list(root).await;
+    (entries.len(), entries.iter().map(|entry| entry.size).sum())
+}
+
+/// 检查新任务需要的 `estimate` 字节能否放进 `quota`，`quota` 为0时不限制
+///
+/// 超出时先删除至少 `min_age` 没有修改过的临时目录再检查一次，仍然超出时返回当前占用的大小。
+pub async fn ensure_quota(
+    root: &Path,
+    quota: u64,
+    estimate: u64,
+    min_age: Duration,
+) -> Result<(), u64> {
+    if quota == 0 {
+        return Ok(());
+    }
+    let (_, used) = usage(root).await;
+    if used.saturating_add(estimate) <= quota {
+        return Ok(());
+    }
+    let removed = remove_stale(root, min_age).await;
+    log::info!(
+        "临时文件占用 {} 字节，加上新任务超过了配额，已清理 {} 个遗留的临时目录",
+        used,
+        removed
+    );
+    let (_, used) = usage(root).await;
+    if used.saturating_add(estimate) <= quota {
+        Ok(())
+    } else {
+        Err(used)
+    }
+}
+
+/// 文件或目录的总大小，不跟随符号链接，无法读取的部分忽略
+fn tree_size(path: &Path) -> u64 {
+    let Ok(metadata) = std::fs::symlink_metadata(path) else {
+        return 0;
+    };
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+    std::fs::read_dir(path)
+        .map(|entries| {
+            entries
+                .flatten()
+                .map(|entry| tree_size(&entry.path()))
+                .sum()
+        })
+        .unwrap_or_default()
+}
+
+async fn remove_matching(root: &Path, prefix: &str, min_age: Duration) -> usize {
+    let Ok(mut entries) = tokio::fs::read_dir(root).await else {
+        return 0;
+    };
+    let mut removed = 0;
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        if !entry.file_name().to_string_lossy().starts_with(prefix) {
+            continue;
+        }
+        let Ok(metadata) = entry.metadata().await else {
+            continue;
+        };
+        // 无法获取修改时间时视为足够旧
+        let age = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.elapsed().ok())
+            .unwrap_or(Duration::MAX);
+        if !metadata.is_dir() || age < min_age {
+            continue;
+        }
+        match tokio::fs::remove_dir_all(entry.path()).await {
+            Ok(()) => removed += 1,
+            Err(why) => log::error!("无法删除 {}: {}", entry.path().display(), why),
+        }
+    }
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn prepares_nested_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("a").join("b");
+        prepare_root(&root).await.unwrap();
+        // 检查写入的文件已经删除
+        assert_eq!(std::fs::read_dir(&root).unwrap().count(), 0);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&root).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o700);
+        }
+    }
+
+    #[tokio::test]
+    async fn unusable_root_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file");
+        std::fs::write(&file, b"").unwrap();
+        assert!(prepare_root(&file.join("root")).await.is_err());
+    }
+
+    /// 在 `root` 中创建一个临时目录，包含一个 `size` 字节的文件，修改时间为 `age` 之前
+    fn temp_dir(root: &Path, name: &str, size: usize, age: Duration) -> PathBuf {
+        let dir = root.join(format!("{}{}", TEMP_PREFIX, name));
+        std::fs::create_dir_all(dir.join("nested")).unwrap();
+        std::fs::write(dir.join("nested").join("image.jpg"), vec![0; size]).unwrap();
+        let modified = std::time::SystemTime::now() - age;
+        std::fs::File::open(&dir)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        dir
+    }
+
+    const HOUR: Duration = Duration::from_secs(60 * 60);
+
+    #[tokio::test]
+    async fn usage_counts_only_temp_dirs() {
+        let root = tempfile::tempdir().unwrap();
+        temp_dir(root.path(), "1_a", 100, Duration::ZERO);
+        temp_dir(root.path(), "2_b", 300, Duration::ZERO);
+        std::fs::write(root.path().join("stats.json"), vec![0; 1000]).unwrap();
+
+        assert_eq!(usage(root.path()).await, (2, 400));
+        let names = list(root.path())
+            .await
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["temp_2_b", "temp_1_a"]);
+    }
+
+    #[tokio::test]
+    async fn quota_allows_jobs_that_fit() {
+        let root = tempfile::tempdir().unwrap();
+        let leftover = temp_dir(root.path(), "1_a", 100, 2 * HOUR);
+        // 没有设置配额时不检查
+        assert_eq!(ensure_quota(root.path(), 0, u64::MAX, HOUR).await, Ok(()));
+        assert_eq!(ensure_quota(root.path(), 200, 100, HOUR).await, Ok(()));
+        // 没有超出配额时不清理
+        assert!(leftover.exists());
+    }
+
+    #[tokio::test]
+    async fn quota_removes_leftovers_first() {
+        let root = tempfile::tempdir().unwrap();
+        let leftover = temp_dir(root.path(), "1_a", 100, 2 * HOUR);
+        let running = temp_dir(root.path(), "2_b", 100, Duration::ZERO);
+
+        assert_eq!(ensure_quota(root.path(), 250, 150, HOUR).await, Ok(()));
+        assert!(!leftover.exists());
+        assert!(running.exists());
+    }
+
+    #[tokio::test]
+    async fn quota_refuses_when_still_full() {
+        let root = tempfile::tempdir().unwrap();
+        let leftover = temp_dir(root.path(), "1_a", 100, 2 * HOUR);
+        let running = temp_dir(root.path(), "2_b", 200, Duration::ZERO);
+
+        assert_eq!(ensure_quota(root.path(), 250, 100, HOUR).await, Err(200));
+        assert!(!leftover.exists());
+        assert!(running.exists());
+    }
+}